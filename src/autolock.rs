@@ -0,0 +1,204 @@
+use crate::storage::{self, KeyValueStore};
+use gloo::console;
+use js_sys::Date;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::window;
+
+const SETTINGS_KEY: &str = "lockSettings";
+// The private key encrypted with the lock passphrase. While this exists the
+// plain key is not stored, so its presence means auto-lock is on.
+const PROTECTED_KEY: &str = "protectedSecretKey";
+const CHECK_INTERVAL_MS: i32 = 10_000;
+const ACTIVITY_EVENTS: [&str; 5] = [
+    "pointerdown",
+    "pointermove",
+    "keydown",
+    "touchstart",
+    "scroll",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LockSettings {
+    // Minutes without input before locking, 0 to never lock when idle
+    pub idle_minutes: u32,
+    // Minutes the tab may stay in the background before locking
+    pub hidden_minutes: Option<u32>,
+}
+
+impl Default for LockSettings {
+    fn default() -> Self {
+        Self {
+            idle_minutes: 10,
+            hidden_minutes: Some(1),
+        }
+    }
+}
+
+struct Timer {
+    settings: LockSettings,
+    // Only set while the app is unlocked with a protected key
+    armed: bool,
+    last_activity: f64,
+    hidden_since: Option<f64>,
+    on_lock: Rc<dyn Fn()>,
+}
+
+thread_local! {
+    static TIMER: RefCell<Option<Timer>> = const { RefCell::new(None) };
+}
+
+// Installs the activity and visibility listeners. `on_lock` is called once
+// each time the timer fires; it has to be armed again after unlocking.
+pub fn start(on_lock: impl Fn() + 'static) {
+    let Some(window) = window() else {
+        return;
+    };
+    TIMER.with(|timer| {
+        *timer.borrow_mut() = Some(Timer {
+            settings: LockSettings::default(),
+            armed: false,
+            last_activity: Date::now(),
+            hidden_since: None,
+            on_lock: Rc::new(on_lock),
+        })
+    });
+
+    let activity = Closure::wrap(Box::new(move |_event: web_sys::Event| {
+        TIMER.with(|timer| {
+            if let Some(timer) = timer.borrow_mut().as_mut() {
+                timer.last_activity = Date::now();
+            }
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    for event in ACTIVITY_EVENTS {
+        // scroll does not bubble, so listen in the capture phase
+        let _ = window.add_event_listener_with_callback_and_bool(
+            event,
+            activity.as_ref().unchecked_ref(),
+            true,
+        );
+    }
+    activity.forget();
+
+    if let Some(document) = window.document() {
+        let visibility = Closure::wrap(Box::new(move |_event: web_sys::Event| {
+            let hidden = web_sys::window()
+                .and_then(|w| w.document())
+                .map(|d| d.hidden())
+                .unwrap_or(false);
+            if hidden {
+                TIMER.with(|timer| {
+                    if let Some(timer) = timer.borrow_mut().as_mut() {
+                        timer.hidden_since = Some(Date::now());
+                    }
+                });
+            }
+            // 非表示中はタイマーが間引かれるので、表示に戻った時にも確認する
+            check();
+            if !hidden {
+                TIMER.with(|timer| {
+                    if let Some(timer) = timer.borrow_mut().as_mut() {
+                        timer.hidden_since = None;
+                    }
+                });
+            }
+        }) as Box<dyn FnMut(web_sys::Event)>);
+        let _ = document.add_event_listener_with_callback(
+            "visibilitychange",
+            visibility.as_ref().unchecked_ref(),
+        );
+        visibility.forget();
+    }
+
+    let interval = Closure::wrap(Box::new(check) as Box<dyn FnMut()>);
+    let _ = window.set_interval_with_callback_and_timeout_and_arguments_0(
+        interval.as_ref().unchecked_ref(),
+        CHECK_INTERVAL_MS,
+    );
+    interval.forget();
+}
+
+// Starts counting from now with the given settings
+pub fn arm(settings: LockSettings) {
+    let now = Date::now();
+    let hidden = window()
+        .and_then(|w| w.document())
+        .map(|d| d.hidden())
+        .unwrap_or(false);
+    TIMER.with(|timer| {
+        if let Some(timer) = timer.borrow_mut().as_mut() {
+            timer.settings = settings;
+            timer.armed = true;
+            timer.last_activity = now;
+            timer.hidden_since = hidden.then_some(now);
+        }
+    });
+}
+
+pub fn disarm() {
+    TIMER.with(|timer| {
+        if let Some(timer) = timer.borrow_mut().as_mut() {
+            timer.armed = false;
+        }
+    });
+}
+
+fn check() {
+    let now = Date::now();
+    let on_lock = TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        let timer = timer.as_mut().filter(|timer| timer.armed)?;
+        let minutes = |m: u32| f64::from(m) * 60_000.0;
+        let idle = timer.settings.idle_minutes > 0
+            && now - timer.last_activity >= minutes(timer.settings.idle_minutes);
+        let hidden = match (timer.hidden_since, timer.settings.hidden_minutes) {
+            (Some(since), Some(limit)) => now - since >= minutes(limit),
+            _ => false,
+        };
+        if !idle && !hidden {
+            return None;
+        }
+        timer.armed = false;
+        Some(timer.on_lock.clone())
+    });
+    if let Some(on_lock) = on_lock {
+        console::log!("🔒 Auto-lock triggered");
+        on_lock();
+    }
+}
+
+pub async fn load_settings() -> LockSettings {
+    match storage::store().await.get_json(SETTINGS_KEY).await {
+        Ok(settings) => settings.unwrap_or_default(),
+        Err(e) => {
+            console::error!(&format!("❌ Failed to load lock settings: {}", e));
+            LockSettings::default()
+        }
+    }
+}
+
+pub async fn save_settings(settings: &LockSettings) -> Result<(), String> {
+    let json = serde_json::to_vec(settings).map_err(|e| e.to_string())?;
+    storage::store().await.set(SETTINGS_KEY, &json).await
+}
+
+pub async fn protected_key() -> Result<Option<Vec<u8>>, String> {
+    storage::store().await.get(PROTECTED_KEY).await
+}
+
+// Replaces the stored private key with its passphrase-encrypted form
+pub async fn protect_key(data: &[u8]) -> Result<(), String> {
+    let store = storage::store().await;
+    store.set(PROTECTED_KEY, data).await?;
+    store.remove("mySecretKey").await
+}
+
+// Called whenever a plain private key is saved again
+pub async fn remove_protection() -> Result<(), String> {
+    storage::store().await.remove(PROTECTED_KEY).await
+}
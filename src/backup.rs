@@ -0,0 +1,490 @@
+use crate::groups::ContactGroup;
+use crate::qr::QrSettings;
+use crate::rtc::IceSettings;
+use crate::{contacts_from_legacy, sort_contacts, ChatMessage, Contact, KeyPair, Profile};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const BACKUP_FORMAT: &str = "qr-encrypt-backup";
+// Version 2 stores contact records instead of a name to key map. Version 3
+// keeps chat history by peer public key. Version 4 adds the ICE settings,
+// TURN credentials included.
+pub const BACKUP_VERSION: u32 = 4;
+
+// The plaintext inside the passphrase-encrypted age file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupBundle {
+    pub format: String,
+    pub version: u32,
+    pub created_at: u64,
+    pub identities: Vec<KeyPair>,
+    pub contacts: Vec<Contact>,
+    #[serde(default)]
+    pub groups: Vec<ContactGroup>,
+    pub qr_settings: QrSettings,
+    // None in backups made before version 4
+    #[serde(default)]
+    pub ice_settings: Option<IceSettings>,
+    pub profile: Profile,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_history: Option<HashMap<String, Vec<ChatMessage>>>,
+}
+
+#[derive(Deserialize)]
+struct BackupHeader {
+    format: String,
+    version: u32,
+}
+
+impl BackupBundle {
+    // `now` is the time recorded on contacts upgraded from a version 1 backup
+    pub fn parse(json: &str, now: u64) -> Result<Self, String> {
+        let mut value = serde_json::from_str::<serde_json::Value>(json)
+            .map_err(|_| "This file is not a qr-encrypt backup".to_string())?;
+        let header = serde_json::from_value::<BackupHeader>(value.clone())
+            .map_err(|_| "This file is not a qr-encrypt backup".to_string())?;
+        if header.format != BACKUP_FORMAT {
+            return Err("This file is not a qr-encrypt backup".to_string());
+        }
+        if header.version > BACKUP_VERSION {
+            return Err(format!(
+                "Backup version {} is newer than this app supports ({})",
+                header.version, BACKUP_VERSION
+            ));
+        }
+        if header.version < 2 {
+            let legacy =
+                serde_json::from_value::<HashMap<String, String>>(value["contacts"].take())
+                    .map_err(|e| format!("Backup is damaged: {}", e))?;
+            value["contacts"] = serde_json::to_value(contacts_from_legacy(legacy, now))
+                .map_err(|e| e.to_string())?;
+        }
+        if header.version < 3 {
+            // Older backups do not say whose messages they are, so they
+            // cannot be put back into the right conversation
+            if let Some(bundle) = value.as_object_mut() {
+                bundle.remove("chat_history");
+            }
+        }
+        serde_json::from_value(value).map_err(|e| format!("Backup is damaged: {}", e))
+    }
+}
+
+// Result of merging a backup into the current data. Nothing is applied
+// until the user has seen the summary.
+#[derive(Clone, Default)]
+pub struct ImportPlan {
+    pub contacts: Vec<Contact>,
+    pub added: Vec<String>,
+    // Contacts we already have whose notes, tags or flags were merged
+    pub updated: Vec<String>,
+    // (name in the backup, existing contact with the same key)
+    pub already_known: Vec<(String, String)>,
+    // Added contacts that share a name with a different existing contact
+    pub shared_names: Vec<String>,
+    pub unchanged: usize,
+    pub groups: Vec<ContactGroup>,
+    pub groups_added: Vec<String>,
+    // A backup identity that differs from ours
+    pub identity_conflict: Option<KeyPair>,
+    pub qr_settings: Option<QrSettings>,
+    pub qr_settings_kept: bool,
+    pub ice_settings: Option<IceSettings>,
+    pub ice_settings_kept: bool,
+    // Why the backup's ICE settings cannot be used
+    pub ice_settings_rejected: Option<String>,
+    pub profile: Option<Profile>,
+    pub profile_kept: bool,
+    // Messages by peer public key
+    pub chats: Vec<(String, Vec<ChatMessage>)>,
+}
+
+impl ImportPlan {
+    pub fn chat_message_count(&self) -> usize {
+        self.chats.iter().map(|(_, messages)| messages.len()).sum()
+    }
+}
+
+pub fn plan_import(
+    bundle: &BackupBundle,
+    my_keys: Option<&KeyPair>,
+    contacts: &[Contact],
+    groups: &[ContactGroup],
+    qr_settings: &QrSettings,
+    ice_settings: &IceSettings,
+    profile: &Profile,
+) -> ImportPlan {
+    let mut plan = ImportPlan {
+        contacts: contacts.to_vec(),
+        groups: groups.to_vec(),
+        ..ImportPlan::default()
+    };
+
+    // 連絡先は公開鍵で照合する
+    for imported in &bundle.contacts {
+        let Some(existing) = plan
+            .contacts
+            .iter_mut()
+            .find(|c| c.public_key == imported.public_key)
+        else {
+            if plan.contacts.iter().any(|c| c.name == imported.name) {
+                plan.shared_names.push(imported.name.clone());
+            }
+            plan.added.push(imported.name.clone());
+            plan.contacts.push(imported.clone());
+            continue;
+        };
+
+        let before = existing.clone();
+        if existing.notes.is_empty() {
+            existing.notes = imported.notes.clone();
+        }
+        for tag in &imported.tags {
+            if !existing.tags.contains(tag) {
+                existing.tags.push(tag.clone());
+            }
+        }
+        existing.verified |= imported.verified;
+        existing.added_at = existing.added_at.min(imported.added_at);
+        existing.last_used = existing.last_used.max(imported.last_used);
+        if existing.signing_key.is_none() {
+            existing.signing_key = imported.signing_key.clone();
+        }
+
+        if existing.name != imported.name {
+            plan.already_known
+                .push((imported.name.clone(), existing.name.clone()));
+        } else if *existing != before {
+            plan.updated.push(existing.name.clone());
+        } else {
+            plan.unchanged += 1;
+        }
+    }
+    sort_contacts(&mut plan.contacts);
+
+    // グループのメンバーは公開鍵を介して取り込み後の連絡先 ID に付け替える
+    for group in &bundle.groups {
+        if plan.groups.iter().any(|g| g.id == group.id) {
+            continue;
+        }
+        let mut group = group.clone();
+        for member in &mut group.members {
+            let merged = bundle
+                .contacts
+                .iter()
+                .find(|c| c.id == member.contact_id)
+                .and_then(|imported| {
+                    plan.contacts
+                        .iter()
+                        .find(|c| c.public_key == imported.public_key)
+                });
+            if let Some(contact) = merged {
+                member.contact_id = contact.id.clone();
+            }
+        }
+        plan.groups_added.push(group.name.clone());
+        plan.groups.push(group);
+    }
+
+    plan.identity_conflict = bundle
+        .identities
+        .iter()
+        .find(|identity| my_keys.map(|keys| keys != *identity).unwrap_or(true))
+        .cloned();
+
+    // 現在の設定が初期値のままならバックアップの設定を使う
+    if bundle.qr_settings != *qr_settings {
+        if *qr_settings == QrSettings::default() {
+            plan.qr_settings = Some(bundle.qr_settings.clone().normalized());
+        } else {
+            plan.qr_settings_kept = true;
+        }
+    }
+    if let Some(imported) = bundle.ice_settings.as_ref().map(IceSettings::normalized) {
+        if imported != *ice_settings {
+            // The settings are used as they are when connecting, so they get
+            // the same checks as in the settings dialog
+            let checked = IceSettings::parse_servers(&imported.servers.join("\n"))
+                .and_then(|_| imported.validate());
+            if let Err(e) = checked {
+                plan.ice_settings_rejected = Some(e);
+            } else if *ice_settings == IceSettings::default() {
+                plan.ice_settings = Some(imported);
+            } else {
+                plan.ice_settings_kept = true;
+            }
+        }
+    }
+    if bundle.profile != *profile {
+        if profile.display_name.is_empty() {
+            plan.profile = Some(bundle.profile.clone());
+        } else {
+            plan.profile_kept = true;
+        }
+    }
+
+    if let Some(history) = &bundle.chat_history {
+        plan.chats = history
+            .iter()
+            .filter(|(_, messages)| !messages.is_empty())
+            .map(|(peer, messages)| (peer.clone(), messages.clone()))
+            .collect();
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::GroupMember;
+    use serde_json::json;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn contact(name: &str, public_key: &str) -> Contact {
+        Contact::new(name.to_string(), public_key.to_string(), NOW - 60)
+    }
+
+    fn keys(public_key: &str) -> KeyPair {
+        KeyPair {
+            public_key: public_key.to_string(),
+            private_key: format!("AGE-SECRET-KEY-{}", public_key),
+        }
+    }
+
+    fn bundle(contacts: Vec<Contact>) -> BackupBundle {
+        BackupBundle {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created_at: NOW,
+            identities: vec![keys("age1me")],
+            contacts,
+            groups: Vec::new(),
+            qr_settings: QrSettings::default(),
+            ice_settings: Some(IceSettings::default()),
+            profile: Profile::default(),
+            chat_history: None,
+        }
+    }
+
+    fn plan(bundle: &BackupBundle, contacts: &[Contact], groups: &[ContactGroup]) -> ImportPlan {
+        plan_import(
+            bundle,
+            Some(&keys("age1me")),
+            contacts,
+            groups,
+            &QrSettings::default(),
+            &IceSettings::default(),
+            &Profile::default(),
+        )
+    }
+
+    #[test]
+    fn same_name_with_another_key_keeps_both() {
+        let alice = contact("Alice", "age1alice");
+        let plan = plan(&bundle(vec![contact("Alice", "age1other")]), &[alice], &[]);
+        assert_eq!(plan.added, vec!["Alice"]);
+        assert_eq!(plan.shared_names, vec!["Alice"]);
+        let keys: Vec<&str> = plan
+            .contacts
+            .iter()
+            .map(|c| c.public_key.as_str())
+            .collect();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&"age1alice") && keys.contains(&"age1other"));
+    }
+
+    #[test]
+    fn same_key_under_another_name_is_merged() {
+        let alice = contact("Alice", "age1alice");
+        let mut ally = contact("Ally", "age1alice");
+        ally.notes = "met at the conference".to_string();
+        ally.tags = vec!["work".to_string()];
+        ally.verified = true;
+        ally.added_at = NOW - 3600;
+
+        let plan = plan(&bundle(vec![ally]), std::slice::from_ref(&alice), &[]);
+        assert!(plan.added.is_empty());
+        assert_eq!(
+            plan.already_known,
+            vec![("Ally".to_string(), "Alice".to_string())]
+        );
+        assert_eq!(plan.contacts.len(), 1);
+        let merged = &plan.contacts[0];
+        assert_eq!(merged.id, alice.id);
+        assert_eq!(merged.name, "Alice");
+        assert_eq!(merged.notes, "met at the conference");
+        assert_eq!(merged.tags, vec!["work"]);
+        assert!(merged.verified);
+        assert_eq!(merged.added_at, NOW - 3600);
+    }
+
+    #[test]
+    fn identical_contacts_are_unchanged() {
+        let alice = contact("Alice", "age1alice");
+        let plan = plan(&bundle(vec![alice.clone()]), &[alice], &[]);
+        assert_eq!(plan.unchanged, 1);
+        assert!(plan.added.is_empty() && plan.updated.is_empty());
+    }
+
+    #[test]
+    fn group_members_follow_the_merged_contacts() {
+        let bob = contact("Bob", "age1bob");
+        let imported_bob = contact("Robert", "age1bob");
+        let carol = contact("Carol", "age1carol");
+        let mut backup = bundle(vec![imported_bob.clone(), carol.clone()]);
+        let group = ContactGroup {
+            id: "g1".to_string(),
+            name: "Team".to_string(),
+            members: vec![GroupMember::of(&imported_bob), GroupMember::of(&carol)],
+            created_at: NOW,
+        };
+        let existing = ContactGroup {
+            id: "g2".to_string(),
+            name: "Family".to_string(),
+            members: Vec::new(),
+            created_at: NOW,
+        };
+        backup.groups = vec![group, existing.clone()];
+
+        let plan = plan(&backup, std::slice::from_ref(&bob), &[existing]);
+        assert_eq!(plan.groups_added, vec!["Team"]);
+        assert_eq!(plan.groups.len(), 2);
+        let team = plan.groups.iter().find(|g| g.id == "g1").unwrap();
+        // Bob keeps the existing contact id, Carol is added with the backup one
+        let ids: Vec<&str> = team.members.iter().map(|m| m.contact_id.as_str()).collect();
+        assert_eq!(ids, vec![bob.id.as_str(), carol.id.as_str()]);
+        assert_eq!(team.resolve(&plan.contacts).public_keys.len(), 2);
+    }
+
+    #[test]
+    fn another_identity_is_a_conflict() {
+        let backup = bundle(Vec::new());
+        assert_eq!(plan(&backup, &[], &[]).identity_conflict, None);
+
+        let mut other = backup.clone();
+        other.identities = vec![keys("age1else")];
+        assert_eq!(
+            plan(&other, &[], &[]).identity_conflict,
+            Some(keys("age1else"))
+        );
+
+        // Without keys of our own the backup's identity is offered
+        let plan = plan_import(
+            &backup,
+            None,
+            &[],
+            &[],
+            &QrSettings::default(),
+            &IceSettings::default(),
+            &Profile::default(),
+        );
+        assert_eq!(plan.identity_conflict, Some(keys("age1me")));
+    }
+
+    #[test]
+    fn ice_settings_are_restored_only_over_defaults() {
+        let mut backup = bundle(Vec::new());
+        let custom = IceSettings {
+            servers: vec!["turn:turn.example:3478".to_string()],
+            turn_username: Some("alice".to_string()),
+            turn_credential: Some("secret".to_string()),
+            ..IceSettings::default()
+        };
+        backup.ice_settings = Some(custom.clone());
+        assert_eq!(plan(&backup, &[], &[]).ice_settings, Some(custom.clone()));
+
+        let mine = IceSettings {
+            host_only: true,
+            ..IceSettings::default()
+        };
+        let kept = plan_import(
+            &backup,
+            None,
+            &[],
+            &[],
+            &QrSettings::default(),
+            &mine,
+            &Profile::default(),
+        );
+        assert_eq!(kept.ice_settings, None);
+        assert!(kept.ice_settings_kept);
+
+        backup.ice_settings = Some(IceSettings {
+            turn_credential: None,
+            ..custom
+        });
+        let rejected = plan(&backup, &[], &[]);
+        assert_eq!(rejected.ice_settings, None);
+        assert!(rejected.ice_settings_rejected.is_some());
+    }
+
+    #[test]
+    fn version_1_backup_is_upgraded() {
+        let json = json!({
+            "format": BACKUP_FORMAT,
+            "version": 1,
+            "created_at": NOW - 86400,
+            "identities": [keys("age1me")],
+            "contacts": { "Bob": "age1bob", "alice": "age1alice" },
+            "qr_settings": QrSettings::default(),
+            "profile": Profile::default(),
+            "chat_history": { "Bob": [{ "content": "hi", "is_sent": true, "timestamp": 1.0 }] },
+        });
+        let bundle = BackupBundle::parse(&json.to_string(), NOW).unwrap();
+        let contacts: Vec<(&str, &str, u64)> = bundle
+            .contacts
+            .iter()
+            .map(|c| (c.name.as_str(), c.public_key.as_str(), c.added_at))
+            .collect();
+        assert_eq!(
+            contacts,
+            vec![("alice", "age1alice", NOW), ("Bob", "age1bob", NOW)]
+        );
+        assert!(bundle.groups.is_empty());
+        assert_eq!(bundle.chat_history, None);
+        assert_eq!(bundle.ice_settings, None);
+    }
+
+    #[test]
+    fn version_2_backup_drops_chat_history_by_name() {
+        let alice = contact("Alice", "age1alice");
+        let json = json!({
+            "format": BACKUP_FORMAT,
+            "version": 2,
+            "created_at": NOW,
+            "identities": [keys("age1me")],
+            "contacts": [alice],
+            "qr_settings": QrSettings::default(),
+            "profile": Profile::default(),
+            "chat_history": { "Alice": [{ "content": "hi", "is_sent": false, "timestamp": 1.0 }] },
+        });
+        let bundle = BackupBundle::parse(&json.to_string(), NOW).unwrap();
+        assert_eq!(bundle.contacts, vec![alice]);
+        assert_eq!(bundle.chat_history, None);
+
+        // A version 3 backup keeps its history by public key
+        let mut json = json;
+        json["version"] = json!(3);
+        json["chat_history"] =
+            json!({ "age1alice": [{ "content": "hi", "is_sent": false, "timestamp": 1.0 }] });
+        let bundle = BackupBundle::parse(&json.to_string(), NOW).unwrap();
+        assert_eq!(bundle.chat_history.map(|h| h.len()), Some(1));
+    }
+
+    #[test]
+    fn foreign_and_newer_files_are_rejected() {
+        let mut json = serde_json::to_value(bundle(Vec::new())).unwrap();
+        assert!(BackupBundle::parse(&json.to_string(), NOW).is_ok());
+        json["version"] = json!(BACKUP_VERSION + 1);
+        assert!(BackupBundle::parse(&json.to_string(), NOW)
+            .err()
+            .is_some_and(|e| e.contains("newer")));
+        json["format"] = json!("something-else");
+        assert_eq!(
+            BackupBundle::parse(&json.to_string(), NOW).err(),
+            Some("This file is not a qr-encrypt backup".to_string())
+        );
+        assert!(BackupBundle::parse("not json", NOW).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const CARD_VERSION: u8 = 1;
+
+// Domain separation strings, so that neither the derived key nor a card
+// signature can be reused in another context.
+const SIGNING_KEY_DOMAIN: &[u8] = b"qr-encrypt signing key v1";
+const CARD_SIGNATURE_DOMAIN: &str = "qr-encrypt contact card v1";
+
+// Short field names keep the QR code small.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContactCard {
+    #[serde(rename = "v")]
+    pub version: u8,
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "pk")]
+    pub public_key: String,
+    #[serde(rename = "vk", default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    #[serde(rename = "ts")]
+    pub created_at: u64,
+    #[serde(rename = "sig", default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+// A valid self-signature only shows that the card is consistent: it is made
+// with the signing key the card itself carries, so it does not prove who
+// owns the public key. See peer_auth for how a signing key gets confirmed.
+pub enum CardStatus {
+    Signed,
+    Unsigned,
+    Invalid(String),
+}
+
+// The Ed25519 key is derived from the age identity, so it survives a
+// private key export/import without being stored separately.
+pub fn signing_key_from_identity(private_key: &str) -> SigningKey {
+    let seed: [u8; 32] = Sha256::new()
+        .chain_update(SIGNING_KEY_DOMAIN)
+        .chain_update(private_key.trim().as_bytes())
+        .finalize()
+        .into();
+    SigningKey::from_bytes(&seed)
+}
+
+impl ContactCard {
+    pub fn sign(name: &str, public_key: &str, private_key: &str, created_at: u64) -> Self {
+        let signing_key = signing_key_from_identity(private_key);
+        let mut card = Self {
+            version: CARD_VERSION,
+            name: name.trim().to_string(),
+            public_key: public_key.to_string(),
+            signing_key: Some(BASE64.encode(signing_key.verifying_key().as_bytes())),
+            created_at,
+            signature: None,
+        };
+        let signature = signing_key.sign(&card.signed_bytes());
+        card.signature = Some(BASE64.encode(signature.to_bytes()));
+        card
+    }
+
+    // Returns None unless the data is a card of a known version carrying a
+    // valid age public key.
+    pub fn parse(data: &str) -> Option<Self> {
+        let card = serde_json::from_str::<Self>(data).ok()?;
+        if card.version != CARD_VERSION
+            || card.public_key.parse::<age::x25519::Recipient>().is_err()
+        {
+            return None;
+        }
+        Some(card)
+    }
+
+    pub fn to_payload(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn verify(&self) -> CardStatus {
+        let (Some(signing_key), Some(signature)) = (&self.signing_key, &self.signature) else {
+            return CardStatus::Unsigned;
+        };
+        let verifying_key = match decode_array(signing_key)
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string()))
+        {
+            Ok(key) => key,
+            Err(e) => return CardStatus::Invalid(format!("Invalid signing key: {}", e)),
+        };
+        let signature = match decode_array(signature) {
+            Ok(bytes) => Signature::from_bytes(&bytes),
+            Err(e) => return CardStatus::Invalid(format!("Invalid signature: {}", e)),
+        };
+        match verifying_key.verify(&self.signed_bytes(), &signature) {
+            Ok(()) => CardStatus::Signed,
+            Err(_) => CardStatus::Invalid("Signature does not match the card".to_string()),
+        }
+    }
+
+    // Short, human comparable form of the signing key
+    pub fn fingerprint(&self) -> Option<String> {
+        let key = BASE64.decode(self.signing_key.as_ref()?).ok()?;
+        let digest = Sha256::digest(key);
+        Some(
+            digest[..8]
+                .chunks(2)
+                .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            CARD_SIGNATURE_DOMAIN,
+            self.version,
+            &self.name,
+            &self.public_key,
+            &self.signing_key,
+            self.created_at,
+        ))
+        .unwrap_or_default()
+    }
+}
+
+pub fn decode_array<const N: usize>(data: &str) -> Result<[u8; N], String> {
+    BASE64
+        .decode(data)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| format!("expected {} bytes", N))
+}
@@ -24,6 +24,54 @@ pub enum WorkerMessage {
         event_type: String,
         event_data: String,
     },
+    QrImageDecoded {
+        data: String,
+    },
+    BackupEncrypted {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    BackupDecrypted {
+        data: String,
+    },
+    BackupFailed {
+        message: String,
+    },
+    KeyProtected {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    KeyUnlocked {
+        private_key: String,
+    },
+    KeyUnlockFailed {
+        message: String,
+    },
+    // Chat messages carry the connection they belong to
+    ChatEncrypted {
+        peer: String,
+        encrypted_data: String,
+    },
+    ChatDecrypted {
+        peer: String,
+        decrypted_data: String,
+    },
+    FileEncrypted {
+        id: String,
+        // Of the ciphertext, checked by the receiver before decrypting
+        sha256: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    FileDecrypted {
+        id: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    FileFailed {
+        id: String,
+        message: String,
+    },
     Error {
         message: String,
     },
@@ -34,7 +82,8 @@ pub enum WorkerMessage {
 pub enum MainMessage {
     GenerateKeyPair,
     Encrypt {
-        public_key: String,
+        // Every key can decrypt the result
+        public_keys: Vec<String>,
         data: String,
     },
     Decrypt {
@@ -51,11 +100,90 @@ pub enum MainMessage {
     ProcessQrData {
         data: String,
     },
+    DecodeQrImage {
+        #[serde(with = "serde_bytes")]
+        image: Vec<u8>,
+    },
+    EncryptBackup {
+        passphrase: String,
+        data: String,
+    },
+    DecryptBackup {
+        passphrase: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // The private key is kept encrypted with a passphrase while auto-lock is on
+    ProtectKey {
+        passphrase: String,
+        private_key: String,
+    },
+    UnlockKey {
+        passphrase: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    EncryptChat {
+        peer: String,
+        public_key: String,
+        data: String,
+    },
+    DecryptChat {
+        peer: String,
+        private_key: String,
+        data: String,
+    },
+    // Files sent over the data channel
+    EncryptFile {
+        id: String,
+        public_key: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    DecryptFile {
+        id: String,
+        private_key: String,
+        sha256: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum RtcMessage {
-    PublicKey { public_key: String },
-    EncryptedData { encrypted_data: String },
+    PublicKey {
+        public_key: String,
+    },
+    // The peer authentication exchange, see peer_auth
+    Challenge {
+        encrypted_challenge: String,
+    },
+    Proof {
+        proof: String,
+        signing_key: String,
+    },
+    // File transfers. The ciphertext follows as binary frames, see file_transfer
+    FileOffer {
+        id: String,
+        name: String,
+        size: u64,
+        encrypted_size: u64,
+        sha256: String,
+    },
+    // Where to continue from; 0 unless part of the file already arrived
+    FileAccept {
+        id: String,
+        offset: u64,
+    },
+    FileComplete {
+        id: String,
+    },
+    FileFailed {
+        id: String,
+        message: String,
+    },
+    EncryptedData {
+        encrypted_data: String,
+    },
 }
@@ -0,0 +1,426 @@
+use crate::common::RtcMessage;
+use crate::message_log::Direction;
+use crate::rtc::Connection;
+use wasm_bindgen::JsValue;
+
+// Larger messages are not delivered by every browser
+const CHUNK_SIZE: usize = 16 * 1024;
+// Sending stops above this many queued bytes and continues once the channel
+// has drained below LOW_BUFFERED
+const MAX_BUFFERED: u32 = 1024 * 1024;
+pub const LOW_BUFFERED: u32 = 256 * 1024;
+// Files are held in memory while they are encrypted, sent and decrypted
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Binary frame: transfer id (8 bytes), offset in the ciphertext (u64 BE),
+// then the chunk
+const ID_LEN: usize = 8;
+const HEADER_LEN: usize = ID_LEN + 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferStatus {
+    Encrypting,
+    // Offered to the peer, waiting for the offset to start from
+    Offered,
+    Transferring,
+    // Everything has arrived and the receiver is checking it
+    Verifying,
+    Complete,
+    Failed(String),
+}
+
+#[derive(Clone)]
+pub struct FileTransfer {
+    pub id: String,
+    pub peer_key: String,
+    pub direction: Direction,
+    pub name: String,
+    pub size: u64,
+    pub encrypted_size: u64,
+    // Base64 SHA-256 of the ciphertext
+    pub sha256: String,
+    // The age ciphertext being sent, or received so far
+    data: Vec<u8>,
+    sent: u64,
+    pub status: TransferStatus,
+    // The decrypted file, on the receiving side
+    pub file: Option<Vec<u8>>,
+}
+
+impl FileTransfer {
+    pub fn outgoing(id: String, peer_key: String, name: String, size: u64) -> Self {
+        Self {
+            id,
+            peer_key,
+            direction: Direction::Sent,
+            name,
+            size,
+            encrypted_size: 0,
+            sha256: String::new(),
+            data: Vec::new(),
+            sent: 0,
+            status: TransferStatus::Encrypting,
+            file: None,
+        }
+    }
+
+    pub fn incoming(
+        id: String,
+        peer_key: String,
+        name: String,
+        size: u64,
+        encrypted_size: u64,
+        sha256: String,
+    ) -> Result<Self, String> {
+        if decode_id(&id).is_none() {
+            return Err("Invalid transfer id".to_string());
+        }
+        // age adds a header and 16 bytes per 64 KiB chunk
+        if size > MAX_FILE_SIZE || encrypted_size > size + size / 1024 + 4096 {
+            return Err(format!(
+                "Files larger than {} MB cannot be received",
+                MAX_FILE_SIZE / (1024 * 1024)
+            ));
+        }
+        Ok(Self {
+            id,
+            peer_key,
+            direction: Direction::Received,
+            name,
+            size,
+            encrypted_size,
+            sha256,
+            data: Vec::new(),
+            sent: 0,
+            status: TransferStatus::Offered,
+            file: None,
+        })
+    }
+
+    pub fn set_encrypted(&mut self, data: Vec<u8>, sha256: String) {
+        self.encrypted_size = data.len() as u64;
+        self.sha256 = sha256;
+        self.data = data;
+        self.status = TransferStatus::Offered;
+    }
+
+    // Ciphertext bytes sent or received so far
+    pub fn transferred(&self) -> u64 {
+        match self.direction {
+            Direction::Sent => self.sent,
+            Direction::Received => self.data.len() as u64,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Complete | TransferStatus::Failed(_)
+        )
+    }
+
+    // Whether an offer continues this transfer rather than starting over
+    pub fn same_file(&self, name: &str, encrypted_size: u64, sha256: &str) -> bool {
+        self.name == name && self.encrypted_size == encrypted_size && self.sha256 == sha256
+    }
+
+    // The receiver answers with the offset to continue from
+    pub fn offer(&self) -> RtcMessage {
+        RtcMessage::FileOffer {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            size: self.size,
+            encrypted_size: self.encrypted_size,
+            sha256: self.sha256.clone(),
+        }
+    }
+
+    // The sender continues from where the receiver says it got to
+    pub fn start_at(&mut self, offset: u64) {
+        self.sent = offset.min(self.encrypted_size);
+        self.status = TransferStatus::Transferring;
+    }
+
+    // Appends a chunk received at `offset`. Returns true once the whole
+    // ciphertext has arrived.
+    pub fn receive(&mut self, offset: u64, chunk: &[u8]) -> bool {
+        if self.status != TransferStatus::Transferring || offset != self.data.len() as u64 {
+            return false;
+        }
+        if offset + chunk.len() as u64 > self.encrypted_size {
+            self.fail("The peer sent more data than it offered");
+            return false;
+        }
+        self.data.extend_from_slice(chunk);
+        if self.data.len() as u64 == self.encrypted_size {
+            self.status = TransferStatus::Verifying;
+            return true;
+        }
+        false
+    }
+
+    // The received ciphertext, handed over for decryption
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    pub fn complete(&mut self, file: Option<Vec<u8>>) {
+        self.status = TransferStatus::Complete;
+        self.data = Vec::new();
+        self.file = file;
+    }
+
+    // Partial data is dropped so that a retry starts from the beginning
+    pub fn fail(&mut self, message: &str) {
+        self.status = TransferStatus::Failed(message.to_string());
+        self.data = Vec::new();
+        self.sent = 0;
+    }
+
+    // Sends frames until the channel's buffer is full. Returns true once
+    // the last frame has been queued.
+    fn pump(&mut self, connection: &Connection) -> Result<bool, JsValue> {
+        let id = decode_id(&self.id).ok_or("Invalid transfer id")?;
+        while (self.sent as usize) < self.data.len() {
+            if connection.buffered_amount() >= MAX_BUFFERED {
+                return Ok(false);
+            }
+            let start = self.sent as usize;
+            let end = (start + CHUNK_SIZE).min(self.data.len());
+            connection.send_binary(&encode_frame(&id, self.sent, &self.data[start..end]))?;
+            self.sent = end as u64;
+        }
+        self.status = TransferStatus::Verifying;
+        Ok(true)
+    }
+}
+
+// Continues the outgoing transfers to `peer_key`, one after another
+pub fn pump(
+    transfers: &mut [FileTransfer],
+    peer_key: &str,
+    connection: &Connection,
+) -> Result<(), JsValue> {
+    for transfer in transfers.iter_mut().filter(|t| {
+        t.direction == Direction::Sent
+            && t.peer_key == peer_key
+            && t.status == TransferStatus::Transferring
+    }) {
+        if !transfer.pump(connection)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn encode_frame(id: &[u8; ID_LEN], offset: u64, chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+    frame.extend_from_slice(id);
+    frame.extend_from_slice(&offset.to_be_bytes());
+    frame.extend_from_slice(chunk);
+    frame
+}
+
+// Splits a binary frame into the transfer id, the offset and the chunk
+pub fn decode_frame(frame: &[u8]) -> Option<(String, u64, &[u8])> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    let id = frame[..ID_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let offset = u64::from_be_bytes(frame[ID_LEN..HEADER_LEN].try_into().ok()?);
+    Some((id, offset, &frame[HEADER_LEN..]))
+}
+
+// Transfer ids are 16 hex digits, as made by new_id()
+fn decode_id(id: &str) -> Option<[u8; ID_LEN]> {
+    if id.len() != ID_LEN * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; ID_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(id.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "0123456789abcdef";
+
+    // A receiver that has accepted an offer of `encrypted_size` bytes
+    fn receiving(encrypted_size: u64) -> FileTransfer {
+        let mut transfer = FileTransfer::incoming(
+            ID.to_string(),
+            "age1peer".to_string(),
+            "notes.txt".to_string(),
+            encrypted_size,
+            encrypted_size,
+            "hash".to_string(),
+        )
+        .unwrap();
+        transfer.status = TransferStatus::Transferring;
+        transfer
+    }
+
+    #[test]
+    fn chunks_in_order_complete_the_transfer() {
+        let mut transfer = receiving(10);
+        assert!(!transfer.receive(0, b"0123"));
+        assert!(!transfer.receive(4, b"4567"));
+        assert_eq!(transfer.transferred(), 8);
+        assert!(transfer.receive(8, b"89"));
+        assert_eq!(transfer.status, TransferStatus::Verifying);
+        assert_eq!(transfer.take_data(), b"0123456789");
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_chunks_are_ignored() {
+        let mut transfer = receiving(8);
+        assert!(!transfer.receive(4, b"4567"));
+        assert_eq!(transfer.transferred(), 0);
+        assert!(!transfer.receive(0, b"0123"));
+        // Sent again after a reconnect
+        assert!(!transfer.receive(0, b"0123"));
+        assert!(!transfer.receive(2, b"23"));
+        assert_eq!(transfer.transferred(), 4);
+        assert_eq!(transfer.status, TransferStatus::Transferring);
+        assert!(transfer.receive(4, b"4567"));
+        assert_eq!(transfer.take_data(), b"01234567");
+    }
+
+    #[test]
+    fn data_past_the_offered_size_fails() {
+        let mut transfer = receiving(6);
+        assert!(!transfer.receive(0, b"0123"));
+        assert!(!transfer.receive(4, b"4567"));
+        assert_eq!(
+            transfer.status,
+            TransferStatus::Failed("The peer sent more data than it offered".to_string())
+        );
+        // Partial data is dropped so that a retry starts over
+        assert_eq!(transfer.transferred(), 0);
+        assert!(!transfer.receive(0, b"0123"));
+    }
+
+    #[test]
+    fn chunks_are_only_taken_while_transferring() {
+        let mut transfer = receiving(4);
+        transfer.status = TransferStatus::Offered;
+        assert!(!transfer.receive(0, b"0123"));
+        assert_eq!(transfer.transferred(), 0);
+
+        transfer.status = TransferStatus::Transferring;
+        assert!(transfer.receive(0, b"0123"));
+        // Nothing more is taken while the ciphertext is checked
+        assert!(!transfer.receive(4, b""));
+        assert_eq!(transfer.status, TransferStatus::Verifying);
+    }
+
+    #[test]
+    fn resumes_from_the_received_offset() {
+        let mut receiver = receiving(10);
+        receiver.receive(0, b"01234");
+        // The connection drops and the sender offers the file again
+        receiver.status = TransferStatus::Offered;
+        assert!(receiver.same_file("notes.txt", 10, "hash"));
+        receiver.status = TransferStatus::Transferring;
+        let offset = receiver.transferred();
+        assert_eq!(offset, 5);
+
+        let mut sender = FileTransfer::outgoing(
+            ID.to_string(),
+            "age1me".to_string(),
+            "notes.txt".to_string(),
+            10,
+        );
+        sender.set_encrypted(b"0123456789".to_vec(), "hash".to_string());
+        sender.start_at(offset);
+        assert_eq!(sender.transferred(), 5);
+        assert_eq!(sender.status, TransferStatus::Transferring);
+
+        assert!(receiver.receive(offset, &sender.data[offset as usize..]));
+        assert_eq!(receiver.take_data(), sender.data);
+    }
+
+    #[test]
+    fn sender_offset_is_clamped() {
+        let mut sender = FileTransfer::outgoing(
+            ID.to_string(),
+            "age1me".to_string(),
+            "notes.txt".to_string(),
+            4,
+        );
+        sender.set_encrypted(b"0123".to_vec(), "hash".to_string());
+        sender.start_at(u64::MAX);
+        assert_eq!(sender.transferred(), 4);
+    }
+
+    #[test]
+    fn incoming_checks_the_size() {
+        let incoming = |size: u64, encrypted_size: u64| {
+            FileTransfer::incoming(
+                ID.to_string(),
+                "age1peer".to_string(),
+                "big.bin".to_string(),
+                size,
+                encrypted_size,
+                "hash".to_string(),
+            )
+        };
+        let too_large = Some("Files larger than 64 MB cannot be received".to_string());
+
+        assert!(incoming(MAX_FILE_SIZE, MAX_FILE_SIZE + MAX_FILE_SIZE / 1024 + 4096).is_ok());
+        assert_eq!(incoming(MAX_FILE_SIZE + 1, 0).err(), too_large.clone());
+        // The ciphertext cannot be much larger than the file
+        assert!(incoming(0, 4096).is_ok());
+        assert_eq!(incoming(0, 4097).err(), too_large.clone());
+        assert_eq!(incoming(1024, 1024 + 1 + 4097).err(), too_large);
+        assert!(incoming(u64::MAX, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn incoming_checks_the_id() {
+        for id in [
+            "",
+            "0123456789abcde",
+            "0123456789abcdef0",
+            "0123456789abcdeg",
+        ] {
+            assert!(FileTransfer::incoming(
+                id.to_string(),
+                "age1peer".to_string(),
+                "a".to_string(),
+                1,
+                1,
+                "hash".to_string(),
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let id = decode_id(ID).unwrap();
+        let frame = encode_frame(&id, 0x0102_0304_0506, b"chunk");
+        assert_eq!(frame.len(), HEADER_LEN + 5);
+        assert_eq!(
+            decode_frame(&frame),
+            Some((ID.to_string(), 0x0102_0304_0506, &b"chunk"[..]))
+        );
+        let empty = encode_frame(&id, 7, b"");
+        assert_eq!(decode_frame(&empty), Some((ID.to_string(), 7, &b""[..])));
+    }
+
+    #[test]
+    fn short_frames_are_rejected() {
+        let frame = encode_frame(&decode_id(ID).unwrap(), 1, b"");
+        for len in 0..HEADER_LEN {
+            assert_eq!(decode_frame(&frame[..len]), None);
+        }
+    }
+}
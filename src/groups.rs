@@ -0,0 +1,61 @@
+use crate::{new_id, unix_time, Contact};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContactGroup {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<GroupMember>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GroupMember {
+    pub contact_id: String,
+    // Name when the member was added, shown if the contact is deleted later
+    pub name: String,
+}
+
+pub struct GroupRecipients {
+    pub public_keys: Vec<String>,
+    // Names of members whose contact no longer exists
+    pub missing: Vec<String>,
+}
+
+impl ContactGroup {
+    pub fn new(name: String, members: &[&Contact]) -> Self {
+        Self {
+            id: new_id(),
+            name,
+            members: members.iter().map(|c| GroupMember::of(c)).collect(),
+            created_at: unix_time(),
+        }
+    }
+
+    pub fn resolve(&self, contacts: &[Contact]) -> GroupRecipients {
+        let mut recipients = GroupRecipients {
+            public_keys: Vec::new(),
+            missing: Vec::new(),
+        };
+        for member in &self.members {
+            match contacts.iter().find(|c| c.id == member.contact_id) {
+                Some(contact) => {
+                    if !recipients.public_keys.contains(&contact.public_key) {
+                        recipients.public_keys.push(contact.public_key.clone());
+                    }
+                }
+                None => recipients.missing.push(member.name.clone()),
+            }
+        }
+        recipients
+    }
+}
+
+impl GroupMember {
+    pub fn of(contact: &Contact) -> Self {
+        Self {
+            contact_id: contact.id.clone(),
+            name: contact.name.clone(),
+        }
+    }
+}
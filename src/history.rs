@@ -0,0 +1,249 @@
+use crate::{new_id, vault, ChatMessage};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Conversations are stored as vault records of this many messages each, so
+// only the newest page has to be decrypted when a chat opens.
+pub const PAGE_SIZE: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatIndex {
+    pub id: String,
+    pub pages: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatPage {
+    pub messages: Vec<ChatMessage>,
+    // The next older page, if there is one
+    pub older: Option<u32>,
+}
+
+// The newest page of a conversation. Appends go through this copy so
+// messages arriving back to back are never lost between read and write.
+struct OpenPage {
+    index: ChatIndex,
+    messages: Vec<ChatMessage>,
+}
+
+thread_local! {
+    // By peer public key; several conversations can be open at once
+    static OPEN_PAGES: RefCell<HashMap<String, OpenPage>> = RefCell::new(HashMap::new());
+    // Writes waiting to run, by peer. An entry exists while a write for that
+    // peer is running, and later writes only queue here.
+    static PENDING: RefCell<HashMap<String, Vec<Write>>> = RefCell::new(HashMap::new());
+}
+
+enum Write {
+    Append(ChatMessage),
+    // Messages from a backup, merged into the stored conversation
+    Import(Vec<ChatMessage>),
+}
+
+fn record_name(id: &str, page: u32) -> String {
+    format!("chat:{}:{}", id, page)
+}
+
+fn chat_index(peer: &str) -> Option<ChatIndex> {
+    vault::data()?.chats.get(peer).cloned()
+}
+
+// Loads the newest page of the conversation with `peer`
+pub async fn load_latest(peer: &str) -> Result<ChatPage, String> {
+    let index = chat_index(peer).unwrap_or_else(|| ChatIndex {
+        id: new_id(),
+        pages: 0,
+    });
+    let page = index.pages.saturating_sub(1);
+    let messages = match index.pages {
+        0 => Vec::new(),
+        _ => read_page(&index.id, page).await?,
+    };
+    // An append may have opened the page while we were reading it
+    let (messages, page) = OPEN_PAGES.with(|pages| {
+        let mut pages = pages.borrow_mut();
+        let open = pages
+            .entry(peer.to_string())
+            .or_insert(OpenPage { index, messages });
+        (open.messages.clone(), open.index.pages.saturating_sub(1))
+    });
+    Ok(ChatPage {
+        messages,
+        older: page.checked_sub(1),
+    })
+}
+
+pub async fn load_page(peer: &str, page: u32) -> Result<ChatPage, String> {
+    let index = chat_index(peer).ok_or("No chat history for this contact")?;
+    Ok(ChatPage {
+        messages: read_page(&index.id, page).await?,
+        older: page.checked_sub(1),
+    })
+}
+
+// Appends for the same peer are written one after another, in order
+pub async fn append(peer: &str, message: &ChatMessage) -> Result<(), String> {
+    run(peer, Write::Append(message.clone())).await
+}
+
+pub async fn import(peer: &str, messages: Vec<ChatMessage>) -> Result<(), String> {
+    run(peer, Write::Import(messages)).await
+}
+
+// Runs `write` now, or queues it behind the write already running for `peer`
+async fn run(peer: &str, write: Write) -> Result<(), String> {
+    let writing = PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        match pending.get_mut(peer) {
+            Some(queue) => {
+                queue.push(write);
+                true
+            }
+            None => {
+                pending.insert(peer.to_string(), vec![write]);
+                false
+            }
+        }
+    });
+    if writing {
+        return Ok(());
+    }
+
+    loop {
+        let queue = PENDING.with(|pending| {
+            let mut pending = pending.borrow_mut();
+            let queue = pending
+                .get_mut(peer)
+                .map(std::mem::take)
+                .unwrap_or_default();
+            if queue.is_empty() {
+                pending.remove(peer);
+            }
+            queue
+        });
+        if queue.is_empty() {
+            return Ok(());
+        }
+        for write in queue {
+            let result = match write {
+                Write::Append(message) => write_message(peer, &message).await,
+                Write::Import(messages) => write_import(peer, messages).await,
+            };
+            if let Err(e) = result {
+                // The next write starts over from the stored page
+                PENDING.with(|pending| pending.borrow_mut().remove(peer));
+                OPEN_PAGES.with(|pages| pages.borrow_mut().remove(peer));
+                return Err(e);
+            }
+        }
+    }
+}
+
+async fn write_message(peer: &str, message: &ChatMessage) -> Result<(), String> {
+    let is_open = OPEN_PAGES.with(|pages| pages.borrow().contains_key(peer));
+    if !is_open {
+        load_latest(peer).await?;
+    }
+
+    // ここから先は await するまで同期的に更新する
+    let (index, messages, new_page) = OPEN_PAGES.with(|pages| {
+        let mut pages = pages.borrow_mut();
+        let open = pages.get_mut(peer).ok_or("The conversation was closed")?;
+        let new_page = open.index.pages == 0 || open.messages.len() >= PAGE_SIZE;
+        if new_page {
+            open.index.pages += 1;
+            open.messages.clear();
+        }
+        open.messages.push(message.clone());
+        Ok::<_, String>((open.index.clone(), open.messages.clone(), new_page))
+    })?;
+
+    vault::write_record(&record_name(&index.id, index.pages - 1), &messages).await?;
+    if new_page {
+        let peer = peer.to_string();
+        vault::update(move |data| {
+            data.chats.insert(peer, index);
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+// Every stored conversation by peer public key, for backups
+pub async fn read_all_chats() -> Result<HashMap<String, Vec<ChatMessage>>, String> {
+    let chats = vault::data().ok_or("Vault is locked")?.chats;
+    let mut all = HashMap::new();
+    for (peer, index) in chats {
+        all.insert(peer, read_all(&index).await?);
+    }
+    Ok(all)
+}
+
+// Rewrites the conversation with the imported messages in timestamp order
+async fn write_import(peer: &str, messages: Vec<ChatMessage>) -> Result<(), String> {
+    let mut index = chat_index(peer).unwrap_or_else(|| ChatIndex {
+        id: new_id(),
+        pages: 0,
+    });
+    let mut all = read_all(&index).await?;
+    let stored = all.len();
+    for message in messages {
+        if !all.contains(&message) {
+            all.push(message);
+        }
+    }
+    if all.len() == stored {
+        return Ok(());
+    }
+    all.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+
+    // The cached page no longer matches what is stored
+    OPEN_PAGES.with(|pages| pages.borrow_mut().remove(peer));
+    let pages: Vec<&[ChatMessage]> = all.chunks(PAGE_SIZE).collect();
+    for (page, messages) in pages.iter().enumerate() {
+        vault::write_record(&record_name(&index.id, page as u32), messages).await?;
+    }
+    index.pages = pages.len() as u32;
+    let peer = peer.to_string();
+    vault::update(move |data| {
+        data.chats.insert(peer, index);
+    })
+    .await
+}
+
+pub async fn clear(peer: &str) -> Result<(), String> {
+    OPEN_PAGES.with(|pages| pages.borrow_mut().remove(peer));
+    let Some(index) = chat_index(peer) else {
+        return Ok(());
+    };
+    let peer = peer.to_string();
+    vault::update(move |data| {
+        data.chats.remove(&peer);
+    })
+    .await?;
+    for page in 0..index.pages {
+        vault::remove_record(&record_name(&index.id, page)).await?;
+    }
+    Ok(())
+}
+
+// Drops the cached pages, e.g. when the app locks
+pub fn close() {
+    OPEN_PAGES.with(|pages| pages.borrow_mut().clear());
+    PENDING.with(|pending| pending.borrow_mut().clear());
+}
+
+async fn read_all(index: &ChatIndex) -> Result<Vec<ChatMessage>, String> {
+    let mut messages = Vec::new();
+    for page in 0..index.pages {
+        messages.extend(read_page(&index.id, page).await?);
+    }
+    Ok(messages)
+}
+
+async fn read_page(id: &str, page: u32) -> Result<Vec<ChatMessage>, String> {
+    Ok(vault::read_record(&record_name(id, page))
+        .await?
+        .unwrap_or_default())
+}
@@ -1,12 +1,47 @@
+mod autolock;
+use autolock::LockSettings;
+mod backup;
+use backup::{BackupBundle, ImportPlan};
+mod card;
+use card::{CardStatus, ContactCard};
 mod common;
 use common::*;
+mod file_transfer;
+use file_transfer::{FileTransfer, TransferStatus};
+mod groups;
+use groups::ContactGroup;
+mod history;
+use history::ChatPage;
+mod qr;
+use qr::{QrEcLevel, QrSettings};
+mod message_log;
+use message_log::{Direction, LogEntry};
+mod migrations;
+mod peer_auth;
+use peer_auth::{PeerAuth, PeerStatus};
+mod peers;
+use peers::RtcPeer;
+mod recipients;
+use recipients::RecipientsDiff;
 mod rtc;
-use rtc::Connection;
+use rtc::{Connection, IceCandidate, IceSettings};
+mod sdp;
+mod signaling;
+use signaling::Session;
+mod storage;
+use storage::KeyValueStore;
+mod sync;
+use sync::SyncEvent;
+mod transport;
+use transport::LinkKind;
+mod vault;
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use gloo::{console, dialogs::alert};
+use gloo::{
+    console,
+    dialogs::{alert, confirm},
+};
 use js_sys::Date;
-use qrcode::QrCode;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::collections::HashMap;
@@ -15,31 +50,99 @@ use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::spawn_local;
 use web_sys::{
-    window, CanvasRenderingContext2d, CustomEvent, CustomEventInit, Event, HtmlCanvasElement,
-    HtmlElement, HtmlTextAreaElement, MessageEvent, Storage, Worker,
+    window, Blob, BlobPropertyBag, ClipboardEvent, CustomEvent, CustomEventInit, Event,
+    HtmlAnchorElement, HtmlCanvasElement, HtmlElement, HtmlTextAreaElement, MessageEvent, Url,
+    Worker,
 };
 use yew::prelude::*;
 
+// QRコードの表示サイズ（CSSピクセル）
+const QR_CANVAS_SIZE: u32 = 300;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct KeyPair {
     pub public_key: String,
     pub private_key: String,
 }
 
+// 連絡先カードに載せる自分の情報
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Profile {
+    pub display_name: String,
+    pub created_at: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "signal_type")]
 pub enum RtcSignalData {
     Offer { sdp_data: String },
     Answer { sdp_data: String },
+    // Found after the offer or answer was shown on the gathering deadline
+    Candidates { candidates: Vec<IceCandidate> },
+    // One of the above, encrypted to a contact and signed
+    Sealed { data: String },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Contact {
+    pub id: String,
     pub name: String,
     pub public_key: String,
+    #[serde(default)]
+    pub notes: String,
+    pub added_at: u64,
+    #[serde(default)]
+    pub last_used: Option<u64>,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // ピア認証で相手の鍵に結び付いていることを確認した署名鍵
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+impl Contact {
+    pub fn new(name: String, public_key: String, added_at: u64) -> Self {
+        Self {
+            id: new_id(),
+            name,
+            public_key,
+            notes: String::new(),
+            added_at,
+            last_used: None,
+            verified: false,
+            tags: Vec::new(),
+            signing_key: None,
+        }
+    }
+
+    // 同名の連絡先を見分けるための短い鍵表示
+    pub fn short_key(&self) -> String {
+        format!("{}…", self.public_key.get(..12).unwrap_or(&self.public_key))
+    }
+}
+
+// 以前の名前→公開鍵のマップを連絡先レコードに変換する
+pub fn contacts_from_legacy(map: HashMap<String, String>, added_at: u64) -> Vec<Contact> {
+    let mut contacts: Vec<Contact> = map
+        .into_iter()
+        .map(|(name, public_key)| Contact::new(name, public_key, added_at))
+        .collect();
+    sort_contacts(&mut contacts);
+    contacts
+}
+
+pub fn sort_contacts(contacts: &mut [Contact]) {
+    contacts.sort_by(|a, b| {
+        a.name
+            .to_lowercase()
+            .cmp(&b.name.to_lowercase())
+            .then(a.added_at.cmp(&b.added_at))
+    });
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ChatMessage {
     pub content: String,
     pub is_sent: bool,  // true for sent, false for received
@@ -50,7 +153,7 @@ pub struct ChatMessage {
 pub enum Msg {
     LoadMyKeys,
     GenerateKeys,
-    KeysLoaded(KeyPair, HashMap<String, String>),
+    KeysLoaded(KeyPair, Vec<Contact>),
     DrawQrCode(String),
     ShowQrReader,
     HideQrReader,
@@ -82,6 +185,10 @@ pub enum Msg {
     HideAddContactDialog,
     ConfirmAddContact(String),
     CancelAddContact,
+    ShowContactCardDialog(ContactCard),
+    ShowEditContact(String), // contact id
+    HideEditContact,
+    SaveContact(Contact),
     ShowResetConfirm,
     HideResetConfirm,
     ConfirmReset,
@@ -89,25 +196,113 @@ pub enum Msg {
     // RTC関連のメッセージ
     ShowRtcDialog,
     HideRtcDialog,
-    StartRtcConnection,
+    StartRtcConnection(Option<String>), // contact public key
     ProcessRtcSignal(String),
-    RtcConnectionEstablished,
-    SendPublicKeyViaRtc,
+    // The first String of the peer messages is the connection id
+    RtcConnectionEstablished(String),
+    RtcExtraCandidates(String, String), // peer, signal json
+    SendPublicKeyViaRtc(String),
     ShowChatView,
     HideChatView,
-    SendChatMessage(String),
-    UpdateChatInput(String),
-    AddChatMessage(String, bool), // content, is_sent
-    SetPeerPublicKey(String),
-    DecryptReceivedMessage(String),   // encrypted_data
-    SendEncryptedChatMessage(String), // encrypted_data
-    ClearChatHistory,
+    OpenChat(String),
+    CloseRtcConnection(String),
+    SendChatMessage(String, String),
+    UpdateChatInput(String, String),
+    AddChatMessage(String, String, bool), // peer, content, is_sent
+    SetPeerPublicKey(String, String),
+    PeerPublicKeyReceived(String, String),
+    PeerChallengeReceived(String, String), // peer, encrypted challenge
+    PeerProofReceived(String, String, String), // peer, proof, signing key
+    DecryptReceivedMessage(String, String), // peer, encrypted_data
+    SendEncryptedChatMessage(String, String), // peer, encrypted_data
+    ClearChatHistory(String),
+    // File transfers over the data channel
+    SendFile(String, web_sys::File),
+    FileEncrypted(String, Vec<u8>, String), // id, ciphertext, sha256
+    FileDecrypted(String, Vec<u8>),
+    FileTransferFailed(String, String), // id, message
+    FileTransferMessage(String, RtcMessage),
+    FileChunkReceived(String, Vec<u8>),
+    PumpFileTransfers(String),
+    ResumeFileTransfer(String),
+    DownloadFile(String),
+    DismissFileTransfer(String),
+    ChatHistoryLoaded(String, ChatPage), // peer_public_key, newest page
+    LoadOlderChatMessages(String),
+    OlderChatMessagesLoaded(String, ChatPage),
+    // QR settings
+    QrSettingsLoaded(QrSettings),
+    ShowQrSettingsDialog,
+    HideQrSettingsDialog,
+    SaveQrSettings(QrSettings),
+    QrCodeDrawn(String, String), // canvas_id, parameters or error
+    // ICE servers
+    ShowIceSettingsDialog,
+    HideIceSettingsDialog,
+    SaveIceSettings(IceSettings),
+    DownloadQrSvg(String), // canvas_id
+    DownloadQrPng(String), // canvas_id
+    HandleDeepLink(String),
+    DecodeQrImage(web_sys::File),
+    // Contact card
+    ProfileLoaded(Profile),
+    SaveDisplayName(String),
+    // Backup
+    ShowBackupDialog,
+    HideBackupDialog,
+    ExportBackup(String, bool), // passphrase, include_chat_history
+    BackupEncrypted(Vec<u8>),
+    ImportBackup(web_sys::File, String), // file, passphrase
+    BackupDecrypted(String),
+    BackupFailed(String),
+    ConfirmImportBackup(bool), // replace_identity
+    CancelImportBackup,
+    // Contact groups
+    ShowGroupDialog(Option<String>), // group id, None for a new group
+    HideGroupDialog,
+    SaveGroup(String, Vec<String>), // name, member contact ids
+    DeleteGroup(String),
+    // age recipients files
+    ExportRecipients,
+    ImportRecipients(web_sys::File),
+    RecipientsDiffReady(RecipientsDiff),
+    ConfirmRecipientsImport(bool), // replace_changed_keys
+    CancelRecipientsImport,
+    // Message log
+    MessageLogLoaded(Vec<LogEntry>),
+    LogMessage(LogEntry),
+    ShowMessageLog,
+    HideMessageLog,
+    UpdateMessageLogQuery(String),
+    SelectLogEntry(Option<String>), // entry id
+    DeleteLogEntry(String),
+    // Other tabs
+    Sync(SyncEvent),
+    VaultSynced,
+    MessageLogSynced(Vec<LogEntry>),
+    WaitForKeyGeneration,
+    // Storage migrations
+    MigrationFailed(String),
+    ExportRecoveryDump(String), // passphrase
+    // Auto-lock
+    LockSettingsLoaded(LockSettings, bool), // settings, key is protected
+    ShowLockSettings,
+    HideLockSettings,
+    EnableAutoLock(String, LockSettings), // passphrase, settings
+    KeyProtected(Vec<u8>),
+    SaveLockSettings(LockSettings),
+    DisableAutoLock,
+    LockNow,
+    Locked,
+    Unlock(String), // passphrase
+    KeyUnlocked(String),
+    KeyUnlockFailed(String),
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub my_keys: Option<KeyPair>,
-    pub contacts: HashMap<String, String>,
+    pub contacts: Vec<Contact>,
     pub qr_reader_visible: bool,
     pub camera_started: bool,
     pub dialog_message: Option<String>,
@@ -126,22 +321,62 @@ pub struct AppState {
     pub private_key_to_import: Option<String>,
     pub add_contact_dialog_visible: bool,
     pub public_key_to_add: Option<String>,
+    pub contact_card_to_add: Option<(ContactCard, CardStatus)>,
+    pub editing_contact: Option<Contact>,
     pub reset_confirm_visible: bool,
     // RTC関連の状態
     pub rtc_dialog_visible: bool,
-    pub rtc_connection: Option<Connection>,
-    pub rtc_connected: bool,
-    pub rtc_peer_public_key: Option<String>,
+    pub rtc_peers: HashMap<String, RtcPeer>,
+    // The connection waiting for a reply; unsealed answers go to it
+    pub rtc_pending: Option<String>,
     pub chat_visible: bool,
-    pub chat_input: String,
-    pub chat_messages: Vec<ChatMessage>,
+    pub active_chat: Option<String>,
+    // Kept across reconnections so that interrupted transfers can resume
+    pub file_transfers: Vec<FileTransfer>,
+    // QR settings
+    pub qr_settings: QrSettings,
+    pub qr_settings_dialog_visible: bool,
+    pub ice_settings: IceSettings,
+    pub ice_settings_dialog_visible: bool,
+    pub qr_params: HashMap<String, String>,
+    // Deep link received before the keys were loaded
+    pub pending_deep_link: Option<String>,
+    pub profile: Profile,
+    // Backup
+    pub backup_dialog_visible: bool,
+    pub backup_status: Option<String>,
+    pub pending_import: Option<(BackupBundle, ImportPlan)>,
+    pub pending_recipients: Option<RecipientsDiff>,
+    pub groups: Vec<ContactGroup>,
+    // Some(None) while creating a group, Some(Some(id)) while editing one
+    pub group_dialog: Option<Option<String>>,
+    // Message log
+    pub message_log: Vec<LogEntry>,
+    pub message_log_visible: bool,
+    pub message_log_query: String,
+    pub selected_log_entry: Option<String>,
+    // Recipient name and key (for a single contact) of the QR message
+    // currently being encrypted
+    pub pending_outgoing: Option<(String, Option<String>)>,
+    // Another tab is generating the keys
+    pub waiting_for_leader: bool,
+    // Set when stored data could not be migrated; the app stays read-only
+    pub recovery_error: Option<String>,
+    // Auto-lock
+    pub lock_settings: LockSettings,
+    pub key_protected: bool,
+    pub lock_settings_visible: bool,
+    pub pending_lock_settings: Option<LockSettings>,
+    // The private key has to be unlocked with the passphrase
+    pub locked: bool,
+    pub lock_status: Option<String>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
         Self {
             my_keys: None,
-            contacts: HashMap::new(),
+            contacts: Vec::new(),
             qr_reader_visible: false,
             camera_started: false,
             dialog_message: None,
@@ -160,14 +395,41 @@ impl Default for AppState {
             private_key_to_import: None,
             add_contact_dialog_visible: false,
             public_key_to_add: None,
+            contact_card_to_add: None,
+            editing_contact: None,
             reset_confirm_visible: false,
             rtc_dialog_visible: false,
-            rtc_connection: None,
-            rtc_connected: false,
-            rtc_peer_public_key: None,
+            rtc_peers: HashMap::new(),
+            rtc_pending: None,
             chat_visible: false,
-            chat_input: String::new(),
-            chat_messages: Vec::new(),
+            active_chat: None,
+            file_transfers: Vec::new(),
+            qr_settings: QrSettings::default(),
+            qr_settings_dialog_visible: false,
+            ice_settings: IceSettings::default(),
+            ice_settings_dialog_visible: false,
+            qr_params: HashMap::new(),
+            pending_deep_link: None,
+            profile: Profile::default(),
+            backup_dialog_visible: false,
+            backup_status: None,
+            pending_import: None,
+            pending_recipients: None,
+            groups: Vec::new(),
+            group_dialog: None,
+            message_log: Vec::new(),
+            message_log_visible: false,
+            message_log_query: String::new(),
+            selected_log_entry: None,
+            pending_outgoing: None,
+            waiting_for_leader: false,
+            recovery_error: None,
+            lock_settings: LockSettings::default(),
+            key_protected: false,
+            lock_settings_visible: false,
+            pending_lock_settings: None,
+            locked: false,
+            lock_status: None,
         }
     }
 }
@@ -176,6 +438,12 @@ pub struct App {
     state: AppState,
 }
 
+// Where an opened RTC signal belongs
+enum SignalTarget {
+    NewPeer(Option<Session>),
+    Peer(String), // connection id
+}
+
 impl Component for App {
     type Message = Msg;
     type Properties = ();
@@ -184,11 +452,17 @@ impl Component for App {
         ctx.link().send_message(Msg::LoadMyKeys);
 
         setup_custom_event_listener(ctx.link().clone());
+        setup_deep_link_listener(ctx.link().clone());
+        setup_paste_listener(ctx.link().clone());
+        let sync_link = ctx.link().clone();
+        sync::start(move |event| sync_link.send_message(Msg::Sync(event)));
+        let lock_link = ctx.link().clone();
+        autolock::start(move || lock_link.send_message(Msg::LockNow));
 
         Self {
             state: AppState {
                 my_keys: None,
-                contacts: HashMap::new(),
+                contacts: Vec::new(),
                 qr_reader_visible: false,
                 camera_started: false,
                 dialog_message: None,
@@ -207,14 +481,41 @@ impl Component for App {
                 private_key_to_import: None,
                 add_contact_dialog_visible: false,
                 public_key_to_add: None,
+                contact_card_to_add: None,
+                editing_contact: None,
                 reset_confirm_visible: false,
                 rtc_dialog_visible: false,
-                rtc_connection: None,
-                rtc_connected: false,
-                rtc_peer_public_key: None,
+                rtc_peers: HashMap::new(),
+                rtc_pending: None,
                 chat_visible: false,
-                chat_input: String::new(),
-                chat_messages: Vec::new(),
+                active_chat: None,
+                file_transfers: Vec::new(),
+                qr_settings: QrSettings::default(),
+                qr_settings_dialog_visible: false,
+                ice_settings: IceSettings::default(),
+                ice_settings_dialog_visible: false,
+                qr_params: HashMap::new(),
+                pending_deep_link: take_location_deep_link(),
+                profile: Profile::default(),
+                backup_dialog_visible: false,
+                backup_status: None,
+                pending_import: None,
+                pending_recipients: None,
+                groups: Vec::new(),
+                group_dialog: None,
+                message_log: Vec::new(),
+                message_log_visible: false,
+                message_log_query: String::new(),
+                selected_log_entry: None,
+                pending_outgoing: None,
+                waiting_for_leader: false,
+                recovery_error: None,
+                lock_settings: LockSettings::default(),
+                key_protected: false,
+                lock_settings_visible: false,
+                pending_lock_settings: None,
+                locked: false,
+                lock_status: None,
             },
         }
     }
@@ -228,6 +529,7 @@ impl Component for App {
             }
             Msg::GenerateKeys => {
                 console::log!("📨 GenerateKeys message received");
+                self.state.waiting_for_leader = false;
                 self.generate_new_keys(ctx);
                 true
             }
@@ -235,17 +537,50 @@ impl Component for App {
                 console::log!("📨 KeysLoaded message received");
                 self.state.my_keys = Some(keys.clone());
                 self.state.contacts = contacts;
+                if let Some(data) = vault::data() {
+                    self.state.groups = data.groups;
+                    self.state.ice_settings = data.ice_settings;
+                }
                 self.state.is_loading = false;
+                self.state.locked = false;
+                self.state.lock_status = None;
+                if self.state.key_protected {
+                    autolock::arm(self.state.lock_settings);
+                }
 
                 ctx.link().send_message(Msg::DrawQrCode(keys.public_key));
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    match message_log::load().await {
+                        Ok(entries) => ctx_link.send_message(Msg::MessageLogLoaded(entries)),
+                        Err(e) => console::error!(&format!("❌ Failed to load message log: {}", e)),
+                    }
+                });
+                if let Some(link) = self.state.pending_deep_link.take() {
+                    ctx.link().send_message(Msg::HandleDeepLink(link));
+                }
                 true
             }
             Msg::DrawQrCode(public_key) => {
                 console::log!("📨 DrawQrCode message received");
 
+                let settings = self.state.qr_settings.clone();
+                let payload = self.my_qr_payload(&public_key);
+                let public_key =
+                    settings.encode_payload(&payload, payload_link_kind(&payload), &app_base_url());
+                let link = ctx.link().clone();
                 let closure = Closure::wrap(Box::new(move || {
                     console::log!("⏰ QR code delayed drawing started");
-                    draw_qr_code_to_canvas(&public_key);
+                    let params = qr::render_to_canvas(
+                        "qr-canvas",
+                        &public_key,
+                        &settings,
+                        &settings.render_options(QR_CANVAS_SIZE),
+                    );
+                    link.send_message(Msg::QrCodeDrawn(
+                        "qr-canvas".to_string(),
+                        params.map(|p| p.to_string()).unwrap_or_else(|e| e),
+                    ));
                 }) as Box<dyn FnMut()>);
 
                 if let Some(window) = window() {
@@ -288,13 +623,33 @@ impl Component for App {
             }
             Msg::ShowEncryptedQr(encrypted_data) => {
                 console::log!("📨 ShowEncryptedQr message received");
+                for peer in self.state.rtc_peers.values_mut() {
+                    if peer.extra_candidates.as_ref() == Some(&encrypted_data) {
+                        peer.extra_candidates = None;
+                    }
+                }
                 self.state.encrypted_qr_data = Some(encrypted_data.clone());
                 self.state.encrypted_qr_visible = true;
 
-                let encrypted_data_clone = encrypted_data.clone();
+                let encrypted_data_clone = self.state.qr_settings.encode_payload(
+                    &encrypted_data,
+                    payload_link_kind(&encrypted_data),
+                    &app_base_url(),
+                );
+                let settings = self.state.qr_settings.clone();
+                let link = ctx.link().clone();
                 let closure = Closure::wrap(Box::new(move || {
                     console::log!("⏰ Encrypted QR code delayed drawing started");
-                    draw_encrypted_qr_code_to_canvas(&encrypted_data_clone);
+                    let params = qr::render_to_canvas(
+                        "encrypted-qr-canvas",
+                        &encrypted_data_clone,
+                        &settings,
+                        &settings.render_options(QR_CANVAS_SIZE),
+                    );
+                    link.send_message(Msg::QrCodeDrawn(
+                        "encrypted-qr-canvas".to_string(),
+                        params.map(|p| p.to_string()).unwrap_or_else(|e| e),
+                    ));
                 }) as Box<dyn FnMut()>);
 
                 if let Some(window) = window() {
@@ -310,19 +665,20 @@ impl Component for App {
                 console::log!("📨 HideEncryptedQr メッセージ受信");
                 self.state.encrypted_qr_visible = false;
                 self.state.encrypted_qr_data = None;
+                self.state.qr_params.remove("encrypted-qr-canvas");
                 true
             }
-            Msg::ShowDeleteConfirm(name) => {
+            Msg::ShowDeleteConfirm(id) => {
                 console::log!("📨 ShowDeleteConfirm message received");
-                console::log!(&format!("❓ Delete confirmation displayed: {}", name));
-                self.state.delete_target = Some(name);
+                console::log!(&format!("❓ Delete confirmation displayed: {}", id));
+                self.state.delete_target = Some(id);
                 self.state.delete_confirm_visible = true;
                 true
             }
-            Msg::ConfirmDeleteContact(name) => {
+            Msg::ConfirmDeleteContact(id) => {
                 console::log!("📨 ConfirmDeleteContact message received");
-                console::log!(&format!("👤 Contact deletion executed: {}", name));
-                self.delete_contact(name);
+                console::log!(&format!("👤 Contact deletion executed: {}", id));
+                self.delete_contact(&id);
                 self.state.delete_confirm_visible = false;
                 self.state.delete_target = None;
                 true
@@ -357,6 +713,356 @@ impl Component for App {
                 self.state.is_loading = is_loading;
                 true
             }
+            Msg::MessageLogLoaded(entries) => {
+                console::log!("📨 MessageLogLoaded message received");
+                // Keep entries logged while the saved log was loading
+                let mut log = entries;
+                for entry in self.state.message_log.drain(..) {
+                    if !log.iter().any(|e| e.id == entry.id) {
+                        log.push(entry);
+                    }
+                }
+                self.state.message_log = log;
+                true
+            }
+            Msg::LogMessage(entry) => {
+                console::log!("📨 LogMessage message received");
+                self.state.message_log.push(entry);
+                self.save_message_log();
+                true
+            }
+            Msg::ShowMessageLog => {
+                self.state.message_log_visible = true;
+                true
+            }
+            Msg::HideMessageLog => {
+                self.state.message_log_visible = false;
+                self.state.message_log_query.clear();
+                self.state.selected_log_entry = None;
+                true
+            }
+            Msg::UpdateMessageLogQuery(query) => {
+                self.state.message_log_query = query;
+                true
+            }
+            Msg::SelectLogEntry(id) => {
+                self.state.selected_log_entry = id;
+                true
+            }
+            Msg::DeleteLogEntry(id) => {
+                console::log!("📨 DeleteLogEntry message received");
+                self.state.message_log.retain(|entry| entry.id != id);
+                if self.state.selected_log_entry.as_ref() == Some(&id) {
+                    self.state.selected_log_entry = None;
+                }
+                self.save_message_log();
+                true
+            }
+            Msg::Sync(event) => {
+                console::log!(&format!("📨 Sync message received: {:?}", event));
+                match event {
+                    // ロック中は復号済みのデータを持たない
+                    SyncEvent::VaultChanged | SyncEvent::MessageLogChanged if self.state.locked => {
+                    }
+                    // 鍵が変わった場合は読み込み直す
+                    SyncEvent::KeysChanged => {
+                        if let Some(window) = window() {
+                            let _ = window.location().reload();
+                        }
+                    }
+                    SyncEvent::VaultChanged => {
+                        let ctx_link = ctx.link().clone();
+                        spawn_local(async move {
+                            match vault::reload().await {
+                                Ok(()) => ctx_link.send_message(Msg::VaultSynced),
+                                Err(e) => {
+                                    console::error!(&format!("❌ Failed to reload vault: {}", e))
+                                }
+                            }
+                        });
+                    }
+                    SyncEvent::MessageLogChanged => {
+                        let ctx_link = ctx.link().clone();
+                        spawn_local(async move {
+                            match message_log::load().await {
+                                Ok(entries) => {
+                                    ctx_link.send_message(Msg::MessageLogSynced(entries))
+                                }
+                                Err(e) => console::error!(&format!(
+                                    "❌ Failed to reload message log: {}",
+                                    e
+                                )),
+                            }
+                        });
+                    }
+                    SyncEvent::BecameLeader => {
+                        if self.state.waiting_for_leader {
+                            ctx.link().send_message(Msg::GenerateKeys);
+                        }
+                    }
+                }
+                false
+            }
+            Msg::VaultSynced => {
+                console::log!("📨 VaultSynced message received");
+                let Some(data) = vault::data() else {
+                    return false;
+                };
+                let qr_settings = data.qr_settings.normalized();
+                let redraw =
+                    self.state.qr_settings != qr_settings || self.state.profile != data.profile;
+                self.state.contacts = data.contacts;
+                self.state.groups = data.groups;
+                self.state.ice_settings = data.ice_settings;
+                self.state.qr_settings = qr_settings;
+                self.state.profile = data.profile;
+                if redraw {
+                    if let Some(ref keys) = self.state.my_keys {
+                        ctx.link()
+                            .send_message(Msg::DrawQrCode(keys.public_key.clone()));
+                    }
+                }
+                true
+            }
+            Msg::MessageLogSynced(entries) => {
+                console::log!("📨 MessageLogSynced message received");
+                self.state.message_log = entries;
+                if let Some(ref id) = self.state.selected_log_entry {
+                    if !self.state.message_log.iter().any(|entry| &entry.id == id) {
+                        self.state.selected_log_entry = None;
+                    }
+                }
+                true
+            }
+            Msg::WaitForKeyGeneration => {
+                console::log!("📨 WaitForKeyGeneration message received");
+                self.state.waiting_for_leader = true;
+                self.state.loading_message =
+                    "Waiting for another tab to generate keys...".to_string();
+                true
+            }
+            Msg::MigrationFailed(error) => {
+                console::log!("📨 MigrationFailed message received");
+                console::error!(&format!("❌ {}", error));
+                self.state.recovery_error = Some(error);
+                self.state.is_loading = false;
+                true
+            }
+            Msg::ExportRecoveryDump(passphrase) => {
+                console::log!("📨 ExportRecoveryDump message received");
+                let Some(worker) = self.state.worker.clone() else {
+                    return false;
+                };
+                self.state.backup_status = Some("Encrypting raw data...".to_string());
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    let result = recovery_dump().await.and_then(|data| {
+                        serde_wasm_bindgen::to_value(&MainMessage::EncryptBackup {
+                            passphrase,
+                            data,
+                        })
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|message| {
+                            worker
+                                .post_message(&message)
+                                .map_err(|e| format!("{:?}", e))
+                        })
+                    });
+                    if let Err(e) = result {
+                        ctx_link.send_message(Msg::BackupFailed(format!(
+                            "Failed to export raw data: {}",
+                            e
+                        )));
+                    }
+                });
+                true
+            }
+            Msg::LockSettingsLoaded(settings, protected) => {
+                self.state.lock_settings = settings;
+                self.state.key_protected = protected;
+                false
+            }
+            Msg::ShowLockSettings => {
+                self.state.lock_settings_visible = true;
+                self.state.lock_status = None;
+                true
+            }
+            Msg::HideLockSettings => {
+                self.state.lock_settings_visible = false;
+                self.state.lock_status = None;
+                true
+            }
+            Msg::EnableAutoLock(passphrase, settings) => {
+                console::log!("📨 EnableAutoLock message received");
+                let (Some(worker), Some(keys)) =
+                    (self.state.worker.clone(), self.state.my_keys.clone())
+                else {
+                    return false;
+                };
+                self.state.pending_lock_settings = Some(settings);
+                self.state.lock_status = Some("Encrypting private key...".to_string());
+                match serde_wasm_bindgen::to_value(&MainMessage::ProtectKey {
+                    passphrase,
+                    private_key: keys.private_key,
+                }) {
+                    Ok(message) => {
+                        if let Err(e) = worker.post_message(&message) {
+                            error_report(&format!("❌ Failed to post protect message: {:?}", e));
+                        }
+                    }
+                    Err(e) => {
+                        error_report(&format!("❌ Failed to serialize protect message: {:?}", e));
+                    }
+                }
+                true
+            }
+            Msg::KeyProtected(data) => {
+                console::log!("📨 KeyProtected message received");
+                let settings = self
+                    .state
+                    .pending_lock_settings
+                    .take()
+                    .unwrap_or(self.state.lock_settings);
+                self.state.lock_settings = settings;
+                self.state.key_protected = true;
+                self.state.lock_settings_visible = false;
+                self.state.lock_status = None;
+                autolock::arm(settings);
+                spawn_local(async move {
+                    let result = match autolock::save_settings(&settings).await {
+                        Ok(()) => autolock::protect_key(&data).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        error_report(&format!("❌ Failed to enable auto-lock: {}", e));
+                    }
+                    sync::notify(SyncEvent::KeysChanged);
+                });
+                ctx.link().send_message(Msg::ShowDialog(
+                    "Auto-lock enabled. The passphrase is needed to unlock the app.".to_string(),
+                ));
+                true
+            }
+            Msg::SaveLockSettings(settings) => {
+                console::log!("📨 SaveLockSettings message received");
+                self.state.lock_settings = settings;
+                self.state.lock_settings_visible = false;
+                if self.state.key_protected {
+                    autolock::arm(settings);
+                }
+                spawn_local(async move {
+                    if let Err(e) = autolock::save_settings(&settings).await {
+                        console::error!(&format!("❌ Failed to save lock settings: {}", e));
+                    }
+                });
+                true
+            }
+            Msg::DisableAutoLock => {
+                console::log!("📨 DisableAutoLock message received");
+                let Some(keys) = self.state.my_keys.clone() else {
+                    return false;
+                };
+                self.state.key_protected = false;
+                self.state.lock_settings_visible = false;
+                autolock::disarm();
+                // 秘密鍵を平文で保存し直す
+                spawn_local(async move {
+                    save_my_keys(&keys.private_key, &keys.public_key).await;
+                    sync::notify(SyncEvent::KeysChanged);
+                });
+                true
+            }
+            Msg::LockNow => {
+                console::log!("📨 LockNow message received");
+                if !self.state.key_protected || self.state.my_keys.is_none() {
+                    return false;
+                }
+                autolock::disarm();
+                for peer in self.state.rtc_peers.values() {
+                    peer.connection.close();
+                }
+                if self.state.camera_started {
+                    stop_qr_reader_js();
+                }
+                vault::lock();
+                history::close();
+                // 復号済みの鍵とデータは状態ごと破棄する
+                self.state = AppState {
+                    worker: self.state.worker.take(),
+                    lock_settings: self.state.lock_settings,
+                    key_protected: true,
+                    locked: true,
+                    is_loading: false,
+                    pending_deep_link: self.state.pending_deep_link.take(),
+                    ..AppState::default()
+                };
+                true
+            }
+            Msg::Locked => {
+                console::log!("📨 Locked message received");
+                self.state.locked = true;
+                self.state.is_loading = false;
+                true
+            }
+            Msg::Unlock(passphrase) => {
+                console::log!("📨 Unlock message received");
+                let Some(worker) = self.state.worker.clone() else {
+                    return false;
+                };
+                self.state.lock_status = Some("Unlocking...".to_string());
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    let result = match autolock::protected_key().await {
+                        Ok(Some(data)) => serde_wasm_bindgen::to_value(&MainMessage::UnlockKey {
+                            passphrase,
+                            data,
+                        })
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|message| {
+                            worker
+                                .post_message(&message)
+                                .map_err(|e| format!("{:?}", e))
+                        }),
+                        Ok(None) => Err("No protected key found".to_string()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        ctx_link.send_message(Msg::KeyUnlockFailed(e));
+                    }
+                });
+                true
+            }
+            Msg::KeyUnlocked(private_key) => {
+                console::log!("📨 KeyUnlocked message received");
+                self.state.lock_status = Some("Opening vault...".to_string());
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    match storage::store().await.get_string("myPublicKey").await {
+                        Ok(Some(public_key)) => {
+                            open_session(
+                                ctx_link,
+                                KeyPair {
+                                    public_key,
+                                    private_key,
+                                },
+                            )
+                            .await
+                        }
+                        Ok(None) => ctx_link.send_message(Msg::KeyUnlockFailed(
+                            "Public key is missing".to_string(),
+                        )),
+                        Err(e) => ctx_link.send_message(Msg::KeyUnlockFailed(e)),
+                    }
+                });
+                true
+            }
+            Msg::KeyUnlockFailed(message) => {
+                console::log!("📨 KeyUnlockFailed message received");
+                self.state.pending_lock_settings = None;
+                self.state.lock_status = None;
+                ctx.link().send_message(Msg::ShowDialog(message));
+                true
+            }
             Msg::HandleCustomEvent(event_type, data) => {
                 console::log!("📨 HandleCustomEvent message received");
                 console::log!(&format!("🎯 Custom event: {}", event_type));
@@ -368,9 +1074,15 @@ impl Component for App {
                         ctx.link().send_message(Msg::ShowAddContactDialog(data));
                         ctx.link().send_message(Msg::HideQrReader);
                     }
+                    "add_contact_card" => {
+                        if let Some(card) = ContactCard::parse(&data) {
+                            ctx.link().send_message(Msg::ShowContactCardDialog(card));
+                            ctx.link().send_message(Msg::HideQrReader);
+                        }
+                    }
                     "delete_contact" => {
-                        if let Ok(name) = serde_json::from_str::<String>(&data) {
-                            ctx.link().send_message(Msg::ShowDeleteConfirm(name));
+                        if let Ok(id) = serde_json::from_str::<String>(&data) {
+                            ctx.link().send_message(Msg::ShowDeleteConfirm(id));
                             ctx.link().send_message(Msg::HideQrReader);
                         }
                     }
@@ -386,77 +1098,43 @@ impl Component for App {
                         ctx.link().send_message(Msg::ShowEncryptedQr(data));
                     }
                     "encrypted_message_ready" => {
-                        // Check if this is for chat (RTC connection is active) or QR generation
-                        if self.state.rtc_connected && self.state.chat_visible {
-                            ctx.link().send_message(Msg::SendEncryptedChatMessage(data));
-                        } else {
-                            dispatch_custom_event("show_encrypted_qr", &data);
+                        // Chat messages come back as ChatEncrypted, so this is for QR generation
+                        if let Some((name, peer_key)) = self.state.pending_outgoing.take() {
+                            ctx.link().send_message(Msg::LogMessage(LogEntry {
+                                id: new_id(),
+                                direction: Direction::Sent,
+                                timestamp: unix_time(),
+                                text: data.clone(),
+                                peer_key,
+                                peer_name: Some(name),
+                            }));
                         }
+                        dispatch_custom_event("show_encrypted_qr", &data);
                     }
                     "decrypted_message_ready" => {
-                        // Check if this is for chat (RTC connection is active) or other purposes
-                        if self.state.rtc_connected && self.state.chat_visible {
-                            // This is a decrypted chat message, add it to the chat
-                            ctx.link().send_message(Msg::AddChatMessage(data, false));
-                        } else {
-                            dispatch_custom_event("show_dialog", &data);
-                        }
+                        // QR messages carry no sender information
+                        ctx.link().send_message(Msg::LogMessage(LogEntry {
+                            id: new_id(),
+                            direction: Direction::Received,
+                            timestamp: unix_time(),
+                            text: data.clone(),
+                            peer_key: None,
+                            peer_name: None,
+                        }));
+                        dispatch_custom_event("show_dialog", &data);
                     }
                     "encrypt_message" => {
                         console::log!("🔐 Encrypt message request received");
                         if let Ok(encrypt_data) = serde_json::from_str::<serde_json::Value>(&data) {
-                            if let (Some(contact), Some(message)) = (
+                            if let (Some(destination), Some(message)) = (
                                 encrypt_data["contact"].as_str(),
                                 encrypt_data["message"].as_str(),
                             ) {
-                                console::log!(&format!("📋 Encrypting for contact: {}", contact));
-
-                                // 連絡先の公開鍵を取得
-                                if let Some(public_key) = self.state.contacts.get(contact) {
-                                    // 暗号化を実行
-                                    if let Some(worker) = self.state.worker.clone() {
-                                        match serde_wasm_bindgen::to_value(&MainMessage::Encrypt {
-                                            public_key: public_key.clone(),
-                                            data: message.to_string(),
-                                        }) {
-                                            Ok(encrypt_message) => {
-                                                if let Err(e) =
-                                                    worker.post_message(&encrypt_message)
-                                                {
-                                                    console::error!(&format!(
-                                                        "❌ Failed to post encrypt message: {:?}",
-                                                        e
-                                                    ));
-                                                    ctx.link().send_message(Msg::ShowDialog(
-                                                        "Failed to send encryption request"
-                                                            .to_string(),
-                                                    ));
-                                                }
-                                            }
-                                            Err(e) => {
-                                                console::error!(&format!(
-                                                    "❌ Failed to serialize encrypt message: {:?}",
-                                                    e
-                                                ));
-                                                ctx.link().send_message(Msg::ShowDialog(
-                                                    "Failed to prepare encryption request"
-                                                        .to_string(),
-                                                ));
-                                            }
-                                        }
-                                    } else {
-                                        console::error!("❌ Worker not available");
-                                        ctx.link().send_message(Msg::ShowDialog(
-                                            "Worker not available".to_string(),
-                                        ));
-                                    }
-                                } else {
-                                    console::error!(&format!("❌ Contact {} not found", contact));
-                                    ctx.link().send_message(Msg::ShowDialog(format!(
-                                        "Contact '{}' not found",
-                                        contact
-                                    )));
-                                }
+                                console::log!(&format!(
+                                    "📋 Encrypting for destination: {}",
+                                    destination
+                                ));
+                                self.encrypt_for_destination(ctx, destination, message);
                             } else {
                                 console::error!("❌ Invalid encrypt_message data format");
                                 ctx.link().send_message(Msg::ShowDialog(
@@ -546,10 +1224,14 @@ impl Component for App {
                 self.state.export_private_key_dialog_visible = false;
                 true
             }
-            Msg::ExportPrivateKey(recipient_name) => {
+            Msg::ExportPrivateKey(recipient_id) => {
                 console::log!("📨 ExportPrivateKey message received");
-                if let Some(ref my_keys) = self.state.my_keys {
-                    if let Some(recipient_public_key) = self.state.contacts.get(&recipient_name) {
+                if let Some(ref my_keys) = self.state.my_keys.clone() {
+                    if let Some(recipient_public_key) = self
+                        .find_contact(&recipient_id)
+                        .map(|c| c.public_key.clone())
+                    {
+                        self.touch_contact(&recipient_id);
                         if let Some(worker) = self.state.worker.clone() {
                             match serde_wasm_bindgen::to_value(&MainMessage::ExportPrivateKey {
                                 recipient_public_key: recipient_public_key.clone(),
@@ -669,14 +1351,30 @@ impl Component for App {
 
                 // 新しい鍵ペアを保存
                 spawn_local(async move {
+                    // 保存済みデータを新しい鍵で暗号化し直してから鍵を保存する
+                    if let Err(e) = vault::rekey(&new_keys.private_key).await {
+                        error_report(&format!(
+                            "❌ Failed to re-encrypt vault, the previous key is kept: {}",
+                            e
+                        ));
+                        return;
+                    }
                     save_my_keys(&new_keys.private_key, &new_keys.public_key).await;
+                    sync::notify(SyncEvent::KeysChanged);
                 });
 
                 // QRコードを更新
                 ctx.link().send_message(Msg::DrawQrCode(public_key));
-                ctx.link().send_message(Msg::ShowDialog(
-                    "Private key imported and saved successfully!".to_string(),
-                ));
+                let mut message = "Private key imported and saved successfully!".to_string();
+                // 新しい鍵は平文で保存されるので自動ロックは解除される
+                if self.state.key_protected {
+                    self.state.key_protected = false;
+                    autolock::disarm();
+                    message.push_str(
+                        " Auto-lock has been turned off; set a passphrase again to re-enable it.",
+                    );
+                }
+                ctx.link().send_message(Msg::ShowDialog(message));
                 true
             }
             Msg::ShowAddContactDialog(public_key) => {
@@ -689,15 +1387,33 @@ impl Component for App {
                 console::log!("📨 HideAddContactDialog message received");
                 self.state.add_contact_dialog_visible = false;
                 self.state.public_key_to_add = None;
+                self.state.contact_card_to_add = None;
                 true
             }
             Msg::ConfirmAddContact(name) => {
                 console::log!("📨 ConfirmAddContact message received");
-                if let Some(ref public_key) = self.state.public_key_to_add {
+                if let Some(public_key) = self.state.public_key_to_add.clone() {
                     if !name.trim().is_empty() {
-                        self.add_contact(name, public_key.clone());
+                        // カードの署名鍵は暗号化鍵と結び付いていないので記録しない。
+                        // 最初の接続でピア認証が成功したときに記録する
+                        let contact =
+                            Contact::new(name.trim().to_string(), public_key, unix_time());
+                        if let Some(existing) = self
+                            .state
+                            .contacts
+                            .iter()
+                            .find(|c| c.public_key == contact.public_key)
+                        {
+                            ctx.link().send_message(Msg::ShowDialog(format!(
+                                "This key is already saved as \"{}\"",
+                                existing.name
+                            )));
+                        } else {
+                            self.add_contact(contact);
+                        }
                         self.state.add_contact_dialog_visible = false;
                         self.state.public_key_to_add = None;
+                        self.state.contact_card_to_add = None;
                     }
                 }
                 true
@@ -706,6 +1422,36 @@ impl Component for App {
                 console::log!("📨 CancelAddContact message received");
                 self.state.add_contact_dialog_visible = false;
                 self.state.public_key_to_add = None;
+                self.state.contact_card_to_add = None;
+                true
+            }
+            Msg::ShowEditContact(id) => {
+                console::log!("📨 ShowEditContact message received");
+                self.state.editing_contact = self.find_contact(&id).cloned();
+                true
+            }
+            Msg::HideEditContact => {
+                self.state.editing_contact = None;
+                true
+            }
+            Msg::SaveContact(contact) => {
+                console::log!("📨 SaveContact message received");
+                if let Some(existing) = self.state.contacts.iter_mut().find(|c| c.id == contact.id)
+                {
+                    *existing = contact;
+                    sort_contacts(&mut self.state.contacts);
+                    self.save_contacts();
+                }
+                self.state.editing_contact = None;
+                true
+            }
+            Msg::ShowContactCardDialog(card) => {
+                console::log!("📨 ShowContactCardDialog message received");
+                let status = card.verify();
+                console::log!(&format!("🔏 Contact card status: {:?}", status));
+                self.state.public_key_to_add = Some(card.public_key.clone());
+                self.state.contact_card_to_add = Some((card, status));
+                self.state.add_contact_dialog_visible = true;
                 true
             }
             Msg::ShowResetConfirm => {
@@ -737,32 +1483,25 @@ impl Component for App {
                 self.state.rtc_dialog_visible = false;
                 true
             }
-            Msg::StartRtcConnection => {
+            Msg::StartRtcConnection(peer_key) => {
                 console::log!("📨 StartRtcConnection message received");
-                self.state.rtc_connected = false;
-                self.state.rtc_peer_public_key = None;
+                let session = match peer_key.map(|key| Session::new(&key)) {
+                    Some(Ok(session)) => Some(session),
+                    Some(Err(e)) => {
+                        error_report(&format!("❌ Failed to start RTC session: {}", e));
+                        return false;
+                    }
+                    None => None,
+                };
                 self.state.rtc_dialog_visible = false;
 
                 // 実際のWebRTC接続を開始
-                let connection = Connection::new();
+                let sealing = self.rtc_sealing(&session);
+                let Some(connection) = self.add_rtc_peer(ctx, session) else {
+                    return true;
+                };
                 let ctx_link = ctx.link().clone();
 
-                // 接続確立ハンドラを設定（ICE接続のみ）
-                let ctx_link_for_ice = ctx_link.clone();
-                let _ = connection.set_connection_established_handler(move || {
-                    console::log!("🎉 WebRTC ICE connection established!");
-                    ctx_link_for_ice.send_message(Msg::RtcConnectionEstablished);
-                });
-
-                // データチャネルオープンハンドラを設定（メッセージング準備完了）
-                let ctx_link_for_data = ctx_link.clone();
-                let _ = connection.set_data_channel_open_handler(move || {
-                    console::log!("🎉 Data channel ready! Starting public key exchange");
-                    ctx_link_for_data.send_message(Msg::SendPublicKeyViaRtc);
-                });
-
-                self.state.rtc_connection = Some(connection.clone());
-
                 spawn_local(async move {
                     let mut conn = connection;
                     match conn
@@ -771,7 +1510,7 @@ impl Component for App {
                                 sdp_data: offer_sdp,
                             };
 
-                            if let Ok(offer_json) = serde_json::to_string(&offer_data) {
+                            if let Some(offer_json) = encode_rtc_signal(&offer_data, &sealing) {
                                 ctx_link.send_message(Msg::ShowEncryptedQr(offer_json));
                             }
                         })
@@ -794,32 +1533,25 @@ impl Component for App {
                 if let Ok(signal_data) = serde_json::from_str::<RtcSignalData>(&sdp_data) {
                     console::log!("📡 Processing RTC signal");
 
-                    match signal_data {
-                        RtcSignalData::Offer { sdp_data } => {
+                    let (signal_data, target) = match self.open_rtc_signal(signal_data) {
+                        Ok(opened) => opened,
+                        Err(e) => {
+                            console::error!(&format!("❌ Rejected RTC signal: {}", e));
+                            ctx.link().send_message(Msg::ShowDialog(e));
+                            return true;
+                        }
+                    };
+
+                    match (signal_data, target) {
+                        (RtcSignalData::Offer { sdp_data }, SignalTarget::NewPeer(session)) => {
                             // rtc.rsの実装を使用してOfferを処理
-                            let connection = Connection::new();
+                            let sealing = self.rtc_sealing(&session);
+                            let Some(connection) = self.add_rtc_peer(ctx, session) else {
+                                return true;
+                            };
                             let ctx_link = ctx.link().clone();
                             let offer_sdp = sdp_data.clone();
 
-                            // 接続確立ハンドラを設定（ICE接続のみ）
-                            let ctx_link_for_ice = ctx_link.clone();
-                            let _ = connection.set_connection_established_handler(move || {
-                                console::log!(
-                                    "🎉 WebRTC ICE connection established (Answer side)!"
-                                );
-                                ctx_link_for_ice.send_message(Msg::RtcConnectionEstablished);
-                            });
-
-                            // データチャネルオープンハンドラを設定（メッセージング準備完了）
-                            let ctx_link_for_data = ctx_link.clone();
-                            let _ = connection.set_data_channel_open_handler(move || {
-                                 console::log!("🎉 Data channel ready (Answer side)! Starting public key exchange");
-                                 ctx_link_for_data.send_message(Msg::SendPublicKeyViaRtc);
-                             });
-
-                            // 接続をstateに保存
-                            self.state.rtc_connection = Some(connection.clone());
-
                             spawn_local(async move {
                                 let mut conn = connection;
                                 match conn
@@ -828,7 +1560,8 @@ impl Component for App {
                                             sdp_data: answer_sdp,
                                         };
 
-                                        if let Ok(answer_json) = serde_json::to_string(&answer_data)
+                                        if let Some(answer_json) =
+                                            encode_rtc_signal(&answer_data, &sealing)
                                         {
                                             ctx_link
                                                 .send_message(Msg::ShowEncryptedQr(answer_json));
@@ -848,10 +1581,10 @@ impl Component for App {
                                 }
                             });
                         }
-                        RtcSignalData::Answer { sdp_data } => {
+                        (RtcSignalData::Answer { sdp_data }, SignalTarget::Peer(peer_id)) => {
                             // Answerを受信したので既存の接続でAnswer処理
-                            if let Some(ref connection) = self.state.rtc_connection {
-                                let connection_clone = connection.clone();
+                            if let Some(peer) = self.state.rtc_peers.get(&peer_id) {
+                                let connection_clone = peer.connection.clone();
                                 let answer_sdp = sdp_data.clone();
 
                                 spawn_local(async move {
@@ -868,10 +1601,31 @@ impl Component for App {
                                         }
                                     }
                                 });
-                            } else {
-                                console::error!("❌ No RTC connection to process answer");
                             }
                         }
+                        (RtcSignalData::Candidates { candidates }, SignalTarget::Peer(peer_id)) => {
+                            if let Some(peer) = self.state.rtc_peers.get(&peer_id) {
+                                let connection = peer.connection.clone();
+                                spawn_local(async move {
+                                    match connection.add_remote_candidates(candidates).await {
+                                        Ok(_) => {
+                                            console::log!(
+                                                "✅ Extra candidates processed successfully"
+                                            );
+                                        }
+                                        Err(e) => {
+                                            console::error!(&format!(
+                                                "❌ Failed to add extra candidates: {:?}",
+                                                e
+                                            ));
+                                        }
+                                    }
+                                });
+                            }
+                        }
+                        _ => {
+                            console::error!("❌ Unexpected RTC signal");
+                        }
                     }
                 } else {
                     console::error!("❌ Failed to parse RTC signal data");
@@ -880,25 +1634,49 @@ impl Component for App {
                 }
                 true
             }
-            Msg::RtcConnectionEstablished => {
+            Msg::RtcExtraCandidates(peer_id, signal) => {
+                console::log!("📨 RtcExtraCandidates message received");
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                if peer.connected {
+                    return false;
+                }
+                // 最初のQRが閉じられていれば、すぐに表示する
+                if self.state.encrypted_qr_visible {
+                    peer.extra_candidates = Some(signal);
+                } else {
+                    ctx.link().send_message(Msg::ShowEncryptedQr(signal));
+                }
+                true
+            }
+            Msg::RtcConnectionEstablished(peer_id) => {
                 console::log!(
                     "📨 RtcConnectionEstablished message received - ICE connection ready"
                 );
-                self.state.rtc_connected = true;
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                peer.connected = true;
+                peer.extra_candidates = None;
                 self.state.rtc_dialog_visible = false;
+                if self.state.rtc_pending.as_ref() == Some(&peer_id) {
+                    self.state.rtc_pending = None;
+                }
 
                 // RTC接続確立時に自動でチャット画面を表示
+                self.state.active_chat = Some(peer_id);
                 self.state.chat_visible = true;
 
                 // 注意: 公開鍵交換はデータチャネルオープン時に自動開始される
 
                 true
             }
-            Msg::SendPublicKeyViaRtc => {
+            Msg::SendPublicKeyViaRtc(peer_id) => {
                 console::log!("📨 SendPublicKeyViaRtc message received");
 
                 if let Some(ref my_keys) = self.state.my_keys {
-                    if let Some(ref connection) = self.state.rtc_connection {
+                    if let Some(peer) = self.state.rtc_peers.get(&peer_id) {
                         // RTC経由で公開鍵メッセージを送信
                         let public_key_message = RtcMessage::PublicKey {
                             public_key: my_keys.public_key.clone(),
@@ -906,7 +1684,7 @@ impl Component for App {
 
                         console::log!("🔑 Sending public key via RTC");
 
-                        let _connection_clone = connection.clone();
+                        let _connection_clone = peer.connection.clone();
                         let ctx_link = ctx.link().clone();
 
                         spawn_local(async move {
@@ -914,15 +1692,39 @@ impl Component for App {
 
                             // データハンドラを設定して受信メッセージを処理
                             let ctx_link_clone = ctx_link.clone();
+                            let id = peer_id.clone();
                             if let Err(e) = conn.set_data_handler(move |data| {
                                 console::log!(&format!("📨 Received RTC data: {}", data));
 
                                 if let Ok(message) = serde_json::from_str::<RtcMessage>(&data) {
+                                    let id = id.clone();
                                     match message {
                                         RtcMessage::PublicKey { public_key } => {
                                             console::log!("🔑 Received peer public key");
-                                            ctx_link_clone.send_message(Msg::SetPeerPublicKey(
-                                                public_key.clone(),
+                                            ctx_link_clone.send_message(
+                                                Msg::PeerPublicKeyReceived(id, public_key),
+                                            );
+                                        }
+                                        RtcMessage::Challenge {
+                                            encrypted_challenge,
+                                        } => {
+                                            ctx_link_clone.send_message(
+                                                Msg::PeerChallengeReceived(id, encrypted_challenge),
+                                            );
+                                        }
+                                        RtcMessage::Proof { proof, signing_key } => {
+                                            ctx_link_clone.send_message(Msg::PeerProofReceived(
+                                                id,
+                                                proof,
+                                                signing_key,
+                                            ));
+                                        }
+                                        message @ (RtcMessage::FileOffer { .. }
+                                        | RtcMessage::FileAccept { .. }
+                                        | RtcMessage::FileComplete { .. }
+                                        | RtcMessage::FileFailed { .. }) => {
+                                            ctx_link_clone.send_message(Msg::FileTransferMessage(
+                                                id, message,
                                             ));
                                         }
                                         RtcMessage::EncryptedData { encrypted_data } => {
@@ -931,7 +1733,7 @@ impl Component for App {
                                             );
                                             // Decrypt received message before adding to chat
                                             ctx_link_clone.send_message(
-                                                Msg::DecryptReceivedMessage(encrypted_data),
+                                                Msg::DecryptReceivedMessage(id, encrypted_data),
                                             );
                                         }
                                     }
@@ -939,6 +1741,20 @@ impl Component for App {
                             }) {
                                 console::error!(&format!("❌ Failed to set data handler: {:?}", e));
                             }
+                            let ctx_link_binary = ctx_link.clone();
+                            let id = peer_id.clone();
+                            conn.set_binary_handler(move |frame| {
+                                ctx_link_binary
+                                    .send_message(Msg::FileChunkReceived(id.clone(), frame));
+                            });
+                            let ctx_link_pump = ctx_link.clone();
+                            let id = peer_id.clone();
+                            conn.set_buffered_amount_low_handler(
+                                file_transfer::LOW_BUFFERED,
+                                move || {
+                                    ctx_link_pump.send_message(Msg::PumpFileTransfers(id.clone()))
+                                },
+                            );
 
                             // データチャネルの状態を確認してからメッセージを送信
                             console::log!("⏳ Attempting to send public key via RTC...");
@@ -979,59 +1795,93 @@ impl Component for App {
                 self.state.chat_visible = false;
                 true
             }
-            Msg::SendChatMessage(message) => {
+            Msg::OpenChat(peer_id) => {
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                peer.unread = 0;
+                self.state.active_chat = Some(peer_id);
+                self.state.chat_visible = true;
+                true
+            }
+            Msg::CloseRtcConnection(peer_id) => {
+                console::log!("📨 CloseRtcConnection message received");
+                if let Some(peer) = self.state.rtc_peers.remove(&peer_id) {
+                    peer.connection.close();
+                }
+                if self.state.active_chat.as_ref() == Some(&peer_id) {
+                    self.state.active_chat = None;
+                }
+                if self.state.rtc_pending.as_ref() == Some(&peer_id) {
+                    self.state.rtc_pending = None;
+                }
+                true
+            }
+            Msg::SendChatMessage(peer_id, message) => {
                 console::log!("📨 SendChatMessage:", &message);
-                if let Some(ref connection) = self.state.rtc_connection {
-                    if let Some(ref peer_public_key) = self.state.rtc_peer_public_key {
-                        // メッセージを暗号化してから送信
-                        if let Some(ref worker) = self.state.worker {
-                            let worker_clone = worker.clone();
-                            let message_clone = message.clone();
-                            let peer_key_clone = peer_public_key.clone();
-                            let _connection_clone = connection.clone();
-                            let ctx_link = ctx.link().clone();
-
-                            spawn_local(async move {
-                                let encrypt_message = MainMessage::Encrypt {
-                                    public_key: peer_key_clone,
-                                    data: message_clone.clone(),
-                                };
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                if let Some(ref peer_public_key) = peer.public_key {
+                    // メッセージを暗号化してから送信
+                    if let Some(ref worker) = self.state.worker {
+                        let encrypt_message = MainMessage::EncryptChat {
+                            peer: peer_id.clone(),
+                            public_key: peer_public_key.clone(),
+                            data: message.clone(),
+                        };
 
-                                if let Ok(js_message) =
-                                    serde_wasm_bindgen::to_value(&encrypt_message)
-                                {
-                                    worker_clone.post_message(&js_message).unwrap_or_else(|e| {
-                                        console::error!(&format!(
-                                            "❌ Failed to post encrypt message: {:?}",
-                                            e
-                                        ));
-                                    });
-                                }
-
-                                // 送信済みメッセージをチャットに追加（暗号化前の平文で表示）
-                                ctx_link.send_message(Msg::AddChatMessage(message_clone, true));
+                        if let Ok(js_message) = serde_wasm_bindgen::to_value(&encrypt_message) {
+                            worker.post_message(&js_message).unwrap_or_else(|e| {
+                                console::error!(&format!(
+                                    "❌ Failed to post encrypt message: {:?}",
+                                    e
+                                ));
                             });
                         }
-                        self.state.chat_input.clear();
-                    } else {
-                        console::error!("❌ No peer public key available for encryption");
-                        ctx.link().send_message(Msg::ShowDialog("Peer public key not available. Please ensure the connection is fully established.".to_string()));
+
+                        // 送信済みメッセージをチャットに追加（暗号化前の平文で表示）
+                        ctx.link()
+                            .send_message(Msg::AddChatMessage(peer_id, message, true));
                     }
+                    peer.chat_input.clear();
+                } else {
+                    console::error!("❌ No peer public key available for encryption");
+                    ctx.link().send_message(Msg::ShowDialog("The peer has not proven its public key yet. Please wait until the connection is verified.".to_string()));
                 }
                 true
             }
-            Msg::UpdateChatInput(input) => {
-                self.state.chat_input = input;
+            Msg::UpdateChatInput(peer_id, input) => {
+                if let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) {
+                    peer.chat_input = input;
+                }
                 true
             }
-            Msg::AddChatMessage(content, is_sent) => {
+            Msg::AddChatMessage(peer_id, content, is_sent) => {
+                let shown =
+                    self.state.chat_visible && self.state.active_chat == Some(peer_id.clone());
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
                 let timestamp = Date::now();
                 let message = ChatMessage {
                     content,
                     is_sent,
                     timestamp,
                 };
-                self.state.chat_messages.push(message);
+                if let Some(key) = peer.public_key.clone() {
+                    let message = message.clone();
+                    spawn_local(async move {
+                        if let Err(e) = history::append(&key, &message).await {
+                            console::error!(&format!("❌ Failed to save chat message: {}", e));
+                        }
+                    });
+                }
+                peer.chat_messages.push(message);
+                if !shown {
+                    peer.unread += 1;
+                    return true;
+                }
 
                 // Auto-scroll to bottom after adding message
                 spawn_local(async move {
@@ -1050,41 +1900,214 @@ impl Component for App {
 
                 true
             }
-            Msg::SetPeerPublicKey(public_key) => {
-                self.state.rtc_peer_public_key = Some(public_key);
+            Msg::PeerPublicKeyReceived(peer_id, public_key) => {
+                console::log!("📨 PeerPublicKeyReceived message received");
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                // 同じ鍵の再送なら検証をやり直さない
+                if matches!(peer.auth, Some(ref auth)
+                    if auth.peer_key == public_key && !matches!(auth.status, PeerStatus::Failed(_)))
+                {
+                    return false;
+                }
+                let (mut auth, encrypted_challenge) = match PeerAuth::start(&public_key) {
+                    Ok(started) => started,
+                    Err(e) => {
+                        console::error!(&format!("❌ Failed to start peer verification: {}", e));
+                        return false;
+                    }
+                };
+                peer.public_key = None;
+                match peer.session {
+                    // The sealed signals already told us who should be on the other end
+                    Some(ref session) if session.peer_key != public_key => {
+                        auth.status = PeerStatus::Failed(
+                            "The peer's key is not the key of the contact this connection was set up with. The connection may be intercepted.".to_string(),
+                        );
+                    }
+                    _ => {
+                        let message = RtcMessage::Challenge {
+                            encrypted_challenge,
+                        };
+                        if let Err(e) = peer.connection.clone().send_message(&message) {
+                            console::error!(&format!("❌ Failed to send challenge: {:?}", e));
+                        }
+                    }
+                }
+                peer.auth = Some(auth);
+                true
+            }
+            Msg::PeerChallengeReceived(peer_id, encrypted_challenge) => {
+                console::log!("📨 PeerChallengeReceived message received");
+                let (Some(ref keys), Some(peer)) =
+                    (&self.state.my_keys, self.state.rtc_peers.get(&peer_id))
+                else {
+                    return false;
+                };
+                let connection = &peer.connection;
+                match peer_auth::respond(&encrypted_challenge, keys, connection.dtls_fingerprints())
+                {
+                    Ok((proof, signing_key)) => {
+                        if let Err(e) = connection
+                            .clone()
+                            .send_message(&RtcMessage::Proof { proof, signing_key })
+                        {
+                            console::error!(&format!("❌ Failed to send proof: {:?}", e));
+                        }
+                    }
+                    Err(e) => console::error!(&format!("❌ Failed to answer challenge: {}", e)),
+                }
+                false
+            }
+            Msg::PeerProofReceived(peer_id, proof, signing_key) => {
+                console::log!("📨 PeerProofReceived message received");
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                let fingerprints = peer.connection.dtls_fingerprints();
+                let Some(ref mut auth) = peer.auth else {
+                    return false;
+                };
+                auth.check(&proof, &signing_key, fingerprints);
+                match auth.status {
+                    PeerStatus::Verified => {
+                        console::log!("✅ Peer key verified");
+                        ctx.link().send_message(Msg::SetPeerPublicKey(
+                            peer_id.clone(),
+                            auth.peer_key.clone(),
+                        ));
+                    }
+                    PeerStatus::Failed(ref reason) => {
+                        console::error!(&format!("❌ Peer verification failed: {}", reason));
+                    }
+                    PeerStatus::Verifying => {}
+                }
+                // 接続で確認できた署名鍵だけを連絡先に記録する
+                if let (peer_key, Some(signing_key)) =
+                    (auth.peer_key.clone(), auth.signing_key.clone())
+                {
+                    self.confirm_signing_key(&peer_key, signing_key);
+                }
+                true
+            }
+            Msg::SetPeerPublicKey(peer_id, public_key) => {
+                // 同じ相手への古い接続は再接続で置き換える
+                let stale: Vec<String> = self
+                    .state
+                    .rtc_peers
+                    .iter()
+                    .filter(|(id, peer)| {
+                        **id != peer_id && peer.public_key.as_ref() == Some(&public_key)
+                    })
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                for id in stale {
+                    if let Some(peer) = self.state.rtc_peers.remove(&id) {
+                        peer.connection.close();
+                    }
+                    if self.state.active_chat.as_ref() == Some(&id) {
+                        self.state.active_chat = Some(peer_id.clone());
+                    }
+                }
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                peer.public_key = Some(public_key.clone());
+                // 中断された送信を再開する
+                for transfer in self.state.file_transfers.iter().filter(|t| {
+                    t.direction == Direction::Sent
+                        && t.peer_key == public_key
+                        && !t.is_finished()
+                        && t.status != TransferStatus::Encrypting
+                }) {
+                    self.send_rtc_message(&peer_id, &transfer.offer());
+                }
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    match history::load_latest(&public_key).await {
+                        Ok(page) => ctx_link.send_message(Msg::ChatHistoryLoaded(public_key, page)),
+                        Err(e) => {
+                            console::error!(&format!("❌ Failed to load chat history: {}", e))
+                        }
+                    }
+                });
+                true
+            }
+            Msg::ChatHistoryLoaded(peer_key, page) => {
+                console::log!("📨 ChatHistoryLoaded message received");
+                let Some(peer) = self.rtc_peer_by_key_mut(&peer_key) else {
+                    return false;
+                };
+                // Messages received before the peer key arrived are kept
+                let mut messages = page.messages;
+                for message in peer.chat_messages.drain(..) {
+                    if !messages.contains(&message) {
+                        messages.push(message);
+                    }
+                }
+                peer.chat_messages = messages;
+                peer.chat_older_page = page.older;
+                true
+            }
+            Msg::LoadOlderChatMessages(peer_id) => {
+                console::log!("📨 LoadOlderChatMessages message received");
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                let (Some(peer_key), Some(page)) =
+                    (peer.public_key.clone(), peer.chat_older_page.take())
+                else {
+                    return false;
+                };
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    match history::load_page(&peer_key, page).await {
+                        Ok(page) => {
+                            ctx_link.send_message(Msg::OlderChatMessagesLoaded(peer_key, page))
+                        }
+                        Err(e) => {
+                            console::error!(&format!("❌ Failed to load chat history: {}", e))
+                        }
+                    }
+                });
+                true
+            }
+            Msg::OlderChatMessagesLoaded(peer_key, page) => {
+                console::log!("📨 OlderChatMessagesLoaded message received");
+                let Some(peer) = self.rtc_peer_by_key_mut(&peer_key) else {
+                    return false;
+                };
+                let mut messages = page.messages;
+                messages.append(&mut peer.chat_messages);
+                peer.chat_messages = messages;
+                peer.chat_older_page = page.older;
                 true
             }
-            Msg::DecryptReceivedMessage(encrypted_data) => {
+            Msg::DecryptReceivedMessage(peer_id, encrypted_data) => {
                 console::log!("🔓 Decrypting received message");
-                if let Some(ref my_keys) = self.state.my_keys {
-                    if let Some(ref worker) = self.state.worker {
-                        let worker_clone = worker.clone();
-                        let private_key = my_keys.private_key.clone();
-
-                        spawn_local(async move {
-                            let decrypt_message = MainMessage::Decrypt {
-                                private_key,
-                                data: encrypted_data,
-                            };
-
-                            if let Ok(js_message) = serde_wasm_bindgen::to_value(&decrypt_message) {
-                                worker_clone.post_message(&js_message).unwrap_or_else(|e| {
-                                    console::error!(&format!(
-                                        "❌ Failed to post decrypt message: {:?}",
-                                        e
-                                    ));
-                                });
-                            }
+                if let (Some(ref my_keys), Some(ref worker)) =
+                    (&self.state.my_keys, &self.state.worker)
+                {
+                    let decrypt_message = MainMessage::DecryptChat {
+                        peer: peer_id,
+                        private_key: my_keys.private_key.clone(),
+                        data: encrypted_data,
+                    };
+
+                    if let Ok(js_message) = serde_wasm_bindgen::to_value(&decrypt_message) {
+                        worker.post_message(&js_message).unwrap_or_else(|e| {
+                            console::error!(&format!("❌ Failed to post decrypt message: {:?}", e));
                         });
                     }
                 }
                 true
             }
-            Msg::SendEncryptedChatMessage(encrypted_data) => {
+            Msg::SendEncryptedChatMessage(peer_id, encrypted_data) => {
                 console::log!("📨 Sending encrypted chat message");
-                if let Some(ref connection) = self.state.rtc_connection {
+                if let Some(peer) = self.state.rtc_peers.get(&peer_id) {
                     let rtc_message = RtcMessage::EncryptedData { encrypted_data };
-                    let mut conn = connection.clone();
+                    let mut conn = peer.connection.clone();
                     match conn.send_message(&rtc_message) {
                         Ok(_) => {
                             console::log!("✅ Encrypted chat message sent successfully");
@@ -1099,10 +2122,802 @@ impl Component for App {
                 }
                 true
             }
-            Msg::ClearChatHistory => {
-                self.state.chat_messages.clear();
+            Msg::ClearChatHistory(peer_id) => {
+                let Some(peer) = self.state.rtc_peers.get_mut(&peer_id) else {
+                    return false;
+                };
+                peer.chat_messages.clear();
+                peer.chat_older_page = None;
+                if let Some(key) = peer.public_key.clone() {
+                    spawn_local(async move {
+                        if let Err(e) = history::clear(&key).await {
+                            console::error!(&format!("❌ Failed to delete chat history: {}", e));
+                        }
+                    });
+                }
+                true
+            }
+            Msg::SendFile(peer_id, file) => {
+                console::log!("📨 SendFile message received");
+                let Some(peer_key) = self
+                    .state
+                    .rtc_peers
+                    .get(&peer_id)
+                    .and_then(|peer| peer.public_key.clone())
+                else {
+                    ctx.link().send_message(Msg::ShowDialog(
+                        "The peer has not proven its public key yet. Please wait until the connection is verified.".to_string(),
+                    ));
+                    return false;
+                };
+                let size = file.size() as u64;
+                if size > file_transfer::MAX_FILE_SIZE {
+                    ctx.link().send_message(Msg::ShowDialog(format!(
+                        "Files larger than {} MB cannot be sent.",
+                        file_transfer::MAX_FILE_SIZE / (1024 * 1024)
+                    )));
+                    return false;
+                }
+                let Some(worker) = self.state.worker.clone() else {
+                    return false;
+                };
+                let id = new_id();
+                self.state.file_transfers.push(FileTransfer::outgoing(
+                    id.clone(),
+                    peer_key.clone(),
+                    file.name(),
+                    size,
+                ));
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    let result = read_file_bytes(&file)
+                        .await
+                        .map_err(|e| format!("Failed to read the file: {:?}", e))
+                        .and_then(|data| {
+                            serde_wasm_bindgen::to_value(&MainMessage::EncryptFile {
+                                id: id.clone(),
+                                public_key: peer_key,
+                                data,
+                            })
+                            .map_err(|e| format!("{:?}", e))
+                        })
+                        .and_then(|message| {
+                            worker
+                                .post_message(&message)
+                                .map_err(|e| format!("{:?}", e))
+                        });
+                    if let Err(e) = result {
+                        console::error!(&format!("❌ Failed to encrypt file: {}", e));
+                        ctx_link.send_message(Msg::FileTransferFailed(id, e));
+                    }
+                });
+                true
+            }
+            Msg::FileEncrypted(id, data, sha256) => {
+                console::log!("📨 FileEncrypted message received");
+                let Some(transfer) = self.state.file_transfers.iter_mut().find(|t| t.id == id)
+                else {
+                    return false;
+                };
+                transfer.set_encrypted(data, sha256);
+                let offer = transfer.offer();
+                let peer_key = transfer.peer_key.clone();
+                if let Some(peer_id) = self.rtc_peer_id(&peer_key) {
+                    self.send_rtc_message(&peer_id, &offer);
+                }
+                true
+            }
+            Msg::FileDecrypted(id, data) => {
+                console::log!("📨 FileDecrypted message received");
+                let Some(transfer) = self.state.file_transfers.iter_mut().find(|t| t.id == id)
+                else {
+                    return false;
+                };
+                transfer.complete(Some(data));
+                let peer_key = transfer.peer_key.clone();
+                if let Some(peer_id) = self.rtc_peer_id(&peer_key) {
+                    self.send_rtc_message(&peer_id, &RtcMessage::FileComplete { id });
+                }
+                true
+            }
+            Msg::FileTransferFailed(id, message) => {
+                console::log!("📨 FileTransferFailed message received");
+                let Some(transfer) = self.state.file_transfers.iter_mut().find(|t| t.id == id)
+                else {
+                    return false;
+                };
+                transfer.fail(&message);
+                // 受信側の失敗は送信側にも伝える
+                if transfer.direction == Direction::Received {
+                    let peer_key = transfer.peer_key.clone();
+                    if let Some(peer_id) = self.rtc_peer_id(&peer_key) {
+                        self.send_rtc_message(&peer_id, &RtcMessage::FileFailed { id, message });
+                    }
+                }
+                true
+            }
+            Msg::FileTransferMessage(peer_id, message) => {
+                console::log!("📨 FileTransferMessage message received");
+                // Files are only exchanged with a verified peer
+                let Some(peer_key) = self
+                    .state
+                    .rtc_peers
+                    .get(&peer_id)
+                    .and_then(|peer| peer.public_key.clone())
+                else {
+                    return false;
+                };
+                let find = |transfers: &mut Vec<FileTransfer>, id: &str, direction: Direction| {
+                    transfers.iter_mut().position(|t| {
+                        t.id == id && t.peer_key == peer_key && t.direction == direction
+                    })
+                };
+                match message {
+                    RtcMessage::FileOffer {
+                        id,
+                        name,
+                        size,
+                        encrypted_size,
+                        sha256,
+                    } => {
+                        let index = find(&mut self.state.file_transfers, &id, Direction::Received);
+                        let offset = match index.map(|i| &mut self.state.file_transfers[i]) {
+                            Some(transfer)
+                                if transfer.same_file(&name, encrypted_size, &sha256) =>
+                            {
+                                match transfer.status {
+                                    TransferStatus::Complete => {
+                                        self.send_rtc_message(
+                                            &peer_id,
+                                            &RtcMessage::FileComplete { id },
+                                        );
+                                        return false;
+                                    }
+                                    TransferStatus::Verifying => return false,
+                                    _ => {
+                                        transfer.status = TransferStatus::Transferring;
+                                        transfer.transferred()
+                                    }
+                                }
+                            }
+                            _ => {
+                                match FileTransfer::incoming(
+                                    id.clone(),
+                                    peer_key.clone(),
+                                    name,
+                                    size,
+                                    encrypted_size,
+                                    sha256,
+                                ) {
+                                    Ok(mut transfer) => {
+                                        transfer.status = TransferStatus::Transferring;
+                                        self.state.file_transfers.retain(|t| t.id != id);
+                                        self.state.file_transfers.push(transfer);
+                                        0
+                                    }
+                                    Err(message) => {
+                                        self.send_rtc_message(
+                                            &peer_id,
+                                            &RtcMessage::FileFailed { id, message },
+                                        );
+                                        return false;
+                                    }
+                                }
+                            }
+                        };
+                        self.send_rtc_message(&peer_id, &RtcMessage::FileAccept { id, offset });
+                    }
+                    RtcMessage::FileAccept { id, offset } => {
+                        let Some(index) =
+                            find(&mut self.state.file_transfers, &id, Direction::Sent)
+                        else {
+                            return false;
+                        };
+                        let transfer = &mut self.state.file_transfers[index];
+                        if transfer.is_finished() || transfer.status == TransferStatus::Encrypting {
+                            return false;
+                        }
+                        transfer.start_at(offset);
+                        ctx.link().send_message(Msg::PumpFileTransfers(peer_id));
+                    }
+                    RtcMessage::FileComplete { id } => {
+                        if let Some(index) =
+                            find(&mut self.state.file_transfers, &id, Direction::Sent)
+                        {
+                            self.state.file_transfers[index].complete(None);
+                        }
+                    }
+                    RtcMessage::FileFailed { id, message } => {
+                        if let Some(transfer) = self
+                            .state
+                            .file_transfers
+                            .iter_mut()
+                            .find(|t| t.id == id && t.peer_key == peer_key)
+                        {
+                            transfer.fail(&format!("The peer reported: {}", message));
+                        }
+                    }
+                    _ => return false,
+                }
+                true
+            }
+            Msg::FileChunkReceived(peer_id, frame) => {
+                let Some(peer_key) = self
+                    .state
+                    .rtc_peers
+                    .get(&peer_id)
+                    .and_then(|peer| peer.public_key.clone())
+                else {
+                    return false;
+                };
+                let Some((id, offset, chunk)) = file_transfer::decode_frame(&frame) else {
+                    console::error!("❌ Malformed file frame");
+                    return false;
+                };
+                let Some(transfer) = self.state.file_transfers.iter_mut().find(|t| {
+                    t.id == id && t.peer_key == peer_key && t.direction == Direction::Received
+                }) else {
+                    return false;
+                };
+                let percent = |t: &FileTransfer| t.transferred() * 100 / t.encrypted_size.max(1);
+                let before = percent(transfer);
+                if transfer.receive(offset, chunk) {
+                    let (Some(worker), Some(keys)) = (&self.state.worker, &self.state.my_keys)
+                    else {
+                        return false;
+                    };
+                    let result = serde_wasm_bindgen::to_value(&MainMessage::DecryptFile {
+                        id: id.clone(),
+                        private_key: keys.private_key.clone(),
+                        sha256: transfer.sha256.clone(),
+                        data: transfer.take_data(),
+                    })
+                    .map_err(|e| format!("{:?}", e))
+                    .and_then(|message| {
+                        worker
+                            .post_message(&message)
+                            .map_err(|e| format!("{:?}", e))
+                    });
+                    if let Err(e) = result {
+                        ctx.link().send_message(Msg::FileTransferFailed(id, e));
+                    }
+                    return true;
+                }
+                // 進捗が変わった時だけ再描画する
+                before != percent(transfer) || transfer.is_finished()
+            }
+            Msg::PumpFileTransfers(peer_id) => {
+                let Some(peer) = self.state.rtc_peers.get(&peer_id) else {
+                    return false;
+                };
+                let Some(ref peer_key) = peer.public_key else {
+                    return false;
+                };
+                // Sending stops with the channel; the transfer resumes on reconnection
+                if let Err(e) =
+                    file_transfer::pump(&mut self.state.file_transfers, peer_key, &peer.connection)
+                {
+                    console::error!(&format!("❌ Failed to send file data: {:?}", e));
+                }
+                true
+            }
+            Msg::ResumeFileTransfer(id) => {
+                console::log!("📨 ResumeFileTransfer message received");
+                if let Some(transfer) = self.state.file_transfers.iter().find(|t| t.id == id) {
+                    if let Some(peer_id) = self.rtc_peer_id(&transfer.peer_key) {
+                        self.send_rtc_message(&peer_id, &transfer.offer());
+                    }
+                }
+                false
+            }
+            Msg::DownloadFile(id) => {
+                if let Some(transfer) = self.state.file_transfers.iter().find(|t| t.id == id) {
+                    if let Some(ref file) = transfer.file {
+                        if let Err(e) =
+                            download_bytes(&transfer.name, "application/octet-stream", file)
+                        {
+                            error_report(&format!("❌ Failed to download file: {:?}", e));
+                        }
+                    }
+                }
+                false
+            }
+            Msg::DismissFileTransfer(id) => {
+                self.state.file_transfers.retain(|t| t.id != id);
+                true
+            }
+            Msg::QrSettingsLoaded(settings) => {
+                console::log!("📨 QrSettingsLoaded message received");
+                self.state.qr_settings = settings;
+                true
+            }
+            Msg::ShowQrSettingsDialog => {
+                self.state.qr_settings_dialog_visible = true;
+                true
+            }
+            Msg::HideQrSettingsDialog => {
+                self.state.qr_settings_dialog_visible = false;
+                true
+            }
+            Msg::ShowIceSettingsDialog => {
+                self.state.ice_settings_dialog_visible = true;
+                true
+            }
+            Msg::HideIceSettingsDialog => {
+                self.state.ice_settings_dialog_visible = false;
+                true
+            }
+            Msg::SaveIceSettings(settings) => {
+                console::log!("📨 SaveIceSettings message received");
+                let settings = settings.normalized();
+                self.state.ice_settings = settings.clone();
+                self.state.ice_settings_dialog_visible = false;
+
+                // 次の接続から新しい設定を使う
+                spawn_local(async move {
+                    save_ice_settings(&settings).await;
+                });
+                true
+            }
+            Msg::SaveQrSettings(settings) => {
+                console::log!("📨 SaveQrSettings message received");
+                let settings = settings.normalized();
+                if let Some(ref logo_url) = settings.logo_url {
+                    if let Err(e) = qr::check_logo_url(logo_url, &qr::page_origin()) {
+                        ctx.link().send_message(Msg::ShowDialog(e));
+                        return false;
+                    }
+                }
+                self.state.qr_settings = settings.clone();
+                self.state.qr_settings_dialog_visible = false;
+
+                spawn_local(async move {
+                    save_qr_settings(&settings).await;
+                });
+
+                // 新しい設定でQRコードを再描画
+                if let Some(ref keys) = self.state.my_keys {
+                    ctx.link()
+                        .send_message(Msg::DrawQrCode(keys.public_key.clone()));
+                }
+                if let Some(ref encrypted_data) = self.state.encrypted_qr_data {
+                    ctx.link()
+                        .send_message(Msg::ShowEncryptedQr(encrypted_data.clone()));
+                }
+                true
+            }
+            Msg::QrCodeDrawn(canvas_id, params) => {
+                self.state.qr_params.insert(canvas_id, params);
+                true
+            }
+            Msg::DownloadQrSvg(canvas_id) => {
+                console::log!("📨 DownloadQrSvg message received");
+                if let Some((payload, file_stem)) = self.qr_payload(&canvas_id) {
+                    let result = qr::render_svg(&payload, &self.state.qr_settings)
+                        .map_err(|e| JsValue::from_str(&e))
+                        .and_then(|svg| {
+                            download_text(&format!("{}.svg", file_stem), "image/svg+xml", &svg)
+                        });
+                    if let Err(e) = result {
+                        console::error!(&format!("❌ Failed to download SVG: {:?}", e));
+                        ctx.link()
+                            .send_message(Msg::ShowDialog("Failed to download SVG".to_string()));
+                    }
+                }
+                false
+            }
+            Msg::DecodeQrImage(file) => {
+                console::log!("📨 DecodeQrImage message received");
+                if let Some(worker) = self.state.worker.clone() {
+                    let ctx_link = ctx.link().clone();
+                    spawn_local(async move {
+                        match read_file_bytes(&file).await {
+                            Ok(image) => {
+                                match serde_wasm_bindgen::to_value(&MainMessage::DecodeQrImage {
+                                    image,
+                                }) {
+                                    Ok(decode_message) => {
+                                        if let Err(e) = worker.post_message(&decode_message) {
+                                            console::error!(&format!(
+                                                "❌ Failed to post decode image message: {:?}",
+                                                e
+                                            ));
+                                        }
+                                    }
+                                    Err(e) => {
+                                        console::error!(&format!(
+                                            "❌ Failed to serialize decode image message: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                console::error!(&format!("❌ Failed to read image: {:?}", e));
+                                ctx_link.send_message(Msg::ShowDialog(
+                                    "Failed to read image file".to_string(),
+                                ));
+                            }
+                        }
+                    });
+                } else {
+                    ctx.link()
+                        .send_message(Msg::ShowDialog("Worker not available".to_string()));
+                }
+                false
+            }
+            Msg::HandleDeepLink(link) => {
+                console::log!("📨 HandleDeepLink message received");
+                if self.state.my_keys.is_some() && self.state.worker.is_some() {
+                    process_qr_data(&link);
+                } else {
+                    // 鍵の読み込み完了後に処理する
+                    self.state.pending_deep_link = Some(link);
+                }
+                false
+            }
+            Msg::ProfileLoaded(profile) => {
+                self.state.profile = profile;
+                true
+            }
+            Msg::SaveDisplayName(name) => {
+                console::log!("📨 SaveDisplayName message received");
+                let name = name.trim().to_string();
+                if name != self.state.profile.display_name {
+                    self.state.profile = Profile {
+                        display_name: name,
+                        created_at: unix_time(),
+                    };
+                    let profile = self.state.profile.clone();
+                    spawn_local(async move {
+                        save_profile(&profile).await;
+                    });
+                    if let Some(ref keys) = self.state.my_keys {
+                        ctx.link()
+                            .send_message(Msg::DrawQrCode(keys.public_key.clone()));
+                    }
+                }
+                true
+            }
+            Msg::ShowBackupDialog => {
+                self.state.backup_dialog_visible = true;
+                true
+            }
+            Msg::HideBackupDialog => {
+                self.state.backup_dialog_visible = false;
+                true
+            }
+            Msg::ExportBackup(passphrase, include_chat_history) => {
+                console::log!("📨 ExportBackup message received");
+                let (Some(ref keys), Some(worker)) =
+                    (&self.state.my_keys, self.state.worker.clone())
+                else {
+                    return false;
+                };
+                let mut bundle = BackupBundle {
+                    format: backup::BACKUP_FORMAT.to_string(),
+                    version: backup::BACKUP_VERSION,
+                    created_at: unix_time(),
+                    identities: vec![keys.clone()],
+                    contacts: self.state.contacts.clone(),
+                    groups: self.state.groups.clone(),
+                    qr_settings: self.state.qr_settings.clone(),
+                    ice_settings: Some(self.state.ice_settings.clone()),
+                    profile: self.state.profile.clone(),
+                    chat_history: None,
+                };
+                self.state.backup_status = Some("Encrypting backup...".to_string());
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    // The stored history of every conversation, not only the open ones
+                    let result = async {
+                        if include_chat_history {
+                            bundle.chat_history = Some(history::read_all_chats().await?);
+                        }
+                        let data = serde_json::to_string(&bundle)
+                            .map_err(|e| format!("Failed to serialize backup: {}", e))?;
+                        let message = serde_wasm_bindgen::to_value(&MainMessage::EncryptBackup {
+                            passphrase,
+                            data,
+                        })
+                        .map_err(|e| format!("{:?}", e))?;
+                        worker
+                            .post_message(&message)
+                            .map_err(|e| format!("{:?}", e))
+                    }
+                    .await;
+                    if let Err(e) = result {
+                        console::error!(&format!("❌ Failed to export backup: {}", e));
+                        ctx_link.send_message(Msg::BackupFailed(format!(
+                            "Failed to export backup: {}",
+                            e
+                        )));
+                    }
+                });
+                true
+            }
+            Msg::BackupEncrypted(data) => {
+                console::log!("📨 BackupEncrypted message received");
+                self.state.backup_status = None;
+                let date = Date::new_0()
+                    .to_iso_string()
+                    .as_string()
+                    .unwrap_or_default();
+                let kind = if self.state.recovery_error.is_some() {
+                    "recovery"
+                } else {
+                    "backup"
+                };
+                let filename = format!(
+                    "qr-encrypt-{}-{}.age",
+                    kind,
+                    date.get(..10).unwrap_or_default()
+                );
+                match download_bytes(&filename, "application/octet-stream", &data) {
+                    Ok(()) => {
+                        self.state.backup_dialog_visible = false;
+                        ctx.link().send_message(Msg::ShowDialog(
+                            "Backup exported. Keep the file and its passphrase safe.".to_string(),
+                        ));
+                    }
+                    Err(e) => {
+                        console::error!(&format!("❌ Failed to download backup: {:?}", e));
+                        ctx.link()
+                            .send_message(Msg::ShowDialog("Failed to download backup".to_string()));
+                    }
+                }
+                true
+            }
+            Msg::ImportBackup(file, passphrase) => {
+                console::log!("📨 ImportBackup message received");
+                if let Some(worker) = self.state.worker.clone() {
+                    self.state.backup_status = Some("Decrypting backup...".to_string());
+                    let ctx_link = ctx.link().clone();
+                    spawn_local(async move {
+                        let result = match read_file_bytes(&file).await {
+                            Ok(data) => serde_wasm_bindgen::to_value(&MainMessage::DecryptBackup {
+                                passphrase,
+                                data,
+                            })
+                            .map_err(|e| format!("{:?}", e))
+                            .and_then(|message| {
+                                worker
+                                    .post_message(&message)
+                                    .map_err(|e| format!("{:?}", e))
+                            }),
+                            Err(e) => Err(format!("{:?}", e)),
+                        };
+                        if let Err(e) = result {
+                            console::error!(&format!("❌ Failed to start backup import: {}", e));
+                            ctx_link.send_message(Msg::BackupFailed(
+                                "Failed to read backup file".to_string(),
+                            ));
+                        }
+                    });
+                }
+                true
+            }
+            Msg::BackupDecrypted(data) => {
+                console::log!("📨 BackupDecrypted message received");
+                self.state.backup_status = None;
+                match BackupBundle::parse(&data, unix_time()) {
+                    Ok(bundle) => {
+                        let plan = backup::plan_import(
+                            &bundle,
+                            self.state.my_keys.as_ref(),
+                            &self.state.contacts,
+                            &self.state.groups,
+                            &self.state.qr_settings,
+                            &self.state.ice_settings,
+                            &self.state.profile,
+                        );
+                        self.state.backup_dialog_visible = false;
+                        self.state.pending_import = Some((bundle, plan));
+                    }
+                    Err(e) => {
+                        ctx.link().send_message(Msg::ShowDialog(e));
+                    }
+                }
+                true
+            }
+            Msg::BackupFailed(message) => {
+                console::log!("📨 BackupFailed message received");
+                self.state.backup_status = None;
+                ctx.link().send_message(Msg::ShowDialog(message));
                 true
             }
+            Msg::ConfirmImportBackup(replace_identity) => {
+                console::log!("📨 ConfirmImportBackup message received");
+                let Some((_, plan)) = self.state.pending_import.take() else {
+                    return false;
+                };
+
+                self.state.contacts = plan.contacts.clone();
+                let contacts = plan.contacts;
+                spawn_local(async move {
+                    save_contacts(&contacts).await;
+                });
+                if !plan.groups_added.is_empty() {
+                    self.state.groups = plan.groups;
+                    self.save_groups();
+                }
+                if let Some(settings) = plan.qr_settings {
+                    ctx.link().send_message(Msg::SaveQrSettings(settings));
+                }
+                if let Some(settings) = plan.ice_settings {
+                    ctx.link().send_message(Msg::SaveIceSettings(settings));
+                }
+                if let Some(profile) = plan.profile {
+                    self.state.profile = profile.clone();
+                    spawn_local(async move {
+                        save_profile(&profile).await;
+                    });
+                }
+                let replacement = match plan.identity_conflict {
+                    Some(keys) if replace_identity => Some(keys),
+                    _ => None,
+                };
+                if replacement.is_none() {
+                    if let Some(ref keys) = self.state.my_keys {
+                        ctx.link()
+                            .send_message(Msg::DrawQrCode(keys.public_key.clone()));
+                    }
+                    ctx.link()
+                        .send_message(Msg::ShowDialog("Backup imported successfully!".to_string()));
+                }
+                // Open conversations with these peers show the merged history
+                let open: Vec<String> = plan
+                    .chats
+                    .iter()
+                    .map(|(peer_key, _)| peer_key.clone())
+                    .filter(|peer_key| self.rtc_peer_id(peer_key).is_some())
+                    .collect();
+                let chats = plan.chats;
+                let ctx_link = ctx.link().clone();
+                spawn_local(async move {
+                    // 鍵を置き換える前に履歴を書き込む（置き換え時に再暗号化される）
+                    for (peer_key, messages) in chats {
+                        if let Err(e) = history::import(&peer_key, messages).await {
+                            error_report(&format!("❌ Failed to import chat history: {}", e));
+                            continue;
+                        }
+                        if !open.contains(&peer_key) {
+                            continue;
+                        }
+                        match history::load_latest(&peer_key).await {
+                            Ok(page) => {
+                                ctx_link.send_message(Msg::ChatHistoryLoaded(peer_key, page))
+                            }
+                            Err(e) => {
+                                console::error!(&format!("❌ Failed to load chat history: {}", e))
+                            }
+                        }
+                    }
+                    if let Some(keys) = replacement {
+                        ctx_link.send_message(Msg::ImportPrivateKeyWithPublicKey(
+                            keys.private_key,
+                            keys.public_key,
+                        ));
+                    }
+                });
+                true
+            }
+            Msg::CancelImportBackup => {
+                self.state.pending_import = None;
+                true
+            }
+            Msg::ShowGroupDialog(id) => {
+                self.state.group_dialog = Some(id);
+                true
+            }
+            Msg::HideGroupDialog => {
+                self.state.group_dialog = None;
+                true
+            }
+            Msg::SaveGroup(name, member_ids) => {
+                console::log!("📨 SaveGroup message received");
+                let Some(editing) = self.state.group_dialog.take() else {
+                    return false;
+                };
+                let members: Vec<&Contact> = self
+                    .state
+                    .contacts
+                    .iter()
+                    .filter(|c| member_ids.contains(&c.id))
+                    .collect();
+                let group = ContactGroup::new(name, &members);
+                match editing.and_then(|id| self.state.groups.iter_mut().find(|g| g.id == id)) {
+                    Some(existing) => {
+                        existing.name = group.name;
+                        existing.members = group.members;
+                    }
+                    None => self.state.groups.push(group),
+                }
+                self.state
+                    .groups
+                    .sort_by_key(|group| group.name.to_lowercase());
+                self.save_groups();
+                true
+            }
+            Msg::DeleteGroup(id) => {
+                console::log!("📨 DeleteGroup message received");
+                self.state.groups.retain(|group| group.id != id);
+                self.save_groups();
+                true
+            }
+            Msg::ExportRecipients => {
+                console::log!("📨 ExportRecipients message received");
+                let text = recipients::export(&self.state.contacts);
+                if let Err(e) = download_bytes("recipients.txt", "text/plain", text.as_bytes()) {
+                    console::error!(&format!("❌ Failed to download recipients: {:?}", e));
+                    ctx.link().send_message(Msg::ShowDialog(
+                        "Failed to download recipients file".to_string(),
+                    ));
+                }
+                false
+            }
+            Msg::ImportRecipients(file) => {
+                console::log!("📨 ImportRecipients message received");
+                let ctx_link = ctx.link().clone();
+                let contacts = self.state.contacts.clone();
+                spawn_local(async move {
+                    let text = read_file_bytes(&file)
+                        .await
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|bytes| {
+                            String::from_utf8(bytes)
+                                .map_err(|_| "The file is not a text file".to_string())
+                        });
+                    match text {
+                        Ok(text) => {
+                            let diff = recipients::diff(&text, &contacts, unix_time());
+                            ctx_link.send_message(Msg::RecipientsDiffReady(diff));
+                        }
+                        Err(e) => ctx_link.send_message(Msg::ShowDialog(format!(
+                            "Failed to read recipients file: {}",
+                            e
+                        ))),
+                    }
+                });
+                false
+            }
+            Msg::RecipientsDiffReady(diff) => {
+                console::log!("📨 RecipientsDiffReady message received");
+                if diff.is_empty() && diff.unchanged.is_empty() {
+                    let mut message = "No age recipients found in this file.".to_string();
+                    if !diff.skipped.is_empty() {
+                        message = format!("{}\n{}", message, diff.skipped.join("\n"));
+                    }
+                    ctx.link().send_message(Msg::ShowDialog(message));
+                    return false;
+                }
+                self.state.pending_recipients = Some(diff);
+                true
+            }
+            Msg::ConfirmRecipientsImport(replace_changed) => {
+                console::log!("📨 ConfirmRecipientsImport message received");
+                let Some(diff) = self.state.pending_recipients.take() else {
+                    return false;
+                };
+                self.state.contacts = diff.apply(&self.state.contacts, replace_changed);
+                self.save_contacts();
+                true
+            }
+            Msg::CancelRecipientsImport => {
+                self.state.pending_recipients = None;
+                true
+            }
+            Msg::DownloadQrPng(canvas_id) => {
+                console::log!("📨 DownloadQrPng message received");
+                if let Some((_, file_stem)) = self.qr_payload(&canvas_id) {
+                    if let Err(e) = download_canvas_png(&canvas_id, &format!("{}.png", file_stem)) {
+                        console::error!(&format!("❌ Failed to download PNG: {:?}", e));
+                        ctx.link()
+                            .send_message(Msg::ShowDialog("Failed to download PNG".to_string()));
+                    }
+                }
+                false
+            }
         }
     }
 
@@ -1122,7 +2937,11 @@ impl Component for App {
             <div class="app">
                 <h1>{"qr-encrypt"}</h1>
 
-                if self.state.is_loading {
+                if let Some(ref error) = self.state.recovery_error {
+                    { self.render_recovery_screen(ctx, error) }
+                } else if self.state.locked {
+                    { self.render_lock_screen(ctx) }
+                } else if self.state.is_loading {
                     { self.render_loading_screen() }
                 } else if self.state.chat_visible {
                     { self.render_chat_view(ctx) }
@@ -1144,28 +2963,64 @@ impl Component for App {
                     { self.render_message_dialog(ctx) }
                 }
 
-                if self.state.encrypted_qr_visible && !self.state.is_loading {
-                    { self.render_encrypted_qr_dialog(ctx) }
+                if self.state.encrypted_qr_visible && !self.state.is_loading {
+                    { self.render_encrypted_qr_dialog(ctx) }
+                }
+
+                if self.state.delete_confirm_visible && !self.state.is_loading {
+                    { self.render_delete_confirm_dialog(ctx) }
+                }
+
+                if self.state.export_private_key_dialog_visible && !self.state.is_loading {
+                    { self.render_export_private_key_dialog(ctx) }
+                }
+
+                if self.state.private_key_import_confirm_visible && !self.state.is_loading {
+                    { self.render_private_key_import_confirm_dialog(ctx) }
+                }
+
+                if self.state.add_contact_dialog_visible && !self.state.is_loading {
+                    { self.render_add_contact_dialog(ctx) }
+                }
+
+                if self.state.editing_contact.is_some() && !self.state.is_loading {
+                    { self.render_edit_contact_dialog(ctx) }
+                }
+
+                if self.state.group_dialog.is_some() && !self.state.is_loading {
+                    { self.render_group_dialog(ctx) }
+                }
+
+                if self.state.reset_confirm_visible && !self.state.is_loading {
+                    { self.render_reset_confirm_dialog(ctx) }
+                }
+
+                if self.state.qr_settings_dialog_visible && !self.state.is_loading {
+                    { self.render_qr_settings_dialog(ctx) }
+                }
+
+                if self.state.lock_settings_visible && !self.state.is_loading {
+                    { self.render_lock_settings_dialog(ctx) }
                 }
 
-                if self.state.delete_confirm_visible && !self.state.is_loading {
-                    { self.render_delete_confirm_dialog(ctx) }
+                if self.state.ice_settings_dialog_visible && !self.state.is_loading {
+                    { self.render_ice_settings_dialog(ctx) }
                 }
 
-                if self.state.export_private_key_dialog_visible && !self.state.is_loading {
-                    { self.render_export_private_key_dialog(ctx) }
+                if self.state.backup_dialog_visible && !self.state.is_loading {
+                    { self.render_backup_dialog(ctx) }
                 }
 
-                if self.state.private_key_import_confirm_visible && !self.state.is_loading {
-                    { self.render_private_key_import_confirm_dialog(ctx) }
+                if self.state.message_log_visible && !self.state.is_loading {
+                    { self.render_message_log_dialog(ctx) }
                 }
 
-                if self.state.add_contact_dialog_visible && !self.state.is_loading {
-                    { self.render_add_contact_dialog(ctx) }
+                if self.state.pending_import.is_some() && !self.state.is_loading {
+                    { self.render_import_summary_dialog(ctx) }
                 }
 
-                if self.state.reset_confirm_visible && !self.state.is_loading {
-                    { self.render_reset_confirm_dialog(ctx) }
+                if self.state.pending_recipients.is_some() && !self.state.is_loading {
+                    { self.render_recipients_diff_dialog(ctx) }
                 }
 
                 if let Some(ref message) = self.state.dialog_message {
@@ -1206,37 +3061,41 @@ impl App {
                     ));
 
                     spawn_local(async move {
-                        console::log!("💾 localStorage check started");
+                        console::log!("💾 Storage check started");
 
                         link_clone.send_message(Msg::UpdateLoadingProgress(
                             "Searching for keys...".to_string(),
                             Some(20),
                         ));
 
-                        if let Some(keys) = load_my_keys().await {
-                            console::log!("✅ Existing keys found");
-
-                            link_clone.send_message(Msg::UpdateLoadingProgress(
-                                "Keys loaded successfully".to_string(),
-                                Some(90),
-                            ));
-
-                            let contacts = load_contacts().await;
+                        if let Err(e) = migrations::run(None).await {
+                            link_clone.send_message(Msg::MigrationFailed(e));
+                            return;
+                        }
 
-                            link_clone.send_message(Msg::UpdateLoadingProgress(
-                                "Application ready".to_string(),
-                                Some(100),
-                            ));
+                        let lock_settings = autolock::load_settings().await;
+                        let protected = matches!(autolock::protected_key().await, Ok(Some(_)));
+                        link_clone.send_message(Msg::LockSettingsLoaded(lock_settings, protected));
 
-                            link_clone.send_message(Msg::SetLoading(false));
-                            link_clone.send_message(Msg::KeysLoaded(keys, contacts));
+                        if let Some(keys) = load_my_keys().await {
+                            console::log!("✅ Existing keys found");
+                            open_session(link_clone, keys).await;
+                        } else if protected {
+                            console::log!("🔒 Private key is protected by a passphrase");
+                            link_clone.send_message(Msg::Locked);
                         } else {
                             console::log!("⚪ No existing keys found");
-                            link_clone.send_message(Msg::UpdateLoadingProgress(
-                                "Generating new keys...".to_string(),
-                                Some(30),
-                            ));
-                            link_clone.send_message(Msg::GenerateKeys);
+                            // 鍵の生成は一つのタブだけが行う
+                            sync::discover().await;
+                            if sync::is_leader() {
+                                link_clone.send_message(Msg::UpdateLoadingProgress(
+                                    "Generating new keys...".to_string(),
+                                    Some(30),
+                                ));
+                                link_clone.send_message(Msg::GenerateKeys);
+                            } else {
+                                link_clone.send_message(Msg::WaitForKeyGeneration);
+                            }
                         }
                     });
                 }
@@ -1255,13 +3114,24 @@ impl App {
 
                         save_my_keys(&private_key, &public_key).await;
                         console::log!("✅ Keys saved successfully");
+                        sync::notify(SyncEvent::KeysChanged);
+
+                        if let Err(e) = migrations::run(Some(&private_key)).await {
+                            link_clone.send_message(Msg::MigrationFailed(e));
+                            return;
+                        }
+                        if let Err(e) = vault::unlock(&private_key).await {
+                            error_report(&format!("❌ Failed to create vault: {}", e));
+                        }
+                        link_clone.send_message(Msg::QrSettingsLoaded(load_qr_settings().await));
+                        link_clone.send_message(Msg::ProfileLoaded(load_profile().await));
 
                         let keys = KeyPair {
                             public_key: public_key.clone(),
                             private_key,
                         };
 
-                        let contacts = HashMap::new();
+                        let contacts = load_contacts().await;
                         link_clone.send_message(Msg::UpdateLoadingProgress(
                             "Application ready".to_string(),
                             Some(100),
@@ -1304,6 +3174,58 @@ impl App {
                     console::log!("✅ QR data processed successfully");
                     dispatch_custom_event(&event_type, &event_data);
                 }
+                Ok(WorkerMessage::QrImageDecoded { data }) => {
+                    console::log!("✅ QR code decoded from image");
+                    dispatch_custom_event("process_qr_data", &data);
+                }
+                Ok(WorkerMessage::BackupEncrypted { data }) => {
+                    console::log!("✅ Backup encrypted");
+                    link.send_message(Msg::BackupEncrypted(data));
+                }
+                Ok(WorkerMessage::BackupDecrypted { data }) => {
+                    console::log!("✅ Backup decrypted");
+                    link.send_message(Msg::BackupDecrypted(data));
+                }
+                Ok(WorkerMessage::BackupFailed { message }) => {
+                    console::error!(&format!("❌ {}", message));
+                    link.send_message(Msg::BackupFailed(message));
+                }
+                Ok(WorkerMessage::KeyProtected { data }) => {
+                    console::log!("✅ Private key protected");
+                    link.send_message(Msg::KeyProtected(data));
+                }
+                Ok(WorkerMessage::KeyUnlocked { private_key }) => {
+                    console::log!("✅ Private key unlocked");
+                    link.send_message(Msg::KeyUnlocked(private_key));
+                }
+                Ok(WorkerMessage::KeyUnlockFailed { message }) => {
+                    console::error!(&format!("❌ {}", message));
+                    link.send_message(Msg::KeyUnlockFailed(message));
+                }
+                Ok(WorkerMessage::ChatEncrypted {
+                    peer,
+                    encrypted_data,
+                }) => {
+                    link.send_message(Msg::SendEncryptedChatMessage(peer, encrypted_data));
+                }
+                Ok(WorkerMessage::ChatDecrypted {
+                    peer,
+                    decrypted_data,
+                }) => {
+                    link.send_message(Msg::AddChatMessage(peer, decrypted_data, false));
+                }
+                Ok(WorkerMessage::FileEncrypted { id, sha256, data }) => {
+                    console::log!("✅ File encrypted");
+                    link.send_message(Msg::FileEncrypted(id, data, sha256));
+                }
+                Ok(WorkerMessage::FileDecrypted { id, data }) => {
+                    console::log!("✅ File decrypted");
+                    link.send_message(Msg::FileDecrypted(id, data));
+                }
+                Ok(WorkerMessage::FileFailed { id, message }) => {
+                    console::error!(&format!("❌ {}", message));
+                    link.send_message(Msg::FileTransferFailed(id, message));
+                }
                 Ok(WorkerMessage::Error { message }) => {
                     error_report(&message);
                 }
@@ -1368,37 +3290,303 @@ impl App {
         }
     }
 
-    fn add_contact(&mut self, name: String, public_key: String) {
-        self.state.contacts.insert(name.clone(), public_key.clone());
+    fn add_contact(&mut self, contact: Contact) {
+        self.state.contacts.push(contact);
+        sort_contacts(&mut self.state.contacts);
+        self.save_contacts();
+    }
+
+    fn delete_contact(&mut self, id: &str) {
+        self.state.contacts.retain(|c| c.id != id);
+        self.save_contacts();
+    }
+
+    // The destination is a contact id or "group:" followed by a group id
+    fn encrypt_for_destination(&mut self, ctx: &Context<Self>, destination: &str, message: &str) {
+        let (public_keys, name, peer_key) = match destination.strip_prefix("group:") {
+            Some(group_id) => {
+                let Some(group) = self.state.groups.iter().find(|g| g.id == group_id).cloned()
+                else {
+                    ctx.link()
+                        .send_message(Msg::ShowDialog("Group not found".to_string()));
+                    return;
+                };
+                let recipients = group.resolve(&self.state.contacts);
+                if recipients.public_keys.is_empty() {
+                    ctx.link().send_message(Msg::ShowDialog(format!(
+                        "None of the members of '{}' are in your contacts anymore",
+                        group.name
+                    )));
+                    return;
+                }
+                // 削除された連絡先がある場合は確認する
+                if !recipients.missing.is_empty()
+                    && !confirm(&format!(
+                        "These members of '{}' have been deleted from your contacts and will not be able to read the message:\n\n{}\n\nSend to the remaining {} member(s)?",
+                        group.name,
+                        recipients.missing.join("\n"),
+                        recipients.public_keys.len()
+                    ))
+                {
+                    return;
+                }
+                let now = unix_time();
+                for contact in self.state.contacts.iter_mut() {
+                    if group.members.iter().any(|m| m.contact_id == contact.id) {
+                        contact.last_used = Some(now);
+                    }
+                }
+                self.save_contacts();
+                (
+                    recipients.public_keys,
+                    format!("Group: {}", group.name),
+                    None,
+                )
+            }
+            None => {
+                let Some(contact) = self.find_contact(destination).cloned() else {
+                    console::error!(&format!("❌ Contact {} not found", destination));
+                    ctx.link().send_message(Msg::ShowDialog(format!(
+                        "Contact '{}' not found",
+                        destination
+                    )));
+                    return;
+                };
+                self.touch_contact(destination);
+                (
+                    vec![contact.public_key.clone()],
+                    contact.name,
+                    Some(contact.public_key),
+                )
+            }
+        };
+
+        let Some(worker) = self.state.worker.clone() else {
+            console::error!("❌ Worker not available");
+            ctx.link()
+                .send_message(Msg::ShowDialog("Worker not available".to_string()));
+            return;
+        };
+        self.state.pending_outgoing = Some((name, peer_key));
+        match serde_wasm_bindgen::to_value(&MainMessage::Encrypt {
+            public_keys,
+            data: message.to_string(),
+        }) {
+            Ok(encrypt_message) => {
+                if let Err(e) = worker.post_message(&encrypt_message) {
+                    console::error!(&format!("❌ Failed to post encrypt message: {:?}", e));
+                    ctx.link().send_message(Msg::ShowDialog(
+                        "Failed to send encryption request".to_string(),
+                    ));
+                }
+            }
+            Err(e) => {
+                console::error!(&format!("❌ Failed to serialize encrypt message: {:?}", e));
+                ctx.link().send_message(Msg::ShowDialog(
+                    "Failed to prepare encryption request".to_string(),
+                ));
+            }
+        }
+    }
+
+    fn find_contact(&self, id: &str) -> Option<&Contact> {
+        self.state.contacts.iter().find(|c| c.id == id)
+    }
+
+    // 最終使用日時を更新する
+    fn touch_contact(&mut self, id: &str) {
+        if let Some(contact) = self.state.contacts.iter_mut().find(|c| c.id == id) {
+            contact.last_used = Some(unix_time());
+            self.save_contacts();
+        }
+    }
+
+    fn confirm_signing_key(&mut self, public_key: &str, signing_key: String) {
+        if let Some(contact) =
+            self.state.contacts.iter_mut().find(|c| {
+                c.public_key == public_key && c.signing_key.as_ref() != Some(&signing_key)
+            })
+        {
+            contact.signing_key = Some(signing_key);
+            self.save_contacts();
+        }
+    }
+
+    fn save_contacts(&self) {
         let contacts_clone = self.state.contacts.clone();
         spawn_local(async move {
             save_contacts(&contacts_clone).await;
         });
     }
 
-    fn delete_contact(&mut self, name: String) {
-        self.state.contacts.remove(&name);
-        let contacts_clone = self.state.contacts.clone();
+    fn save_groups(&self) {
+        let groups = self.state.groups.clone();
         spawn_local(async move {
-            save_contacts(&contacts_clone).await;
+            if let Err(e) = vault::update(|data| data.groups = groups).await {
+                console::error!(&format!("❌ Failed to save groups: {}", e));
+            }
         });
     }
 
-    fn reset_all_data(&mut self) {
-        console::log!("🗑️ Resetting all data");
+    fn save_message_log(&self) {
+        let entries = self.state.message_log.clone();
+        spawn_local(async move {
+            if let Err(e) = message_log::save(&entries).await {
+                console::error!(&format!("❌ Failed to save message log: {}", e));
+            }
+        });
+    }
 
-        // Clear localStorage
-        if let Some(storage) = get_local_storage() {
-            let _ = storage.remove_item("mySecretKey");
-            let _ = storage.remove_item("myPublicKey");
-            let _ = storage.remove_item("keys");
-            console::log!("✅ localStorage cleared");
+    fn send_rtc_message(&self, peer_id: &str, message: &RtcMessage) {
+        if let Some(peer) = self.state.rtc_peers.get(peer_id) {
+            if let Err(e) = peer.connection.clone().send_message(message) {
+                console::error!(&format!("❌ Failed to send RTC message: {:?}", e));
+            }
         }
+    }
+
+    // The connection on which `peer_key` has been verified
+    fn rtc_peer_id(&self, peer_key: &str) -> Option<String> {
+        self.state
+            .rtc_peers
+            .iter()
+            .find(|(_, peer)| peer.public_key.as_deref() == Some(peer_key))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn rtc_peer_by_key_mut(&mut self, peer_key: &str) -> Option<&mut RtcPeer> {
+        self.state
+            .rtc_peers
+            .values_mut()
+            .find(|peer| peer.public_key.as_deref() == Some(peer_key))
+    }
+
+    // Creates a connection with its handlers and makes it the one waiting
+    // for a reply. Shows the error if the ICE settings are rejected.
+    fn add_rtc_peer(
+        &mut self,
+        ctx: &Context<Self>,
+        session: Option<Session>,
+    ) -> Option<Connection> {
+        let peer_id = new_id();
+        let connection = match Connection::new(&self.state.ice_settings) {
+            Ok(connection) => connection,
+            Err(e) => {
+                let message = format!(
+                    "Failed to create the connection. Please check the ICE server settings: {}",
+                    storage::js_error(e)
+                );
+                console::error!(&message);
+                ctx.link().send_message(Msg::ShowDialog(message));
+                return None;
+            }
+        };
+
+        // 接続確立ハンドラを設定（ICE接続のみ）
+        let ctx_link_for_ice = ctx.link().clone();
+        let id = peer_id.clone();
+        let _ = connection.set_connection_established_handler(move || {
+            console::log!("🎉 WebRTC ICE connection established!");
+            ctx_link_for_ice.send_message(Msg::RtcConnectionEstablished(id.clone()));
+        });
+
+        // データチャネルオープンハンドラを設定（メッセージング準備完了）
+        let ctx_link_for_data = ctx.link().clone();
+        let id = peer_id.clone();
+        let _ = connection.set_data_channel_open_handler(move || {
+            console::log!("🎉 Data channel ready! Starting public key exchange");
+            ctx_link_for_data.send_message(Msg::SendPublicKeyViaRtc(id.clone()));
+        });
+
+        connection.set_extra_candidates_handler(extra_candidates_handler(
+            ctx.link(),
+            peer_id.clone(),
+            self.rtc_sealing(&session),
+        ));
+
+        self.state
+            .rtc_peers
+            .insert(peer_id.clone(), RtcPeer::new(connection.clone(), session));
+        self.state.rtc_pending = Some(peer_id);
+        Some(connection)
+    }
+
+    fn rtc_sealing(&self, session: &Option<Session>) -> Option<(Session, KeyPair)> {
+        Some((session.clone()?, self.state.my_keys.clone()?))
+    }
+
+    // Opens sealed signals and checks where they come from. An offer starts
+    // a new connection; answers and candidates go to the connection they
+    // reply to.
+    fn open_rtc_signal(
+        &self,
+        signal: RtcSignalData,
+    ) -> Result<(RtcSignalData, SignalTarget), String> {
+        let RtcSignalData::Sealed { data } = signal else {
+            if matches!(signal, RtcSignalData::Offer { .. }) {
+                return Ok((signal, SignalTarget::NewPeer(None)));
+            }
+            let peer_id =
+                self.state.rtc_pending.clone().ok_or(
+                    "This connection code is a reply, but no connection is waiting for one.",
+                )?;
+            if let Some(ref session) = self.state.rtc_peers[&peer_id].session {
+                let name = self
+                    .state
+                    .contacts
+                    .iter()
+                    .find(|c| c.public_key == session.peer_key)
+                    .map(|c| c.name.clone())
+                    .unwrap_or_else(|| "your contact".to_string());
+                return Err(format!(
+                    "This connection code is not encrypted, so it cannot be from {}.",
+                    name
+                ));
+            }
+            return Ok((signal, SignalTarget::Peer(peer_id)));
+        };
 
-        // Reload the page to restart the application
-        if let Some(window) = window() {
-            let _ = window.location().reload();
+        let keys = self.state.my_keys.as_ref().ok_or("Keys are not loaded")?;
+        let opened = signaling::open(&data, keys)?;
+        if matches!(opened.signal, RtcSignalData::Offer { .. }) {
+            let contact = opened.sender(&self.state.contacts)?;
+            console::log!(&format!("🔏 Sealed offer from {}", contact.name));
+            let session = Session {
+                peer_key: opened.from.clone(),
+                nonce: opened.nonce.clone(),
+            };
+            return Ok((opened.signal, SignalTarget::NewPeer(Some(session))));
         }
+        let (peer_id, session) = self
+            .state
+            .rtc_peers
+            .iter()
+            .find_map(|(id, peer)| {
+                peer.session
+                    .as_ref()
+                    .filter(|session| session.nonce == opened.nonce)
+                    .map(|session| (id.clone(), session))
+            })
+            .ok_or("This connection code is a reply to a connection that was not started here.")?;
+        opened.check_session(session, &self.state.contacts)?;
+        Ok((opened.signal, SignalTarget::Peer(peer_id)))
+    }
+
+    fn reset_all_data(&mut self) {
+        console::log!("🗑️ Resetting all data");
+
+        spawn_local(async move {
+            match storage::store().await.clear().await {
+                Ok(()) => console::log!("✅ Storage cleared"),
+                Err(e) => console::error!(&format!("❌ Failed to clear storage: {}", e)),
+            }
+            sync::notify(SyncEvent::KeysChanged);
+
+            // Reload the page to restart the application
+            if let Some(window) = window() {
+                let _ = window.location().reload();
+            }
+        });
     }
 
     fn decrypt_and_show_message(&mut self, encrypted_message: String) {
@@ -1433,6 +3621,274 @@ impl App {
         }
     }
 
+    // 表示名が設定されていれば署名付きの連絡先カード、なければ公開鍵のみ
+    fn my_qr_payload(&self, public_key: &str) -> String {
+        match self.state.my_keys {
+            Some(ref keys)
+                if keys.public_key == public_key && !self.state.profile.display_name.is_empty() =>
+            {
+                ContactCard::sign(
+                    &self.state.profile.display_name,
+                    &keys.public_key,
+                    &keys.private_key,
+                    self.state.profile.created_at,
+                )
+                .to_payload()
+            }
+            _ => public_key.to_string(),
+        }
+    }
+
+    // QRコードのペイロードとダウンロード用ファイル名
+    fn qr_payload(&self, canvas_id: &str) -> Option<(String, &'static str)> {
+        match canvas_id {
+            "qr-canvas" => self.state.my_keys.as_ref().map(|keys| {
+                let payload = self.my_qr_payload(&keys.public_key);
+                (
+                    self.state.qr_settings.encode_payload(
+                        &payload,
+                        payload_link_kind(&payload),
+                        &app_base_url(),
+                    ),
+                    "qr-encrypt-public-key",
+                )
+            }),
+            "encrypted-qr-canvas" => self.state.encrypted_qr_data.as_ref().map(|data| {
+                (
+                    self.state.qr_settings.encode_payload(
+                        data,
+                        payload_link_kind(data),
+                        &app_base_url(),
+                    ),
+                    "qr-encrypt-message",
+                )
+            }),
+            _ => None,
+        }
+    }
+
+    fn render_recovery_screen(&self, ctx: &Context<Self>, error: &str) -> Html {
+        let on_retry = Callback::from(|_| {
+            if let Some(window) = window() {
+                let _ = window.location().reload();
+            }
+        });
+        let read_value = |id: &str| -> Option<String> {
+            let element = window()?.document()?.get_element_by_id(id)?;
+            js_sys::Reflect::get(&element, &"value".into())
+                .ok()?
+                .as_string()
+        };
+        let on_export = ctx.link().batch_callback(move |_| {
+            let passphrase = read_value("recovery-passphrase").unwrap_or_default();
+            let confirmation = read_value("recovery-passphrase-confirm").unwrap_or_default();
+            if passphrase.is_empty() {
+                alert("Please enter a passphrase.");
+                return None;
+            }
+            if passphrase != confirmation {
+                alert("The passphrases do not match.");
+                return None;
+            }
+            Some(Msg::ExportRecoveryDump(passphrase))
+        });
+        let busy = self.state.backup_status.is_some();
+
+        html! {
+            <div class="dialog" style="max-width: 500px; margin: 20px auto; text-align: left;">
+                <h3>{"⚠️ Stored data could not be upgraded"}</h3>
+                <p style="font-family: monospace; font-size: 13px; background: #fdecea; padding: 10px; border-radius: 4px; word-break: break-word;">
+                    {error}
+                </p>
+                <p style="font-size: 14px;">
+                    {"Your data has not been changed or deleted. You can retry, or download a raw copy of everything stored by this app, encrypted with a passphrase, before trying anything else."}
+                </p>
+                <button onclick={on_retry} style="width: 100%; margin-bottom: 20px;">
+                    {"Retry"}
+                </button>
+                <input type="password"
+                       id="recovery-passphrase"
+                       placeholder="Passphrase"
+                       style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                       />
+                <input type="password"
+                       id="recovery-passphrase-confirm"
+                       placeholder="Repeat passphrase"
+                       style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                       />
+                <button onclick={on_export} disabled={busy} style="width: 100%; margin-top: 10px; background-color: #16a085;">
+                    {"Export raw data"}
+                </button>
+                if let Some(ref status) = self.state.backup_status {
+                    <p style="font-size: 13px; color: #7f8c8d;">{status}</p>
+                }
+            </div>
+        }
+    }
+
+    fn render_lock_screen(&self, ctx: &Context<Self>) -> Html {
+        let read_passphrase = || -> String {
+            window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("unlock-passphrase"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"value".into()).ok())
+                .and_then(|value| value.as_string())
+                .unwrap_or_default()
+        };
+        let on_unlock = ctx.link().batch_callback(move |_| {
+            let passphrase = read_passphrase();
+            (!passphrase.is_empty()).then_some(Msg::Unlock(passphrase))
+        });
+        let on_keydown = ctx.link().batch_callback(move |e: web_sys::KeyboardEvent| {
+            let passphrase = read_passphrase();
+            (e.key() == "Enter" && !passphrase.is_empty()).then_some(Msg::Unlock(passphrase))
+        });
+        let busy = self.state.lock_status.is_some();
+
+        html! {
+            <div class="dialog" style="max-width: 400px; margin: 20px auto;">
+                <h3>{"🔒 Locked"}</h3>
+                <p style="font-size: 14px;">
+                    {"Enter your passphrase to unlock your keys and messages."}
+                </p>
+                <input type="password"
+                       id="unlock-passphrase"
+                       placeholder="Passphrase"
+                       autofocus=true
+                       onkeydown={on_keydown}
+                       style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                       />
+                <button onclick={on_unlock} disabled={busy} style="width: 100%; margin-top: 10px;">
+                    {"Unlock"}
+                </button>
+                if let Some(ref status) = self.state.lock_status {
+                    <p style="font-size: 13px; color: #7f8c8d;">{status}</p>
+                }
+                <p style="font-size: 12px; color: #7f8c8d; margin-top: 20px;">
+                    {"Forgot the passphrase? The keys cannot be recovered without it, but you can "}
+                    <a href="#" onclick={ctx.link().callback(|e: MouseEvent| {
+                        e.prevent_default();
+                        Msg::ShowResetConfirm
+                    })}>{"reset all data"}</a>
+                    {"."}
+                </p>
+            </div>
+        }
+    }
+
+    fn render_lock_settings_dialog(&self, ctx: &Context<Self>) -> Html {
+        let on_cancel = ctx.link().callback(|_| Msg::HideLockSettings);
+        let settings = self.state.lock_settings;
+        let protected = self.state.key_protected;
+        let selected_hidden = settings
+            .hidden_minutes
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let on_save = ctx.link().batch_callback(move |_| {
+            let read_value = |id: &str| -> Option<String> {
+                let element = window()?.document()?.get_element_by_id(id)?;
+                js_sys::Reflect::get(&element, &"value".into())
+                    .ok()?
+                    .as_string()
+            };
+            let settings = LockSettings {
+                idle_minutes: read_value("lock-idle-select")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(settings.idle_minutes),
+                hidden_minutes: read_value("lock-hidden-select").and_then(|v| v.parse().ok()),
+            };
+            if protected {
+                return Some(Msg::SaveLockSettings(settings));
+            }
+            let passphrase = read_value("lock-passphrase").unwrap_or_default();
+            let confirmation = read_value("lock-passphrase-confirm").unwrap_or_default();
+            if passphrase.is_empty() {
+                alert("Please enter a passphrase.");
+                return None;
+            }
+            if passphrase != confirmation {
+                alert("The passphrases do not match.");
+                return None;
+            }
+            Some(Msg::EnableAutoLock(passphrase, settings))
+        });
+        let on_disable = ctx.link().batch_callback(|_| {
+            confirm(
+                "Turn off auto-lock? Your private key will be stored without a passphrase again.",
+            )
+            .then_some(Msg::DisableAutoLock)
+        });
+        let busy = self.state.pending_lock_settings.is_some();
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 400px;">
+                    <h3>{"Auto-lock"}</h3>
+                    <p style="font-size: 13px; color: #7f8c8d; text-align: left;">
+                        {"When the app locks, your keys, contacts and chats are cleared from memory and the passphrase is needed to open them again."}
+                    </p>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"Lock after inactivity:"}</label>
+                        <select id="lock-idle-select" style="width: 100%; padding: 8px; margin: 5px 0;">
+                            { for [
+                                (1, "1 minute"),
+                                (5, "5 minutes"),
+                                (10, "10 minutes"),
+                                (30, "30 minutes"),
+                                (60, "1 hour"),
+                                (0, "Never"),
+                            ].iter().map(|(value, label)| {
+                                html! { <option value={value.to_string()} selected={*value == settings.idle_minutes}>{*label}</option> }
+                            })}
+                        </select>
+                        <label>{"Lock when the tab is in the background:"}</label>
+                        <select id="lock-hidden-select" style="width: 100%; padding: 8px; margin: 5px 0;">
+                            { for [
+                                ("0", "Immediately"),
+                                ("1", "After 1 minute"),
+                                ("5", "After 5 minutes"),
+                                ("15", "After 15 minutes"),
+                                ("never", "Never"),
+                            ].iter().map(|(value, label)| {
+                                html! { <option value={*value} selected={*value == selected_hidden}>{*label}</option> }
+                            })}
+                        </select>
+                    </div>
+                    if !protected {
+                        <div style="margin: 20px 0; text-align: left;">
+                            <label>{"Passphrase to unlock:"}</label>
+                            <input type="password"
+                                   id="lock-passphrase"
+                                   placeholder="Passphrase"
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                                   />
+                            <input type="password"
+                                   id="lock-passphrase-confirm"
+                                   placeholder="Repeat passphrase"
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                                   />
+                            <p style="font-size: 12px; color: #7f8c8d;">
+                                {"There is no way to recover the passphrase. Make a backup first if you are unsure."}
+                            </p>
+                        </div>
+                    }
+                    if let Some(ref status) = self.state.lock_status {
+                        <p style="font-size: 13px; color: #7f8c8d;">{status}</p>
+                    }
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        if protected {
+                            <button onclick={on_disable} style="background-color: #e74c3c; flex: 1;">{"Turn Off"}</button>
+                        }
+                        <button onclick={on_save} disabled={busy} style="flex: 1;">
+                            { if protected { "Save" } else { "Turn On" } }
+                        </button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
     fn render_loading_screen(&self) -> Html {
         html! {
             <div class="loading-screen">
@@ -1471,11 +3927,26 @@ impl App {
 
     fn render_main_view(&self, ctx: &Context<Self>) -> Html {
         console::log!("🏠 Main view rendering started");
+        let on_recipients_selected = ctx.link().batch_callback(|e: Event| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            let file = input.files().and_then(|files| files.get(0));
+            input.set_value("");
+            file.map(Msg::ImportRecipients)
+        });
         let on_qr_read_click = ctx.link().callback(|_| Msg::ShowQrReader);
         let on_message_send_click = ctx.link().callback(|_| Msg::ShowMessageDialog);
         let on_export_private_key_click = ctx
             .link()
             .callback(|_: web_sys::MouseEvent| Msg::ShowExportPrivateKeyDialog);
+        let on_save_display_name = ctx.link().callback(|_| {
+            let name = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("display-name-input"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"value".into()).ok())
+                .and_then(|value| value.as_string())
+                .unwrap_or_default();
+            Msg::SaveDisplayName(name)
+        });
 
         html! {
             <div class="main-view">
@@ -1485,6 +3956,8 @@ impl App {
                         <canvas id="qr-canvas" width="300" height="300"
                                 style="max-width: 100%; height: auto; display: block; margin: 0 auto; border-radius: 8px; box-shadow: 0 2px 8px rgba(0,0,0,0.1);"></canvas>
                     </div>
+                    { self.render_qr_params("qr-canvas") }
+                    { self.render_qr_download_buttons(ctx, "qr-canvas") }
                     <div style="margin-top: 10px;">
                         <button onclick={ctx.link().callback(|_| Msg::CopyPublicKey)}
                                 class="copy-key-btn"
@@ -1492,6 +3965,24 @@ impl App {
                             {"Copy My Public Key"}
                         </button>
                     </div>
+                    <div class="display-name" style="margin-top: 10px; display: flex; justify-content: center; gap: 8px;">
+                        <input type="text"
+                               id="display-name-input"
+                               placeholder="Your display name"
+                               value={self.state.profile.display_name.clone()}
+                               style="padding: 8px; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <button onclick={on_save_display_name} style="background-color: #34495e; padding: 8px 12px; font-size: 14px;">
+                            {"Save name"}
+                        </button>
+                    </div>
+                    <p style="margin: 6px 0 0 0; font-size: 12px; color: #7f8c8d;">
+                        if self.state.profile.display_name.is_empty() {
+                            {"Set a display name to share a signed contact card instead of the bare key."}
+                        } else {
+                            {"This QR code is a contact card signed by your key."}
+                        }
+                    </p>
                 </div>
 
                 <div class="actions">
@@ -1504,27 +3995,128 @@ impl App {
                     <button onclick={ctx.link().callback(|_| Msg::ShowRtcDialog)} class="rtc-connect-btn" style="margin-left: 10px; background-color: #9b59b6;">
                         {"Chat"}
                     </button>
+                    if !self.state.rtc_peers.is_empty() {
+                        <button onclick={ctx.link().callback(|_| Msg::ShowChatView)} class="chats-btn" style="margin-left: 10px; background-color: #8e44ad;">
+                            {format!("💬 Chats ({})", self.state.rtc_peers.len())}
+                        </button>
+                    }
+                    <button onclick={ctx.link().callback(|_| Msg::ShowMessageLog)} class="message-log-btn" style="margin-left: 10px; background-color: #2c3e50;">
+                        {"Messages"}
+                    </button>
                 </div>
 
                 <div class="contacts">
                     <h3>{"Contacts"}</h3>
+                    <div style="margin-bottom: 10px; text-align: right;">
+                        <button onclick={ctx.link().callback(|_| Msg::ExportRecipients)} disabled={self.state.contacts.is_empty()} style="background-color: #34495e; padding: 5px 10px; font-size: 12px;">
+                            {"Export recipients file"}
+                        </button>
+                        <label for="recipients-import-input"
+                               style="display: inline-block; margin-left: 10px; background-color: #34495e; color: white; padding: 5px 10px; border-radius: 4px; cursor: pointer; font-size: 12px;">
+                            {"Import recipients file"}
+                        </label>
+                        <input type="file" id="recipients-import-input" accept=".txt,text/plain"
+                               style="display: none;" onchange={on_recipients_selected} />
+                    </div>
                     if self.state.contacts.is_empty() {
                         <p style="color: #7f8c8d; font-style: italic; text-align: center; padding: 20px;">
                             {"No contacts"}
                         </p>
                     } else {
                         <ul>
-                            { for self.state.contacts.iter().map(|(name, _)| {
-                                let name_clone = name.clone();
-                                let on_delete = ctx.link().callback(move |_| Msg::ShowDeleteConfirm(name_clone.clone()));
+                            { for self.state.contacts.iter().map(|contact| {
+                                let edit_id = contact.id.clone();
+                                let delete_id = contact.id.clone();
+                                let on_edit = ctx.link().callback(move |_| Msg::ShowEditContact(edit_id.clone()));
+                                let on_delete = ctx.link().callback(move |_| Msg::ShowDeleteConfirm(delete_id.clone()));
 
                                 html! {
                                    <li class="contact-item">
-                                        <span class="contact-name">{name}</span>
+                                        <span class="contact-name" title={contact.public_key.clone()}>
+                                            {&contact.name}
+                                            if contact.verified {
+                                                {" ✅"}
+                                            }
+                                        </span>
+                                        <span style="margin-left: 8px; font-size: 12px; color: #7f8c8d; font-family: monospace;">
+                                            {contact.short_key()}
+                                        </span>
+                                        { for contact.tags.iter().map(|tag| html! {
+                                            <span class="contact-tag" style="margin-left: 6px; padding: 2px 6px; border-radius: 10px; background-color: #ecf0f1; font-size: 11px; color: #34495e;">
+                                                {tag}
+                                            </span>
+                                        })}
+                                        <button
+                                            onclick={on_edit}
+                                            class="edit-contact-btn"
+                                            title={format!("Edit {}", contact.name)}
+                                            style="background-color: #3498db; color: white; border: none; padding: 5px 10px; border-radius: 4px; cursor: pointer; font-size: 12px; margin-left: 10px;">
+                                            {"Edit"}
+                                        </button>
                                         <button
                                             onclick={on_delete}
                                             class="delete-contact-btn"
-                                            title={format!("Delete {}", name)}
+                                            title={format!("Delete {}", contact.name)}
+                                            style="background-color: #e74c3c; color: white; border: none; padding: 5px 10px; border-radius: 4px; cursor: pointer; font-size: 12px; margin-left: 10px;">
+                                            {"Delete"}
+                                        </button>
+                                    </li>
+                                }
+                            })}
+                        </ul>
+                    }
+                </div>
+
+                <div class="groups">
+                    <h3>{"Groups"}</h3>
+                    <div style="margin-bottom: 10px; text-align: right;">
+                        <button onclick={ctx.link().callback(|_| Msg::ShowGroupDialog(None))} disabled={self.state.contacts.is_empty()} style="background-color: #34495e; padding: 5px 10px; font-size: 12px;">
+                            {"New group"}
+                        </button>
+                    </div>
+                    if self.state.groups.is_empty() {
+                        <p style="color: #7f8c8d; font-style: italic; text-align: center; padding: 20px;">
+                            {"No groups"}
+                        </p>
+                    } else {
+                        <ul>
+                            { for self.state.groups.iter().map(|group| {
+                                let edit_id = group.id.clone();
+                                let delete_id = group.id.clone();
+                                let on_edit = ctx.link().callback(move |_| Msg::ShowGroupDialog(Some(edit_id.clone())));
+                                let on_delete = ctx.link().batch_callback(move |_| {
+                                    confirm("Delete this group? The contacts in it are kept.")
+                                        .then(|| Msg::DeleteGroup(delete_id.clone()))
+                                });
+
+                                html! {
+                                    <li class="group-item">
+                                        <span class="group-name">{&group.name}</span>
+                                        <span style="margin-left: 8px; font-size: 12px; color: #7f8c8d;">
+                                            { for group.members.iter().enumerate().map(|(index, member)| {
+                                                let separator = if index > 0 { ", " } else { "" };
+                                                match self.find_contact(&member.contact_id) {
+                                                    Some(contact) => html! { <>{separator}{&contact.name}</> },
+                                                    None => html! {
+                                                        <>
+                                                            {separator}
+                                                            <span style="color: #e74c3c; text-decoration: line-through;" title="Deleted contact">
+                                                                {&member.name}
+                                                            </span>
+                                                        </>
+                                                    },
+                                                }
+                                            })}
+                                        </span>
+                                        <button
+                                            onclick={on_edit}
+                                            class="edit-group-btn"
+                                            style="background-color: #3498db; color: white; border: none; padding: 5px 10px; border-radius: 4px; cursor: pointer; font-size: 12px; margin-left: 10px;">
+                                            {"Edit"}
+                                        </button>
+                                        <button
+                                            onclick={on_delete}
+                                            class="delete-group-btn"
                                             style="background-color: #e74c3c; color: white; border: none; padding: 5px 10px; border-radius: 4px; cursor: pointer; font-size: 12px; margin-left: 10px;">
                                             {"Delete"}
                                         </button>
@@ -1535,9 +4127,23 @@ impl App {
                     }
                 </div>
                 <div style="margin-top: 20px; text-align: center;">
+                    <button onclick={ctx.link().callback(|_| Msg::ShowQrSettingsDialog)} class="qr-settings-btn" style="background-color: #34495e;">
+                        {"QR Settings"}
+                    </button>
+                    <button onclick={ctx.link().callback(|_| Msg::ShowBackupDialog)} class="backup-btn" style="margin-left: 10px; background-color: #16a085;">
+                        {"Backup"}
+                    </button>
                     <button onclick={on_export_private_key_click} class="export-private-key-btn" style="margin-left: 10px; background-color: #e67e22;">
                         {"Export Private Key"}
                     </button>
+                    <button onclick={ctx.link().callback(|_| Msg::ShowLockSettings)} class="lock-settings-btn" style="margin-left: 10px; background-color: #8e44ad;">
+                        {"Auto-lock"}
+                    </button>
+                    if self.state.key_protected {
+                        <button onclick={ctx.link().callback(|_| Msg::LockNow)} class="lock-now-btn" style="margin-left: 10px; background-color: #2c3e50;">
+                            {"Lock Now"}
+                        </button>
+                    }
                     <button onclick={ctx.link().callback(|_| Msg::ShowResetConfirm)} class="reset-btn" style="margin-left: 10px; background-color: #e74c3c;">
                         {"Reset All Data"}
                     </button>
@@ -1549,6 +4155,12 @@ impl App {
     fn render_qr_reader(&self, ctx: &Context<Self>) -> Html {
         let on_close = ctx.link().callback(|_| Msg::HideQrReader);
         let on_start_camera = ctx.link().callback(|_| Msg::StartCamera);
+        let on_image_selected = ctx.link().batch_callback(|e: Event| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            let file = input.files().and_then(|files| files.get(0));
+            input.set_value("");
+            file.map(Msg::DecodeQrImage)
+        });
 
         html! {
             <div class="qr-reader-overlay">
@@ -1590,6 +4202,18 @@ impl App {
                         </div>
                     }
 
+                    <div style="margin: 10px 0; text-align: center;">
+                        <label for="qr-image-input"
+                               style="display: inline-block; background-color: #3498db; color: white; padding: 10px 20px; border-radius: 5px; cursor: pointer; font-size: 14px;">
+                            {"🖼️ Open image"}
+                        </label>
+                        <input type="file" id="qr-image-input" accept="image/png,image/jpeg"
+                               style="display: none;" onchange={on_image_selected} />
+                        <p style="margin: 8px 0 0 0; font-size: 12px; color: #7f8c8d;">
+                            {"You can also paste a screenshot of a QR code (Ctrl+V / ⌘V)"}
+                        </p>
+                    </div>
+
                     <div class="manual-input-section">
                         <h4>{"Manual Input"}</h4>
                         <p style="font-size: 13px; color: #7f8c8d; text-align: center; margin-bottom: 15px;">
@@ -1646,9 +4270,33 @@ impl App {
                         <label>{"Select destination:"}</label>
                         <select id="contact-select" style="width: 100%; padding: 8px; margin: 5px 0;">
                             <option value="">{"Select destination"}</option>
-                            { for self.state.contacts.iter().map(|(name, _)| {
-                                html! { <option value={name.clone()}>{name}</option> }
+                            { for self.state.contacts.iter().map(|contact| {
+                                html! {
+                                    <option value={contact.id.clone()}>
+                                        {format!("{} ({})", contact.name, contact.short_key())}
+                                    </option>
+                                }
                             })}
+                            if !self.state.groups.is_empty() {
+                                <optgroup label="Groups">
+                                    { for self.state.groups.iter().map(|group| {
+                                        let recipients = group.resolve(&self.state.contacts);
+                                        let label = if recipients.missing.is_empty() {
+                                            format!("{} ({} members)", group.name, recipients.public_keys.len())
+                                        } else {
+                                            format!(
+                                                "⚠️ {} ({} members, {} deleted)",
+                                                group.name,
+                                                recipients.public_keys.len(),
+                                                recipients.missing.len()
+                                            )
+                                        };
+                                        html! {
+                                            <option value={format!("group:{}", group.id)}>{label}</option>
+                                        }
+                                    })}
+                                </optgroup>
+                            }
                         </select>
                     </div>
                     <div style="margin: 20px 0;">
@@ -1709,7 +4357,22 @@ impl App {
                         <p>{"Please send this QR to the other party"}</p>
                         <canvas id="encrypted-qr-canvas" width="300" height="300"
                                 style="max-width: 100%; height: auto; display: block; margin: 10px auto; border: 1px solid #ddd; border-radius: 8px; box-shadow: 0 2px 8px rgba(0,0,0,0.1);"></canvas>
+                        { self.render_qr_params("encrypted-qr-canvas") }
+                        { self.render_qr_download_buttons(ctx, "encrypted-qr-canvas") }
                     </div>
+                    if let Some(extra) = self.state.rtc_peers.values().find_map(|p| p.extra_candidates.as_ref()) {
+                        <div style="margin: 10px 0; padding: 10px; background-color: #fff3cd; border-radius: 5px; font-size: 13px; text-align: left;">
+                            <p style="margin: 0 0 8px 0;">
+                                {"More network candidates were found after this code was shown. Once the other device has scanned this code, show it the extra candidates too."}
+                            </p>
+                            <button onclick={
+                                let extra = extra.clone();
+                                ctx.link().callback(move |_| Msg::ShowEncryptedQr(extra.clone()))
+                            } style="width: 100%; background-color: #e67e22;">
+                                {"Show Extra Candidates"}
+                            </button>
+                        </div>
+                    }
                     <div class="encrypted-dialog-buttons">
                         <button onclick={on_copy}
                                 class="copy-encrypted-btn">
@@ -1731,15 +4394,39 @@ impl App {
             .link()
             .callback(move |_| Msg::ConfirmDeleteContact(delete_target.clone()));
         let on_cancel = ctx.link().callback(|_| Msg::CancelDeleteContact);
+        let target_name = self
+            .state
+            .delete_target
+            .as_ref()
+            .and_then(|id| self.find_contact(id))
+            .map(|c| c.name.clone())
+            .unwrap_or_else(|| "Unknown".to_string());
+        let member_of: Vec<String> = self
+            .state
+            .groups
+            .iter()
+            .filter(|group| {
+                group
+                    .members
+                    .iter()
+                    .any(|m| Some(&m.contact_id) == self.state.delete_target.as_ref())
+            })
+            .map(|group| group.name.clone())
+            .collect();
 
         html! {
             <div class="dialog-overlay">
                 <div class="dialog" style="max-width: 300px;">
                     <h3>{"Delete contact?"}</h3>
                     <p style="margin: 15px 0;">
-                        <strong>{&self.state.delete_target.as_ref().unwrap_or(&"Unknown".to_string())}</strong>
+                        <strong>{target_name}</strong>
                         {" will no longer be able to communicate with this contact."}
                     </p>
+                    if !member_of.is_empty() {
+                        <p style="margin: 15px 0; font-size: 13px; color: #e67e22;">
+                            {format!("Messages to {} will no longer include this contact.", member_of.join(", "))}
+                        </p>
+                    }
                     <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
                         <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
                         <button onclick={on_confirm} style="background-color: #e74c3c; flex: 1;">{"Delete"}</button>
@@ -1763,8 +4450,12 @@ impl App {
                         <label>{"Select recipient:"}</label>
                         <select id="export-recipient-select" style="width: 100%; padding: 8px; margin: 5px 0;">
                             <option value="">{"Select recipient"}</option>
-                            { for self.state.contacts.iter().map(|(name, _)| {
-                                html! { <option value={name.clone()}>{name}</option> }
+                            { for self.state.contacts.iter().map(|contact| {
+                                html! {
+                                    <option value={contact.id.clone()}>
+                                        {format!("{} ({})", contact.name, contact.short_key())}
+                                    </option>
+                                }
                             })}
                         </select>
                     </div>
@@ -1821,11 +4512,18 @@ impl App {
 
     fn render_add_contact_dialog(&self, ctx: &Context<Self>) -> Html {
         let on_cancel = ctx.link().callback(|_| Msg::CancelAddContact);
+        let suggested_name = self
+            .state
+            .contact_card_to_add
+            .as_ref()
+            .map(|(card, _)| card.name.clone())
+            .unwrap_or_default();
 
         html! {
             <div class="dialog-overlay">
                 <div class="dialog" style="max-width: 400px;">
                     <h3>{"Add Contact"}</h3>
+                    { self.render_contact_card_status() }
                     <p style="margin: 15px 0;">
                         {"Please enter a name for this public key:"}
                     </p>
@@ -1833,6 +4531,7 @@ impl App {
                         <input type="text"
                                id="contact-name-input"
                                placeholder="Enter contact name"
+                               value={suggested_name}
                                style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
                                />
                     </div>
@@ -1864,6 +4563,220 @@ impl App {
         }
     }
 
+    fn render_group_dialog(&self, ctx: &Context<Self>) -> Html {
+        let Some(ref editing) = self.state.group_dialog else {
+            return html! {};
+        };
+        let group = editing
+            .as_ref()
+            .and_then(|id| self.state.groups.iter().find(|g| &g.id == id));
+        let deleted_members = group
+            .map(|group| group.resolve(&self.state.contacts).missing)
+            .unwrap_or_default();
+        let on_cancel = ctx.link().callback(|_| Msg::HideGroupDialog);
+        let contact_ids: Vec<String> = self.state.contacts.iter().map(|c| c.id.clone()).collect();
+        let on_save = ctx.link().batch_callback(move |_| {
+            let document = window()?.document()?;
+            let name = document
+                .get_element_by_id("group-name")
+                .and_then(|el| js_sys::Reflect::get(&el, &"value".into()).ok())
+                .and_then(|value| value.as_string())
+                .unwrap_or_default();
+            if name.trim().is_empty() {
+                alert("Please enter a group name.");
+                return None;
+            }
+            let members: Vec<String> = contact_ids
+                .iter()
+                .filter(|id| {
+                    document
+                        .get_element_by_id(&format!("group-member-{}", id))
+                        .and_then(|el| js_sys::Reflect::get(&el, &"checked".into()).ok())
+                        .and_then(|checked| checked.as_bool())
+                        .unwrap_or(false)
+                })
+                .cloned()
+                .collect();
+            if members.is_empty() {
+                alert("Please choose at least one member.");
+                return None;
+            }
+            Some(Msg::SaveGroup(name.trim().to_string(), members))
+        });
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{if group.is_some() { "Edit group" } else { "New group" }}</h3>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label for="group-name">{"Name"}</label>
+                        <input type="text"
+                               id="group-name"
+                               value={group.map(|g| g.name.clone()).unwrap_or_default()}
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <p style="margin: 10px 0 5px 0;">{"Members"}</p>
+                        <div style="max-height: 250px; overflow-y: auto;">
+                            { for self.state.contacts.iter().map(|contact| {
+                                let checked = group
+                                    .map(|g| g.members.iter().any(|m| m.contact_id == contact.id))
+                                    .unwrap_or(false);
+                                html! {
+                                    <label style="display: block; font-size: 14px; margin: 4px 0;">
+                                        <input type="checkbox" id={format!("group-member-{}", contact.id)} checked={checked} />
+                                        {format!(" {} ", contact.name)}
+                                        <span style="font-size: 12px; color: #7f8c8d; font-family: monospace;">
+                                            {contact.short_key()}
+                                        </span>
+                                    </label>
+                                }
+                            })}
+                        </div>
+                        if !deleted_members.is_empty() {
+                            <p style="margin: 10px 0 0 0; font-size: 13px; color: #e67e22;">
+                                {format!("Deleted contacts will be removed from the group: {}", deleted_members.join(", "))}
+                            </p>
+                        }
+                    </div>
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={on_save} style="background-color: #27ae60; flex: 1;">{"Save"}</button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_edit_contact_dialog(&self, ctx: &Context<Self>) -> Html {
+        let Some(ref contact) = self.state.editing_contact else {
+            return html! {};
+        };
+        let on_cancel = ctx.link().callback(|_| Msg::HideEditContact);
+        let original = contact.clone();
+        let on_save = ctx.link().batch_callback(move |_| {
+            let read_value = |id: &str| -> Option<String> {
+                let element = window()?.document()?.get_element_by_id(id)?;
+                js_sys::Reflect::get(&element, &"value".into())
+                    .ok()?
+                    .as_string()
+            };
+            let name = read_value("edit-contact-name").unwrap_or_default();
+            if name.trim().is_empty() {
+                alert("Please enter a contact name.");
+                return None;
+            }
+            let verified = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("edit-contact-verified"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"checked".into()).ok())
+                .and_then(|checked| checked.as_bool())
+                .unwrap_or(original.verified);
+            Some(Msg::SaveContact(Contact {
+                name: name.trim().to_string(),
+                notes: read_value("edit-contact-notes").unwrap_or_default(),
+                tags: read_value("edit-contact-tags")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect(),
+                verified,
+                ..original.clone()
+            }))
+        });
+        let format_date = |timestamp: u64| {
+            Date::new(&JsValue::from_f64(timestamp as f64 * 1000.0))
+                .to_iso_string()
+                .as_string()
+                .unwrap_or_default()
+                .get(..10)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{"Edit Contact"}</h3>
+                    <div style="margin: 15px 0; text-align: left;">
+                        <label>{"Name:"}</label>
+                        <input type="text"
+                               id="edit-contact-name"
+                               value={contact.name.clone()}
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <label>{"Notes:"}</label>
+                        <textarea id="edit-contact-notes"
+                                  value={contact.notes.clone()}
+                                  style="width: 100%; height: 60px; padding: 8px; margin: 5px 0; resize: vertical;" />
+                        <label>{"Tags (comma separated):"}</label>
+                        <input type="text"
+                               id="edit-contact-tags"
+                               value={contact.tags.join(", ")}
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <label>
+                            <input type="checkbox" id="edit-contact-verified" checked={contact.verified} />
+                            {" Verified (I have confirmed this key with its owner)"}
+                        </label>
+                    </div>
+                    <div style="margin: 15px 0; text-align: left; font-size: 12px; color: #7f8c8d;">
+                        <p style="margin: 0; word-break: break-all; font-family: monospace;">{&contact.public_key}</p>
+                        <p style="margin: 6px 0 0 0;">
+                            {format!("Added {}", format_date(contact.added_at))}
+                            if let Some(last_used) = contact.last_used {
+                                {format!(" · Last used {}", format_date(last_used))}
+                            }
+                        </p>
+                    </div>
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={on_save} style="background-color: #27ae60; flex: 1;">{"Save"}</button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_contact_card_status(&self) -> Html {
+        let Some((card, status)) = self.state.contact_card_to_add.as_ref() else {
+            return html! {};
+        };
+        let created = Date::new(&JsValue::from_f64(card.created_at as f64 * 1000.0))
+            .to_iso_string()
+            .as_string()
+            .unwrap_or_default();
+        let (color, message) = match status {
+            CardStatus::Signed => (
+                "#7f8c8d",
+                "✍️ The card is signed with the signing key below, but that key is not yet tied to the public key. Check the name with its owner; the key is confirmed the first time you connect.".to_string(),
+            ),
+            CardStatus::Unsigned => (
+                "#7f8c8d",
+                "⚪ This card is not signed. Check the name with its owner.".to_string(),
+            ),
+            CardStatus::Invalid(e) => (
+                "#e74c3c",
+                format!("⚠️ Signature check failed: {}. The suggested name may have been tampered with.", e),
+            ),
+        };
+
+        html! {
+            <div class="contact-card-status" style="margin: 15px 0; padding: 10px; background-color: #f8f9fa; border-radius: 4px; text-align: left; font-size: 13px;">
+                <p style="margin: 0 0 6px 0;">
+                    {"Contact card from "}<strong>{&card.name}</strong>
+                    {format!(" (created {})", created.get(..10).unwrap_or_default())}
+                </p>
+                <p style={format!("margin: 0; color: {};", color)}>{message}</p>
+                if let Some(fingerprint) = card.fingerprint() {
+                    <p style="margin: 6px 0 0 0; color: #7f8c8d; font-family: monospace;">
+                        {format!("Signing key: {}", fingerprint)}
+                    </p>
+                }
+            </div>
+        }
+    }
+
     fn render_reset_confirm_dialog(&self, ctx: &Context<Self>) -> Html {
         let on_confirm = ctx.link().callback(|_| Msg::ConfirmReset);
         let on_cancel = ctx.link().callback(|_| Msg::CancelReset);
@@ -1892,6 +4805,639 @@ impl App {
         }
     }
 
+    fn render_qr_params(&self, canvas_id: &str) -> Html {
+        html! {
+            if let Some(params) = self.state.qr_params.get(canvas_id) {
+                <p class="qr-params" style="margin: 8px 0 0 0; font-size: 12px; color: #7f8c8d; text-align: center;">
+                    {params}
+                </p>
+            }
+        }
+    }
+
+    fn render_qr_download_buttons(&self, ctx: &Context<Self>, canvas_id: &str) -> Html {
+        let svg_id = canvas_id.to_string();
+        let png_id = canvas_id.to_string();
+        let on_svg = ctx
+            .link()
+            .callback(move |_| Msg::DownloadQrSvg(svg_id.clone()));
+        let on_png = ctx
+            .link()
+            .callback(move |_| Msg::DownloadQrPng(png_id.clone()));
+
+        html! {
+            <div class="qr-download-buttons" style="margin-top: 8px; display: flex; justify-content: center; gap: 8px;">
+                <button onclick={on_svg} style="background-color: #16a085; padding: 6px 12px; font-size: 12px;">
+                    {"Download SVG"}
+                </button>
+                <button onclick={on_png} style="background-color: #16a085; padding: 6px 12px; font-size: 12px;">
+                    {"Download PNG"}
+                </button>
+            </div>
+        }
+    }
+
+    fn render_ice_settings_dialog(&self, ctx: &Context<Self>) -> Html {
+        let on_cancel = ctx.link().callback(|_| Msg::HideIceSettingsDialog);
+        let settings = &self.state.ice_settings;
+        let on_defaults = ctx.link().batch_callback(|_| {
+            confirm("Restore the default public STUN servers and clear the TURN credentials?")
+                .then(|| Msg::SaveIceSettings(IceSettings::default()))
+        });
+        let on_save = ctx.link().batch_callback(|_| {
+            let read_value = |id: &str| -> Option<String> {
+                let element = window()?.document()?.get_element_by_id(id)?;
+                js_sys::Reflect::get(&element, &"value".into())
+                    .ok()?
+                    .as_string()
+            };
+            let read_checked = |id: &str| -> Option<bool> {
+                let element = window()?.document()?.get_element_by_id(id)?;
+                Some(
+                    element
+                        .dyn_into::<web_sys::HtmlInputElement>()
+                        .ok()?
+                        .checked(),
+                )
+            };
+            let servers =
+                match IceSettings::parse_servers(&read_value("ice-servers").unwrap_or_default()) {
+                    Ok(servers) => servers,
+                    Err(e) => {
+                        alert(&e);
+                        return None;
+                    }
+                };
+            let settings = IceSettings {
+                servers,
+                turn_username: read_value("ice-turn-username"),
+                turn_credential: read_value("ice-turn-credential"),
+                host_only: read_checked("ice-host-only").unwrap_or(false),
+                gathering_timeout_secs: read_value("ice-gathering-timeout")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(IceSettings::default().gathering_timeout_secs),
+            }
+            .normalized();
+            if let Err(e) = settings.validate() {
+                alert(&e);
+                return None;
+            }
+            Some(Msg::SaveIceSettings(settings))
+        });
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{"Connection Settings"}</h3>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"STUN / TURN servers (one URL per line):"}</label>
+                        <textarea id="ice-servers"
+                                  rows="5"
+                                  placeholder="stun:stun.example.com:3478\nturn:turn.example.com:3478?transport=udp"
+                                  value={settings.servers.join("\n")}
+                                  style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-family: monospace; font-size: 12px;" />
+                    </div>
+                    <div style="margin: 20px 0; display: flex; gap: 10px; text-align: left;">
+                        <div style="flex: 1;">
+                            <label>{"TURN username:"}</label>
+                            <input type="text" id="ice-turn-username"
+                                   autocomplete="off"
+                                   value={settings.turn_username.clone().unwrap_or_default()}
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        </div>
+                        <div style="flex: 1;">
+                            <label>{"TURN credential:"}</label>
+                            <input type="password" id="ice-turn-credential"
+                                   autocomplete="off"
+                                   value={settings.turn_credential.clone().unwrap_or_default()}
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        </div>
+                    </div>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"Gathering timeout (seconds, 0 waits until complete):"}</label>
+                        <input type="number" id="ice-gathering-timeout"
+                               min="0" max={rtc::MAX_GATHERING_TIMEOUT_SECS.to_string()}
+                               value={settings.gathering_timeout_secs.to_string()}
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        <p style="font-size: 12px; color: #7f8c8d;">
+                            {"On slow networks the connection code is shown with the candidates found so far, and any found later are offered as a second code."}
+                        </p>
+                    </div>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>
+                            <input type="checkbox" id="ice-host-only" checked={settings.host_only} />
+                            {" Host candidates only (LAN)"}
+                        </label>
+                        <p style="font-size: 12px; color: #7f8c8d;">
+                            {"No STUN or TURN server is contacted, so your public IP address is not revealed to any third party. Both devices must be on the same network."}
+                        </p>
+                    </div>
+                    <p style="font-size: 12px; color: #7f8c8d; text-align: left;">
+                        {"Changes apply to the next connection. The credentials are stored encrypted with your other settings."}
+                    </p>
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={on_defaults} style="background-color: #7f8c8d; flex: 1;">{"Defaults"}</button>
+                        <button onclick={on_save} style="flex: 1;">{"Save"}</button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_qr_settings_dialog(&self, ctx: &Context<Self>) -> Html {
+        let on_cancel = ctx.link().callback(|_| Msg::HideQrSettingsDialog);
+        let settings = &self.state.qr_settings;
+        let selected_ec = if settings.auto_ec {
+            "auto"
+        } else {
+            settings.ec_level.as_str()
+        };
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 400px;">
+                    <h3>{"QR Settings"}</h3>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"Error correction level:"}</label>
+                        <select id="qr-ec-select" style="width: 100%; padding: 8px; margin: 5px 0;">
+                            { for [
+                                ("auto", "Auto (highest level that fits)"),
+                                ("L", "L (~7% recovery, most capacity)"),
+                                ("M", "M (~15% recovery)"),
+                                ("Q", "Q (~25% recovery)"),
+                                ("H", "H (~30% recovery, least capacity)"),
+                            ].iter().map(|(value, label)| {
+                                html! { <option value={*value} selected={*value == selected_ec}>{*label}</option> }
+                            })}
+                        </select>
+                    </div>
+                    <div style="margin: 20px 0; display: flex; gap: 10px; text-align: left;">
+                        <div style="flex: 1;">
+                            <label>{"Min version:"}</label>
+                            <input type="number" id="qr-min-version"
+                                   min={qr::MIN_VERSION.to_string()} max={qr::MAX_VERSION.to_string()}
+                                   value={settings.min_version.to_string()}
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        </div>
+                        <div style="flex: 1;">
+                            <label>{"Max version:"}</label>
+                            <input type="number" id="qr-max-version"
+                                   min={qr::MIN_VERSION.to_string()} max={qr::MAX_VERSION.to_string()}
+                                   value={settings.max_version.to_string()}
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        </div>
+                    </div>
+                    <p style="font-size: 12px; color: #7f8c8d;">
+                        {"A lower maximum version keeps codes small and easier to scan from screens, but limits how much data fits."}
+                    </p>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>
+                            <input type="checkbox" id="qr-compact-encoding" checked={settings.compact_encoding} />
+                            {" Compact Base45 encoding for messages and connection codes"}
+                        </label>
+                        <br />
+                        <label>
+                            <input type="checkbox" id="qr-deep-links" checked={settings.deep_links} />
+                            {" Encode as app links (opens this app from a phone camera, overrides Base45)"}
+                        </label>
+                    </div>
+                    <div style="margin: 20px 0; display: flex; gap: 10px; text-align: left;">
+                        <div style="flex: 1;">
+                            <label>{"Dark color:"}</label>
+                            <input type="color" id="qr-dark-color" value={settings.dark_color.clone()}
+                                   style="width: 100%; height: 36px; margin: 5px 0;" />
+                        </div>
+                        <div style="flex: 1;">
+                            <label>{"Light color:"}</label>
+                            <input type="color" id="qr-light-color" value={settings.light_color.clone()}
+                                   style="width: 100%; height: 36px; margin: 5px 0;" />
+                        </div>
+                        <div style="flex: 1;">
+                            <label>{"Quiet zone:"}</label>
+                            <input type="number" id="qr-quiet-zone"
+                                   min="0" max={qr::MAX_QUIET_ZONE.to_string()}
+                                   value={settings.quiet_zone.to_string()}
+                                   style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                        </div>
+                    </div>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"Center logo URL (optional, requires EC level Q or H; data:, blob: or this site only):"}</label>
+                        <input type="text" id="qr-logo-url"
+                               placeholder="/logo.png"
+                               value={settings.logo_url.clone().unwrap_or_default()}
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px;" />
+                    </div>
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={ctx.link().callback(|_| {
+                            let read_value = |id: &str| -> Option<String> {
+                                let element = window()?.document()?.get_element_by_id(id)?;
+                                js_sys::Reflect::get(&element, &"value".into()).ok()?.as_string()
+                            };
+                            let mut settings = QrSettings::default();
+                            match read_value("qr-ec-select").and_then(|v| QrEcLevel::parse(&v)) {
+                                Some(ec_level) => {
+                                    settings.auto_ec = false;
+                                    settings.ec_level = ec_level;
+                                }
+                                None => settings.auto_ec = true,
+                            }
+                            if let Some(min_version) = read_value("qr-min-version").and_then(|v| v.parse().ok()) {
+                                settings.min_version = min_version;
+                            }
+                            if let Some(max_version) = read_value("qr-max-version").and_then(|v| v.parse().ok()) {
+                                settings.max_version = max_version;
+                            }
+                            let read_checked = |id: &str| -> Option<bool> {
+                                let element = window()?.document()?.get_element_by_id(id)?;
+                                Some(element.dyn_into::<web_sys::HtmlInputElement>().ok()?.checked())
+                            };
+                            if let Some(checked) = read_checked("qr-compact-encoding") {
+                                settings.compact_encoding = checked;
+                            }
+                            if let Some(checked) = read_checked("qr-deep-links") {
+                                settings.deep_links = checked;
+                            }
+                            if let Some(dark_color) = read_value("qr-dark-color") {
+                                settings.dark_color = dark_color;
+                            }
+                            if let Some(light_color) = read_value("qr-light-color") {
+                                settings.light_color = light_color;
+                            }
+                            if let Some(quiet_zone) = read_value("qr-quiet-zone").and_then(|v| v.parse().ok()) {
+                                settings.quiet_zone = quiet_zone;
+                            }
+                            settings.logo_url = read_value("qr-logo-url");
+                            Msg::SaveQrSettings(settings)
+                        })} style="background-color: #27ae60; flex: 1;">
+                            {"Save"}
+                        </button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_message_log_dialog(&self, ctx: &Context<Self>) -> Html {
+        let on_close = ctx.link().callback(|_| Msg::HideMessageLog);
+        let on_search = ctx.link().callback(|e: web_sys::InputEvent| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            Msg::UpdateMessageLogQuery(input.value())
+        });
+        let entries: Vec<&LogEntry> = self
+            .state
+            .message_log
+            .iter()
+            .rev()
+            .filter(|entry| entry.matches(&self.state.message_log_query))
+            .collect();
+        // 連絡先の現在の名前を優先する
+        let peer_label = |entry: &LogEntry| -> String {
+            entry
+                .peer_key
+                .as_ref()
+                .and_then(|key| self.state.contacts.iter().find(|c| &c.public_key == key))
+                .map(|contact| contact.name.clone())
+                .or_else(|| entry.peer_name.clone())
+                .unwrap_or_else(|| "Unknown sender".to_string())
+        };
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 500px;">
+                    <h3>{"Messages"}</h3>
+                    <input type="search"
+                           placeholder="Search messages or contacts"
+                           value={self.state.message_log_query.clone()}
+                           oninput={on_search}
+                           style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                           />
+                    <div style="max-height: 400px; overflow-y: auto; margin: 10px 0; text-align: left;">
+                        if entries.is_empty() {
+                            <p style="color: #7f8c8d; font-style: italic; text-align: center; padding: 20px;">
+                                {"No messages"}
+                            </p>
+                        }
+                        { for entries.into_iter().map(|entry| {
+                            let selected = self.state.selected_log_entry.as_ref() == Some(&entry.id);
+                            let (icon, label) = match entry.direction {
+                                Direction::Received => ("📥", format!("From {}", peer_label(entry))),
+                                Direction::Sent => ("📤", format!("To {}", peer_label(entry))),
+                            };
+                            let preview = match entry.direction {
+                                Direction::Received => entry.text.chars().take(60).collect::<String>(),
+                                Direction::Sent => "Encrypted message".to_string(),
+                            };
+                            let toggle_id = (!selected).then(|| entry.id.clone());
+                            let on_toggle = ctx.link().callback(move |_| Msg::SelectLogEntry(toggle_id.clone()));
+                            let delete_id = entry.id.clone();
+                            let on_delete = ctx.link().callback(move |_| Msg::DeleteLogEntry(delete_id.clone()));
+                            let ciphertext = entry.text.clone();
+                            let on_show_qr = ctx.link().batch_callback(move |_| {
+                                vec![Msg::HideMessageLog, Msg::ShowEncryptedQr(ciphertext.clone())]
+                            });
+
+                            html! {
+                                <div class="message-log-entry" style="border-bottom: 1px solid #eee; padding: 8px 0;">
+                                    <div onclick={on_toggle} style="cursor: pointer;">
+                                        <span>{icon}</span>
+                                        <strong style="margin-left: 6px;">{label}</strong>
+                                        <span style="float: right; font-size: 12px; color: #7f8c8d;">
+                                            {format_timestamp(entry.timestamp)}
+                                        </span>
+                                        if !selected {
+                                            <div style="font-size: 13px; color: #7f8c8d; white-space: nowrap; overflow: hidden; text-overflow: ellipsis;">
+                                                {preview}
+                                            </div>
+                                        }
+                                    </div>
+                                    if selected {
+                                        if entry.direction == Direction::Received {
+                                            <p style="white-space: pre-wrap; word-break: break-word; font-size: 14px;">{&entry.text}</p>
+                                        } else {
+                                            <p style="font-family: monospace; font-size: 11px; word-break: break-all; max-height: 120px; overflow-y: auto;">{&entry.text}</p>
+                                        }
+                                        <div style="display: flex; gap: 10px;">
+                                            if entry.direction == Direction::Sent {
+                                                <button onclick={on_show_qr} style="background-color: #3498db; padding: 5px 10px; font-size: 12px;">
+                                                    {"Show QR"}
+                                                </button>
+                                            }
+                                            <button onclick={on_delete} style="background-color: #e74c3c; padding: 5px 10px; font-size: 12px;">
+                                                {"Delete"}
+                                            </button>
+                                        </div>
+                                    }
+                                </div>
+                            }
+                        })}
+                    </div>
+                    <button onclick={on_close} style="background-color: #95a5a6; width: 100%;">{"Close"}</button>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_backup_dialog(&self, ctx: &Context<Self>) -> Html {
+        let on_close = ctx.link().callback(|_| Msg::HideBackupDialog);
+        let read_value = |id: &str| -> Option<String> {
+            let element = window()?.document()?.get_element_by_id(id)?;
+            js_sys::Reflect::get(&element, &"value".into())
+                .ok()?
+                .as_string()
+        };
+        let on_export = ctx.link().batch_callback(move |_| {
+            let passphrase = read_value("backup-export-passphrase").unwrap_or_default();
+            let confirmation = read_value("backup-export-passphrase-confirm").unwrap_or_default();
+            if passphrase.is_empty() {
+                alert("Please enter a passphrase.");
+                return None;
+            }
+            if passphrase != confirmation {
+                alert("The passphrases do not match.");
+                return None;
+            }
+            let include_chat_history = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("backup-include-chat"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"checked".into()).ok())
+                .and_then(|checked| checked.as_bool())
+                .unwrap_or(false);
+            Some(Msg::ExportBackup(passphrase, include_chat_history))
+        });
+        let on_import = ctx.link().batch_callback(move |_| {
+            let file = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("backup-import-file"))
+                .and_then(|el| el.dyn_into::<web_sys::HtmlInputElement>().ok())
+                .and_then(|input| input.files())
+                .and_then(|files| files.get(0));
+            let Some(file) = file else {
+                alert("Please choose a backup file.");
+                return None;
+            };
+            let passphrase = read_value("backup-import-passphrase").unwrap_or_default();
+            Some(Msg::ImportBackup(file, passphrase))
+        });
+        let busy = self.state.backup_status.is_some();
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{"Backup"}</h3>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <h4 style="margin: 0 0 8px 0;">{"Export backup"}</h4>
+                        <p style="margin: 0 0 8px 0; font-size: 13px; color: #7f8c8d;">
+                            {"Your private key, contacts and settings are saved as a single age file encrypted with a passphrase."}
+                        </p>
+                        <input type="password"
+                               id="backup-export-passphrase"
+                               placeholder="Passphrase"
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <input type="password"
+                               id="backup-export-passphrase-confirm"
+                               placeholder="Repeat passphrase"
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <label style="font-size: 14px;">
+                            <input type="checkbox" id="backup-include-chat" />
+                            {" Include chat history"}
+                        </label>
+                        <button onclick={on_export} disabled={busy} style="width: 100%; margin-top: 10px; background-color: #16a085;">
+                            {"Export backup"}
+                        </button>
+                    </div>
+                    <div style="margin: 20px 0; text-align: left;">
+                        <h4 style="margin: 0 0 8px 0;">{"Import backup"}</h4>
+                        <input type="file"
+                               id="backup-import-file"
+                               accept=".age,application/octet-stream"
+                               style="width: 100%; margin: 5px 0;"
+                               />
+                        <input type="password"
+                               id="backup-import-passphrase"
+                               placeholder="Passphrase"
+                               style="width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-size: 14px;"
+                               />
+                        <button onclick={on_import} disabled={busy} style="width: 100%; margin-top: 10px; background-color: #2980b9;">
+                            {"Import backup"}
+                        </button>
+                    </div>
+                    if let Some(ref status) = self.state.backup_status {
+                        <p style="margin: 10px 0; color: #7f8c8d;">{status}</p>
+                    }
+                    <button onclick={on_close} style="background-color: #95a5a6; width: 100%;">{"Close"}</button>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_import_summary_dialog(&self, ctx: &Context<Self>) -> Html {
+        let Some((ref bundle, ref plan)) = self.state.pending_import else {
+            return html! {};
+        };
+        let on_cancel = ctx.link().callback(|_| Msg::CancelImportBackup);
+        let on_confirm = ctx.link().callback(|_| {
+            let replace_identity = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("backup-replace-identity"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"checked".into()).ok())
+                .and_then(|checked| checked.as_bool())
+                .unwrap_or(false);
+            Msg::ConfirmImportBackup(replace_identity)
+        });
+        let created = Date::new(&JsValue::from_f64(bundle.created_at as f64 * 1000.0))
+            .to_iso_string()
+            .as_string()
+            .unwrap_or_default();
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{"Import Backup"}</h3>
+                    <p style="margin: 10px 0; font-size: 13px; color: #7f8c8d;">
+                        {format!("Backup created {}", created.get(..10).unwrap_or_default())}
+                    </p>
+                    <ul style="text-align: left; margin: 15px 0; color: #34495e;">
+                        <li>{format!("{} new contact(s)", plan.added.len())}</li>
+                        <li>{format!("{} contact(s) already up to date", plan.unchanged)}</li>
+                        if !plan.groups_added.is_empty() {
+                            <li>{format!("Groups: {}", plan.groups_added.join(", "))}</li>
+                        }
+                        if !plan.updated.is_empty() {
+                            <li>{format!("Notes and tags merged into: {}", plan.updated.join(", "))}</li>
+                        }
+                        { for plan.shared_names.iter().map(|name| html! {
+                            <li style="color: #e67e22;">
+                                {format!("\"{}\" has a different key than your contact of the same name; both are kept", name)}
+                            </li>
+                        })}
+                        { for plan.already_known.iter().map(|(name, existing)| html! {
+                            <li style="color: #e67e22;">
+                                {format!("\"{}\" is already saved as \"{}\"; your name is kept", name, existing)}
+                            </li>
+                        })}
+                        if plan.qr_settings.is_some() {
+                            <li>{"QR settings will be restored"}</li>
+                        }
+                        if plan.qr_settings_kept {
+                            <li style="color: #e67e22;">{"Your current QR settings differ from the backup and are kept"}</li>
+                        }
+                        if plan.ice_settings.is_some() {
+                            <li>{"Connection settings (ICE servers and TURN credentials) will be restored"}</li>
+                        }
+                        if plan.ice_settings_kept {
+                            <li style="color: #e67e22;">{"Your current connection settings differ from the backup and are kept"}</li>
+                        }
+                        if let Some(ref reason) = plan.ice_settings_rejected {
+                            <li style="color: #e67e22;">{format!("The backup's connection settings are not restored: {}", reason)}</li>
+                        }
+                        if let Some(ref profile) = plan.profile {
+                            <li>{format!("Display name will be set to \"{}\"", profile.display_name)}</li>
+                        }
+                        if plan.profile_kept {
+                            <li style="color: #e67e22;">{"Your current display name differs from the backup and is kept"}</li>
+                        }
+                        if !plan.chats.is_empty() {
+                            <li>{format!("{} chat message(s) in {} conversation(s)", plan.chat_message_count(), plan.chats.len())}</li>
+                        }
+                    </ul>
+                    if plan.identity_conflict.is_some() {
+                        <div style="margin: 15px 0; padding: 10px; background-color: #fdf2e9; border-radius: 4px; text-align: left; font-size: 13px;">
+                            <p style="margin: 0 0 8px 0; color: #e74c3c; font-weight: bold;">
+                                {"⚠️ The backup contains a different private key than the one in use."}
+                            </p>
+                            <label>
+                                <input type="checkbox" id="backup-replace-identity" />
+                                {" Replace my private key with the one from the backup (export the current key first if you still need it)"}
+                            </label>
+                        </div>
+                    }
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={on_confirm} style="background-color: #27ae60; flex: 1;">{"Import"}</button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_recipients_diff_dialog(&self, ctx: &Context<Self>) -> Html {
+        let Some(ref diff) = self.state.pending_recipients else {
+            return html! {};
+        };
+        let on_cancel = ctx.link().callback(|_| Msg::CancelRecipientsImport);
+        let on_confirm = ctx.link().callback(|_| {
+            let replace_changed = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("recipients-replace-changed"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"checked".into()).ok())
+                .and_then(|checked| checked.as_bool())
+                .unwrap_or(false);
+            Msg::ConfirmRecipientsImport(replace_changed)
+        });
+
+        html! {
+            <div class="dialog-overlay">
+                <div class="dialog" style="max-width: 450px;">
+                    <h3>{"Import Recipients"}</h3>
+                    <div style="max-height: 350px; overflow-y: auto; text-align: left; margin: 15px 0; font-size: 14px;">
+                        if !diff.new.is_empty() {
+                            <h4 style="margin: 0 0 5px 0; color: #27ae60;">{format!("New ({})", diff.new.len())}</h4>
+                            <ul style="margin: 0 0 10px 0;">
+                                { for diff.new.iter().map(|contact| html! {
+                                    <li>
+                                        {&contact.name}
+                                        <span style="margin-left: 8px; font-size: 12px; color: #7f8c8d; font-family: monospace;">
+                                            {contact.short_key()}
+                                        </span>
+                                    </li>
+                                })}
+                            </ul>
+                        }
+                        if !diff.changed.is_empty() {
+                            <h4 style="margin: 0 0 5px 0; color: #e67e22;">{format!("Changed key ({})", diff.changed.len())}</h4>
+                            <ul style="margin: 0 0 10px 0;">
+                                { for diff.changed.iter().map(|(contact, public_key)| html! {
+                                    <li>
+                                        {&contact.name}
+                                        <div style="font-size: 12px; color: #7f8c8d; font-family: monospace;">
+                                            {format!("{} → {}…", contact.short_key(), public_key.get(..12).unwrap_or_default())}
+                                        </div>
+                                    </li>
+                                })}
+                            </ul>
+                        }
+                        if !diff.unchanged.is_empty() {
+                            <h4 style="margin: 0 0 5px 0; color: #7f8c8d;">{format!("Unchanged ({})", diff.unchanged.len())}</h4>
+                            <p style="margin: 0 0 10px 0; font-size: 13px; color: #7f8c8d;">{diff.unchanged.join(", ")}</p>
+                        }
+                        if !diff.skipped.is_empty() {
+                            <h4 style="margin: 0 0 5px 0; color: #e74c3c;">{format!("Skipped ({})", diff.skipped.len())}</h4>
+                            <ul style="margin: 0; font-size: 12px; color: #7f8c8d;">
+                                { for diff.skipped.iter().map(|line| html! { <li>{line}</li> })}
+                            </ul>
+                        }
+                    </div>
+                    if !diff.changed.is_empty() {
+                        <div style="margin: 15px 0; padding: 10px; background-color: #fdf2e9; border-radius: 4px; text-align: left; font-size: 13px;">
+                            <label>
+                                <input type="checkbox" id="recipients-replace-changed" />
+                                {" Replace the keys of contacts listed under \"Changed key\". Only do this if you trust the file; they will need to be verified again."}
+                            </label>
+                        </div>
+                    }
+                    <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
+                        <button onclick={on_cancel} style="background-color: #95a5a6; flex: 1;">{"Cancel"}</button>
+                        <button onclick={on_confirm} disabled={diff.is_empty()} style="background-color: #27ae60; flex: 1;">{"Import"}</button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
     fn render_dialog(&self, ctx: &Context<Self>, message: &str) -> Html {
         let on_close = ctx.link().callback(|_| Msg::HideDialog);
 
@@ -1907,7 +5453,29 @@ impl App {
 
     fn render_rtc_dialog(&self, ctx: &Context<Self>) -> Html {
         let on_close = ctx.link().callback(|_| Msg::HideRtcDialog);
-        let on_generate_offer = ctx.link().callback(|_| Msg::StartRtcConnection);
+        let on_generate_offer = ctx.link().callback(|_| {
+            let peer_key = window()
+                .and_then(|w| w.document())
+                .and_then(|d| d.get_element_by_id("rtc-peer-select"))
+                .and_then(|el| js_sys::Reflect::get(&el, &"value".into()).ok())
+                .and_then(|value| value.as_string())
+                .filter(|value| !value.is_empty());
+            Msg::StartRtcConnection(peer_key)
+        });
+        let connected = self
+            .state
+            .rtc_peers
+            .values()
+            .filter(|p| p.connected)
+            .count();
+        let ice = &self.state.ice_settings;
+        let ice_summary = if ice.host_only {
+            "Network: same network only (no STUN/TURN servers).".to_string()
+        } else if ice.has_turn() {
+            format!("Network: {} ICE servers including TURN.", ice.servers.len())
+        } else {
+            format!("Network: {} STUN servers.", ice.servers.len())
+        };
 
         html! {
             <div class="dialog-overlay">
@@ -1917,6 +5485,23 @@ impl App {
                         {"Start a secure peer-to-peer connection:"}
                     </p>
 
+                    <div style="margin: 20px 0; text-align: left;">
+                        <label>{"Connect with:"}</label>
+                        <select id="rtc-peer-select" style="width: 100%; padding: 8px; margin: 5px 0;">
+                            <option value="">{"Anyone (unencrypted code)"}</option>
+                            { for self.state.contacts.iter().map(|contact| {
+                                html! {
+                                    <option value={contact.public_key.clone()}>
+                                        {format!("{} ({})", contact.name, contact.short_key())}
+                                    </option>
+                                }
+                            })}
+                        </select>
+                        <p style="font-size: 12px; color: #7f8c8d;">
+                            {"Codes for a contact are encrypted to them and signed, so only they can read your network details and only their reply is accepted."}
+                        </p>
+                    </div>
+
                     <div style="margin: 20px 0;">
                         <button onclick={on_generate_offer} style="background-color: #3498db; padding: 15px; font-size: 16px; width: 100%;">
                             {"Create Offer & Generate QR Code"}
@@ -1930,28 +5515,35 @@ impl App {
                             <li>{"Share the QR code with the other party"}</li>
                             <li>{"They scan it and generate an answer QR code"}</li>
                             <li>{"Scan their answer QR code to complete the connection"}</li>
-                            <li>{"Public keys will be exchanged automatically"}</li>
+                            <li>{"Public keys will be exchanged and verified automatically"}</li>
                         </ol>
                     </div>
 
-                    if self.state.rtc_connected {
+                    <p style="font-size: 12px; color: #7f8c8d;">
+                        { ice_summary }
+                        {" "}
+                        <a href="#" onclick={ctx.link().callback(|e: MouseEvent| {
+                            e.prevent_default();
+                            Msg::ShowIceSettingsDialog
+                        })}>{"Change"}</a>
+                    </p>
+
+                    if connected > 0 {
                         <div style="margin: 20px 0; padding: 15px; background-color: #d4edda; border-radius: 5px;">
                             <p style="color: #155724; margin: 0; font-weight: bold;">
-                                {"✅ RTC Connection Established!"}
+                                {format!("✅ {} connection(s) open", connected)}
+                            </p>
+                            <p style="color: #155724; margin: 5px 0 0 0; font-size: 12px;">
+                                {"A new offer adds another conversation; the open ones stay connected."}
                             </p>
-                            if let Some(ref peer_key) = self.state.rtc_peer_public_key {
-                                <p style="color: #155724; margin: 5px 0 0 0; font-size: 12px;">
-                                    {format!("Peer public key: {}...", &peer_key[..20])}
-                                </p>
-                            }
                         </div>
                     }
 
                     <div style="display: flex; justify-content: space-between; gap: 10px; margin-top: 20px;">
                         <button onclick={on_close} style="background-color: #95a5a6; flex: 1;">{"Close"}</button>
-                        if self.state.rtc_connected {
-                            <button onclick={ctx.link().callback(|_| Msg::SendPublicKeyViaRtc)} style="background-color: #e67e22; flex: 1;">
-                                {"Send My Public Key"}
+                        if !self.state.rtc_peers.is_empty() {
+                            <button onclick={ctx.link().batch_callback(|_| vec![Msg::HideRtcDialog, Msg::ShowChatView])} style="background-color: #8e44ad; flex: 1;">
+                                {"Open Chats"}
                             </button>
                         }
                     </div>
@@ -1960,52 +5552,291 @@ impl App {
         }
     }
 
+    // Who is on the other end of the data channel, as far as we can tell
+    fn render_peer_status(
+        &self,
+        ctx: &Context<Self>,
+        peer_id: &str,
+        peer: &RtcPeer,
+        is_contact: bool,
+    ) -> Html {
+        let Some(ref auth) = peer.auth else {
+            let peer_id = peer_id.to_string();
+            return html! {
+                <p style="color: #7f8c8d; font-style: italic; margin: 0 0 15px 0;">
+                    { if peer.connected { "Waiting for the peer's public key... " } else { "Connecting..." } }
+                    if peer.connected {
+                        <a href="#" onclick={ctx.link().callback(move |e: MouseEvent| {
+                            e.prevent_default();
+                            Msg::SendPublicKeyViaRtc(peer_id.clone())
+                        })}>{"Send my key again"}</a>
+                    }
+                </p>
+            };
+        };
+        match auth.status {
+            PeerStatus::Verifying => html! {
+                <p style="color: #7f8c8d; font-style: italic; margin: 0 0 15px 0;">
+                    {"Verifying the peer's key..."}
+                </p>
+            },
+            PeerStatus::Verified if is_contact => html! {},
+            PeerStatus::Verified => {
+                let public_key = auth.peer_key.clone();
+                html! {
+                    <div style="margin-bottom: 15px; padding: 15px; background-color: #f8d7da; border: 2px solid #e74c3c; border-radius: 5px; color: #721c24;">
+                        <p style="margin: 0 0 8px 0; font-weight: bold; font-size: 18px;">{"⚠️ Unknown peer"}</p>
+                        <p style="margin: 0 0 8px 0;">
+                            {"This peer holds the key below, but it is not one of your contacts. Anyone could be on the other end. Compare the key with them in person or over another channel before trusting this conversation."}
+                        </p>
+                        <p style="margin: 0 0 8px 0; font-family: monospace; font-size: 12px; word-break: break-all;">{ &auth.peer_key }</p>
+                        <button onclick={ctx.link().callback(move |_| Msg::ShowAddContactDialog(public_key.clone()))} style="background-color: #e74c3c; padding: 5px 12px; font-size: 13px;">
+                            {"Add to Contacts"}
+                        </button>
+                    </div>
+                }
+            }
+            PeerStatus::Failed(ref reason) => html! {
+                <div style="margin-bottom: 15px; padding: 15px; background-color: #f8d7da; border: 2px solid #e74c3c; border-radius: 5px; color: #721c24;">
+                    <p style="margin: 0 0 8px 0; font-weight: bold; font-size: 18px;">{"⛔ Peer verification failed"}</p>
+                    <p style="margin: 0;">{ reason }{" Messages cannot be sent on this connection."}</p>
+                </div>
+            },
+        }
+    }
+
+    fn render_file_transfers(&self, ctx: &Context<Self>, peer: &RtcPeer) -> Html {
+        let Some(ref peer_key) = peer.public_key else {
+            return html! {};
+        };
+        let transfers: Vec<&FileTransfer> = self
+            .state
+            .file_transfers
+            .iter()
+            .filter(|t| t.peer_key == *peer_key)
+            .collect();
+        if transfers.is_empty() {
+            return html! {};
+        }
+        let connected = peer.connected;
+        html! {
+            <div style="margin-bottom: 15px; border: 1px solid #ddd; border-radius: 5px; padding: 10px;">
+                { for transfers.into_iter().map(|transfer| {
+                    let sending = transfer.direction == Direction::Sent;
+                    let percent = transfer.transferred() * 100 / transfer.encrypted_size.max(1);
+                    let status = match transfer.status {
+                        TransferStatus::Encrypting => "Encrypting...".to_string(),
+                        TransferStatus::Offered | TransferStatus::Transferring if !connected => {
+                            "Paused, reconnect to this peer to resume".to_string()
+                        }
+                        TransferStatus::Offered => "Waiting for the peer...".to_string(),
+                        TransferStatus::Transferring => format!("{}%", percent),
+                        TransferStatus::Verifying if sending => "Waiting for the peer to verify...".to_string(),
+                        TransferStatus::Verifying => "Verifying and decrypting...".to_string(),
+                        TransferStatus::Complete if sending => "✅ Sent".to_string(),
+                        TransferStatus::Complete => "✅ Received".to_string(),
+                        TransferStatus::Failed(ref message) => format!("❌ {}", message),
+                    };
+                    let bar_color = match transfer.status {
+                        TransferStatus::Failed(_) => "#e74c3c",
+                        TransferStatus::Complete => "#27ae60",
+                        _ => "#3498db",
+                    };
+                    let download_id = transfer.id.clone();
+                    let resume_id = transfer.id.clone();
+                    let dismiss_id = transfer.id.clone();
+                    html! {
+                        <div style="margin-bottom: 8px; font-size: 14px;">
+                            <div style="display: flex; align-items: center; gap: 8px;">
+                                <span>{ if sending { "⬆️" } else { "⬇️" } }</span>
+                                <span style="flex: 1; overflow: hidden; text-overflow: ellipsis; white-space: nowrap;">
+                                    { &transfer.name }
+                                    <span style="color: #7f8c8d;">{format!(" ({})", format_size(transfer.size))}</span>
+                                </span>
+                                if transfer.file.is_some() {
+                                    <button onclick={ctx.link().callback(move |_| Msg::DownloadFile(download_id.clone()))} style="background-color: #27ae60; padding: 4px 10px; font-size: 12px;">
+                                        {"Download"}
+                                    </button>
+                                }
+                                if sending && connected && !transfer.is_finished() && transfer.status != TransferStatus::Encrypting {
+                                    <button onclick={ctx.link().callback(move |_| Msg::ResumeFileTransfer(resume_id.clone()))} style="background-color: #e67e22; padding: 4px 10px; font-size: 12px;">
+                                        {"Resume"}
+                                    </button>
+                                }
+                                <button onclick={ctx.link().callback(move |_| Msg::DismissFileTransfer(dismiss_id.clone()))} style="background-color: #95a5a6; padding: 4px 10px; font-size: 12px;">
+                                    {"✕"}
+                                </button>
+                            </div>
+                            <div style="height: 6px; background-color: #ecf0f1; border-radius: 3px; margin: 4px 0; overflow: hidden;">
+                                <div style={format!("height: 100%; width: {}%; background-color: {};", percent, bar_color)}></div>
+                            </div>
+                            <div style="font-size: 12px; color: #7f8c8d;">{ status }</div>
+                        </div>
+                    }
+                }) }
+            </div>
+        }
+    }
+
+    // The verified contact on a connection
+    fn rtc_peer_contact(&self, peer: &RtcPeer) -> Option<&Contact> {
+        let auth = peer.auth.as_ref()?;
+        if auth.status != PeerStatus::Verified {
+            return None;
+        }
+        self.state
+            .contacts
+            .iter()
+            .find(|c| c.public_key == auth.peer_key)
+    }
+
     fn render_chat_view(&self, ctx: &Context<Self>) -> Html {
-        let on_input = ctx.link().callback(|e: web_sys::InputEvent| {
+        let on_back = ctx.link().callback(|_| Msg::HideChatView);
+
+        // 古い接続から順に並べる
+        let mut peers: Vec<(&String, &RtcPeer)> = self.state.rtc_peers.iter().collect();
+        peers.sort_by(|a, b| a.1.started_at.total_cmp(&b.1.started_at));
+        let active = self
+            .state
+            .active_chat
+            .as_ref()
+            .and_then(|id| self.state.rtc_peers.get_key_value(id));
+
+        html! {
+            <div class="chat-container" style="max-width: 1000px; margin: 0 auto; padding: 20px;">
+                <div class="chat-header" style="display: flex; align-items: center; padding: 10px 0; border-bottom: 1px solid #ddd; margin-bottom: 20px;">
+                    <button onclick={on_back} style="background-color: #95a5a6; margin-right: 15px; padding: 8px 15px;">
+                        {"← Back"}
+                    </button>
+                    <h2 style="margin: 0; flex: 1;">{"Chats"}</h2>
+                    <button onclick={ctx.link().batch_callback(|_| vec![Msg::HideChatView, Msg::ShowRtcDialog])} style="background-color: #9b59b6; padding: 8px 15px;">
+                        {"+ New Connection"}
+                    </button>
+                </div>
+
+                <div style="display: flex; gap: 20px; align-items: flex-start;">
+                    <div class="conversation-list" style="width: 220px; flex-shrink: 0; border: 1px solid #ddd; border-radius: 5px; overflow: hidden;">
+                        if peers.is_empty() {
+                            <p style="padding: 10px; color: #666; font-style: italic; margin: 0;">{"No connections"}</p>
+                        }
+                        { for peers.into_iter().map(|(id, peer)| {
+                            let name = match (self.rtc_peer_contact(peer), peer.claimed_key()) {
+                                (Some(contact), _) => contact.name.clone(),
+                                (None, Some(key)) => format!("Unknown peer {}…", key.get(..12).unwrap_or(key)),
+                                (None, None) => "New connection".to_string(),
+                            };
+                            let status = match peer.auth {
+                                _ if !peer.connected => "Connecting...",
+                                Some(ref auth) => match auth.status {
+                                    PeerStatus::Verified => "🔒 Verified",
+                                    PeerStatus::Verifying => "Verifying...",
+                                    PeerStatus::Failed(_) => "⛔ Verification failed",
+                                },
+                                None => "Waiting for key...",
+                            };
+                            let selected = self.state.active_chat.as_ref() == Some(id);
+                            let background = if selected { "#eaf2f8" } else { "white" };
+                            let id = id.clone();
+                            html! {
+                                <div onclick={ctx.link().callback(move |_| Msg::OpenChat(id.clone()))}
+                                     style={format!("padding: 10px; border-bottom: 1px solid #eee; cursor: pointer; background-color: {};", background)}>
+                                    <div style="display: flex; align-items: center; gap: 6px;">
+                                        <span style="flex: 1; font-weight: bold; overflow: hidden; text-overflow: ellipsis; white-space: nowrap;">{ name }</span>
+                                        if peer.unread > 0 {
+                                            <span style="background-color: #e74c3c; color: white; border-radius: 10px; padding: 1px 7px; font-size: 12px;">{ peer.unread }</span>
+                                        }
+                                    </div>
+                                    <div style="font-size: 12px; color: #7f8c8d;">{ status }</div>
+                                </div>
+                            }
+                        }) }
+                    </div>
+
+                    <div style="flex: 1; min-width: 0;">
+                        if let Some((peer_id, peer)) = active {
+                            { self.render_chat_pane(ctx, peer_id, peer) }
+                        } else {
+                            <p style="text-align: center; color: #666; font-style: italic;">{"Select a conversation"}</p>
+                        }
+                    </div>
+                </div>
+            </div>
+        }
+    }
+
+    fn render_chat_pane(&self, ctx: &Context<Self>, peer_id: &str, peer: &RtcPeer) -> Html {
+        let id = peer_id.to_string();
+        let on_input = ctx.link().callback(move |e: web_sys::InputEvent| {
             let input: web_sys::HtmlInputElement = e.target_unchecked_into();
-            Msg::UpdateChatInput(input.value())
+            Msg::UpdateChatInput(id.clone(), input.value())
         });
 
         let on_send = {
-            let message = self.state.chat_input.clone();
+            let id = peer_id.to_string();
+            let message = peer.chat_input.clone();
             ctx.link().callback(move |_| {
                 if !message.trim().is_empty() {
-                    Msg::SendChatMessage(message.clone())
+                    Msg::SendChatMessage(id.clone(), message.clone())
                 } else {
-                    Msg::UpdateChatInput(String::new())
+                    Msg::UpdateChatInput(id.clone(), String::new())
                 }
             })
         };
 
         let on_keydown = {
-            let message = self.state.chat_input.clone();
+            let id = peer_id.to_string();
+            let message = peer.chat_input.clone();
             ctx.link().callback(move |e: web_sys::KeyboardEvent| {
                 if e.key() == "Enter" && !message.trim().is_empty() {
-                    Msg::SendChatMessage(message.clone())
+                    Msg::SendChatMessage(id.clone(), message.clone())
                 } else {
-                    Msg::UpdateChatInput(message.clone())
+                    Msg::UpdateChatInput(id.clone(), message.clone())
                 }
             })
         };
 
-        let on_back = ctx.link().callback(|_| Msg::HideChatView);
+        let id = peer_id.to_string();
+        let on_file_selected = ctx.link().batch_callback(move |e: Event| {
+            let input: web_sys::HtmlInputElement = e.target_unchecked_into();
+            let file = input.files().and_then(|files| files.get(0));
+            input.set_value("");
+            file.map(|file| Msg::SendFile(id.clone(), file))
+        });
+
+        let peer_contact = self.rtc_peer_contact(peer);
+        let title = match peer_contact {
+            Some(contact) => format!("Chat with {}", contact.name),
+            None => "Chat".to_string(),
+        };
+        let clear_id = peer_id.to_string();
+        let close_id = peer_id.to_string();
+        let older_id = peer_id.to_string();
 
         html! {
-            <div class="chat-container" style="max-width: 800px; margin: 0 auto; padding: 20px;">
-                <div class="chat-header" style="display: flex; align-items: center; padding: 10px 0; border-bottom: 1px solid #ddd; margin-bottom: 20px;">
-                    <button onclick={on_back} style="background-color: #95a5a6; margin-right: 15px; padding: 8px 15px;">
-                        {"← Back"}
-                    </button>
-                    <h2 style="margin: 0; flex: 1;">{"Chat"}</h2>
-                    <button onclick={ctx.link().callback(|_| Msg::ClearChatHistory)} style="background-color: #e74c3c; color: white; margin-right: 15px; padding: 8px 15px; border: none; border-radius: 4px; cursor: pointer;">
+            <>
+                <div style="display: flex; align-items: center; margin-bottom: 15px;">
+                    <h3 style="margin: 0; flex: 1;">{ title }</h3>
+                    <button onclick={ctx.link().callback(move |_| Msg::ClearChatHistory(clear_id.clone()))} style="background-color: #e74c3c; color: white; margin-right: 10px; padding: 8px 15px; border: none; border-radius: 4px; cursor: pointer;">
                         {"🗑️ Clear"}
                     </button>
+                    <button onclick={ctx.link().callback(move |_| Msg::CloseRtcConnection(close_id.clone()))} style="background-color: #7f8c8d; margin-right: 15px; padding: 8px 15px;">
+                        {"Disconnect"}
+                    </button>
                     <span style="color: #27ae60; font-weight: bold;">{"🔒"}</span>
                 </div>
 
+                { self.render_peer_status(ctx, peer_id, peer, peer_contact.is_some()) }
+
                 <div id="chat-messages" class="chat-messages" style="min-height: 400px; max-height: 400px; overflow-y: auto; border: 1px solid #ddd; padding: 15px; margin-bottom: 20px; background-color: #f9f9f9;">
+                    if peer.chat_older_page.is_some() {
+                        <div style="text-align: center; margin-bottom: 10px;">
+                            <button onclick={ctx.link().callback(move |_| Msg::LoadOlderChatMessages(older_id.clone()))} style="background-color: #95a5a6; padding: 5px 12px; font-size: 13px;">
+                                {"Load older messages"}
+                            </button>
+                        </div>
+                    }
                     {
-                        if self.state.chat_messages.is_empty() {
+                        if peer.chat_messages.is_empty() {
                             html! {
                                 <p style="text-align: center; color: #666; font-style: italic;">
                                     {"Your messages are end-to-end encrypted via WebRTC"}
@@ -2015,7 +5846,7 @@ impl App {
                             html! {
                                 <>
                                     {
-                                        self.state.chat_messages.iter().map(|msg| {
+                                        peer.chat_messages.iter().map(|msg| {
                                             let (message_style, alignment) = if msg.is_sent {
                                                 ("background-color: #3498db; color: white; margin-left: auto; margin-right: 0;", "flex-end")
                                             } else {
@@ -2037,31 +5868,40 @@ impl App {
                     }
                 </div>
 
+                { self.render_file_transfers(ctx, peer) }
+
                 <div class="chat-input" style="display: flex; gap: 10px;">
+                    <label for="chat-file-input" title="Send a file"
+                           style="display: flex; align-items: center; padding: 0 14px; background-color: #34495e; color: white; border-radius: 5px; font-size: 18px; cursor: pointer;">
+                        {"📎"}
+                    </label>
+                    <input type="file" id="chat-file-input" style="display: none;"
+                           disabled={peer.public_key.is_none()}
+                           onchange={on_file_selected} />
                     <input
                         type="text"
                         placeholder="Type your message..."
-                        value={self.state.chat_input.clone()}
+                        value={peer.chat_input.clone()}
                         oninput={on_input}
                         onkeydown={on_keydown}
                         style="flex: 1; padding: 12px; border: 1px solid #ddd; border-radius: 5px; font-size: 16px;"
                     />
                     <button
                         onclick={on_send}
-                        disabled={self.state.chat_input.trim().is_empty()}
+                        disabled={peer.chat_input.trim().is_empty() || peer.public_key.is_none()}
                         style="padding: 12px 20px; background-color: #3498db; color: white; border: none; border-radius: 5px; font-size: 16px; cursor: pointer;"
                     >
                         {"Send"}
                     </button>
                 </div>
 
-                if let Some(ref peer_key) = self.state.rtc_peer_public_key {
+                if let Some(ref peer_key) = peer.public_key {
                     <div style="margin-top: 15px; padding: 10px; background-color: #d4edda; border-radius: 5px; font-size: 12px;">
                         <strong>{"Connected to: "}</strong>
                         <span style="font-family: monospace;">{format!("{}...", &peer_key[..20])}</span>
                     </div>
                 }
-            </div>
+            </>
         }
     }
 }
@@ -2092,6 +5932,120 @@ fn setup_custom_event_listener(link: yew::html::Scope<App>) {
     }
 }
 
+// Pasted screenshots are decoded like a scanned QR code
+fn setup_paste_listener(link: yew::html::Scope<App>) {
+    if let Some(document) = window().and_then(|w| w.document()) {
+        let closure = Closure::wrap(Box::new(move |event: ClipboardEvent| {
+            let Some(files) = event.clipboard_data().and_then(|d| d.files()) else {
+                return;
+            };
+            for index in 0..files.length() {
+                if let Some(file) = files.get(index) {
+                    if file.type_().starts_with("image/") {
+                        console::log!("📋 Image pasted from clipboard");
+                        event.prevent_default();
+                        link.send_message(Msg::DecodeQrImage(file));
+                        return;
+                    }
+                }
+            }
+        }) as Box<dyn FnMut(ClipboardEvent)>);
+
+        let _ =
+            document.add_event_listener_with_callback("paste", closure.as_ref().unchecked_ref());
+        closure.forget();
+    }
+}
+
+fn setup_deep_link_listener(link: yew::html::Scope<App>) {
+    if let Some(window) = window() {
+        let closure = Closure::wrap(Box::new(move |_event: Event| {
+            if let Some(deep_link) = take_location_deep_link() {
+                console::log!("🔗 Deep link received");
+                link.send_message(Msg::HandleDeepLink(deep_link));
+            }
+        }) as Box<dyn FnMut(Event)>);
+
+        let _ =
+            window.add_event_listener_with_callback("hashchange", closure.as_ref().unchecked_ref());
+        closure.forget();
+    }
+}
+
+// Reads a deep link from `location.hash` and clears it so that secrets do not
+// stay in the browser history.
+fn take_location_deep_link() -> Option<String> {
+    let window = window()?;
+    let location = window.location();
+    let hash = location.hash().ok()?;
+    let _ = transport::decode_deep_link(&hash)?;
+
+    let clean_url = format!(
+        "{}{}",
+        location.pathname().unwrap_or_default(),
+        location.search().unwrap_or_default()
+    );
+    if let Ok(history) = window.history() {
+        let _ = history.replace_state_with_url(&JsValue::NULL, "", Some(&clean_url));
+    }
+    Some(hash)
+}
+
+fn app_base_url() -> String {
+    window()
+        .map(|w| {
+            let location = w.location();
+            format!(
+                "{}{}",
+                location.origin().unwrap_or_default(),
+                location.pathname().unwrap_or_default()
+            )
+        })
+        .unwrap_or_default()
+}
+
+fn extra_candidates_handler(
+    link: &yew::html::Scope<App>,
+    peer_id: String,
+    sealing: Option<(Session, KeyPair)>,
+) -> impl Fn(Vec<IceCandidate>) + 'static {
+    let link = link.clone();
+    move |candidates| {
+        if let Some(json) = encode_rtc_signal(&RtcSignalData::Candidates { candidates }, &sealing) {
+            link.send_message(Msg::RtcExtraCandidates(peer_id.clone(), json));
+        }
+    }
+}
+
+// Signals for a contact are encrypted to them and signed
+fn encode_rtc_signal(
+    signal: &RtcSignalData,
+    sealing: &Option<(Session, KeyPair)>,
+) -> Option<String> {
+    match sealing {
+        Some((session, keys)) => match signaling::seal(signal, session, keys) {
+            Ok(sealed) => Some(sealed),
+            Err(e) => {
+                error_report(&format!("❌ Failed to seal RTC signal: {}", e));
+                None
+            }
+        },
+        None => serde_json::to_string(signal).ok(),
+    }
+}
+
+fn payload_link_kind(payload: &str) -> LinkKind {
+    if ContactCard::parse(payload).is_some() {
+        LinkKind::Card
+    } else if payload.parse::<age::x25519::Recipient>().is_ok() {
+        LinkKind::PublicKey
+    } else if serde_json::from_str::<RtcSignalData>(payload).is_ok() {
+        LinkKind::Rtc
+    } else {
+        LinkKind::Message
+    }
+}
+
 fn dispatch_custom_event(event_type: &str, data: &str) {
     if let Some(window) = window() {
         if let Some(document) = window.document() {
@@ -2130,297 +6084,161 @@ fn stop_qr_reader_js() {
     }
 }
 
-fn draw_qr_code_to_canvas(data: &str) {
-    console::log!("🎨 QR code drawing started");
-    console::log!(&format!("📊 Data length: {}", data.len()));
-    console::log!(&format!("🔍 Data preview: {}", &data[..data.len().min(50)]));
-
-    let window = match window() {
-        Some(w) => w,
-        None => {
-            console::error!("❌ Failed to get window object");
-            return;
-        }
-    };
-
-    let document = match window.document() {
-        Some(d) => d,
-        None => {
-            console::error!("❌ Failed to get document object");
-            return;
-        }
+// 永続化関連の関数（保存先はstorageモジュールが選択する）
+async fn save_my_keys(private_key: &str, public_key: &str) {
+    let store = storage::store().await;
+    let result = match store.set_string("mySecretKey", private_key).await {
+        Ok(()) => store.set_string("myPublicKey", public_key).await,
+        Err(e) => Err(e),
     };
-
-    let canvas_element = match document.get_element_by_id("qr-canvas") {
-        Some(el) => el,
-        None => {
-            console::error!("❌ Canvas element 'qr-canvas' not found");
-            return;
-        }
+    // 平文の鍵を保存したらパスフレーズで保護された鍵は不要になる
+    let result = match result {
+        Ok(()) => autolock::remove_protection().await,
+        Err(e) => Err(e),
     };
+    if let Err(e) = result {
+        console::error!(&format!("❌ Failed to save keys: {}", e));
+    }
+}
 
-    let canvas = match canvas_element.dyn_into::<HtmlCanvasElement>() {
-        Ok(c) => c,
-        Err(_) => {
-            console::error!("❌ Failed to cast element to HtmlCanvasElement");
-            return;
+// Every stored entry as base64, for when the data cannot be loaded normally
+async fn recovery_dump() -> Result<String, String> {
+    let store = storage::store().await;
+    let mut entries = HashMap::new();
+    for key in store.keys().await? {
+        if let Some(value) = store.get(&key).await? {
+            entries.insert(key, BASE64.encode(value));
         }
-    };
-
-    let viewport_width = window
-        .inner_width()
-        .map(|w| w.as_f64().unwrap_or(800.0))
-        .unwrap_or(800.0);
-    let canvas_size = if viewport_width < 360.0 {
-        300
-    } else if viewport_width < 480.0 {
-        300
-    } else {
-        300
-    };
-
-    canvas.set_width(canvas_size as u32);
-    canvas.set_height(canvas_size as u32);
+    }
+    serde_json::to_string(&serde_json::json!({
+        "format": "qr-encrypt-recovery",
+        "version": 1,
+        "created_at": unix_time(),
+        "schema_version": migrations::schema_version(&store).await.ok(),
+        "entries": entries,
+    }))
+    .map_err(|e| e.to_string())
+}
 
-    console::log!(&format!(
-        "📐 Canvas size set: {}x{}",
-        canvas_size, canvas_size
+// Unlocks the vault with the private key and loads everything that needs it
+async fn open_session(link: yew::html::Scope<App>, keys: KeyPair) {
+    link.send_message(Msg::UpdateLoadingProgress(
+        "Unlocking vault...".to_string(),
+        Some(60),
     ));
-
-    let context = match canvas.get_context("2d") {
-        Ok(Some(ctx)) => match ctx.dyn_into::<CanvasRenderingContext2d>() {
-            Ok(c) => c,
-            Err(_) => {
-                console::error!("❌ Failed to cast context to CanvasRenderingContext2d");
-                return;
-            }
-        },
-        Ok(None) => {
-            console::error!("❌ Failed to get 2D context (null)");
-            return;
-        }
-        Err(_) => {
-            console::error!("❌ Failed to get 2D context");
-            return;
-        }
-    };
-
-    if let Ok(qr_code) = QrCode::new(data) {
-        let modules = qr_code.width();
-        console::log!(&format!("⚫ QR modules: {}x{}", modules, modules));
-
-        let margin = 12.0;
-        let available_size = canvas_size as f64 - (margin * 2.0);
-        let cell_size = available_size / modules as f64;
-
-        context.set_fill_style_str("white");
-        context.fill_rect(0.0, 0.0, canvas_size as f64, canvas_size as f64);
-
-        console::log!(&format!(
-            "🎯 Drawing parameters: cell_size={:.2}, margin={:.2}",
-            cell_size, margin
-        ));
-
-        context.set_fill_style_str("black");
-        for y in 0..modules {
-            for x in 0..modules {
-                if qr_code[(x, y)] == qrcode::Color::Dark {
-                    let draw_x = margin + (x as f64 * cell_size);
-                    let draw_y = margin + (y as f64 * cell_size);
-                    context.fill_rect(draw_x, draw_y, cell_size, cell_size);
-                }
-            }
-        }
-
-        console::log!("✅ QR code drawing completed");
-    } else {
-        console::error!("❌ QR code generation failed");
-
-        context.set_fill_style_str("#ffebee");
-        context.fill_rect(0.0, 0.0, canvas_size as f64, canvas_size as f64);
-        context.set_fill_style_str("#c62828");
-        context.set_font("16px Arial");
-        context.set_text_align("center");
-        let _ = context.fill_text(
-            "QR code generation error",
-            canvas_size as f64 / 2.0,
-            canvas_size as f64 / 2.0,
-        );
+    if let Err(e) = migrations::run(Some(&keys.private_key)).await {
+        link.send_message(Msg::MigrationFailed(e));
+        return;
+    }
+    if let Err(e) = vault::unlock(&keys.private_key).await {
+        link.send_message(Msg::MigrationFailed(format!(
+            "Failed to unlock vault: {}",
+            e
+        )));
+        return;
     }
-}
 
-// 暗号化されたメッセージのQRコード描画
-fn draw_encrypted_qr_code_to_canvas(data: &str) {
-    console::log!("🎨 Encrypted QR code drawing started");
-    console::log!(&format!("📊 Encrypted message data length: {}", data.len()));
-    console::log!(&format!(
-        "🔑 Encrypted message preview: {}...",
-        &data[..data.len().min(50)]
+    link.send_message(Msg::UpdateLoadingProgress(
+        "Keys loaded successfully".to_string(),
+        Some(90),
     ));
 
-    let qr_code = match QrCode::new(data) {
-        Ok(qr) => qr,
-        Err(e) => {
-            console::error!(&format!("❌ Encrypted QR code generation error: {:?}", e));
-            return;
-        }
-    };
+    link.send_message(Msg::QrSettingsLoaded(load_qr_settings().await));
+    link.send_message(Msg::ProfileLoaded(load_profile().await));
+    let contacts = load_contacts().await;
 
-    console::log!("✅ Encrypted QR code generated successfully");
-    let modules = qr_code.width();
-    console::log!(&format!(
-        "📐 Encrypted QR code size: {}x{}",
-        modules, modules
+    link.send_message(Msg::UpdateLoadingProgress(
+        "Application ready".to_string(),
+        Some(100),
     ));
 
-    let window = match window() {
-        Some(w) => w,
-        None => {
-            console::error!("❌ Failed to get window object for encrypted QR");
-            return;
-        }
-    };
-
-    let document = match window.document() {
-        Some(d) => d,
-        None => {
-            console::error!("❌ Failed to get document object for encrypted QR");
-            return;
-        }
-    };
+    link.send_message(Msg::SetLoading(false));
+    link.send_message(Msg::KeysLoaded(keys, contacts));
+}
 
-    let canvas_element = match document.get_element_by_id("encrypted-qr-canvas") {
-        Some(el) => el,
-        None => {
-            console::error!("❌ Canvas element 'encrypted-qr-canvas' not found");
-            return;
-        }
-    };
+async fn load_my_keys() -> Option<KeyPair> {
+    let store = storage::store().await;
+    if let (Ok(Some(private_key)), Ok(Some(public_key))) = (
+        store.get_string("mySecretKey").await,
+        store.get_string("myPublicKey").await,
+    ) {
+        return Some(KeyPair {
+            private_key,
+            public_key,
+        });
+    }
+    None
+}
 
-    let canvas = match canvas_element.dyn_into::<HtmlCanvasElement>() {
-        Ok(c) => c,
-        Err(_) => {
-            console::error!("❌ Failed to cast encrypted canvas element to HtmlCanvasElement");
-            return;
-        }
-    };
+// 連絡先と設定は暗号化された保管庫に保存する
+async fn save_contacts(contacts: &[Contact]) {
+    let contacts = contacts.to_vec();
+    if let Err(e) = vault::update(|data| data.contacts = contacts).await {
+        console::error!(&format!("❌ Failed to save contacts: {}", e));
+    }
+}
 
-    let viewport_width = window
-        .inner_width()
-        .map(|w| w.as_f64().unwrap_or(800.0))
-        .unwrap_or(800.0);
-    let canvas_size = if viewport_width < 360.0 {
-        300
-    } else if viewport_width < 480.0 {
-        300
-    } else {
-        300
-    };
+async fn save_qr_settings(settings: &QrSettings) {
+    let settings = settings.clone();
+    if let Err(e) = vault::update(|data| data.qr_settings = settings).await {
+        console::error!(&format!("❌ Failed to save QR settings: {}", e));
+    }
+}
 
-    canvas.set_width(canvas_size);
-    canvas.set_height(canvas_size);
-    console::log!(&format!(
-        "📐 Encrypted canvas size adjusted: {}x{}",
-        canvas_size, canvas_size
-    ));
+async fn save_ice_settings(settings: &IceSettings) {
+    let settings = settings.clone();
+    if let Err(e) = vault::update(|data| data.ice_settings = settings).await {
+        console::error!(&format!("❌ Failed to save ICE settings: {}", e));
+    }
+}
 
-    let context = match canvas.get_context("2d") {
-        Ok(Some(ctx)) => match ctx.dyn_into::<CanvasRenderingContext2d>() {
-            Ok(c) => c,
-            Err(_) => {
-                console::error!("❌ Failed to cast encrypted context to CanvasRenderingContext2d");
-                return;
-            }
-        },
-        Ok(None) => {
-            console::error!("❌ Failed to get 2D context for encrypted canvas (null)");
-            return;
-        }
-        Err(_) => {
-            console::error!("❌ Failed to get 2D context for encrypted canvas");
-            return;
-        }
-    };
+async fn load_qr_settings() -> QrSettings {
+    vault::data()
+        .map(|data| data.qr_settings.normalized())
+        .unwrap_or_default()
+}
 
-    console::log!("🎨 2D context obtained successfully for encrypted QR");
+async fn save_profile(profile: &Profile) {
+    let profile = profile.clone();
+    if let Err(e) = vault::update(|data| data.profile = profile).await {
+        console::error!(&format!("❌ Failed to save profile: {}", e));
+    }
+}
 
-    // マージンを設定（角丸で欠けるのを防ぐ）
-    let margin = 15.0; // 15px のマージン（暗号化QRは少し大きめ）
-    let available_size = canvas_size as f64 - (margin * 2.0);
-    let cell_size = available_size / modules as f64;
-    console::log!(&format!(
-        "📏 Encrypted margin: {}px, available size: {}px, cell size: {}",
-        margin, available_size, cell_size
-    ));
+async fn load_profile() -> Profile {
+    vault::data().map(|data| data.profile).unwrap_or_default()
+}
 
-    // 背景を白に
-    context.set_fill_style_str("white");
-    context.fill_rect(0.0, 0.0, canvas_size as f64, canvas_size as f64);
-    console::log!("⚪ Encrypted background drawing completed");
-
-    // QRコードを描画（マージンを考慮）
-    context.set_fill_style_str("black");
-    let mut dark_modules = 0;
-    for y in 0..modules {
-        for x in 0..modules {
-            if qr_code[(x, y)] == qrcode::Color::Dark {
-                dark_modules += 1;
-                context.fill_rect(
-                    margin + (x as f64 * cell_size),
-                    margin + (y as f64 * cell_size),
-                    cell_size,
-                    cell_size,
-                );
-            }
-        }
-    }
-    console::log!(&format!(
-        "⚫ Encrypted QR code drawing completed - dark modules: {}",
-        dark_modules
-    ));
-    console::log!("🎉 Encrypted QR code drawing completed successfully!");
+async fn load_contacts() -> Vec<Contact> {
+    vault::data().map(|data| data.contacts).unwrap_or_default()
 }
 
-// localStorage関連の関数
-async fn save_my_keys(private_key: &str, public_key: &str) {
-    if let Some(storage) = get_local_storage() {
-        let _ = storage.set_item("mySecretKey", private_key);
-        let _ = storage.set_item("myPublicKey", public_key);
-    }
+fn format_timestamp(timestamp: u64) -> String {
+    Date::new(&JsValue::from_f64(timestamp as f64 * 1000.0))
+        .to_locale_string("default", &JsValue::UNDEFINED)
+        .as_string()
+        .unwrap_or_default()
 }
 
-async fn load_my_keys() -> Option<KeyPair> {
-    if let Some(storage) = get_local_storage() {
-        if let (Ok(Some(private_key)), Ok(Some(public_key))) = (
-            storage.get_item("mySecretKey"),
-            storage.get_item("myPublicKey"),
-        ) {
-            return Some(KeyPair {
-                private_key,
-                public_key,
-            });
-        }
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{} B", b),
     }
-    None
 }
 
-async fn save_contacts(contacts: &HashMap<String, String>) {
-    if let Some(storage) = get_local_storage() {
-        if let Ok(json) = serde_json::to_string(contacts) {
-            let _ = storage.set_item("keys", &json);
-        }
-    }
+fn unix_time() -> u64 {
+    (Date::now() / 1000.0) as u64
 }
 
-async fn load_contacts() -> HashMap<String, String> {
-    if let Some(storage) = get_local_storage() {
-        if let Ok(Some(json)) = storage.get_item("keys") {
-            if let Ok(contacts) = serde_json::from_str(&json) {
-                return contacts;
-            }
-        }
+fn new_id() -> String {
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // 乱数が取れない場合は時刻で代用する
+        bytes = Date::now().to_bits().to_be_bytes();
     }
-    HashMap::new()
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 fn error_report(message: &str) {
@@ -2428,23 +6246,31 @@ fn error_report(message: &str) {
     alert(&message);
 }
 
-fn get_local_storage() -> Option<Storage> {
-    window()?.local_storage().ok()?
-}
-
 #[wasm_bindgen]
 pub fn process_qr_data(data: &str) {
     console::log!("🔄 Wasm processing QR data");
+    let data = match transport::decode_payload(data.trim()) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            console::error!(&format!("❌ {}", e));
+            dispatch_custom_event("show_dialog", &e);
+            return;
+        }
+    };
+    let data = data.as_str();
     console::log!(&format!("📊 Data length: {}", data.len()));
     console::log!(&format!(
         "🔍 Data preview: {}...",
-        &data[..data.len().min(50)]
+        data.chars().take(50).collect::<String>()
     ));
 
     // RTC信号データかチェック
     if let Ok(_signal_data) = serde_json::from_str::<RtcSignalData>(data) {
         console::log!("📡 RTC signal data recognized");
         dispatch_custom_event("process_rtc_signal", data);
+    } else if ContactCard::parse(data).is_some() {
+        console::log!("📇 Contact card recognized");
+        dispatch_custom_event("add_contact_card", data);
     } else if is_valid_age_public_key(data) {
         console::log!("🔑 Age public key recognized");
         dispatch_custom_event("add_contact", &data);
@@ -2485,6 +6311,11 @@ fn is_base64(s: &str) -> bool {
     }
 }
 
+async fn read_file_bytes(file: &web_sys::File) -> Result<Vec<u8>, JsValue> {
+    let buffer = wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await?;
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
 async fn copy_to_clipboard(text: &str) -> Result<(), JsValue> {
     use js_sys::Promise;
     use wasm_bindgen_futures::JsFuture;
@@ -2506,6 +6337,65 @@ async fn copy_to_clipboard(text: &str) -> Result<(), JsValue> {
     Ok(())
 }
 
+fn download_url(filename: &str, url: &str) -> Result<(), JsValue> {
+    let document = window()
+        .and_then(|w| w.document())
+        .ok_or("No document object")?;
+    let anchor: HtmlAnchorElement = document.create_element("a")?.unchecked_into();
+    anchor.set_href(url);
+    anchor.set_download(filename);
+    anchor.click();
+    Ok(())
+}
+
+fn download_text(filename: &str, mime_type: &str, content: &str) -> Result<(), JsValue> {
+    let parts = js_sys::Array::of1(&JsValue::from_str(content));
+    let options = BlobPropertyBag::new();
+    options.set_type(mime_type);
+    let blob = Blob::new_with_str_sequence_and_options(&parts, &options)?;
+    download_blob(filename, &blob)
+}
+
+fn download_bytes(filename: &str, mime_type: &str, content: &[u8]) -> Result<(), JsValue> {
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(content));
+    let options = BlobPropertyBag::new();
+    options.set_type(mime_type);
+    let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    download_blob(filename, &blob)
+}
+
+// Some browsers start the download only after click() returns, so the
+// object URL is revoked from a timer rather than right away
+fn download_blob(filename: &str, blob: &Blob) -> Result<(), JsValue> {
+    let url = Url::create_object_url_with_blob(blob)?;
+    let result = download_url(filename, &url);
+    let Some(window) = window() else {
+        Url::revoke_object_url(&url)?;
+        return result;
+    };
+    let url_for_revoke = url.clone();
+    let revoke = Closure::once_into_js(move || {
+        let _ = Url::revoke_object_url(&url_for_revoke);
+    });
+    if window
+        .set_timeout_with_callback_and_timeout_and_arguments_0(revoke.unchecked_ref(), 1000)
+        .is_err()
+    {
+        Url::revoke_object_url(&url)?;
+    }
+    result
+}
+
+fn download_canvas_png(canvas_id: &str, filename: &str) -> Result<(), JsValue> {
+    let canvas: HtmlCanvasElement = window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.get_element_by_id(canvas_id))
+        .ok_or("Canvas element not found")?
+        .dyn_into()?;
+    let data_url = canvas.to_data_url_with_type("image/png")?;
+    download_url(filename, &data_url)
+}
+
 #[wasm_bindgen(start)]
 pub fn main() {
     yew::Renderer::<App>::new().render();
@@ -0,0 +1,128 @@
+use crate::sync::{self, SyncEvent};
+use crate::vault;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+
+// Stored as a single vault record; the list is small enough to search in
+// memory.
+const RECORD_NAME: &str = "message-log";
+
+thread_local! {
+    // Set while a save is running; later saves only leave their snapshot in
+    // PENDING for it to write next
+    static SAVING: Cell<bool> = const { Cell::new(false) };
+    // The newest snapshot not yet written
+    static PENDING: RefCell<Option<Vec<LogEntry>>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub id: String,
+    pub direction: Direction,
+    pub timestamp: u64,
+    // Plaintext for received messages, the age ciphertext for sent ones
+    pub text: String,
+    // Sender or recipient public key, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_key: Option<String>,
+    // Contact name at the time, kept in case the contact is deleted later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_name: Option<String>,
+}
+
+impl LogEntry {
+    // Received messages are matched on their text, sent ones only on the
+    // recipient since their text is ciphertext.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return true;
+        }
+        let name_matches = self
+            .peer_name
+            .as_ref()
+            .map(|name| name.to_lowercase().contains(&query))
+            .unwrap_or(false);
+        name_matches
+            || (self.direction == Direction::Received && self.text.to_lowercase().contains(&query))
+    }
+}
+
+pub async fn load() -> Result<Vec<LogEntry>, String> {
+    Ok(vault::read_record(RECORD_NAME).await?.unwrap_or_default())
+}
+
+// Saves run one at a time, so an older snapshot can never overwrite a newer
+// one. Snapshots queued behind a running save are replaced by later ones. A
+// failed write does not stop a newer snapshot from being written; the error
+// is only returned if the last write failed too.
+pub async fn save(entries: &[LogEntry]) -> Result<(), String> {
+    PENDING.with(|pending| *pending.borrow_mut() = Some(entries.to_vec()));
+    if SAVING.with(|saving| saving.replace(true)) {
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    loop {
+        let Some(entries) = PENDING.with(|pending| pending.borrow_mut().take()) else {
+            SAVING.with(|saving| saving.set(false));
+            return result;
+        };
+        result = vault::write_record(RECORD_NAME, &entries).await;
+        if result.is_ok() {
+            sync::notify(SyncEvent::MessageLogChanged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(direction: Direction, text: &str, peer_name: Option<&str>) -> LogEntry {
+        LogEntry {
+            id: "1".to_string(),
+            direction,
+            timestamp: 1_700_000_000,
+            text: text.to_string(),
+            peer_key: None,
+            peer_name: peer_name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn received_entries_match_their_text() {
+        let received = entry(Direction::Received, "Lunch at Noon?", Some("Alice"));
+        assert!(received.matches("noon"));
+        assert!(received.matches("  LUNCH "));
+        assert!(received.matches("alice"));
+        assert!(!received.matches("dinner"));
+    }
+
+    #[test]
+    fn sent_entries_are_not_matched_on_their_ciphertext() {
+        let sent = entry(
+            Direction::Sent,
+            "-----BEGIN AGE ENCRYPTED FILE-----\nYWdlLWVuY3J5cHRpb24ub3JnL3Yx\n-----END AGE ENCRYPTED FILE-----",
+            Some("Bob"),
+        );
+        assert!(!sent.matches("age encrypted"));
+        assert!(!sent.matches("YWdl"));
+        assert!(sent.matches("bob"));
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        let unnamed = entry(Direction::Sent, "ciphertext", None);
+        assert!(unnamed.matches(""));
+        assert!(unnamed.matches("   "));
+        assert!(!unnamed.matches("bob"));
+    }
+}
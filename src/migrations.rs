@@ -0,0 +1,333 @@
+use crate::storage::{self, js_error, KeyValueStore, Store};
+use crate::{contacts_from_legacy, unix_time, vault, Contact};
+use age::x25519;
+use gloo::console;
+use serde_json::Value;
+use std::collections::HashMap;
+use web_sys::window;
+
+// Bump this and append a step to MIGRATIONS whenever the stored format
+// changes. Steps must be safe to run again on data that is already migrated.
+pub const SCHEMA_VERSION: u32 = 3;
+
+const SCHEMA_VERSION_KEY: &str = "schemaVersion";
+
+// Keys that older versions wrote directly to localStorage
+const LEGACY_KEYS: [&str; 5] = [
+    "mySecretKey",
+    "myPublicKey",
+    "keys",
+    "qrSettings",
+    "myProfile",
+];
+
+// Entries that were stored as plain JSON before the vault existed
+const PLAINTEXT_KEYS: [&str; 3] = ["keys", "qrSettings", "myProfile"];
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    // Steps touching the vault can only run once our keys are loaded
+    needs_identity: bool,
+}
+
+const MIGRATIONS: [Migration; 3] = [
+    Migration {
+        version: 1,
+        description: "Move localStorage entries into the storage backend",
+        needs_identity: false,
+    },
+    Migration {
+        version: 2,
+        description: "Move contacts and settings into the encrypted vault",
+        needs_identity: true,
+    },
+    Migration {
+        version: 3,
+        description: "Convert contacts into contact records",
+        needs_identity: true,
+    },
+];
+
+// Applies every pending step in order and records the new version after
+// each one. Without a private key, steps that need it are left for the next
+// call. Nothing is deleted unless its replacement has been written.
+pub async fn run(private_key: Option<&str>) -> Result<(), String> {
+    let store = storage::store().await;
+    let current = schema_version(&store).await?;
+    if current > SCHEMA_VERSION {
+        return Err(format!(
+            "The stored data uses schema version {}, but this version of the app only supports up to {}. Please update the app.",
+            current, SCHEMA_VERSION
+        ));
+    }
+    let identity = private_key.map(vault::parse_identity).transpose()?;
+    // Contacts made from the old name -> key map are dated now
+    let now = unix_time();
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let identity = match (&identity, migration.needs_identity) {
+            (Some(identity), _) => Some(identity),
+            (None, false) => None,
+            (None, true) => {
+                console::log!(&format!(
+                    "⏸️ Migration {} waits for the keys to be loaded",
+                    migration.version
+                ));
+                return Ok(());
+            }
+        };
+        console::log!(&format!(
+            "🔄 Migration {}: {}",
+            migration.version, migration.description
+        ));
+        apply(migration.version, &store, identity, now)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version, migration.description, e
+                )
+            })?;
+        store
+            .set_string(SCHEMA_VERSION_KEY, &migration.version.to_string())
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn schema_version(store: &Store) -> Result<u32, String> {
+    match store.get_string(SCHEMA_VERSION_KEY).await? {
+        Some(version) => version
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid schema version record: {}", version)),
+        None => Ok(0),
+    }
+}
+
+async fn apply(
+    version: u32,
+    store: &Store,
+    identity: Option<&x25519::Identity>,
+    now: u64,
+) -> Result<(), String> {
+    match (version, identity) {
+        (1, _) => move_local_storage_entries(store).await,
+        (2, Some(identity)) => encrypt_plaintext_entries(store, identity, now).await,
+        (3, Some(identity)) => convert_contact_records(identity, now).await,
+        _ => Err(format!("Unknown migration {}", version)),
+    }
+}
+
+// v1: plain localStorage entries move into the storage backend
+async fn move_local_storage_entries(store: &Store) -> Result<(), String> {
+    let Some(local) = window().and_then(|w| w.local_storage().ok().flatten()) else {
+        return Ok(());
+    };
+    for key in LEGACY_KEYS {
+        let Some(value) = local.get_item(key).map_err(js_error)? else {
+            continue;
+        };
+        if store.get(key).await?.is_none() {
+            store.set_string(key, &value).await?;
+            console::log!(&format!("📦 Migrated {} from localStorage", key));
+        }
+        local.remove_item(key).map_err(js_error)?;
+    }
+    Ok(())
+}
+
+// v2: contacts and settings stored as plaintext JSON move into the vault
+async fn encrypt_plaintext_entries(
+    store: &Store,
+    identity: &x25519::Identity,
+    now: u64,
+) -> Result<(), String> {
+    let mut value = vault::read::<Value>(identity)
+        .await?
+        .unwrap_or_else(|| Value::Object(Default::default()));
+    let mut changed = false;
+
+    if let Some(legacy) = store.get_json::<HashMap<String, String>>("keys").await? {
+        merge_legacy_contacts(&mut value, legacy, now)?;
+        changed = true;
+    }
+    for (key, field) in [("qrSettings", "qr_settings"), ("myProfile", "profile")] {
+        if let Some(entry) = store.get_json::<Value>(key).await? {
+            value[field] = entry;
+            changed = true;
+        }
+    }
+
+    if changed {
+        vault::write(identity, &value).await?;
+        console::log!("🔐 Plaintext data moved into the vault");
+    }
+    for key in PLAINTEXT_KEYS {
+        store.remove(key).await?;
+    }
+    Ok(())
+}
+
+// Adds the plaintext contact map to whatever the vault already holds
+fn merge_legacy_contacts(
+    value: &mut Value,
+    legacy: HashMap<String, String>,
+    now: u64,
+) -> Result<(), String> {
+    match value.get_mut("contacts") {
+        // 既にレコード形式の場合は未登録の鍵だけを追加する
+        Some(Value::Array(records)) => {
+            for contact in contacts_from_legacy(legacy, now) {
+                let known = records
+                    .iter()
+                    .any(|r| r["public_key"].as_str() == Some(contact.public_key.as_str()));
+                if !known {
+                    records.push(serde_json::to_value(contact).map_err(|e| e.to_string())?);
+                }
+            }
+        }
+        Some(Value::Object(map)) => {
+            for (name, public_key) in legacy {
+                map.entry(name).or_insert(Value::String(public_key));
+            }
+        }
+        _ => {
+            value["contacts"] = serde_json::to_value(legacy).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// v3: the contact map (display name -> public key) becomes contact records
+async fn convert_contact_records(identity: &x25519::Identity, now: u64) -> Result<(), String> {
+    let Some(mut value) = vault::read::<Value>(identity).await? else {
+        return Ok(());
+    };
+    let Some(contacts) = value.get_mut("contacts") else {
+        return Ok(());
+    };
+    if !contacts.is_object() {
+        return Ok(());
+    }
+    let legacy = serde_json::from_value::<HashMap<String, String>>(contacts.take())
+        .map_err(|e| format!("Invalid contact map: {}", e))?;
+    let records: Vec<Contact> = contacts_from_legacy(legacy, now);
+    *contacts = serde_json::to_value(records).map_err(|e| e.to_string())?;
+    vault::write(identity, &value).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn legacy(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, key)| (name.to_string(), key.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn schema_version_defaults_to_zero() {
+        block_on(async {
+            let store = Store::Memory(storage::use_memory_store());
+            assert_eq!(schema_version(&store).await, Ok(0));
+            store.set_string(SCHEMA_VERSION_KEY, " 2\n").await.unwrap();
+            assert_eq!(schema_version(&store).await, Ok(2));
+            store.set_string(SCHEMA_VERSION_KEY, "two").await.unwrap();
+            assert!(schema_version(&store).await.is_err());
+        });
+    }
+
+    #[test]
+    fn migrations_are_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u32 + 1);
+        }
+        assert_eq!(MIGRATIONS.last().map(|m| m.version), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn legacy_contacts_fill_an_empty_vault() {
+        let mut value = json!({});
+        merge_legacy_contacts(&mut value, legacy(&[("Alice", "age1alice")]), NOW).unwrap();
+        assert_eq!(value["contacts"], json!({ "Alice": "age1alice" }));
+    }
+
+    #[test]
+    fn legacy_contacts_merge_into_a_contact_map() {
+        let mut value = json!({ "contacts": { "Alice": "age1alice" } });
+        merge_legacy_contacts(
+            &mut value,
+            legacy(&[("Alice", "age1other"), ("Bob", "age1bob")]),
+            NOW,
+        )
+        .unwrap();
+        // Entries already in the vault win
+        assert_eq!(
+            value["contacts"],
+            json!({ "Alice": "age1alice", "Bob": "age1bob" })
+        );
+    }
+
+    #[test]
+    fn legacy_contacts_merge_into_contact_records() {
+        let existing = Contact::new("Alice".to_string(), "age1alice".to_string(), NOW - 60);
+        let mut value = json!({ "contacts": [existing] });
+        merge_legacy_contacts(
+            &mut value,
+            legacy(&[("Alice again", "age1alice"), ("Bob", "age1bob")]),
+            NOW,
+        )
+        .unwrap();
+        let contacts: Vec<Contact> = serde_json::from_value(value["contacts"].clone()).unwrap();
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0], existing);
+        assert_eq!(contacts[1].name, "Bob");
+        assert_eq!(contacts[1].added_at, NOW);
+    }
+
+    #[test]
+    fn contact_map_becomes_records_once() {
+        block_on(async {
+            let store = Store::Memory(storage::use_memory_store());
+            let identity = x25519::Identity::generate();
+            vault::write(
+                &identity,
+                &json!({ "contacts": { "Bob": "age1bob", "alice": "age1alice" }, "profile": { "display_name": "Me" } }),
+            )
+            .await
+            .unwrap();
+
+            apply(3, &store, Some(&identity), NOW).await.unwrap();
+            let value = vault::read::<Value>(&identity).await.unwrap().unwrap();
+            let contacts: Vec<Contact> = serde_json::from_value(value["contacts"].clone()).unwrap();
+            let names: Vec<&str> = contacts.iter().map(|c| c.name.as_str()).collect();
+            assert_eq!(names, vec!["alice", "Bob"]);
+            assert_eq!(contacts[1].public_key, "age1bob");
+            assert!(contacts.iter().all(|c| c.added_at == NOW));
+            // Other fields are kept
+            assert_eq!(value["profile"]["display_name"], "Me");
+
+            // Running the step again changes nothing
+            apply(3, &store, Some(&identity), NOW).await.unwrap();
+            let again = vault::read::<Value>(&identity).await.unwrap().unwrap();
+            assert_eq!(again, value);
+        });
+    }
+
+    #[test]
+    fn steps_that_need_the_identity_fail_without_it() {
+        block_on(async {
+            let store = Store::Memory(storage::use_memory_store());
+            assert!(apply(3, &store, None, NOW).await.is_err());
+            assert!(apply(99, &store, None, NOW).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,281 @@
+use crate::card::signing_key_from_identity;
+use crate::KeyPair;
+use age::{x25519, Decryptor, Encryptor};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+const PROOF_DOMAIN: &str = "qr-encrypt rtc peer proof v2";
+const CHALLENGE_LEN: usize = 32;
+
+// Proof that the peer on the data channel holds the private key it claims.
+// We send a random challenge encrypted to the claimed key; the peer answers
+// with a hash of the challenge and the DTLS fingerprints it sees. Only the
+// key holder can read the challenge, and an interceptor relaying between two
+// DTLS sessions cannot make both sides see the same fingerprints.
+// The proof also covers the peer's signing key, which is the only way that
+// key gets tied to the age key: a contact card's self-signature is made with
+// the very key it carries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerAuth {
+    pub peer_key: String,
+    challenge: Vec<u8>,
+    pub status: PeerStatus,
+    // Set once the peer has proven that this signing key belongs to its key
+    pub signing_key: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerStatus {
+    Verifying,
+    Verified,
+    Failed(String),
+}
+
+impl PeerAuth {
+    // Returns the new exchange and the challenge to send
+    pub fn start(peer_key: &str) -> Result<(Self, String), String> {
+        let recipient = peer_key
+            .parse::<x25519::Recipient>()
+            .map_err(|e| format!("Invalid peer key: {}", e))?;
+        let mut challenge = vec![0u8; CHALLENGE_LEN];
+        getrandom::getrandom(&mut challenge).map_err(|e| format!("No random source: {}", e))?;
+
+        let encryptor =
+            Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .map_err(|e| e.to_string())?;
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(&mut encrypted)
+            .map_err(|e| e.to_string())?;
+        writer.write_all(&challenge).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+
+        Ok((
+            Self {
+                peer_key: peer_key.to_string(),
+                challenge,
+                status: PeerStatus::Verifying,
+                signing_key: None,
+            },
+            BASE64.encode(encrypted),
+        ))
+    }
+
+    // Checks the peer's answer against our own view of the connection
+    pub fn check(&mut self, proof: &str, signing_key: &str, fingerprints: Option<[[u8; 32]; 2]>) {
+        if self.status != PeerStatus::Verifying {
+            return;
+        }
+        self.status = match fingerprints {
+            Some(fingerprints)
+                if proof
+                    == expected_proof(&self.challenge, &self.peer_key, signing_key, fingerprints) =>
+            {
+                self.signing_key = Some(signing_key.to_string());
+                PeerStatus::Verified
+            }
+            Some(_) => PeerStatus::Failed(
+                "The peer could not prove that it holds its key, or the connection is being intercepted."
+                    .to_string(),
+            ),
+            None => PeerStatus::Failed("The connection has no DTLS fingerprints.".to_string()),
+        };
+    }
+}
+
+// Answers the peer's challenge with our key. Returns the proof and the
+// signing key it vouches for.
+pub fn respond(
+    encrypted_challenge: &str,
+    keys: &KeyPair,
+    fingerprints: Option<[[u8; 32]; 2]>,
+) -> Result<(String, String), String> {
+    let fingerprints = fingerprints.ok_or("The connection has no DTLS fingerprints.")?;
+    let identity = keys
+        .private_key
+        .trim()
+        .parse::<x25519::Identity>()
+        .map_err(|e| format!("Invalid private key: {}", e))?;
+    let encrypted = BASE64
+        .decode(encrypted_challenge)
+        .map_err(|e| e.to_string())?;
+    let decryptor = Decryptor::new(&encrypted[..]).map_err(|e| e.to_string())?;
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|_| "The peer's challenge was not encrypted to our key.".to_string())?;
+    let mut challenge = Vec::new();
+    reader
+        .read_to_end(&mut challenge)
+        .map_err(|e| e.to_string())?;
+    if challenge.len() != CHALLENGE_LEN {
+        return Err("Invalid challenge".to_string());
+    }
+    let signing_key = BASE64.encode(
+        signing_key_from_identity(&keys.private_key)
+            .verifying_key()
+            .as_bytes(),
+    );
+    let proof = expected_proof(&challenge, &keys.public_key, &signing_key, fingerprints);
+    Ok((proof, signing_key))
+}
+
+// Both ends must hash the same fingerprints, so they are sorted rather than
+// taken as local and remote
+fn expected_proof(
+    challenge: &[u8],
+    public_key: &str,
+    signing_key: &str,
+    mut fingerprints: [[u8; 32]; 2],
+) -> String {
+    fingerprints.sort();
+    let digest = Sha256::new()
+        .chain_update(PROOF_DOMAIN.as_bytes())
+        .chain_update(challenge)
+        .chain_update(public_key.as_bytes())
+        .chain_update(signing_key.as_bytes())
+        .chain_update(fingerprints[0])
+        .chain_update(fingerprints[1])
+        .finalize();
+    BASE64.encode(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    const LOCAL: [u8; 32] = [0x11; 32];
+    const REMOTE: [u8; 32] = [0x22; 32];
+    const FAILED: &str =
+        "The peer could not prove that it holds its key, or the connection is being intercepted.";
+
+    fn key_pair() -> KeyPair {
+        let identity = x25519::Identity::generate();
+        KeyPair {
+            public_key: identity.to_public().to_string(),
+            private_key: identity.to_string().expose_secret().to_string(),
+        }
+    }
+
+    #[test]
+    fn proof_with_swapped_fingerprints_verifies() {
+        let bob = key_pair();
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        // Bob sees our fingerprint as the remote one
+        let (proof, signing_key) = respond(&challenge, &bob, Some([REMOTE, LOCAL])).unwrap();
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Verified);
+    }
+
+    #[test]
+    fn relayed_proof_fails() {
+        let bob = key_pair();
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        // An interceptor runs a separate DTLS session with each side
+        let (proof, signing_key) =
+            respond(&challenge, &bob, Some([[0x33; 32], [0x44; 32]])).unwrap();
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Failed(FAILED.to_string()));
+
+        // Only one of the two sessions is shared
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        let (proof, signing_key) = respond(&challenge, &bob, Some([LOCAL, [0x44; 32]])).unwrap();
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Failed(FAILED.to_string()));
+    }
+
+    #[test]
+    fn challenge_for_another_key_fails() {
+        let (bob, carol) = (key_pair(), key_pair());
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        assert_eq!(
+            respond(&challenge, &carol, Some([LOCAL, REMOTE])),
+            Err("The peer's challenge was not encrypted to our key.".to_string())
+        );
+
+        // Carol answers a challenge of their own with Bob's claimed key
+        let (_, own) = PeerAuth::start(&carol.public_key).unwrap();
+        let (proof, signing_key) = respond(&own, &carol, Some([REMOTE, LOCAL])).unwrap();
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Failed(FAILED.to_string()));
+    }
+
+    #[test]
+    fn proof_binds_the_public_key() {
+        let bob = key_pair();
+        let (auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        let (proof, signing_key) = respond(&challenge, &bob, Some([LOCAL, REMOTE])).unwrap();
+        assert_eq!(
+            proof,
+            expected_proof(
+                &auth.challenge,
+                &bob.public_key,
+                &signing_key,
+                [LOCAL, REMOTE]
+            )
+        );
+        assert_ne!(
+            proof,
+            expected_proof(
+                &auth.challenge,
+                &key_pair().public_key,
+                &signing_key,
+                [LOCAL, REMOTE]
+            )
+        );
+    }
+
+    #[test]
+    fn proof_binds_the_signing_key() {
+        let bob = key_pair();
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        let (proof, signing_key) = respond(&challenge, &bob, Some([REMOTE, LOCAL])).unwrap();
+        // An interceptor cannot pass its own signing key off as Bob's
+        let mallory = BASE64.encode(
+            signing_key_from_identity(&key_pair().private_key)
+                .verifying_key()
+                .as_bytes(),
+        );
+        let mut swapped = auth.clone();
+        swapped.check(&proof, &mallory, Some([LOCAL, REMOTE]));
+        assert_eq!(swapped.status, PeerStatus::Failed(FAILED.to_string()));
+        assert_eq!(swapped.signing_key, None);
+
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Verified);
+        assert_eq!(auth.signing_key, Some(signing_key));
+    }
+
+    #[test]
+    fn missing_fingerprints_fail() {
+        let bob = key_pair();
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        assert!(respond(&challenge, &bob, None).is_err());
+
+        let (proof, signing_key) = respond(&challenge, &bob, Some([REMOTE, LOCAL])).unwrap();
+        auth.check(&proof, &signing_key, None);
+        assert_eq!(
+            auth.status,
+            PeerStatus::Failed("The connection has no DTLS fingerprints.".to_string())
+        );
+    }
+
+    #[test]
+    fn result_is_final() {
+        let bob = key_pair();
+        let (mut auth, challenge) = PeerAuth::start(&bob.public_key).unwrap();
+        let (proof, signing_key) = respond(&challenge, &bob, Some([REMOTE, LOCAL])).unwrap();
+        auth.check("wrong", &signing_key, Some([LOCAL, REMOTE]));
+        auth.check(&proof, &signing_key, Some([LOCAL, REMOTE]));
+        assert_eq!(auth.status, PeerStatus::Failed(FAILED.to_string()));
+    }
+
+    #[test]
+    fn malformed_challenge_is_rejected() {
+        let bob = key_pair();
+        let fingerprints = Some([LOCAL, REMOTE]);
+        assert!(respond("not base64!", &bob, fingerprints).is_err());
+        assert!(PeerAuth::start("not a key").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::peer_auth::PeerAuth;
+use crate::rtc::Connection;
+use crate::signaling::Session;
+use crate::ChatMessage;
+use js_sys::Date;
+
+// One WebRTC connection and the conversation on it. Connections are kept by
+// a local id, since the peer's key is only known once it has been verified.
+#[derive(Clone)]
+pub struct RtcPeer {
+    pub connection: Connection,
+    pub connected: bool,
+    // Set while connecting to a contact with sealed signals
+    pub session: Option<Session>,
+    // Only set once the peer has proven that it holds this key
+    pub public_key: Option<String>,
+    pub auth: Option<PeerAuth>,
+    // Extra candidates signal not shown yet
+    pub extra_candidates: Option<String>,
+    pub chat_input: String,
+    pub chat_messages: Vec<ChatMessage>,
+    // Stored page to load when scrolling back in the chat
+    pub chat_older_page: Option<u32>,
+    // Received while another conversation was shown
+    pub unread: usize,
+    pub started_at: f64,
+}
+
+impl RtcPeer {
+    pub fn new(connection: Connection, session: Option<Session>) -> Self {
+        Self {
+            connection,
+            connected: false,
+            session,
+            public_key: None,
+            auth: None,
+            extra_candidates: None,
+            chat_input: String::new(),
+            chat_messages: Vec::new(),
+            chat_older_page: None,
+            unread: 0,
+            started_at: Date::now(),
+        }
+    }
+
+    // The key the peer claims, verified or not
+    pub fn claimed_key(&self) -> Option<&String> {
+        self.public_key
+            .as_ref()
+            .or(self.auth.as_ref().map(|auth| &auth.peer_key))
+    }
+}
@@ -0,0 +1,725 @@
+use crate::transport::{self, LinkKind};
+use gloo::console;
+use qrcode::bits::Bits;
+use qrcode::{EcLevel, QrCode, QrResult, Version};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write as _};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::{Clamped, JsCast};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{window, CanvasRenderingContext2d, HtmlCanvasElement, HtmlImageElement, ImageData};
+
+pub const MIN_VERSION: i16 = 1;
+pub const MAX_VERSION: i16 = 40;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum QrEcLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl QrEcLevel {
+    // Highest error correction first, used by the automatic mode
+    pub const DESCENDING: [QrEcLevel; 4] = [QrEcLevel::H, QrEcLevel::Q, QrEcLevel::M, QrEcLevel::L];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QrEcLevel::L => "L",
+            QrEcLevel::M => "M",
+            QrEcLevel::Q => "Q",
+            QrEcLevel::H => "H",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "L" => Some(QrEcLevel::L),
+            "M" => Some(QrEcLevel::M),
+            "Q" => Some(QrEcLevel::Q),
+            "H" => Some(QrEcLevel::H),
+            _ => None,
+        }
+    }
+
+    fn to_ec_level(self) -> EcLevel {
+        match self {
+            QrEcLevel::L => EcLevel::L,
+            QrEcLevel::M => EcLevel::M,
+            QrEcLevel::Q => EcLevel::Q,
+            QrEcLevel::H => EcLevel::H,
+        }
+    }
+
+    fn from_ec_level(ec_level: EcLevel) -> Self {
+        match ec_level {
+            EcLevel::L => QrEcLevel::L,
+            EcLevel::M => QrEcLevel::M,
+            EcLevel::Q => QrEcLevel::Q,
+            EcLevel::H => QrEcLevel::H,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct QrSettings {
+    // When enabled, the highest EC level that still fits the payload is chosen
+    pub auto_ec: bool,
+    pub ec_level: QrEcLevel,
+    pub min_version: i16,
+    pub max_version: i16,
+    // Encode ciphertexts and signals as Base45 so they use alphanumeric mode
+    pub compact_encoding: bool,
+    // Wrap payloads in an app URL so native camera apps open them directly
+    pub deep_links: bool,
+    // Theme
+    pub dark_color: String,
+    pub light_color: String,
+    pub quiet_zone: u32,
+    pub logo_url: Option<String>,
+}
+
+impl Default for QrSettings {
+    fn default() -> Self {
+        Self {
+            auto_ec: true,
+            ec_level: QrEcLevel::M,
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            compact_encoding: true,
+            deep_links: false,
+            dark_color: "#000000".to_string(),
+            light_color: "#ffffff".to_string(),
+            quiet_zone: 2,
+            logo_url: None,
+        }
+    }
+}
+
+impl QrSettings {
+    // Converts a payload into the form placed in the QR code.
+    // Deep links take precedence because Base45 is not URL-safe.
+    pub fn encode_payload(&self, payload: &str, kind: LinkKind, base_url: &str) -> String {
+        if self.deep_links {
+            transport::encode_deep_link(base_url, kind, payload)
+        } else if self.compact_encoding && kind != LinkKind::PublicKey {
+            transport::encode_base45(payload)
+        } else {
+            payload.to_string()
+        }
+    }
+
+    pub fn normalized(mut self) -> Self {
+        self.min_version = self.min_version.clamp(MIN_VERSION, MAX_VERSION);
+        self.max_version = self.max_version.clamp(MIN_VERSION, MAX_VERSION);
+        if self.min_version > self.max_version {
+            std::mem::swap(&mut self.min_version, &mut self.max_version);
+        }
+        if parse_hex_color(&self.dark_color).is_none() {
+            self.dark_color = QrSettings::default().dark_color;
+        }
+        if parse_hex_color(&self.light_color).is_none() {
+            self.light_color = QrSettings::default().light_color;
+        }
+        self.quiet_zone = self.quiet_zone.min(MAX_QUIET_ZONE);
+        self.logo_url = self.logo_url.filter(|url| !url.trim().is_empty());
+        self
+    }
+
+    pub fn render_options(&self, size: u32) -> RenderOptions {
+        RenderOptions {
+            size,
+            device_pixel_ratio: window().map(|w| w.device_pixel_ratio()).unwrap_or(1.0),
+            quiet_zone: self.quiet_zone,
+            dark_color: parse_hex_color(&self.dark_color).unwrap_or([0, 0, 0]),
+            light_color: parse_hex_color(&self.light_color).unwrap_or([255, 255, 255]),
+            logo_url: self.logo_url.clone(),
+        }
+    }
+}
+
+pub const MAX_QUIET_ZONE: u32 = 8;
+
+// "#rrggbb" -> [r, g, b]
+pub fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QrParams {
+    pub version: i16,
+    pub ec_level: QrEcLevel,
+    pub modules: usize,
+    pub auto: bool,
+}
+
+impl fmt::Display for QrParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Version {} ({}×{} modules), EC level {}{}",
+            self.version,
+            self.modules,
+            self.modules,
+            self.ec_level.as_str(),
+            if self.auto { " (auto)" } else { "" }
+        )
+    }
+}
+
+pub fn params_of(code: &QrCode, settings: &QrSettings) -> QrParams {
+    let version = match code.version() {
+        Version::Normal(v) | Version::Micro(v) => v,
+    };
+    QrParams {
+        version,
+        ec_level: QrEcLevel::from_ec_level(code.error_correction_level()),
+        modules: code.width(),
+        auto: settings.auto_ec,
+    }
+}
+
+fn encode_with_version(data: &str, version: i16, ec_level: EcLevel) -> QrResult<QrCode> {
+    let mut bits = Bits::new(Version::Normal(version));
+    if transport::is_qr_alphanumeric(data) {
+        // Base45 payloads fit entirely in a single alphanumeric segment
+        bits.push_alphanumeric_data(data.as_bytes())?;
+    } else {
+        bits.push_optimal_data(data.as_bytes())?;
+    }
+    bits.push_terminator(ec_level)?;
+    QrCode::with_bits(bits, ec_level)
+}
+
+// Smallest version within the configured range that fits the payload
+fn build_with_ec(data: &str, ec_level: QrEcLevel, settings: &QrSettings) -> QrResult<QrCode> {
+    let mut last_error = qrcode::types::QrError::DataTooLong;
+    for version in settings.min_version..=settings.max_version {
+        match encode_with_version(data, version, ec_level.to_ec_level()) {
+            Ok(code) => return Ok(code),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+pub fn build_qr_code(data: &str, settings: &QrSettings) -> Result<QrCode, String> {
+    let settings = settings.clone().normalized();
+    let levels: &[QrEcLevel] = if settings.auto_ec {
+        &QrEcLevel::DESCENDING
+    } else {
+        std::slice::from_ref(&settings.ec_level)
+    };
+
+    for ec_level in levels {
+        if let Ok(code) = build_with_ec(data, *ec_level, &settings) {
+            return Ok(code);
+        }
+    }
+
+    Err(format!(
+        "Payload ({} bytes) does not fit in QR versions {}-{}",
+        data.len(),
+        settings.min_version,
+        settings.max_version
+    ))
+}
+
+// Vector output for printing or embedding in documents
+pub fn render_svg(data: &str, settings: &QrSettings) -> Result<String, String> {
+    let settings = settings.clone().normalized();
+    let code = build_qr_code(data, &settings)?;
+    let svg = svg_markup(
+        &code,
+        settings.quiet_zone,
+        &settings.dark_color,
+        &settings.light_color,
+    );
+
+    let Some(ref logo_url) = settings.logo_url else {
+        return Ok(svg);
+    };
+    if !has_logo_room(&code) {
+        console::log!("⚠️ Logo skipped: EC level Q or H is required");
+        return Ok(svg);
+    }
+    // The saved file is opened away from this page, so the URL is made absolute
+    let href = check_logo_url(logo_url, &page_origin()).and_then(|()| absolute_url(logo_url))?;
+    Ok(embed_svg_logo(
+        svg,
+        &code,
+        settings.quiet_zone,
+        &href,
+        &settings.light_color,
+    ))
+}
+
+const SVG_MODULE_SIZE: u32 = 8;
+
+// The qrcode renderer only knows a fixed 4 module quiet zone, so the SVG is
+// written here with the configured width. Colors must already be normalized.
+fn svg_markup(code: &QrCode, quiet_zone: u32, dark_color: &str, light_color: &str) -> String {
+    let modules = code.width();
+    let size = (modules as u32 + quiet_zone * 2) * SVG_MODULE_SIZE;
+    let mut path = String::new();
+    for (i, color) in code.to_colors().iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+        let x = (i % modules) as u32 + quiet_zone;
+        let y = (i / modules) as u32 + quiet_zone;
+        let _ = write!(
+            path,
+            "M{} {}h{m}v{m}h-{m}z",
+            x * SVG_MODULE_SIZE,
+            y * SVG_MODULE_SIZE,
+            m = SVG_MODULE_SIZE
+        );
+    }
+    format!(
+        concat!(
+            r#"<?xml version="1.0" standalone="yes"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" viewBox="0 0 {size} {size}" shape-rendering="crispEdges">"#,
+            r#"<rect x="0" y="0" width="{size}" height="{size}" fill="{light}"/>"#,
+            r#"<path fill="{dark}" d="{path}"/>"#,
+            "</svg>",
+        ),
+        size = size,
+        light = escape_xml(light_color),
+        dark = escape_xml(dark_color),
+        path = path,
+    )
+}
+
+// Draws the logo over the middle of an SVG made by svg_markup
+fn embed_svg_logo(
+    svg: String,
+    code: &QrCode,
+    quiet_zone: u32,
+    href: &str,
+    light_color: &str,
+) -> String {
+    let module = SVG_MODULE_SIZE as f64;
+    let (position, logo_size, padding) =
+        logo_area(quiet_zone as f64 * module, code.width() as f64 * module);
+    let href = escape_xml(href);
+    let logo = format!(
+        concat!(
+            r#"<rect x="{bx}" y="{bx}" width="{bs}" height="{bs}" fill="{bg}"/>"#,
+            r#"<image x="{x}" y="{x}" width="{s}" height="{s}" href="{href}" xlink:href="{href}"/>"#,
+        ),
+        bx = position - padding,
+        bs = logo_size + padding * 2.0,
+        bg = escape_xml(light_color),
+        x = position,
+        s = logo_size,
+        href = href,
+    );
+    let mut svg = svg.replacen(
+        r#"xmlns="http://www.w3.org/2000/svg""#,
+        r#"xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink""#,
+        1,
+    );
+    let Some(end) = svg.rfind("</svg>") else {
+        return svg;
+    };
+    svg.insert_str(end, &logo);
+    svg
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    // Size in CSS pixels; the backing store is scaled by the device pixel ratio
+    pub size: u32,
+    pub device_pixel_ratio: f64,
+    // Quiet zone width in modules
+    pub quiet_zone: u32,
+    pub dark_color: [u8; 3],
+    pub light_color: [u8; 3],
+    pub logo_url: Option<String>,
+}
+
+fn get_canvas(canvas_id: &str) -> Result<(HtmlCanvasElement, CanvasRenderingContext2d), String> {
+    let canvas = window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.get_element_by_id(canvas_id))
+        .ok_or_else(|| format!("Canvas element '{}' not found", canvas_id))?
+        .dyn_into::<HtmlCanvasElement>()
+        .map_err(|_| "Failed to cast element to HtmlCanvasElement".to_string())?;
+    let context = canvas
+        .get_context("2d")
+        .ok()
+        .flatten()
+        .and_then(|c| c.dyn_into::<CanvasRenderingContext2d>().ok())
+        .ok_or_else(|| "Failed to get 2D context".to_string())?;
+    Ok((canvas, context))
+}
+
+// Sizes the backing store for the device pixel ratio while keeping the CSS size
+fn resize_canvas(canvas: &HtmlCanvasElement, options: &RenderOptions) -> u32 {
+    let dpr = if options.device_pixel_ratio > 0.0 {
+        options.device_pixel_ratio
+    } else {
+        1.0
+    };
+    let backing_size = (options.size as f64 * dpr).round().max(1.0) as u32;
+    canvas.set_width(backing_size);
+    canvas.set_height(backing_size);
+    let style = canvas.style();
+    let _ = style.set_property("width", &format!("{}px", options.size));
+    let _ = style.set_property("aspect-ratio", "1 / 1");
+    backing_size
+}
+
+// Rasterizes the code into an RGBA buffer using whole device pixels per module
+fn rasterize(code: &QrCode, backing_size: u32, options: &RenderOptions) -> (Vec<u8>, f64, f64) {
+    let modules = code.width() as u32;
+    let total_modules = modules + options.quiet_zone * 2;
+    let module_px = (backing_size / total_modules).max(1);
+    let offset = (backing_size.saturating_sub(module_px * total_modules)) / 2
+        + options.quiet_zone * module_px;
+
+    let [lr, lg, lb] = options.light_color;
+    let mut buffer = [lr, lg, lb, 255].repeat((backing_size * backing_size) as usize);
+    let [dr, dg, db] = options.dark_color;
+    let dark = [dr, dg, db, 255];
+
+    let colors = code.to_colors();
+    for y in 0..modules {
+        for x in 0..modules {
+            if colors[(y * modules + x) as usize] != qrcode::Color::Dark {
+                continue;
+            }
+            for py in 0..module_px {
+                let row = offset + y * module_px + py;
+                if row >= backing_size {
+                    break;
+                }
+                let start = offset + x * module_px;
+                let end = (start + module_px).min(backing_size);
+                for column in start..end {
+                    let index = ((row * backing_size + column) * 4) as usize;
+                    buffer[index..index + 4].copy_from_slice(&dark);
+                }
+            }
+        }
+    }
+
+    (buffer, offset as f64, (module_px * modules) as f64)
+}
+
+// The logo hides modules, so it is only drawn with enough error correction
+fn has_logo_room(code: &QrCode) -> bool {
+    matches!(code.error_correction_level(), EcLevel::Q | EcLevel::H)
+}
+
+// Position and size of the logo and the width of the light border around it
+fn logo_area(code_offset: f64, code_size: f64) -> (f64, f64, f64) {
+    let logo_size = (code_size * 0.2).floor();
+    let position = code_offset + (code_size - logo_size) / 2.0;
+    let padding = (logo_size * 0.1).ceil();
+    (position, logo_size, padding)
+}
+
+// An image from another site taints the canvas, after which it can no longer
+// be exported as PNG, so only data:, blob: and same-origin URLs are accepted
+pub fn check_logo_url(url: &str, origin: &str) -> Result<(), String> {
+    let url = url.trim();
+    let lower = url.to_ascii_lowercase();
+    if lower.starts_with("data:image/") || lower.starts_with("blob:") {
+        return Ok(());
+    }
+    // Browsers read backslashes as slashes and drop tabs and newlines, which
+    // could turn a path into a link to another host
+    let same_origin = if url.contains(|c: char| c == '\\' || c.is_whitespace() || c.is_control())
+        || url.starts_with("//")
+    {
+        false
+    } else if let Some(rest) = lower.strip_prefix(&origin.to_ascii_lowercase()) {
+        rest.is_empty() || rest.starts_with(['/', '?', '#'])
+    } else {
+        !has_scheme(url)
+    };
+    if same_origin {
+        Ok(())
+    } else {
+        Err(
+            "The logo must be a data: or blob: URL or an image from this site (for example /logo.png)"
+                .to_string(),
+        )
+    }
+}
+
+fn has_scheme(url: &str) -> bool {
+    url.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+pub fn page_origin() -> String {
+    window()
+        .and_then(|w| w.location().origin().ok())
+        .unwrap_or_default()
+}
+
+fn absolute_url(url: &str) -> Result<String, String> {
+    let base = window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.base_uri().ok().flatten())
+        .unwrap_or_default();
+    web_sys::Url::new_with_base(url.trim(), &base)
+        .map(|url| url.href())
+        .map_err(|e| format!("Invalid logo URL: {:?}", e))
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn draw_logo(
+    context: &CanvasRenderingContext2d,
+    logo_url: &str,
+    code_offset: f64,
+    code_size: f64,
+    light_color: [u8; 3],
+) -> Result<(), JsValue> {
+    check_logo_url(logo_url, &page_origin()).map_err(|e| JsValue::from_str(&e))?;
+    let image = HtmlImageElement::new()?;
+    let (position, logo_size, padding) = logo_area(code_offset, code_size);
+    let context = context.clone();
+    let background = format!(
+        "rgb({}, {}, {})",
+        light_color[0], light_color[1], light_color[2]
+    );
+    let url = logo_url.to_string();
+
+    image.set_src(logo_url.trim());
+    // Waiting on decode() rather than onload/onerror leaves no closures
+    // behind after the image has loaded or failed
+    spawn_local(async move {
+        if JsFuture::from(image.decode()).await.is_err() {
+            console::error!(&format!("❌ Failed to load logo: {}", url));
+            return;
+        }
+        context.set_fill_style_str(&background);
+        context.fill_rect(
+            position - padding,
+            position - padding,
+            logo_size + padding * 2.0,
+            logo_size + padding * 2.0,
+        );
+        let _ = context.draw_image_with_html_image_element_and_dw_and_dh(
+            &image, position, position, logo_size, logo_size,
+        );
+    });
+    Ok(())
+}
+
+fn render_error(context: &CanvasRenderingContext2d, backing_size: u32, options: &RenderOptions) {
+    let size = backing_size as f64;
+    context.set_fill_style_str("#ffebee");
+    context.fill_rect(0.0, 0.0, size, size);
+    context.set_fill_style_str("#c62828");
+    context.set_font(&format!(
+        "{}px Arial",
+        (16.0 * size / options.size as f64).round()
+    ));
+    context.set_text_align("center");
+    let _ = context.fill_text("QR code generation error", size / 2.0, size / 2.0);
+}
+
+// Renders `data` into the canvas with a single putImageData call
+pub fn render_to_canvas(
+    canvas_id: &str,
+    data: &str,
+    settings: &QrSettings,
+    options: &RenderOptions,
+) -> Result<QrParams, String> {
+    console::log!(&format!("🎨 QR code rendering started: {}", canvas_id));
+    console::log!(&format!("📊 Data length: {}", data.len()));
+
+    let (canvas, context) = get_canvas(canvas_id)?;
+    let backing_size = resize_canvas(&canvas, options);
+    console::log!(&format!(
+        "📐 Canvas size: {}px (backing {}px, dpr {})",
+        options.size, backing_size, options.device_pixel_ratio
+    ));
+
+    let code = match build_qr_code(data, settings) {
+        Ok(code) => code,
+        Err(e) => {
+            console::error!(&format!("❌ QR code generation failed: {}", e));
+            render_error(&context, backing_size, options);
+            return Err(e);
+        }
+    };
+    let params = params_of(&code, settings);
+
+    let (buffer, code_offset, code_size) = rasterize(&code, backing_size, options);
+    let image_data =
+        ImageData::new_with_u8_clamped_array_and_sh(Clamped(&buffer), backing_size, backing_size)
+            .map_err(|e| format!("Failed to create image data: {:?}", e))?;
+    context
+        .put_image_data(&image_data, 0.0, 0.0)
+        .map_err(|e| format!("Failed to draw image data: {:?}", e))?;
+
+    if let Some(ref logo_url) = options.logo_url {
+        if has_logo_room(&code) {
+            if let Err(e) = draw_logo(
+                &context,
+                logo_url,
+                code_offset,
+                code_size,
+                options.light_color,
+            ) {
+                console::error!(&format!("❌ Failed to draw logo: {:?}", e));
+            }
+        } else {
+            console::log!("⚠️ Logo skipped: EC level Q or H is required");
+        }
+    }
+
+    console::log!(&format!("✅ QR code rendering completed: {}", params));
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "https://qr.example";
+
+    #[test]
+    fn logo_urls_that_keep_the_canvas_clean() {
+        for url in [
+            "data:image/png;base64,iVBORw0KGgo=",
+            "DATA:image/svg+xml,%3Csvg%3E",
+            "blob:https://qr.example/7c9e6679-7425-40de-944b-e07fc1f90ae7",
+            "/logo.png",
+            "logo.png",
+            "images/logo.png?v=2",
+            "https://qr.example",
+            "https://qr.example/logo.png",
+            "HTTPS://QR.EXAMPLE/logo.png",
+            " /logo.png ",
+        ] {
+            assert_eq!(check_logo_url(url, ORIGIN), Ok(()), "{}", url);
+        }
+    }
+
+    #[test]
+    fn logo_urls_from_other_origins_are_rejected() {
+        for url in [
+            "https://cdn.example/logo.png",
+            "http://qr.example/logo.png",
+            "https://qr.example:8443/logo.png",
+            "https://qr.example.evil/logo.png",
+            "https://qr.example@evil.example/logo.png",
+            "//evil.example/logo.png",
+            "/\\evil.example/logo.png",
+            "/\t/evil.example/logo.png",
+            "javascript:alert(1)",
+            "data:text/html,<script></script>",
+            "ftp://qr.example/logo.png",
+        ] {
+            assert!(check_logo_url(url, ORIGIN).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn logo_area_is_centered() {
+        assert_eq!(logo_area(32.0, 200.0), (112.0, 40.0, 4.0));
+        let (position, size, _) = logo_area(10.0, 99.0);
+        assert_eq!(size, 19.0);
+        assert_eq!(position * 2.0 + size, 10.0 * 2.0 + 99.0);
+    }
+
+    #[test]
+    fn svg_logo_is_embedded_in_the_middle() {
+        let settings = QrSettings {
+            auto_ec: false,
+            ec_level: QrEcLevel::H,
+            ..QrSettings::default()
+        };
+        let code = build_qr_code("age1example", &settings).unwrap();
+        assert!(has_logo_room(&code));
+        let svg = svg_markup(&code, 4, "#000000", "#ffffff");
+        let svg = embed_svg_logo(
+            svg,
+            &code,
+            4,
+            "https://qr.example/logo.png?a=1&b=\"2\"",
+            "#ffffff",
+        );
+
+        // Version 2: 25 modules and a 4 module quiet zone, 8 pixels each
+        assert_eq!(code.width(), 25);
+        let (position, size, padding) = logo_area(32.0, 200.0);
+        assert!(svg.contains(&format!(
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#ffffff"/>"##,
+            position - padding,
+            position - padding,
+            size + padding * 2.0,
+            size + padding * 2.0
+        )));
+        assert!(svg
+            .contains(r#"href="https://qr.example/logo.png?a=1&amp;b=&quot;2&quot;" xlink:href="#));
+        assert!(svg.contains(r#"xmlns:xlink="http://www.w3.org/1999/xlink""#));
+        assert!(svg.ends_with("/></svg>"));
+    }
+
+    #[test]
+    fn svg_uses_the_configured_quiet_zone() {
+        let settings = QrSettings::default();
+        let code = build_qr_code("age1example", &settings).unwrap();
+        let modules = code.width() as u32;
+        for quiet_zone in [0, 2, MAX_QUIET_ZONE] {
+            let svg = svg_markup(&code, quiet_zone, "#000000", "#ffffff");
+            let size = (modules + quiet_zone * 2) * SVG_MODULE_SIZE;
+            assert!(svg.contains(&format!(r#"width="{0}" height="{0}""#, size)));
+            // The top left module of the finder pattern is dark
+            let offset = quiet_zone * SVG_MODULE_SIZE;
+            assert!(svg.contains(&format!(r#"d="M{0} {0}h8v8h-8z"#, offset)));
+        }
+    }
+
+    #[test]
+    fn svg_colors_are_normalized() {
+        let settings = QrSettings {
+            dark_color: r#"red"/><script/>"#.to_string(),
+            light_color: "#12345".to_string(),
+            ..QrSettings::default()
+        };
+        let svg = render_svg("age1example", &settings).unwrap();
+        assert!(svg.contains(r##"<path fill="#000000""##));
+        assert!(svg.contains(r##"fill="#ffffff"/>"##));
+        assert!(!svg.contains("script"));
+    }
+
+    #[test]
+    fn low_error_correction_leaves_no_room_for_a_logo() {
+        let settings = QrSettings {
+            auto_ec: false,
+            ec_level: QrEcLevel::M,
+            ..QrSettings::default()
+        };
+        assert!(!has_logo_room(
+            &build_qr_code("age1example", &settings).unwrap()
+        ));
+    }
+}
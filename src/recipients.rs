@@ -0,0 +1,271 @@
+use crate::{sort_contacts, Contact};
+use age::x25519;
+
+// Writes contacts in the format read by `age -R`: one recipient per line,
+// each preceded by a `# name` comment.
+pub fn export(contacts: &[Contact]) -> String {
+    let mut text = String::from("# qr-encrypt contacts\n");
+    for contact in contacts {
+        text.push_str(&format!(
+            "\n# {}\n{}\n",
+            contact.name.replace('\n', " "),
+            contact.public_key
+        ));
+    }
+    text
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientEntry {
+    // From the nearest comment above the key, if any
+    pub name: Option<String>,
+    pub public_key: String,
+}
+
+// Lines that are neither comments nor X25519 recipients are returned
+// separately so they can be shown instead of silently dropped.
+pub fn parse(text: &str) -> (Vec<RecipientEntry>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    let mut comment: Option<String> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(text) = line.strip_prefix('#') {
+            let text = text.trim();
+            if !text.is_empty() {
+                comment = Some(text.to_string());
+            }
+            continue;
+        }
+        if line.parse::<x25519::Recipient>().is_ok() {
+            entries.push(RecipientEntry {
+                name: comment.take(),
+                public_key: line.to_string(),
+            });
+        } else {
+            let kind = if line.starts_with("ssh-") {
+                "SSH keys are not supported"
+            } else if line.starts_with("age1") {
+                "plugin recipients are not supported"
+            } else {
+                "not an age recipient"
+            };
+            skipped.push(format!("Line {}: {}", number + 1, kind));
+            comment = None;
+        }
+    }
+    (entries, skipped)
+}
+
+// Result of comparing a recipients file with the current contacts. Nothing
+// is applied until the user has seen it.
+#[derive(Clone, Debug, Default)]
+pub struct RecipientsDiff {
+    pub new: Vec<Contact>,
+    // (existing contact, key from the file) for contacts whose name matches
+    // but whose key differs
+    pub changed: Vec<(Contact, String)>,
+    pub unchanged: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+impl RecipientsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty() && self.changed.is_empty()
+    }
+
+    // Contacts after merging. Changed keys replace the old ones only when
+    // asked to, and the contact then has to be verified again.
+    pub fn apply(&self, contacts: &[Contact], replace_changed: bool) -> Vec<Contact> {
+        let mut merged = contacts.to_vec();
+        if replace_changed {
+            for (existing, public_key) in &self.changed {
+                if let Some(contact) = merged.iter_mut().find(|c| c.id == existing.id) {
+                    contact.public_key = public_key.clone();
+                    contact.verified = false;
+                    contact.signing_key = None;
+                }
+            }
+        }
+        merged.extend(self.new.iter().cloned());
+        sort_contacts(&mut merged);
+        merged
+    }
+}
+
+// `now` is recorded as the added date of new contacts
+pub fn diff(text: &str, contacts: &[Contact], now: u64) -> RecipientsDiff {
+    let (entries, skipped) = parse(text);
+    let mut diff = RecipientsDiff {
+        skipped,
+        ..RecipientsDiff::default()
+    };
+
+    for entry in entries {
+        // A key listed twice in the file is only taken once
+        if diff.new.iter().any(|c| c.public_key == entry.public_key)
+            || diff.changed.iter().any(|(_, key)| *key == entry.public_key)
+        {
+            continue;
+        }
+        let known = contacts.iter().find(|c| c.public_key == entry.public_key);
+        if let Some(contact) = known {
+            if !diff.unchanged.contains(&contact.name) {
+                diff.unchanged.push(contact.name.clone());
+            }
+            continue;
+        }
+
+        // 名前のない鍵は鍵の先頭部分を名前にする
+        let name = entry
+            .name
+            .unwrap_or_else(|| format!("{}…", entry.public_key.get(..16).unwrap_or_default()));
+        let same_name = contacts.iter().find(|c| c.name == name);
+        match same_name {
+            Some(existing) if !diff.changed.iter().any(|(c, _)| c.id == existing.id) => {
+                diff.changed.push((existing.clone(), entry.public_key));
+            }
+            _ => diff.new.push(Contact::new(name, entry.public_key, now)),
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn public_key() -> String {
+        x25519::Identity::generate().to_public().to_string()
+    }
+
+    #[test]
+    fn parses_an_age_recipients_file() {
+        let (alice, bob, carol) = (public_key(), public_key(), public_key());
+        let text = format!(
+            "# Team recipients\n\
+             # generated by hand\n\
+             \n\
+             # Alice\n\
+             {alice}\n\
+             \n\
+             # Bob\n\
+             #\n\
+             \n\
+             {bob}\n\
+             # Dave's laptop\n\
+             ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHmmfTxkTDCvmbIP7uAgtXYdVxJFBjMM5Zpd5h5lxyNk dave@laptop\n\
+             {carol}\n\
+             # Erin\n\
+             age1yubikey1qwt50d05nh5vutpdzmlg5wn80xq5negm4uj9ghv0snvdd3yysf5yw3rhl3t\n\
+             not a key\n"
+        );
+        let (entries, skipped) = parse(&text);
+        assert_eq!(
+            entries,
+            vec![
+                RecipientEntry {
+                    name: Some("Alice".to_string()),
+                    public_key: alice,
+                },
+                // An empty comment line does not reset the name
+                RecipientEntry {
+                    name: Some("Bob".to_string()),
+                    public_key: bob,
+                },
+                // The comment belonged to the SSH key that was skipped
+                RecipientEntry {
+                    name: None,
+                    public_key: carol,
+                },
+            ]
+        );
+        assert_eq!(
+            skipped,
+            vec![
+                "Line 12: SSH keys are not supported",
+                "Line 15: plugin recipients are not supported",
+                "Line 16: not an age recipient",
+            ]
+        );
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let contacts = vec![
+            Contact::new("Alice".to_string(), public_key(), NOW),
+            Contact::new("Bob\nSmith".to_string(), public_key(), NOW),
+        ];
+        let (entries, skipped) = parse(&export(&contacts));
+        assert!(skipped.is_empty());
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("Alice"), Some("Bob Smith")]);
+        assert!(diff(&export(&contacts), &contacts, NOW).is_empty());
+    }
+
+    #[test]
+    fn changed_key_is_matched_by_name() {
+        let alice = Contact::new("Alice".to_string(), public_key(), NOW - 60);
+        let new_key = public_key();
+        let text = format!("# Alice\n{}\n", new_key);
+        let diff = diff(&text, std::slice::from_ref(&alice), NOW);
+        assert!(diff.new.is_empty());
+        assert_eq!(diff.changed, vec![(alice.clone(), new_key.clone())]);
+
+        // Without replacing, only new contacts are merged
+        assert_eq!(
+            diff.apply(std::slice::from_ref(&alice), false),
+            vec![alice.clone()]
+        );
+    }
+
+    #[test]
+    fn replacing_a_key_clears_verification() {
+        let mut alice = Contact::new("Alice".to_string(), public_key(), NOW - 60);
+        alice.verified = true;
+        alice.signing_key = Some("c2lnbmluZyBrZXk=".to_string());
+        alice.notes = "kept".to_string();
+        let new_key = public_key();
+        let diff = diff(&format!("# Alice\n{}\n", new_key), &[alice.clone()], NOW);
+
+        let merged = diff.apply(&[alice.clone()], true);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].id, alice.id);
+        assert_eq!(merged[0].public_key, new_key);
+        assert!(!merged[0].verified);
+        assert_eq!(merged[0].signing_key, None);
+        assert_eq!(merged[0].notes, "kept");
+    }
+
+    #[test]
+    fn duplicate_keys_are_taken_once() {
+        let alice = Contact::new("Alice".to_string(), public_key(), NOW - 60);
+        let (bob, alice_new) = (public_key(), public_key());
+        let text = format!(
+            "# Bob\n{bob}\n# Bob again\n{bob}\n# Alice\n{alice_new}\n# Alice\n{alice_new}\n{}\n{}\n",
+            alice.public_key, alice.public_key
+        );
+        let diff = diff(&text, std::slice::from_ref(&alice), NOW);
+        assert_eq!(diff.new.len(), 1);
+        assert_eq!(diff.new[0].name, "Bob");
+        assert_eq!(diff.new[0].added_at, NOW);
+        assert_eq!(diff.changed, vec![(alice.clone(), alice_new)]);
+        assert_eq!(diff.unchanged, vec!["Alice"]);
+
+        let merged = diff.apply(std::slice::from_ref(&alice), true);
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn unnamed_keys_are_named_after_the_key() {
+        let key = public_key();
+        let diff = diff(&key, &[], NOW);
+        assert_eq!(diff.new[0].name, format!("{}…", &key[..16]));
+    }
+}
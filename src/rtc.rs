@@ -1,15 +1,21 @@
 use crate::common::RtcMessage;
+use crate::sdp::{self, SdpKind};
 use gloo::console;
 use js_sys::{Array, Object, Reflect};
 use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
     Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelEvent, RtcDataChannelInit,
-    RtcDataChannelState, RtcIceConnectionState, RtcPeerConnection, RtcPeerConnectionIceEvent,
-    RtcSdpType, RtcSessionDescriptionInit,
+    RtcDataChannelState, RtcDataChannelType, RtcIceCandidateInit, RtcIceConnectionState,
+    RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,36 +31,270 @@ pub struct SessionDescription {
     pub sdp: String,
 }
 
-fn create_ice_servers() -> Array {
+// Prefer the compact codec so that the signal fits in a small QR code, and
+// fall back to the full SDP when the session cannot be compacted.
+// `complete` is false for a signal sent on the gathering deadline.
+fn encode_signal(sdp: &str, kind: SdpKind, complete: bool) -> Option<String> {
+    match sdp::compress(sdp, kind, complete) {
+        Ok(compact) => {
+            console::log!(&format!(
+                "📦 SDP compacted: {} -> {} bytes",
+                sdp.len(),
+                compact.len()
+            ));
+            Some(compact)
+        }
+        Err(e) => {
+            console::log!(&format!("⚠️ Sending full SDP: {}", e));
+            let session_desc = SessionDescription {
+                sdp_type: kind.as_str().to_string(),
+                sdp: sdp.to_string(),
+            };
+            serde_json::to_string(&session_desc).ok()
+        }
+    }
+}
+
+fn decode_signal(signal: &str, kind: SdpKind) -> Result<String, JsValue> {
+    if signal.trim_start().starts_with('{') {
+        let session_desc: SessionDescription = serde_json::from_str(signal)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse {}: {}", kind.as_str(), e)))?;
+        Ok(session_desc.sdp)
+    } else {
+        sdp::decompress(signal, kind).map_err(|e| JsValue::from_str(&e))
+    }
+}
+
+// Used until the user configures their own servers
+const DEFAULT_ICE_SERVERS: [&str; 3] = [
+    "stun:stun.l.google.com:19302",
+    "stun:stun1.l.google.com:19302",
+    "stun:stun.services.mozilla.com",
+];
+
+pub const MAX_GATHERING_TIMEOUT_SECS: u32 = 60;
+// After the deadline, candidates found within this long of each other are
+// sent together
+const LATE_CANDIDATES_MS: i32 = 1500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct IceSettings {
+    // STUN and TURN server URLs
+    pub servers: Vec<String>,
+    // Sent with every turn: and turns: URL
+    pub turn_username: Option<String>,
+    pub turn_credential: Option<String>,
+    // Only gather host candidates so no third-party server is contacted.
+    // Connections then only work on the same network.
+    pub host_only: bool,
+    // Seconds to wait for candidate gathering before the signal is shown
+    // with the candidates found so far; 0 waits until gathering completes
+    pub gathering_timeout_secs: u32,
+}
+
+impl Default for IceSettings {
+    fn default() -> Self {
+        Self {
+            servers: DEFAULT_ICE_SERVERS.iter().map(|s| s.to_string()).collect(),
+            turn_username: None,
+            turn_credential: None,
+            host_only: false,
+            gathering_timeout_secs: 5,
+        }
+    }
+}
+
+impl IceSettings {
+    // One URL per line; blank lines are ignored
+    pub fn parse_servers(text: &str) -> Result<Vec<String>, String> {
+        let mut servers: Vec<String> = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            check_server_url(line)?;
+            if !servers.iter().any(|s| s == line) {
+                servers.push(line.to_string());
+            }
+        }
+        Ok(servers)
+    }
+
+    pub fn has_turn(&self) -> bool {
+        self.servers
+            .iter()
+            .any(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.has_turn() && (self.turn_username.is_none() || self.turn_credential.is_none()) {
+            return Err("TURN servers need a username and credential.".to_string());
+        }
+        Ok(())
+    }
+
+    // Trims the credentials and treats empty ones as unset
+    pub fn normalized(&self) -> Self {
+        let clean = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        Self {
+            servers: self.servers.clone(),
+            turn_username: clean(&self.turn_username),
+            turn_credential: clean(&self.turn_credential),
+            host_only: self.host_only,
+            gathering_timeout_secs: self.gathering_timeout_secs.min(MAX_GATHERING_TIMEOUT_SECS),
+        }
+    }
+}
+
+// The browser rejects the whole configuration with a SyntaxError if one URL
+// is malformed, so each one is checked against RFC 7064 and RFC 7065:
+// scheme:host[:port], plus ?transport=udp|tcp for TURN
+fn check_server_url(url: &str) -> Result<(), String> {
+    let invalid = |reason: &str| {
+        Err(format!(
+            "\"{}\" is not a valid ICE server URL: {}",
+            url, reason
+        ))
+    };
+    let Some((scheme, rest)) = url.split_once(':') else {
+        return invalid("it must start with stun:, stuns:, turn: or turns:");
+    };
+    let (address, query) = match rest.split_once('?') {
+        Some((address, query)) => (address, Some(query)),
+        None => (rest, None),
+    };
+    match (scheme, query) {
+        ("stun" | "stuns", None) | ("turn" | "turns", None) => {}
+        ("turn" | "turns", Some("transport=udp" | "transport=tcp")) => {}
+        ("stun" | "stuns" | "turn" | "turns", Some(_)) => {
+            return invalid("only TURN URLs take ?transport=udp or ?transport=tcp");
+        }
+        _ => return invalid("it must start with stun:, stuns:, turn: or turns:"),
+    }
+
+    let port = if let Some(bracketed) = address.strip_prefix('[') {
+        let Some((ip, after)) = bracketed.split_once(']') else {
+            return invalid("the IPv6 address is missing its closing bracket");
+        };
+        if ip.parse::<Ipv6Addr>().is_err() {
+            return invalid("the IPv6 address is not valid");
+        }
+        match after {
+            "" => None,
+            _ => match after.strip_prefix(':') {
+                Some(port) => Some(port),
+                None => return invalid("unexpected text after the IPv6 address"),
+            },
+        }
+    } else {
+        let (host, port) = match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        };
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'));
+        if !valid_host {
+            return invalid("the host name is missing or not valid");
+        }
+        port
+    };
+    if let Some(port) = port {
+        if !matches!(port.parse::<u16>(), Ok(port) if port > 0) {
+            return invalid("the port must be a number from 1 to 65535");
+        }
+    }
+    Ok(())
+}
+
+fn create_ice_servers(settings: &IceSettings) -> Array {
     let servers = Array::new();
+    if settings.host_only {
+        console::log!("🏠 Host candidates only, no ICE servers");
+        return servers;
+    }
 
-    let stun_config = Object::new();
-    let _ = Reflect::set(
-        &stun_config,
-        &"urls".into(),
-        &"stun:stun.l.google.com:19302".into(),
-    );
-    servers.push(&stun_config);
-
-    let stun_config2 = Object::new();
-    let _ = Reflect::set(
-        &stun_config2,
-        &"urls".into(),
-        &"stun:stun1.l.google.com:19302".into(),
-    );
-    servers.push(&stun_config2);
-
-    let stun_config6 = Object::new();
-    let _ = Reflect::set(
-        &stun_config6,
-        &"urls".into(),
-        &"stun:stun.services.mozilla.com".into(),
-    );
-    servers.push(&stun_config6);
+    for url in &settings.servers {
+        let server_config = Object::new();
+        let _ = Reflect::set(&server_config, &"urls".into(), &url.as_str().into());
+        if url.starts_with("turn:") || url.starts_with("turns:") {
+            if let Some(ref username) = settings.turn_username {
+                let _ = Reflect::set(
+                    &server_config,
+                    &"username".into(),
+                    &username.as_str().into(),
+                );
+            }
+            if let Some(ref credential) = settings.turn_credential {
+                let _ = Reflect::set(
+                    &server_config,
+                    &"credential".into(),
+                    &credential.as_str().into(),
+                );
+            }
+        }
+        servers.push(&server_config);
+    }
 
     servers
 }
 
+type CandidatesHandler = Rc<RefCell<Option<Box<dyn Fn(Vec<IceCandidate>)>>>>;
+type BinaryHandler = Rc<RefCell<Option<Box<dyn Fn(Vec<u8>)>>>>;
+// The closures behind the peer connection's and data channel's event
+// handlers, by handler name. Setting a handler again drops the old closure;
+// close() detaches them all.
+type EventClosures = Rc<RefCell<HashMap<&'static str, Box<dyn Any>>>>;
+
+// Handles of the timers that are still pending, by the name their closure
+// is kept under
+type Timers = Rc<RefCell<HashMap<&'static str, i32>>>;
+
+fn keep_closure<T: ?Sized + 'static>(
+    closures: &EventClosures,
+    name: &'static str,
+    closure: Closure<T>,
+) {
+    closures.borrow_mut().insert(name, Box::new(closure));
+}
+
+// Calls `callback` once after `ms`, replacing the pending timer of the same name
+fn set_timer(
+    closures: &EventClosures,
+    timers: &Timers,
+    name: &'static str,
+    ms: i32,
+    mut callback: impl FnMut() + 'static,
+) {
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    clear_timer(timers, name);
+    let timers_for_fire = Rc::clone(timers);
+    let closure = Closure::wrap(Box::new(move || {
+        timers_for_fire.borrow_mut().remove(name);
+        callback();
+    }) as Box<dyn FnMut()>);
+    if let Ok(handle) = window
+        .set_timeout_with_callback_and_timeout_and_arguments_0(closure.as_ref().unchecked_ref(), ms)
+    {
+        timers.borrow_mut().insert(name, handle);
+    }
+    keep_closure(closures, name, closure);
+}
+
+fn clear_timer(timers: &Timers, name: &'static str) {
+    if let Some(handle) = timers.borrow_mut().remove(name) {
+        if let Some(window) = web_sys::window() {
+            window.clear_timeout_with_handle(handle);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Connection {
     pc: RtcPeerConnection,
@@ -63,16 +303,25 @@ pub struct Connection {
     current_signal: Arc<Mutex<Option<String>>>,
     on_connection_established: Arc<Mutex<Option<Box<dyn Fn() + 'static>>>>,
     on_data_channel_open: Arc<Mutex<Option<Box<dyn Fn() + 'static>>>>,
+    gathering_timeout_secs: u32,
+    // Candidates found after the signal was emitted on the gathering deadline
+    on_extra_candidates: CandidatesHandler,
+    // Remote candidates that arrived before the remote description
+    pending_candidates: Arc<Mutex<Vec<IceCandidate>>>,
+    // Binary frames (file transfers) go here instead of the data handler
+    on_binary: BinaryHandler,
+    closures: EventClosures,
+    timers: Timers,
 }
 
 impl Connection {
-    pub fn new() -> Self {
+    // Fails if the browser rejects the ICE server configuration
+    pub fn new(ice_settings: &IceSettings) -> Result<Self, JsValue> {
         let config = RtcConfiguration::new();
-        let ice_servers = create_ice_servers();
+        let ice_servers = create_ice_servers(ice_settings);
         config.set_ice_servers(&ice_servers);
 
-        let pc = RtcPeerConnection::new_with_configuration(&config)
-            .expect("Failed to create RtcPeerConnection");
+        let pc = RtcPeerConnection::new_with_configuration(&config)?;
 
         let connection = Self {
             pc,
@@ -81,11 +330,17 @@ impl Connection {
             current_signal: Arc::new(Mutex::new(None)),
             on_connection_established: Arc::new(Mutex::new(None)),
             on_data_channel_open: Arc::new(Mutex::new(None)),
+            gathering_timeout_secs: ice_settings.gathering_timeout_secs,
+            on_extra_candidates: Rc::new(RefCell::new(None)),
+            pending_candidates: Arc::new(Mutex::new(Vec::new())),
+            on_binary: Rc::new(RefCell::new(None)),
+            closures: Rc::new(RefCell::new(HashMap::new())),
+            timers: Rc::new(RefCell::new(HashMap::new())),
         };
 
         connection.setup_ice_monitoring();
 
-        connection
+        Ok(connection)
     }
 
     fn setup_ice_monitoring(&self) {
@@ -122,7 +377,11 @@ impl Connection {
         self.pc.set_oniceconnectionstatechange(Some(
             ice_connection_state_closure.as_ref().unchecked_ref(),
         ));
-        ice_connection_state_closure.forget();
+        keep_closure(
+            &self.closures,
+            "oniceconnectionstatechange",
+            ice_connection_state_closure,
+        );
 
         // ICE gathering状態の監視を設定
         let ice_gathering_state_closure = Closure::wrap(Box::new(move |event: Event| {
@@ -132,7 +391,11 @@ impl Connection {
         self.pc.set_onicegatheringstatechange(Some(
             ice_gathering_state_closure.as_ref().unchecked_ref(),
         ));
-        ice_gathering_state_closure.forget();
+        keep_closure(
+            &self.closures,
+            "onicegatheringstatechange",
+            ice_gathering_state_closure,
+        );
     }
 
     pub fn set_connection_established_handler(
@@ -155,6 +418,167 @@ impl Connection {
         Ok(())
     }
 
+    pub fn set_extra_candidates_handler(&self, handler: impl Fn(Vec<IceCandidate>) + 'static) {
+        *self.on_extra_candidates.borrow_mut() = Some(Box::new(handler));
+    }
+
+    // Calls `callback` with the local description once gathering completes,
+    // or when the deadline started by the returned function passes, whichever
+    // comes first. Candidates found after the deadline go to the extra
+    // candidates handler when gathering does complete.
+    fn watch_gathering(
+        &self,
+        kind: SdpKind,
+        callback: impl Fn(String) + 'static,
+    ) -> Rc<dyn Fn(bool)> {
+        let emitted = Arc::new(Mutex::new(false));
+        let late_candidates: Arc<Mutex<Vec<IceCandidate>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let pc_for_emit = self.pc.clone();
+        let emitted_for_emit = Arc::clone(&emitted);
+        let emit: Rc<dyn Fn(bool)> = Rc::new(move |complete| {
+            if let Ok(mut emitted) = emitted_for_emit.lock() {
+                if *emitted {
+                    return;
+                }
+                *emitted = true;
+            }
+            if let Some(desc) = pc_for_emit.local_description() {
+                if let Some(signal) = encode_signal(&desc.sdp(), kind, complete) {
+                    callback(signal);
+                }
+            }
+        });
+
+        // Sends the candidates found since the last batch. Gathering may
+        // never complete, so this also runs on a timer after each new one.
+        let on_extra_candidates = Rc::clone(&self.on_extra_candidates);
+        let late_for_flush = Arc::clone(&late_candidates);
+        let flush_late: Rc<dyn Fn()> = Rc::new(move || {
+            let late = late_for_flush
+                .lock()
+                .map(|mut late| std::mem::take(&mut *late))
+                .unwrap_or_default();
+            if late.is_empty() {
+                return;
+            }
+            console::log!(&format!(
+                "📤 Sending {} late ICE candidates ({})",
+                late.len(),
+                kind.as_str()
+            ));
+            if let Some(ref handler) = *on_extra_candidates.borrow() {
+                handler(late);
+            }
+        });
+
+        let emit_on_complete = Rc::clone(&emit);
+        let closures = Rc::clone(&self.closures);
+        let timers = Rc::clone(&self.timers);
+        let callback_closure = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
+            let already_emitted = emitted.lock().map(|e| *e).unwrap_or(false);
+            if let Some(candidate) = event.candidate() {
+                console::log!(
+                    &format!("🧊 ICE candidate found ({}):", kind.as_str()),
+                    candidate.candidate()
+                );
+                if already_emitted {
+                    if let Ok(mut late) = late_candidates.lock() {
+                        late.push(IceCandidate {
+                            candidate: candidate.candidate(),
+                            sdp_mid: candidate.sdp_mid(),
+                            sdp_m_line_index: candidate.sdp_m_line_index(),
+                        });
+                    }
+                    // The first candidate of a batch starts its timer
+                    if !timers.borrow().contains_key("late-candidates") {
+                        let flush = Rc::clone(&flush_late);
+                        set_timer(
+                            &closures,
+                            &timers,
+                            "late-candidates",
+                            LATE_CANDIDATES_MS,
+                            move || flush(),
+                        );
+                    }
+                }
+            } else if already_emitted {
+                console::log!(&format!("✅ ICE gathering completed ({})", kind.as_str()));
+                clear_timer(&timers, "late-candidates");
+                flush_late();
+            } else {
+                console::log!(&format!("✅ ICE gathering completed ({})", kind.as_str()));
+                clear_timer(&timers, "gathering-deadline");
+                emit_on_complete(true);
+            }
+        }) as Box<dyn FnMut(_)>);
+
+        self.pc
+            .set_onicecandidate(Some(callback_closure.as_ref().unchecked_ref()));
+        keep_closure(&self.closures, "onicecandidate", callback_closure);
+
+        emit
+    }
+
+    // Must be called after the local description is set
+    fn start_gathering_deadline(&self, emit: Rc<dyn Fn(bool)>) {
+        if self.gathering_timeout_secs == 0 {
+            return;
+        }
+        let timeout_secs = self.gathering_timeout_secs;
+        set_timer(
+            &self.closures,
+            &self.timers,
+            "gathering-deadline",
+            (timeout_secs * 1000) as i32,
+            move || {
+                console::log!(&format!(
+                    "⏰ ICE gathering deadline ({}s) reached, using the candidates found so far",
+                    timeout_secs
+                ));
+                emit(false);
+            },
+        );
+    }
+
+    // Adds candidates from the other side's extra candidates code
+    pub async fn add_remote_candidates(
+        &self,
+        candidates: Vec<IceCandidate>,
+    ) -> Result<(), JsValue> {
+        if self.pc.remote_description().is_none() {
+            console::log!("⏳ Remote description not set yet, keeping candidates for later");
+            if let Ok(mut pending) = self.pending_candidates.lock() {
+                pending.extend(candidates);
+            }
+            return Ok(());
+        }
+        for candidate in candidates {
+            let init = RtcIceCandidateInit::new(&candidate.candidate);
+            init.set_sdp_mid(candidate.sdp_mid.as_deref());
+            init.set_sdp_m_line_index(candidate.sdp_m_line_index);
+            JsFuture::from(
+                self.pc
+                    .add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&init)),
+            )
+            .await?;
+            console::log!("🧊 Remote ICE candidate added:", candidate.candidate);
+        }
+        Ok(())
+    }
+
+    async fn add_pending_candidates(&self) -> Result<(), JsValue> {
+        let pending = self
+            .pending_candidates
+            .lock()
+            .map(|mut pending| std::mem::take(&mut *pending))
+            .unwrap_or_default();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        self.add_remote_candidates(pending).await
+    }
+
     pub async fn start_connection(
         &mut self,
         callback: impl Fn(String) + 'static,
@@ -166,6 +590,7 @@ impl Connection {
         let channel = self
             .pc
             .create_data_channel_with_data_channel_dict("data", &channel_init);
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
 
         // データチャネルオープンイベントハンドラを設定
         let on_data_channel_callback = Arc::clone(&self.on_data_channel_open);
@@ -179,36 +604,14 @@ impl Connection {
         }) as Box<dyn FnMut(_)>);
 
         channel.set_onopen(Some(open_closure.as_ref().unchecked_ref()));
-        open_closure.forget();
+        keep_closure(&self.closures, "channel.onopen", open_closure);
 
         if let Ok(mut channel_guard) = self.channel.lock() {
             *channel_guard = Some(channel);
         }
 
         // ICE candidate イベントハンドラを設定
-        let pc_clone = self.pc.clone();
-        let callback_closure = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
-            if let Some(candidate) = event.candidate() {
-                // ICE candidateが発見された時のログ
-                console::log!("🧊 ICE candidate found:", candidate.candidate());
-            } else {
-                // ICE gathering 完了時にコールバックを呼び出し
-                console::log!("✅ ICE gathering completed");
-                if let Some(desc) = pc_clone.local_description() {
-                    let session_desc = SessionDescription {
-                        sdp_type: "offer".to_string(),
-                        sdp: desc.sdp(),
-                    };
-                    if let Ok(offer_json) = serde_json::to_string(&session_desc) {
-                        callback(offer_json);
-                    }
-                }
-            }
-        }) as Box<dyn FnMut(_)>);
-
-        self.pc
-            .set_onicecandidate(Some(callback_closure.as_ref().unchecked_ref()));
-        callback_closure.forget();
+        let emit = self.watch_gathering(SdpKind::Offer, callback);
 
         // Offer を作成
         let offer = JsFuture::from(self.pc.create_offer()).await?;
@@ -218,6 +621,7 @@ impl Connection {
         offer_obj.set_sdp(&offer_sdp);
 
         JsFuture::from(self.pc.set_local_description(&offer_obj)).await?;
+        self.start_gathering_deadline(emit);
 
         Ok(())
     }
@@ -229,10 +633,12 @@ impl Connection {
     ) -> Result<(), JsValue> {
         let channel_arc = self.channel.clone();
         let on_data_channel_callback = Arc::clone(&self.on_data_channel_open);
+        let closures = Rc::clone(&self.closures);
 
         // データチャネルイベントハンドラを設定
         let datachannel_closure = Closure::wrap(Box::new(move |event: RtcDataChannelEvent| {
             let channel = event.channel();
+            channel.set_binary_type(RtcDataChannelType::Arraybuffer);
 
             // 受信したデータチャネルにオープンハンドラを設定
             let callback_for_open = Arc::clone(&on_data_channel_callback);
@@ -246,7 +652,7 @@ impl Connection {
             }) as Box<dyn FnMut(_)>);
 
             channel.set_onopen(Some(open_closure.as_ref().unchecked_ref()));
-            open_closure.forget();
+            keep_closure(&closures, "channel.onopen", open_closure);
 
             if let Ok(mut channel_guard) = channel_arc.lock() {
                 *channel_guard = Some(channel);
@@ -255,41 +661,19 @@ impl Connection {
 
         self.pc
             .set_ondatachannel(Some(datachannel_closure.as_ref().unchecked_ref()));
-        datachannel_closure.forget();
+        keep_closure(&self.closures, "ondatachannel", datachannel_closure);
 
-        let session_desc: SessionDescription = serde_json::from_str(&offer)
-            .map_err(|e| JsValue::from_str(&format!("Failed to parse offer: {}", e)))?;
+        let offer_sdp = decode_signal(&offer, SdpKind::Offer)?;
 
         // Remote description を設定
         let offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-        offer_obj.set_sdp(&session_desc.sdp);
+        offer_obj.set_sdp(&offer_sdp);
 
         JsFuture::from(self.pc.set_remote_description(&offer_obj)).await?;
+        self.add_pending_candidates().await?;
 
         // ICE candidate イベントハンドラを設定
-        let pc_clone = self.pc.clone();
-        let callback_closure = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
-            if let Some(candidate) = event.candidate() {
-                // ICE candidateが発見された時のログ
-                console::log!("🧊 ICE candidate found (Answer):", candidate.candidate());
-            } else {
-                // ICE gathering 完了時にコールバックを呼び出し
-                console::log!("✅ ICE gathering completed (Answer)");
-                if let Some(desc) = pc_clone.local_description() {
-                    let session_desc = SessionDescription {
-                        sdp_type: "answer".to_string(),
-                        sdp: desc.sdp(),
-                    };
-                    if let Ok(answer_json) = serde_json::to_string(&session_desc) {
-                        callback(answer_json);
-                    }
-                }
-            }
-        }) as Box<dyn FnMut(_)>);
-
-        self.pc
-            .set_onicecandidate(Some(callback_closure.as_ref().unchecked_ref()));
-        callback_closure.forget();
+        let emit = self.watch_gathering(SdpKind::Answer, callback);
 
         // Answer を作成
         let answer = JsFuture::from(self.pc.create_answer()).await?;
@@ -299,18 +683,19 @@ impl Connection {
         answer_obj.set_sdp(&answer_sdp);
 
         JsFuture::from(self.pc.set_local_description(&answer_obj)).await?;
+        self.start_gathering_deadline(emit);
 
         Ok(())
     }
 
     pub async fn recv_answer(&mut self, answer: String) -> Result<(), JsValue> {
-        let session_desc: SessionDescription = serde_json::from_str(&answer)
-            .map_err(|e| JsValue::from_str(&format!("Failed to parse answer: {}", e)))?;
+        let answer_sdp = decode_signal(&answer, SdpKind::Answer)?;
 
         let answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-        answer_obj.set_sdp(&session_desc.sdp);
+        answer_obj.set_sdp(&answer_sdp);
 
         JsFuture::from(self.pc.set_remote_description(&answer_obj)).await?;
+        self.add_pending_candidates().await?;
         Ok(())
     }
 
@@ -356,19 +741,64 @@ impl Connection {
     pub fn set_data_handler(&self, handler: impl Fn(String) + 'static) -> Result<(), JsValue> {
         if let Ok(channel_guard) = self.channel.lock() {
             if let Some(channel) = &*channel_guard {
+                let on_binary = self.on_binary.clone();
                 let message_closure = Closure::wrap(Box::new(move |event: MessageEvent| {
-                    if let Some(data) = event.data().as_string() {
+                    let data = event.data();
+                    if let Some(data) = data.as_string() {
                         handler(data);
+                    } else if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+                        if let Some(ref handler) = *on_binary.borrow() {
+                            handler(js_sys::Uint8Array::new(buffer).to_vec());
+                        }
                     }
                 }) as Box<dyn FnMut(_)>);
 
                 channel.set_onmessage(Some(message_closure.as_ref().unchecked_ref()));
-                message_closure.forget();
+                keep_closure(&self.closures, "channel.onmessage", message_closure);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_binary_handler(&self, handler: impl Fn(Vec<u8>) + 'static) {
+        *self.on_binary.borrow_mut() = Some(Box::new(handler));
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), JsValue> {
+        if let Ok(channel_guard) = self.channel.lock() {
+            match &*channel_guard {
+                Some(channel) if channel.ready_state() == RtcDataChannelState::Open => {
+                    channel.send_with_u8_array(data)?;
+                }
+                _ => return Err(JsValue::from_str("Data channel is not open")),
             }
         }
         Ok(())
     }
 
+    // Bytes queued on the data channel but not yet sent
+    pub fn buffered_amount(&self) -> u32 {
+        self.channel
+            .lock()
+            .ok()
+            .and_then(|guard| guard.as_ref().map(|channel| channel.buffered_amount()))
+            .unwrap_or(0)
+    }
+
+    // Calls `handler` whenever the send buffer drains below `threshold`
+    pub fn set_buffered_amount_low_handler(&self, threshold: u32, handler: impl Fn() + 'static) {
+        if let Ok(channel_guard) = self.channel.lock() {
+            if let Some(channel) = &*channel_guard {
+                channel.set_buffered_amount_low_threshold(threshold);
+                let low_closure = Closure::wrap(Box::new(move |_event: Event| {
+                    handler();
+                }) as Box<dyn FnMut(_)>);
+                channel.set_onbufferedamountlow(Some(low_closure.as_ref().unchecked_ref()));
+                keep_closure(&self.closures, "channel.onbufferedamountlow", low_closure);
+            }
+        }
+    }
+
     pub fn wait_for_open(&self, callback: impl Fn() + 'static) -> Result<(), JsValue> {
         if let Ok(channel_guard) = self.channel.lock() {
             if let Some(channel) = &*channel_guard {
@@ -384,18 +814,84 @@ impl Connection {
                 }) as Box<dyn FnMut(_)>);
 
                 channel.set_onopen(Some(open_closure.as_ref().unchecked_ref()));
-                open_closure.forget();
+                keep_closure(&self.closures, "channel.onopen", open_closure);
             }
         }
         Ok(())
     }
 
+    // Our and the peer's DTLS certificate fingerprints for this connection.
+    // A relay in the middle would have to present its own certificate to
+    // each side, so the two ends would see different pairs.
+    pub fn dtls_fingerprints(&self) -> Option<[[u8; 32]; 2]> {
+        let local = sdp::dtls_fingerprint(&self.pc.local_description()?.sdp())?;
+        let remote = sdp::dtls_fingerprint(&self.pc.remote_description()?.sdp())?;
+        Some([local, remote])
+    }
+
+    // Detaches every handler and cancels every timer before the closures
+    // behind them are dropped, so that nothing can reach a dropped closure
     pub fn close(&self) {
         if let Ok(channel_guard) = self.channel.lock() {
             if let Some(channel) = &*channel_guard {
+                channel.set_onopen(None);
+                channel.set_onmessage(None);
+                channel.set_onbufferedamountlow(None);
                 channel.close();
             }
         }
+        self.pc.set_oniceconnectionstatechange(None);
+        self.pc.set_onicegatheringstatechange(None);
+        self.pc.set_onicecandidate(None);
+        self.pc.set_ondatachannel(None);
         self.pc.close();
+        let pending: Vec<&'static str> = self.timers.borrow().keys().copied().collect();
+        for name in pending {
+            clear_timer(&self.timers, name);
+        }
+        self.closures.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_server_urls() {
+        let text = "stun:stun.l.google.com:19302\n\n  turn:turn.example.com?transport=tcp \nturns:203.0.113.5:5349\nstun:[2001:db8::1]:3478\nstuns:[::1]\nstun:stun.l.google.com:19302\n";
+        assert_eq!(
+            IceSettings::parse_servers(text),
+            Ok(vec![
+                "stun:stun.l.google.com:19302".to_string(),
+                "turn:turn.example.com?transport=tcp".to_string(),
+                "turns:203.0.113.5:5349".to_string(),
+                "stun:[2001:db8::1]:3478".to_string(),
+                "stuns:[::1]".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_server_urls() {
+        for url in [
+            "stun:",
+            "turn::3478",
+            "stun:a b",
+            "turn:host:99999",
+            "turn:host:0",
+            "turn:host:",
+            "turn:host:port",
+            "stun:host:3478:1",
+            "stun:host?transport=udp",
+            "turn:host?transport=sctp",
+            "turn:[2001:db8::1",
+            "turn:[not-ip]:3478",
+            "turn:[::1]3478",
+            "http://stun.example.com",
+            "stun.example.com",
+        ] {
+            assert!(IceSettings::parse_servers(url).is_err(), "{}", url);
+        }
     }
 }
@@ -0,0 +1,807 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Compact signaling codec. Only the parts of a data-channel-only SDP that
+// differ between sessions are kept (ICE credentials, DTLS fingerprint, setup
+// role and a few candidates); the rest is rebuilt from a fixed template.
+
+const FORMAT_VERSION: u8 = 1;
+const FINGERPRINT_LEN: usize = 32;
+const MAX_CANDIDATES: usize = 5;
+const MAX_CANDIDATES_PER_TYPE: usize = 2;
+// Set in the flags byte when more candidates may follow. Codes made before
+// this flag existed leave it clear and are read as complete.
+const INCOMPLETE_FLAG: u8 = 0b1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SdpKind {
+    Offer,
+    Answer,
+}
+
+impl SdpKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SdpKind::Offer => "offer",
+            SdpKind::Answer => "answer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SetupRole {
+    ActPass,
+    Active,
+    Passive,
+}
+
+impl SetupRole {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "actpass" => Some(SetupRole::ActPass),
+            "active" => Some(SetupRole::Active),
+            "passive" => Some(SetupRole::Passive),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SetupRole::ActPass => "actpass",
+            SetupRole::Active => "active",
+            SetupRole::Passive => "passive",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CandidateType {
+    Host,
+    Srflx,
+    Prflx,
+    Relay,
+}
+
+impl CandidateType {
+    const ALL: [CandidateType; 4] = [
+        CandidateType::Host,
+        CandidateType::Srflx,
+        CandidateType::Prflx,
+        CandidateType::Relay,
+    ];
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "host" => Some(CandidateType::Host),
+            "srflx" => Some(CandidateType::Srflx),
+            "prflx" => Some(CandidateType::Prflx),
+            "relay" => Some(CandidateType::Relay),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::Srflx => "srflx",
+            CandidateType::Prflx => "prflx",
+            CandidateType::Relay => "relay",
+        }
+    }
+
+    // RFC 8445 recommended type preferences
+    fn type_preference(&self) -> u32 {
+        match self {
+            CandidateType::Host => 126,
+            CandidateType::Prflx => 110,
+            CandidateType::Srflx => 100,
+            CandidateType::Relay => 0,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            CandidateType::Host => 0,
+            CandidateType::Srflx => 1,
+            CandidateType::Prflx => 2,
+            CandidateType::Relay => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        CandidateType::ALL.get(code as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Address {
+    V4([u8; 4]),
+    V6([u8; 16]),
+    // Host candidates obfuscated by the browser as `<uuid>.local`
+    Mdns([u8; 16]),
+}
+
+impl Address {
+    fn parse(value: &str) -> Option<Self> {
+        if let Ok(ip) = value.parse::<Ipv4Addr>() {
+            return Some(Address::V4(ip.octets()));
+        }
+        if let Ok(ip) = value.parse::<Ipv6Addr>() {
+            return Some(Address::V6(ip.octets()));
+        }
+        let uuid = value.strip_suffix(".local")?;
+        let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+        if uuid.len() != 36 || hex.len() != 32 {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(Address::Mdns(bytes))
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Address::V4(_) => 0,
+            Address::V6(_) => 1,
+            Address::Mdns(_) => 2,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Address::V4(bytes) => bytes,
+            Address::V6(bytes) | Address::Mdns(bytes) => bytes,
+        }
+    }
+
+    fn to_sdp_string(&self) -> String {
+        match self {
+            Address::V4(bytes) => Ipv4Addr::from(*bytes).to_string(),
+            Address::V6(bytes) => Ipv6Addr::from(*bytes).to_string(),
+            Address::Mdns(bytes) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!(
+                    "{}-{}-{}-{}-{}.local",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    kind: CandidateType,
+    address: Address,
+    port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CompactSdp {
+    kind: SdpKind,
+    setup: SetupRole,
+    ufrag: String,
+    pwd: String,
+    fingerprint: [u8; FINGERPRINT_LEN],
+    candidates: Vec<Candidate>,
+    // Whether gathering had finished, so that no more candidates follow
+    complete: bool,
+}
+
+fn parse_candidate(value: &str) -> Option<(u32, Candidate)> {
+    // foundation component transport priority address port "typ" type ...
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    if fields.len() < 8 || fields[1] != "1" || !fields[2].eq_ignore_ascii_case("udp") {
+        return None;
+    }
+    let priority = fields[3].parse().ok()?;
+    let candidate = Candidate {
+        address: Address::parse(fields[4])?,
+        port: fields[5].parse().ok()?,
+        kind: CandidateType::parse(fields.get(7)?)?,
+    };
+    Some((priority, candidate))
+}
+
+fn parse_fingerprint(value: &str) -> Option<[u8; FINGERPRINT_LEN]> {
+    let (algorithm, hex) = value.split_once(' ')?;
+    if !algorithm.eq_ignore_ascii_case("sha-256") {
+        return None;
+    }
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    let mut parts = hex.trim().split(':');
+    for byte in fingerprint.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(fingerprint)
+}
+
+// Keeps a few candidates of each type, in the browser's priority order
+fn select_candidates(mut candidates: Vec<(u32, Candidate)>) -> Vec<Candidate> {
+    candidates.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+    let mut selected: Vec<Candidate> = Vec::new();
+    for kind in CandidateType::ALL {
+        for (_, candidate) in candidates.iter().filter(|(_, c)| c.kind == kind) {
+            let same_kind = selected.iter().filter(|c| c.kind == kind).count();
+            if same_kind >= MAX_CANDIDATES_PER_TYPE || selected.len() >= MAX_CANDIDATES {
+                break;
+            }
+            if !selected.contains(candidate) {
+                selected.push(candidate.clone());
+            }
+        }
+    }
+    selected
+}
+
+fn parse_sdp(sdp: &str, kind: SdpKind) -> Result<CompactSdp, String> {
+    let mut ufrag = None;
+    let mut pwd = None;
+    let mut fingerprint = None;
+    let mut setup = None;
+    let mut candidates = Vec::new();
+
+    for line in sdp.lines() {
+        let Some(attribute) = line.trim().strip_prefix("a=") else {
+            if line.starts_with("m=") && !line.starts_with("m=application") {
+                return Err("Only data channel sessions can be compacted".to_string());
+            }
+            continue;
+        };
+        let (name, value) = attribute.split_once(':').unwrap_or((attribute, ""));
+        match name {
+            "ice-ufrag" if ufrag.is_none() => ufrag = Some(value.to_string()),
+            "ice-pwd" if pwd.is_none() => pwd = Some(value.to_string()),
+            "fingerprint" if fingerprint.is_none() => fingerprint = parse_fingerprint(value),
+            "setup" if setup.is_none() => setup = SetupRole::parse(value),
+            "candidate" => candidates.extend(parse_candidate(value)),
+            _ => {}
+        }
+    }
+
+    let ufrag = ufrag.ok_or("Missing ICE ufrag")?;
+    let pwd = pwd.ok_or("Missing ICE password")?;
+    if ufrag.len() > u8::MAX as usize || pwd.len() > u8::MAX as usize {
+        return Err("ICE credentials are too long".to_string());
+    }
+
+    Ok(CompactSdp {
+        kind,
+        setup: setup.ok_or("Missing DTLS setup role")?,
+        ufrag,
+        pwd,
+        fingerprint: fingerprint.ok_or("Missing SHA-256 DTLS fingerprint")?,
+        candidates: select_candidates(candidates),
+        complete: true,
+    })
+}
+
+fn to_bytes(compact: &CompactSdp) -> Vec<u8> {
+    let mut bytes = vec![FORMAT_VERSION];
+    let kind = match compact.kind {
+        SdpKind::Offer => 0,
+        SdpKind::Answer => 1,
+    };
+    let setup = match compact.setup {
+        SetupRole::ActPass => 0,
+        SetupRole::Active => 1,
+        SetupRole::Passive => 2,
+    };
+    let incomplete = if compact.complete { 0 } else { INCOMPLETE_FLAG };
+    bytes.push(kind | (setup << 1) | incomplete);
+    bytes.push(compact.ufrag.len() as u8);
+    bytes.extend_from_slice(compact.ufrag.as_bytes());
+    bytes.push(compact.pwd.len() as u8);
+    bytes.extend_from_slice(compact.pwd.as_bytes());
+    bytes.extend_from_slice(&compact.fingerprint);
+    bytes.push(compact.candidates.len() as u8);
+    for candidate in &compact.candidates {
+        bytes.push((candidate.kind.code() << 4) | candidate.address.code());
+        bytes.extend_from_slice(candidate.address.bytes());
+        bytes.extend_from_slice(&candidate.port.to_be_bytes());
+    }
+    bytes
+}
+
+fn from_bytes(bytes: &[u8]) -> Option<CompactSdp> {
+    let mut cursor = bytes.iter().copied();
+    let mut take = |n: usize| -> Option<Vec<u8>> {
+        let taken: Vec<u8> = cursor.by_ref().take(n).collect();
+        (taken.len() == n).then_some(taken)
+    };
+
+    if take(1)?[0] != FORMAT_VERSION {
+        return None;
+    }
+    let flags = take(1)?[0];
+    let kind = if flags & 1 == 0 {
+        SdpKind::Offer
+    } else {
+        SdpKind::Answer
+    };
+    let setup = match (flags >> 1) & 0b11 {
+        0 => SetupRole::ActPass,
+        1 => SetupRole::Active,
+        2 => SetupRole::Passive,
+        _ => return None,
+    };
+    let ufrag_len = take(1)?[0] as usize;
+    let ufrag = String::from_utf8(take(ufrag_len)?).ok()?;
+    let pwd_len = take(1)?[0] as usize;
+    let pwd = String::from_utf8(take(pwd_len)?).ok()?;
+    let fingerprint: [u8; FINGERPRINT_LEN] = take(FINGERPRINT_LEN)?.try_into().ok()?;
+
+    let count = take(1)?[0] as usize;
+    let mut candidates = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = take(1)?[0];
+        let kind = CandidateType::from_code(header >> 4)?;
+        let address = match header & 0x0f {
+            0 => Address::V4(take(4)?.try_into().ok()?),
+            1 => Address::V6(take(16)?.try_into().ok()?),
+            2 => Address::Mdns(take(16)?.try_into().ok()?),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(take(2)?.try_into().ok()?);
+        candidates.push(Candidate {
+            kind,
+            address,
+            port,
+        });
+    }
+
+    Some(CompactSdp {
+        kind,
+        setup,
+        ufrag,
+        pwd,
+        fingerprint,
+        candidates,
+        complete: flags & INCOMPLETE_FLAG == 0,
+    })
+}
+
+fn build_sdp(compact: &CompactSdp) -> String {
+    let fingerprint: Vec<String> = compact
+        .fingerprint
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+
+    let mut lines = vec![
+        "v=0".to_string(),
+        "o=- 0 2 IN IP4 127.0.0.1".to_string(),
+        "s=-".to_string(),
+        "t=0 0".to_string(),
+        "a=group:BUNDLE 0".to_string(),
+        "a=msid-semantic: WMS".to_string(),
+        "m=application 9 UDP/DTLS/SCTP webrtc-datachannel".to_string(),
+        "c=IN IP4 0.0.0.0".to_string(),
+        format!("a=ice-ufrag:{}", compact.ufrag),
+        format!("a=ice-pwd:{}", compact.pwd),
+        format!("a=fingerprint:sha-256 {}", fingerprint.join(":")),
+        format!("a=setup:{}", compact.setup.as_str()),
+        "a=mid:0".to_string(),
+        "a=sctp-port:5000".to_string(),
+        "a=max-message-size:262144".to_string(),
+    ];
+    for (index, candidate) in compact.candidates.iter().enumerate() {
+        let local_preference = 65535 - index as u32;
+        let priority = (candidate.kind.type_preference() << 24) | (local_preference << 8) | 255;
+        let mut line = format!(
+            "a=candidate:{} 1 udp {} {} {} typ {}",
+            index + 1,
+            priority,
+            candidate.address.to_sdp_string(),
+            candidate.port,
+            candidate.kind.as_str()
+        );
+        if candidate.kind != CandidateType::Host {
+            line.push_str(" raddr 0.0.0.0 rport 0");
+        }
+        lines.push(line);
+    }
+    if compact.complete {
+        lines.push("a=end-of-candidates".to_string());
+    }
+
+    let mut sdp = lines.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp
+}
+
+// Full SDP -> compact Base64 string. `complete` is false when the signal is
+// sent before gathering has finished and more candidates may follow.
+pub fn compress(sdp: &str, kind: SdpKind, complete: bool) -> Result<String, String> {
+    let compact = CompactSdp {
+        complete,
+        ..parse_sdp(sdp, kind)?
+    };
+    if compact.candidates.is_empty() {
+        return Err("No usable ICE candidates".to_string());
+    }
+    Ok(BASE64.encode(to_bytes(&compact)))
+}
+
+// Compact Base64 string -> rebuilt SDP
+pub fn decompress(data: &str, expected: SdpKind) -> Result<String, String> {
+    let bytes = BASE64
+        .decode(data.trim())
+        .map_err(|e| format!("Invalid compact SDP: {}", e))?;
+    let compact = from_bytes(&bytes).ok_or("Malformed compact SDP")?;
+    if compact.kind != expected {
+        return Err(format!(
+            "Expected {} but received {}",
+            expected.as_str(),
+            compact.kind.as_str()
+        ));
+    }
+    Ok(build_sdp(&compact))
+}
+
+// The SHA-256 DTLS fingerprint of a full SDP, as used by the data channel
+pub fn dtls_fingerprint(sdp: &str) -> Option<[u8; FINGERPRINT_LEN]> {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .find_map(parse_fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_OFFER: &str = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+a=extmap-allow-mixed\r\n\
+a=msid-semantic: WMS\r\n\
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=candidate:3950484012 1 udp 2113937151 4e3b7c1a-6a2f-4d9e-9f0b-2c8d1e5a7b36.local 54321 typ host generation 0 network-cost 999\r\n\
+a=candidate:1204719356 1 tcp 1518280447 4e3b7c1a-6a2f-4d9e-9f0b-2c8d1e5a7b36.local 9 typ host tcptype active generation 0 network-cost 999\r\n\
+a=candidate:842163049 1 udp 1677729535 203.0.113.7 54321 typ srflx raddr 0.0.0.0 rport 0 generation 0 network-cost 999\r\n\
+a=ice-ufrag:EsAw\r\n\
+a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 5A:0E:C3:8B:1F:47:D2:96:3C:E8:71:AA:04:BD:69:F2:13:8E:C7:5D:20:B4:9A:E6:37:0F:D8:61:4C:A5:F9:82\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:262144\r\n";
+
+    const CHROME_ANSWER: &str = "v=0\r\n\
+o=- 8259017632098274310 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+a=extmap-allow-mixed\r\n\
+a=msid-semantic: WMS\r\n\
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=candidate:2999745851 1 udp 2122260223 192.168.1.23 61702 typ host generation 0 network-id 1 network-cost 10\r\n\
+a=candidate:3496357194 1 udp 2122197247 2001:db8::5:6c1e 61703 typ host generation 0 network-id 2 network-cost 10\r\n\
+a=candidate:1526424491 1 udp 2122129151 10.0.0.4 61704 typ host generation 0 network-id 3 network-cost 10\r\n\
+a=candidate:3178404127 1 udp 1686052607 198.51.100.40 61702 typ srflx raddr 192.168.1.23 rport 61702 generation 0 network-id 1 network-cost 10\r\n\
+a=candidate:1357937380 1 udp 41885439 192.0.2.200 3478 typ relay raddr 198.51.100.40 rport 61702 generation 0 network-id 1 network-cost 10\r\n\
+a=ice-ufrag:q9Zx\r\n\
+a=ice-pwd:tV0yKp3hN8mWcR2sLd5fJq7B\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 C4:19:7E:02:B8:55:AF:6D:91:3A:E0:28:7C:D4:16:BB:49:F3:0A:65:8E:D1:27:C9:5B:74:E2:0D:A8:36:9F:11\r\n\
+a=setup:active\r\n\
+a=mid:0\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:262144\r\n";
+
+    // Firefox puts the fingerprint at session level and writes UDP in capitals
+    const FIREFOX_OFFER: &str = "v=0\r\n\
+o=mozilla...THIS_IS_SDPARTA-128.0 5327412387613735613 0 IN IP4 0.0.0.0\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=sendrecv\r\n\
+a=fingerprint:sha-256 8F:3B:D0:61:2E:C7:94:5A:0B:E8:73:1D:A6:4F:C2:39:E5:70:9B:14:D8:6E:21:AC:57:F0:83:3C:B9:06:4A:DD\r\n\
+a=group:BUNDLE 0\r\n\
+a=ice-options:trickle\r\n\
+a=msid-semantic:WMS *\r\n\
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=candidate:0 1 UDP 2122252543 a1b2c3d4-e5f6-4789-8abc-def012345678.local 60001 typ host\r\n\
+a=candidate:2 1 TCP 2105524479 a1b2c3d4-e5f6-4789-8abc-def012345678.local 9 typ host tcptype active\r\n\
+a=candidate:1 1 UDP 1686052863 198.51.100.23 60001 typ srflx raddr 0.0.0.0 rport 0\r\n\
+a=sendrecv\r\n\
+a=end-of-candidates\r\n\
+a=ice-pwd:0a4d1a4b2a3e6b7e5b9c8d7f6e5a4b3c\r\n\
+a=ice-ufrag:2c1a3b4d\r\n\
+a=mid:0\r\n\
+a=setup:actpass\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:1073741823\r\n";
+
+    const FIREFOX_ANSWER: &str = "v=0\r\n\
+o=mozilla...THIS_IS_SDPARTA-128.0 7740512833091746209 0 IN IP4 0.0.0.0\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=sendrecv\r\n\
+a=fingerprint:sha-256 17:A2:5C:E9:30:4D:BB:86:F1:0E:72:C5:39:9A:D4:63:2F:E8:05:B1:7A:CC:48:91:DE:26:6B:F3:08:55:A0:C7\r\n\
+a=group:BUNDLE 0\r\n\
+a=ice-options:trickle\r\n\
+a=msid-semantic:WMS *\r\n\
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=candidate:0 1 UDP 2122187007 fd00:1234:5678::9 51112 typ host\r\n\
+a=candidate:1 1 UDP 2122252543 172.16.4.12 51113 typ host\r\n\
+a=candidate:2 1 UDP 8265727 192.0.2.77 49210 typ relay raddr 192.0.2.77 rport 49210\r\n\
+a=sendrecv\r\n\
+a=end-of-candidates\r\n\
+a=ice-pwd:9c7e1f0b3d5a2e4c6b8a0d1f3e5c7a9b\r\n\
+a=ice-ufrag:7f3e9a21\r\n\
+a=mid:0\r\n\
+a=setup:active\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:1073741823\r\n";
+
+    const SAMPLES: [(&str, SdpKind); 4] = [
+        (CHROME_OFFER, SdpKind::Offer),
+        (CHROME_ANSWER, SdpKind::Answer),
+        (FIREFOX_OFFER, SdpKind::Offer),
+        (FIREFOX_ANSWER, SdpKind::Answer),
+    ];
+
+    fn hex(value: &str) -> [u8; FINGERPRINT_LEN] {
+        parse_fingerprint(&format!("sha-256 {}", value)).unwrap()
+    }
+
+    #[test]
+    fn parses_chrome_offer() {
+        let compact = parse_sdp(CHROME_OFFER, SdpKind::Offer).unwrap();
+        assert_eq!(compact.setup, SetupRole::ActPass);
+        assert_eq!(compact.ufrag, "EsAw");
+        assert_eq!(compact.pwd, "P2uYro0UCOQ4zxjKXaWCBui1");
+        assert_eq!(compact.fingerprint[0], 0x5a);
+        assert_eq!(compact.fingerprint[31], 0x82);
+        // The TCP candidate is dropped
+        assert_eq!(
+            compact.candidates,
+            vec![
+                Candidate {
+                    kind: CandidateType::Host,
+                    address: Address::parse("4e3b7c1a-6a2f-4d9e-9f0b-2c8d1e5a7b36.local").unwrap(),
+                    port: 54321,
+                },
+                Candidate {
+                    kind: CandidateType::Srflx,
+                    address: Address::V4([203, 0, 113, 7]),
+                    port: 54321,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_firefox_offer() {
+        let compact = parse_sdp(FIREFOX_OFFER, SdpKind::Offer).unwrap();
+        assert_eq!(compact.setup, SetupRole::ActPass);
+        assert_eq!(compact.ufrag, "2c1a3b4d");
+        assert_eq!(compact.pwd, "0a4d1a4b2a3e6b7e5b9c8d7f6e5a4b3c");
+        assert_eq!(compact.fingerprint[0], 0x8f);
+        assert_eq!(compact.candidates.len(), 2);
+        assert_eq!(
+            compact.candidates[0].address.to_sdp_string(),
+            "a1b2c3d4-e5f6-4789-8abc-def012345678.local"
+        );
+    }
+
+    #[test]
+    fn keeps_a_few_candidates_per_type() {
+        let compact = parse_sdp(CHROME_ANSWER, SdpKind::Answer).unwrap();
+        let kinds: Vec<CandidateType> = compact.candidates.iter().map(|c| c.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CandidateType::Host,
+                CandidateType::Host,
+                CandidateType::Srflx,
+                CandidateType::Relay,
+            ]
+        );
+        // The two host candidates with the highest priority win
+        assert_eq!(
+            compact.candidates[0].address,
+            Address::V4([192, 168, 1, 23])
+        );
+        assert_eq!(
+            compact.candidates[1].address,
+            Address::parse("2001:db8::5:6c1e").unwrap()
+        );
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for (sdp, kind) in SAMPLES {
+            let compact = parse_sdp(sdp, kind).unwrap();
+            assert_eq!(from_bytes(&to_bytes(&compact)), Some(compact));
+        }
+    }
+
+    #[test]
+    fn rebuilt_sdp_parses_to_the_same_session() {
+        for (sdp, kind) in SAMPLES {
+            let compact = parse_sdp(sdp, kind).unwrap();
+            let rebuilt = build_sdp(&compact);
+            assert_eq!(parse_sdp(&rebuilt, kind), Ok(compact));
+            assert_eq!(dtls_fingerprint(&rebuilt), dtls_fingerprint(sdp));
+        }
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        for (sdp, kind) in SAMPLES {
+            let compressed = compress(sdp, kind, true).unwrap();
+            assert!(compressed.len() < sdp.len() / 4);
+            let rebuilt = decompress(&compressed, kind).unwrap();
+            assert_eq!(parse_sdp(&rebuilt, kind), parse_sdp(sdp, kind));
+            // Compressing the rebuilt SDP gives the same string
+            assert_eq!(compress(&rebuilt, kind, true), Ok(compressed));
+        }
+    }
+
+    #[test]
+    fn incomplete_signal_leaves_out_end_of_candidates() {
+        for (sdp, kind) in SAMPLES {
+            let compressed = compress(sdp, kind, false).unwrap();
+            let rebuilt = decompress(&compressed, kind).unwrap();
+            assert!(!rebuilt.contains("a=end-of-candidates"));
+            assert_eq!(
+                parse_sdp(&rebuilt, kind).map(|c| c.candidates),
+                parse_sdp(sdp, kind).map(|c| c.candidates)
+            );
+
+            let complete = decompress(&compress(sdp, kind, true).unwrap(), kind).unwrap();
+            assert!(complete.contains("a=end-of-candidates\r\n"));
+        }
+    }
+
+    #[test]
+    fn decompress_checks_the_kind() {
+        let compressed = compress(CHROME_OFFER, SdpKind::Offer, true).unwrap();
+        assert_eq!(
+            decompress(&compressed, SdpKind::Answer),
+            Err("Expected answer but received offer".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_sessions_that_cannot_be_compacted() {
+        let video = CHROME_OFFER.replace(
+            "m=application 9 UDP/DTLS/SCTP webrtc-datachannel",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+        );
+        assert!(parse_sdp(&video, SdpKind::Offer).is_err());
+
+        let no_fingerprint: String = FIREFOX_OFFER
+            .lines()
+            .filter(|line| !line.starts_with("a=fingerprint"))
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        assert!(parse_sdp(&no_fingerprint, SdpKind::Offer).is_err());
+
+        let no_candidates: String = CHROME_OFFER
+            .lines()
+            .filter(|line| !line.starts_with("a=candidate"))
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        assert_eq!(
+            compress(&no_candidates, SdpKind::Offer, true),
+            Err("No usable ICE candidates".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated_bytes_are_rejected() {
+        for (sdp, kind) in SAMPLES {
+            let bytes = to_bytes(&parse_sdp(sdp, kind).unwrap());
+            for len in 0..bytes.len() {
+                assert_eq!(from_bytes(&bytes[..len]), None, "prefix of {} bytes", len);
+                let truncated = BASE64.encode(&bytes[..len]);
+                assert!(decompress(&truncated, kind).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_bytes_are_rejected() {
+        let bytes = to_bytes(&parse_sdp(CHROME_OFFER, SdpKind::Offer).unwrap());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = FORMAT_VERSION + 1;
+        assert_eq!(from_bytes(&wrong_version), None);
+
+        let mut bad_setup = bytes.clone();
+        bad_setup[1] = 0b110;
+        assert_eq!(from_bytes(&bad_setup), None);
+
+        let mut bad_utf8 = bytes.clone();
+        bad_utf8[3] = 0xff;
+        assert_eq!(from_bytes(&bad_utf8), None);
+
+        // Header of the first candidate, after the credentials and fingerprint
+        let header = 3 + 4 + 1 + 24 + FINGERPRINT_LEN + 1;
+        let mut bad_address = bytes.clone();
+        bad_address[header] = 0x03;
+        assert_eq!(from_bytes(&bad_address), None);
+        let mut bad_type = bytes.clone();
+        bad_type[header] = 0x40;
+        assert_eq!(from_bytes(&bad_type), None);
+
+        let mut too_many = bytes.clone();
+        too_many[header - 1] = u8::MAX;
+        assert_eq!(from_bytes(&too_many), None);
+
+        // Arbitrary input never panics
+        let mut state: u32 = 0x2545_f491;
+        for len in 0..512 {
+            let garbage: Vec<u8> = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            let _ = from_bytes(&garbage);
+        }
+
+        assert!(decompress("not base64!", SdpKind::Offer).is_err());
+        assert!(decompress("", SdpKind::Offer).is_err());
+    }
+
+    #[test]
+    fn dtls_fingerprint_reads_session_and_media_level() {
+        assert_eq!(
+            dtls_fingerprint(CHROME_OFFER),
+            Some(hex("5A:0E:C3:8B:1F:47:D2:96:3C:E8:71:AA:04:BD:69:F2:13:8E:C7:5D:20:B4:9A:E6:37:0F:D8:61:4C:A5:F9:82"))
+        );
+        assert_eq!(
+            dtls_fingerprint(FIREFOX_ANSWER),
+            Some(hex("17:A2:5C:E9:30:4D:BB:86:F1:0E:72:C5:39:9A:D4:63:2F:E8:05:B1:7A:CC:48:91:DE:26:6B:F3:08:55:A0:C7"))
+        );
+        // Case does not matter
+        assert_eq!(
+            dtls_fingerprint(&CHROME_OFFER.to_lowercase()),
+            dtls_fingerprint(CHROME_OFFER)
+        );
+    }
+
+    #[test]
+    fn dtls_fingerprint_needs_sha_256() {
+        assert_eq!(
+            dtls_fingerprint("v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n"),
+            None
+        );
+
+        let sha1 =
+            "a=fingerprint:sha-1 5A:0E:C3:8B:1F:47:D2:96:3C:E8:71:AA:04:BD:69:F2:13:8E:C7:5D";
+        assert_eq!(dtls_fingerprint(sha1), None);
+        // A SHA-256 line after an unsupported one is still found
+        let both = format!("{}\r\n{}", sha1, CHROME_ANSWER);
+        assert_eq!(dtls_fingerprint(&both), dtls_fingerprint(CHROME_ANSWER));
+
+        let full = "5A:0E:C3:8B:1F:47:D2:96:3C:E8:71:AA:04:BD:69:F2:13:8E:C7:5D:20:B4:9A:E6:37:0F:D8:61:4C:A5:F9:82";
+        let short = format!("a=fingerprint:sha-256 {}", &full[..47]);
+        assert_eq!(dtls_fingerprint(&short), None);
+        let long = format!("a=fingerprint:sha-256 {}:00", full);
+        assert_eq!(dtls_fingerprint(&long), None);
+        let not_hex = format!("a=fingerprint:sha-256 ZZ{}", &full[2..]);
+        assert_eq!(dtls_fingerprint(&not_hex), None);
+    }
+}
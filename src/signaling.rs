@@ -0,0 +1,420 @@
+use crate::card::{decode_array, signing_key_from_identity};
+use crate::{Contact, KeyPair, RtcSignalData};
+use age::{x25519, Decryptor, Encryptor};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+const SIGNAL_SIGNATURE_DOMAIN: &str = "qr-encrypt rtc signal v1";
+
+// A connection set up with a known contact. Every signal of the session is
+// encrypted to the contact and carries the offerer's nonce; since only the
+// contact can read the offer, a correct nonce in the answer shows that it
+// came from them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub peer_key: String,
+    pub nonce: String,
+}
+
+impl Session {
+    pub fn new(peer_key: &str) -> Result<Self, String> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(|e| format!("No random source: {}", e))?;
+        Ok(Self {
+            peer_key: peer_key.to_string(),
+            nonce: BASE64.encode(bytes),
+        })
+    }
+}
+
+// Short field names keep the QR code small
+#[derive(Serialize, Deserialize)]
+struct SignedSignal {
+    // The inner RtcSignalData as JSON
+    #[serde(rename = "s")]
+    signal: String,
+    #[serde(rename = "f")]
+    from: String,
+    #[serde(rename = "t")]
+    to: String,
+    #[serde(rename = "n")]
+    nonce: String,
+    #[serde(rename = "vk")]
+    signing_key: String,
+    #[serde(rename = "sig")]
+    signature: String,
+}
+
+impl SignedSignal {
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            SIGNAL_SIGNATURE_DOMAIN,
+            &self.signal,
+            &self.from,
+            &self.to,
+            &self.nonce,
+            &self.signing_key,
+        ))
+        .unwrap_or_default()
+    }
+}
+
+// A signal that has been decrypted and whose signature is valid, but whose
+// sender has not yet been checked against the contacts
+pub struct OpenedSignal {
+    pub signal: RtcSignalData,
+    pub from: String,
+    pub nonce: String,
+    signing_key: String,
+}
+
+impl OpenedSignal {
+    // Offers are only accepted from contacts. When the contact's signing key
+    // has been confirmed by a peer check the signature must have been made
+    // with it.
+    pub fn sender<'a>(&self, contacts: &'a [Contact]) -> Result<&'a Contact, String> {
+        let contact = contacts
+            .iter()
+            .find(|c| c.public_key == self.from)
+            .ok_or("This connection code is not from one of your contacts.")?;
+        self.check_signing_key(contact)?;
+        Ok(contact)
+    }
+
+    // Answers and extra candidates must belong to the session we started
+    pub fn check_session(&self, session: &Session, contacts: &[Contact]) -> Result<(), String> {
+        if self.from != session.peer_key || self.nonce != session.nonce {
+            return Err(
+                "This connection code is not a reply from the contact you are connecting to."
+                    .to_string(),
+            );
+        }
+        match contacts.iter().find(|c| c.public_key == self.from) {
+            Some(contact) => self.check_signing_key(contact),
+            None => Ok(()),
+        }
+    }
+
+    fn check_signing_key(&self, contact: &Contact) -> Result<(), String> {
+        match contact.signing_key {
+            Some(ref key) if *key != self.signing_key => Err(format!(
+                "The connection code was not signed by {}'s signing key.",
+                contact.name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Encrypts `signal` to the session's peer and signs it with our key
+pub fn seal(signal: &RtcSignalData, session: &Session, keys: &KeyPair) -> Result<String, String> {
+    let signal = serde_json::to_string(signal).map_err(|e| e.to_string())?;
+    encrypt(&sign(signal, session, keys), &session.peer_key)
+}
+
+fn sign(signal: String, session: &Session, keys: &KeyPair) -> SignedSignal {
+    let signing_key = signing_key_from_identity(&keys.private_key);
+    let mut signed = SignedSignal {
+        signal,
+        from: keys.public_key.clone(),
+        to: session.peer_key.clone(),
+        nonce: session.nonce.clone(),
+        signing_key: BASE64.encode(signing_key.verifying_key().as_bytes()),
+        signature: String::new(),
+    };
+    signed.signature = BASE64.encode(signing_key.sign(&signed.signed_bytes()).to_bytes());
+    signed
+}
+
+fn encrypt(signed: &SignedSignal, peer_key: &str) -> Result<String, String> {
+    let json = serde_json::to_vec(signed).map_err(|e| e.to_string())?;
+    let recipient = peer_key
+        .parse::<x25519::Recipient>()
+        .map_err(|e| format!("Invalid contact key: {}", e))?;
+    let encryptor = Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+        .map_err(|e| e.to_string())?;
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut encrypted)
+        .map_err(|e| e.to_string())?;
+    writer.write_all(&json).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+
+    serde_json::to_string(&RtcSignalData::Sealed {
+        data: BASE64.encode(encrypted),
+    })
+    .map_err(|e| e.to_string())
+}
+
+// Decrypts a sealed signal and checks that it was signed by its sender and
+// addressed to us
+pub fn open(data: &str, keys: &KeyPair) -> Result<OpenedSignal, String> {
+    let identity = keys
+        .private_key
+        .trim()
+        .parse::<x25519::Identity>()
+        .map_err(|e| format!("Invalid private key: {}", e))?;
+    let encrypted = BASE64.decode(data).map_err(|e| e.to_string())?;
+    let decryptor = Decryptor::new(&encrypted[..]).map_err(|e| e.to_string())?;
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|_| "This connection code was encrypted for someone else.".to_string())?;
+    let mut json = Vec::new();
+    reader.read_to_end(&mut json).map_err(|e| e.to_string())?;
+
+    let signed: SignedSignal = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    if signed.to != keys.public_key {
+        return Err("This connection code was addressed to someone else.".to_string());
+    }
+    let verifying_key = decode_array(&signed.signing_key)
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Invalid signing key: {}", e))?;
+    let signature = Signature::from_bytes(
+        &decode_array(&signed.signature).map_err(|e| format!("Invalid signature: {}", e))?,
+    );
+    verifying_key
+        .verify_strict(&signed.signed_bytes(), &signature)
+        .map_err(|_| "The connection code's signature is not valid.".to_string())?;
+
+    let signal = serde_json::from_str::<RtcSignalData>(&signed.signal)
+        .map_err(|e| format!("Invalid signal: {}", e))?;
+    if matches!(signal, RtcSignalData::Sealed { .. }) {
+        return Err("Invalid signal: nested sealed signal".to_string());
+    }
+    Ok(OpenedSignal {
+        signal,
+        from: signed.from,
+        nonce: signed.nonce,
+        signing_key: signed.signing_key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    fn key_pair() -> KeyPair {
+        let identity = x25519::Identity::generate();
+        KeyPair {
+            public_key: identity.to_public().to_string(),
+            private_key: identity.to_string().expose_secret().to_string(),
+        }
+    }
+
+    fn offer() -> RtcSignalData {
+        RtcSignalData::Offer {
+            sdp_data: "AQAE".to_string(),
+        }
+    }
+
+    // The `data` of the Sealed wrapper that `seal` returns
+    fn sealed_data(sealed: &str) -> String {
+        match serde_json::from_str(sealed).unwrap() {
+            RtcSignalData::Sealed { data } => data,
+            other => panic!("expected a sealed signal, got {:?}", other),
+        }
+    }
+
+    fn contact_for(keys: &KeyPair) -> Contact {
+        let mut contact = Contact::new("Alice".to_string(), keys.public_key.clone(), 1_700_000_000);
+        contact.signing_key = Some(
+            BASE64.encode(
+                signing_key_from_identity(&keys.private_key)
+                    .verifying_key()
+                    .as_bytes(),
+            ),
+        );
+        contact
+    }
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let (alice, bob) = (key_pair(), key_pair());
+        let session = Session::new(&bob.public_key).unwrap();
+        let sealed = seal(&offer(), &session, &alice).unwrap();
+
+        let opened = open(&sealed_data(&sealed), &bob).unwrap();
+        assert!(
+            matches!(opened.signal, RtcSignalData::Offer { ref sdp_data } if sdp_data == "AQAE")
+        );
+        assert_eq!(opened.from, alice.public_key);
+        assert_eq!(opened.nonce, session.nonce);
+
+        let contacts = vec![contact_for(&alice)];
+        assert_eq!(opened.sender(&contacts), Ok(&contacts[0]));
+        assert_eq!(
+            opened.check_session(
+                &Session {
+                    peer_key: alice.public_key.clone(),
+                    nonce: session.nonce.clone()
+                },
+                &contacts
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn wrong_recipient_cannot_open() {
+        let (alice, bob, carol) = (key_pair(), key_pair(), key_pair());
+        let sealed = seal(&offer(), &Session::new(&bob.public_key).unwrap(), &alice).unwrap();
+        assert_eq!(
+            open(&sealed_data(&sealed), &carol).err(),
+            Some("This connection code was encrypted for someone else.".to_string())
+        );
+    }
+
+    #[test]
+    fn signal_addressed_to_someone_else_is_rejected() {
+        let (alice, bob, carol) = (key_pair(), key_pair(), key_pair());
+        // Signed for Carol but encrypted to Bob
+        let signed = sign(
+            serde_json::to_string(&offer()).unwrap(),
+            &Session::new(&carol.public_key).unwrap(),
+            &alice,
+        );
+        let sealed = encrypt(&signed, &bob.public_key).unwrap();
+        assert_eq!(
+            open(&sealed_data(&sealed), &bob).err(),
+            Some("This connection code was addressed to someone else.".to_string())
+        );
+    }
+
+    #[test]
+    fn tampered_signal_is_rejected() {
+        let (alice, bob, mallory) = (key_pair(), key_pair(), key_pair());
+        let session = Session::new(&bob.public_key).unwrap();
+        let signed = || sign(serde_json::to_string(&offer()).unwrap(), &session, &alice);
+        let invalid = Some("The connection code's signature is not valid.".to_string());
+
+        let mut changed_signal = signed();
+        changed_signal.signal = serde_json::to_string(&RtcSignalData::Offer {
+            sdp_data: "AQAF".to_string(),
+        })
+        .unwrap();
+        let mut changed_sender = signed();
+        changed_sender.from = mallory.public_key.clone();
+        let mut changed_nonce = signed();
+        changed_nonce.nonce = Session::new(&bob.public_key).unwrap().nonce;
+        // Signed by Mallory but claiming Alice's signing key
+        let mut other_signature = signed();
+        other_signature.signature =
+            sign(other_signature.signal.clone(), &session, &mallory).signature;
+
+        for tampered in [
+            changed_signal,
+            changed_sender,
+            changed_nonce,
+            other_signature,
+        ] {
+            let sealed = encrypt(&tampered, &bob.public_key).unwrap();
+            assert_eq!(open(&sealed_data(&sealed), &bob).err(), invalid);
+        }
+
+        let mut garbage = signed();
+        garbage.signature = "not base64".to_string();
+        let sealed = encrypt(&garbage, &bob.public_key).unwrap();
+        assert!(open(&sealed_data(&sealed), &bob).is_err());
+    }
+
+    #[test]
+    fn signing_key_must_match_the_contact() {
+        let (alice, bob, mallory) = (key_pair(), key_pair(), key_pair());
+        let session = Session::new(&bob.public_key).unwrap();
+        // Mallory signs with their own key while claiming to be Alice
+        let mut signed = sign(serde_json::to_string(&offer()).unwrap(), &session, &mallory);
+        signed.from = alice.public_key.clone();
+        let signing_key = signing_key_from_identity(&mallory.private_key);
+        signed.signature = BASE64.encode(signing_key.sign(&signed.signed_bytes()).to_bytes());
+        let opened = open(
+            &sealed_data(&encrypt(&signed, &bob.public_key).unwrap()),
+            &bob,
+        )
+        .unwrap();
+
+        let expected =
+            Err("The connection code was not signed by Alice's signing key.".to_string());
+        let contacts = vec![contact_for(&alice)];
+        assert_eq!(
+            opened.sender(&contacts),
+            expected.clone().map(|()| &contacts[0])
+        );
+        let reply_session = Session {
+            peer_key: alice.public_key.clone(),
+            nonce: session.nonce.clone(),
+        };
+        assert_eq!(opened.check_session(&reply_session, &contacts), expected);
+
+        // Without a card the key cannot be checked
+        let mut no_card = contacts[0].clone();
+        no_card.signing_key = None;
+        assert_eq!(opened.sender(std::slice::from_ref(&no_card)), Ok(&no_card));
+    }
+
+    #[test]
+    fn offers_only_come_from_contacts() {
+        let (alice, bob) = (key_pair(), key_pair());
+        let sealed = seal(&offer(), &Session::new(&bob.public_key).unwrap(), &alice).unwrap();
+        let opened = open(&sealed_data(&sealed), &bob).unwrap();
+        assert_eq!(
+            opened.sender(&[]),
+            Err("This connection code is not from one of your contacts.".to_string())
+        );
+    }
+
+    #[test]
+    fn reply_must_match_the_session() {
+        let (alice, bob, carol) = (key_pair(), key_pair(), key_pair());
+        let ours = Session::new(&bob.public_key).unwrap();
+        let contacts = vec![contact_for(&bob)];
+        let not_ours = Err(
+            "This connection code is not a reply from the contact you are connecting to."
+                .to_string(),
+        );
+
+        // Bob answers with the nonce of another session
+        let other = Session {
+            peer_key: alice.public_key.clone(),
+            nonce: Session::new(&alice.public_key).unwrap().nonce,
+        };
+        let answer = seal(&offer(), &other, &bob).unwrap();
+        let opened = open(&sealed_data(&answer), &alice).unwrap();
+        assert_eq!(opened.check_session(&ours, &contacts), not_ours);
+
+        // Carol answers with the right nonce
+        let reply = Session {
+            peer_key: alice.public_key.clone(),
+            nonce: ours.nonce.clone(),
+        };
+        let answer = seal(&offer(), &reply, &carol).unwrap();
+        let opened = open(&sealed_data(&answer), &alice).unwrap();
+        assert_eq!(opened.check_session(&ours, &contacts), not_ours);
+
+        let answer = seal(&offer(), &reply, &bob).unwrap();
+        let opened = open(&sealed_data(&answer), &alice).unwrap();
+        assert_eq!(opened.check_session(&ours, &contacts), Ok(()));
+    }
+
+    #[test]
+    fn nested_sealed_signal_is_rejected() {
+        let (alice, bob) = (key_pair(), key_pair());
+        let session = Session::new(&bob.public_key).unwrap();
+        let inner: RtcSignalData =
+            serde_json::from_str(&seal(&offer(), &session, &alice).unwrap()).unwrap();
+        let sealed = seal(&inner, &session, &alice).unwrap();
+        assert_eq!(
+            open(&sealed_data(&sealed), &bob).err(),
+            Some("Invalid signal: nested sealed signal".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_data_is_rejected() {
+        let bob = key_pair();
+        assert!(open("not base64!", &bob).is_err());
+        assert!(open(&BASE64.encode(b"not an age file"), &bob).is_err());
+    }
+}
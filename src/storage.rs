@@ -0,0 +1,441 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use gloo::console;
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{window, IdbDatabase, IdbOpenDbRequest, IdbRequest, IdbTransactionMode, Storage};
+
+const DB_NAME: &str = "qr-encrypt";
+const DB_VERSION: u32 = 1;
+const OBJECT_STORE: &str = "kv";
+
+// localStorage entries written by this module are prefixed so they can be
+// told apart from the legacy plain-text entries.
+const LOCAL_PREFIX: &str = "qre:";
+
+pub trait KeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    async fn set(&self, key: &str, value: &[u8]) -> Result<(), String>;
+    async fn remove(&self, key: &str) -> Result<(), String>;
+    async fn keys(&self) -> Result<Vec<String>, String>;
+    async fn clear(&self) -> Result<(), String>;
+
+    async fn get_string(&self, key: &str) -> Result<Option<String>, String> {
+        match self.get(key).await? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map(Some)
+                .map_err(|_| format!("Stored value for {} is not valid text", key)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_string(&self, key: &str, value: &str) -> Result<(), String> {
+        self.set(key, value.as_bytes()).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, String> {
+        match self.get(key).await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| format!("Failed to parse stored {}: {}", key, e)),
+            None => Ok(None),
+        }
+    }
+}
+
+// localStorage only holds strings, so values are stored as base64.
+#[derive(Clone)]
+pub struct LocalStore {
+    storage: Storage,
+}
+
+impl LocalStore {
+    pub fn open() -> Option<Self> {
+        let storage = window()?.local_storage().ok()??;
+        Some(Self { storage })
+    }
+}
+
+impl KeyValueStore for LocalStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let Some(value) = self
+            .storage
+            .get_item(&format!("{}{}", LOCAL_PREFIX, key))
+            .map_err(js_error)?
+        else {
+            return Ok(None);
+        };
+        BASE64.decode(value).map(Some).map_err(|e| e.to_string())
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.storage
+            .set_item(&format!("{}{}", LOCAL_PREFIX, key), &BASE64.encode(value))
+            .map_err(js_error)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), String> {
+        self.storage
+            .remove_item(&format!("{}{}", LOCAL_PREFIX, key))
+            .map_err(js_error)
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, String> {
+        let length = self.storage.length().map_err(js_error)?;
+        let mut keys = Vec::new();
+        for index in 0..length {
+            if let Some(key) = self.storage.key(index).map_err(js_error)? {
+                if let Some(key) = key.strip_prefix(LOCAL_PREFIX) {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn clear(&self) -> Result<(), String> {
+        for key in self.keys().await? {
+            self.remove(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct IndexedDbStore {
+    db: IdbDatabase,
+}
+
+impl IndexedDbStore {
+    pub async fn open() -> Result<Self, String> {
+        let factory = window()
+            .ok_or("No window")?
+            .indexed_db()
+            .map_err(js_error)?
+            .ok_or("IndexedDB is not available")?;
+        let request = factory
+            .open_with_u32(DB_NAME, DB_VERSION)
+            .map_err(js_error)?;
+
+        let onupgradeneeded = Closure::once(move |event: web_sys::Event| {
+            let Some(request) = event
+                .target()
+                .and_then(|target| target.dyn_into::<IdbOpenDbRequest>().ok())
+            else {
+                return;
+            };
+            if let Ok(db) = request.result() {
+                let db = db.unchecked_into::<IdbDatabase>();
+                if !db.object_store_names().contains(OBJECT_STORE) {
+                    if let Err(e) = db.create_object_store(OBJECT_STORE) {
+                        console::error!(&format!("❌ Failed to create object store: {:?}", e));
+                    }
+                }
+            }
+        });
+        request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
+
+        let db = wait_for(&request).await?;
+        request.set_onupgradeneeded(None);
+        Ok(Self {
+            db: db.unchecked_into(),
+        })
+    }
+
+    fn object_store(&self, mode: IdbTransactionMode) -> Result<web_sys::IdbObjectStore, String> {
+        self.db
+            .transaction_with_str_and_mode(OBJECT_STORE, mode)
+            .and_then(|transaction| transaction.object_store(OBJECT_STORE))
+            .map_err(js_error)
+    }
+}
+
+impl KeyValueStore for IndexedDbStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let request = self
+            .object_store(IdbTransactionMode::Readonly)?
+            .get(&JsValue::from_str(key))
+            .map_err(js_error)?;
+        let value = wait_for(&request).await?;
+        if value.is_undefined() {
+            return Ok(None);
+        }
+        Ok(Some(js_sys::Uint8Array::new(&value).to_vec()))
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        let request = self
+            .object_store(IdbTransactionMode::Readwrite)?
+            .put_with_key(
+                &js_sys::Uint8Array::from(value).into(),
+                &JsValue::from_str(key),
+            )
+            .map_err(js_error)?;
+        wait_for(&request).await.map(|_| ())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), String> {
+        let request = self
+            .object_store(IdbTransactionMode::Readwrite)?
+            .delete(&JsValue::from_str(key))
+            .map_err(js_error)?;
+        wait_for(&request).await.map(|_| ())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, String> {
+        let request = self
+            .object_store(IdbTransactionMode::Readonly)?
+            .get_all_keys()
+            .map_err(js_error)?;
+        let keys = wait_for(&request).await?;
+        Ok(js_sys::Array::from(&keys)
+            .iter()
+            .filter_map(|key| key.as_string())
+            .collect())
+    }
+
+    async fn clear(&self) -> Result<(), String> {
+        let request = self
+            .object_store(IdbTransactionMode::Readwrite)?
+            .clear()
+            .map_err(js_error)?;
+        wait_for(&request).await.map(|_| ())
+    }
+}
+
+// Used when neither IndexedDB nor localStorage is available (e.g. some
+// private browsing modes). Nothing survives a reload.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    entries: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+impl KeyValueStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.entries.borrow().get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.entries
+            .borrow_mut()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), String> {
+        self.entries.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, String> {
+        Ok(self.entries.borrow().keys().cloned().collect())
+    }
+
+    async fn clear(&self) -> Result<(), String> {
+        self.entries.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+// `async fn` in traits is not object safe, so the backend in use is picked
+// at runtime through this enum.
+#[derive(Clone)]
+pub enum Store {
+    IndexedDb(IndexedDbStore),
+    Local(LocalStore),
+    Memory(MemoryStore),
+}
+
+impl Store {
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            Store::IndexedDb(_) => "IndexedDB",
+            Store::Local(_) => "localStorage",
+            Store::Memory(_) => "memory",
+        }
+    }
+}
+
+impl KeyValueStore for Store {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Store::IndexedDb(store) => store.get(key).await,
+            Store::Local(store) => store.get(key).await,
+            Store::Memory(store) => store.get(key).await,
+        }
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        match self {
+            Store::IndexedDb(store) => store.set(key, value).await,
+            Store::Local(store) => store.set(key, value).await,
+            Store::Memory(store) => store.set(key, value).await,
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), String> {
+        match self {
+            Store::IndexedDb(store) => store.remove(key).await,
+            Store::Local(store) => store.remove(key).await,
+            Store::Memory(store) => store.remove(key).await,
+        }
+    }
+
+    async fn keys(&self) -> Result<Vec<String>, String> {
+        match self {
+            Store::IndexedDb(store) => store.keys().await,
+            Store::Local(store) => store.keys().await,
+            Store::Memory(store) => store.keys().await,
+        }
+    }
+
+    async fn clear(&self) -> Result<(), String> {
+        match self {
+            Store::IndexedDb(store) => store.clear().await,
+            Store::Local(store) => store.clear().await,
+            Store::Memory(store) => store.clear().await,
+        }
+    }
+}
+
+thread_local! {
+    static STORE: RefCell<Option<Store>> = const { RefCell::new(None) };
+}
+
+// Opens the preferred backend once and hands out clones of it afterwards.
+pub async fn store() -> Store {
+    if let Some(store) = STORE.with(|store| store.borrow().clone()) {
+        return store;
+    }
+    let store = open().await;
+    STORE.with(|cell| *cell.borrow_mut() = Some(store.clone()));
+    store
+}
+
+async fn open() -> Store {
+    let store = match IndexedDbStore::open().await {
+        Ok(store) => Store::IndexedDb(store),
+        Err(e) => {
+            console::warn!(&format!("⚠️ IndexedDB unavailable: {}", e));
+            match LocalStore::open() {
+                Some(store) => Store::Local(store),
+                None => Store::Memory(MemoryStore::default()),
+            }
+        }
+    };
+    console::log!(&format!("💾 Storage backend: {}", store.backend_name()));
+    store
+}
+
+// Tests run outside the browser, so they install a fresh in-memory backend
+#[cfg(test)]
+pub fn use_memory_store() -> MemoryStore {
+    let store = MemoryStore::default();
+    STORE.with(|cell| *cell.borrow_mut() = Some(Store::Memory(store.clone())));
+    store
+}
+
+// Resolves with the request result once the request succeeds.
+async fn wait_for(request: &IdbRequest) -> Result<JsValue, String> {
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    let result = JsFuture::from(promise).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    match result {
+        Ok(_) => request.result().map_err(js_error),
+        Err(_) => Err(match request.error() {
+            Ok(Some(e)) => e.message(),
+            _ => "IndexedDB request failed".to_string(),
+        }),
+    }
+}
+
+pub fn js_error(e: JsValue) -> String {
+    e.as_string().unwrap_or_else(|| format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn memory_store_set_get_remove() {
+        block_on(async {
+            let store = MemoryStore::default();
+            assert_eq!(store.get("a").await, Ok(None));
+            store.set("a", &[1, 2, 3]).await.unwrap();
+            assert_eq!(store.get("a").await, Ok(Some(vec![1, 2, 3])));
+            store.set("a", &[4]).await.unwrap();
+            assert_eq!(store.get("a").await, Ok(Some(vec![4])));
+            store.remove("a").await.unwrap();
+            assert_eq!(store.get("a").await, Ok(None));
+            // Removing a missing key is not an error
+            assert_eq!(store.remove("a").await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn memory_store_keys_and_clear() {
+        block_on(async {
+            let store = MemoryStore::default();
+            store.set("a", b"1").await.unwrap();
+            store.set("vault:b", b"2").await.unwrap();
+            let mut keys = store.keys().await.unwrap();
+            keys.sort();
+            assert_eq!(keys, vec!["a".to_string(), "vault:b".to_string()]);
+            store.clear().await.unwrap();
+            assert_eq!(store.keys().await, Ok(Vec::new()));
+        });
+    }
+
+    #[test]
+    fn clones_share_entries() {
+        block_on(async {
+            let store = MemoryStore::default();
+            let clone = store.clone();
+            clone.set("a", b"1").await.unwrap();
+            assert_eq!(store.get("a").await, Ok(Some(b"1".to_vec())));
+        });
+    }
+
+    #[test]
+    fn string_and_json_helpers() {
+        block_on(async {
+            let store = Store::Memory(MemoryStore::default());
+            store.set_string("text", "héllo").await.unwrap();
+            assert_eq!(
+                store.get_string("text").await,
+                Ok(Some("héllo".to_string()))
+            );
+            assert_eq!(store.get_string("missing").await, Ok(None));
+
+            store.set("binary", &[0xff, 0xfe]).await.unwrap();
+            assert!(store.get_string("binary").await.is_err());
+
+            store.set_string("json", r#"{"a":1}"#).await.unwrap();
+            let value: Option<HashMap<String, u32>> = store.get_json("json").await.unwrap();
+            assert_eq!(value.unwrap()["a"], 1);
+            assert!(store.get_json::<Vec<u32>>("text").await.is_err());
+        });
+    }
+
+    #[test]
+    fn store_returns_the_installed_backend() {
+        block_on(async {
+            let memory = use_memory_store();
+            memory.set("a", b"1").await.unwrap();
+            let store = store().await;
+            assert_eq!(store.backend_name(), "memory");
+            assert_eq!(store.get("a").await, Ok(Some(b"1".to_vec())));
+        });
+    }
+}
@@ -0,0 +1,238 @@
+use crate::new_id;
+use gloo::console;
+use js_sys::Date;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{window, BroadcastChannel, MessageEvent};
+
+const CHANNEL_NAME: &str = "qr-encrypt-sync";
+const HEARTBEAT_MS: i32 = 2000;
+// A tab that has not been heard from for this long is treated as closed
+const PEER_TIMEOUT_MS: f64 = 6000.0;
+// How long a starting tab listens for other tabs before choosing a leader
+const DISCOVERY_MS: i32 = 300;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct TabInfo {
+    id: String,
+    started_at: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+enum SyncMessage {
+    // Sent on start; every other tab answers with Present
+    Hello { tab: TabInfo },
+    Present { tab: TabInfo },
+    Goodbye { id: String },
+    Changed { change: SyncEvent },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncEvent {
+    KeysChanged,
+    VaultChanged,
+    MessageLogChanged,
+    // The previous leader went away and this tab took over
+    BecameLeader,
+}
+
+struct TabSync {
+    channel: BroadcastChannel,
+    me: TabInfo,
+    // Other tabs and when they were last heard from
+    peers: Vec<(TabInfo, f64)>,
+    was_leader: bool,
+    handler: Rc<dyn Fn(SyncEvent)>,
+}
+
+thread_local! {
+    static SYNC: RefCell<Option<TabSync>> = const { RefCell::new(None) };
+}
+
+// Joins the channel shared by all tabs of this origin. Without
+// BroadcastChannel support the tab simply runs on its own.
+pub fn start(handler: impl Fn(SyncEvent) + 'static) {
+    let channel = match BroadcastChannel::new(CHANNEL_NAME) {
+        Ok(channel) => channel,
+        Err(e) => {
+            console::warn!(&format!("⚠️ BroadcastChannel unavailable: {:?}", e));
+            return;
+        }
+    };
+    let me = TabInfo {
+        id: new_id(),
+        started_at: Date::now(),
+    };
+
+    let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let Some(message) = event
+            .data()
+            .as_string()
+            .and_then(|data| serde_json::from_str::<SyncMessage>(&data).ok())
+        else {
+            return;
+        };
+        handle_message(message);
+    }) as Box<dyn FnMut(MessageEvent)>);
+    channel.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+
+    SYNC.with(|sync| {
+        *sync.borrow_mut() = Some(TabSync {
+            channel,
+            me: me.clone(),
+            peers: Vec::new(),
+            was_leader: false,
+            handler: Rc::new(handler),
+        })
+    });
+    post(&SyncMessage::Hello { tab: me });
+
+    if let Some(window) = window() {
+        let heartbeat = Closure::wrap(Box::new(heartbeat) as Box<dyn FnMut()>);
+        let _ = window.set_interval_with_callback_and_timeout_and_arguments_0(
+            heartbeat.as_ref().unchecked_ref(),
+            HEARTBEAT_MS,
+        );
+        heartbeat.forget();
+
+        let pagehide = Closure::wrap(Box::new(move |_event: web_sys::Event| {
+            if let Some(id) = SYNC.with(|sync| sync.borrow().as_ref().map(|s| s.me.id.clone())) {
+                post(&SyncMessage::Goodbye { id });
+            }
+        }) as Box<dyn FnMut(web_sys::Event)>);
+        let _ =
+            window.add_event_listener_with_callback("pagehide", pagehide.as_ref().unchecked_ref());
+        pagehide.forget();
+    }
+}
+
+// Tells the other tabs that stored data has changed
+pub fn notify(event: SyncEvent) {
+    post(&SyncMessage::Changed { change: event });
+}
+
+// Waits until the tabs that were already open have had a chance to answer
+pub async fn discover() {
+    let promise = js_sys::Promise::new(&mut |resolve, _| match window() {
+        Some(window) => {
+            let _ = window
+                .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, DISCOVERY_MS);
+        }
+        None => {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    });
+    let _ = JsFuture::from(promise).await;
+}
+
+// The oldest open tab leads; ties are broken by id
+pub fn is_leader() -> bool {
+    SYNC.with(|sync| {
+        let sync = sync.borrow();
+        let Some(sync) = sync.as_ref() else {
+            return true;
+        };
+        sync.peers
+            .iter()
+            .all(|(peer, _)| (sync.me.started_at, &sync.me.id) < (peer.started_at, &peer.id))
+    })
+}
+
+fn handle_message(message: SyncMessage) {
+    let now = Date::now();
+    let event = SYNC.with(|sync| {
+        let mut sync = sync.borrow_mut();
+        let sync = sync.as_mut()?;
+        match message {
+            SyncMessage::Hello { tab } => {
+                seen(&mut sync.peers, tab, now);
+                post_on(
+                    &sync.channel,
+                    &SyncMessage::Present {
+                        tab: sync.me.clone(),
+                    },
+                );
+                None
+            }
+            SyncMessage::Present { tab } => {
+                seen(&mut sync.peers, tab, now);
+                None
+            }
+            SyncMessage::Goodbye { id } => {
+                sync.peers.retain(|(peer, _)| peer.id != id);
+                None
+            }
+            SyncMessage::Changed { change } => Some((change, sync.handler.clone())),
+        }
+    });
+    if let Some((event, handler)) = event {
+        handler(event);
+    }
+    check_leadership();
+}
+
+fn heartbeat() {
+    let now = Date::now();
+    SYNC.with(|sync| {
+        if let Some(sync) = sync.borrow_mut().as_mut() {
+            sync.peers
+                .retain(|(_, last_seen)| now - last_seen < PEER_TIMEOUT_MS);
+            post_on(
+                &sync.channel,
+                &SyncMessage::Present {
+                    tab: sync.me.clone(),
+                },
+            );
+        }
+    });
+    check_leadership();
+}
+
+fn check_leadership() {
+    let is_leader = is_leader();
+    let handler = SYNC.with(|sync| {
+        let mut sync = sync.borrow_mut();
+        let sync = sync.as_mut()?;
+        let became_leader = is_leader && !sync.was_leader;
+        sync.was_leader = is_leader;
+        became_leader.then(|| sync.handler.clone())
+    });
+    if let Some(handler) = handler {
+        console::log!("👑 This tab is now the leader");
+        handler(SyncEvent::BecameLeader);
+    }
+}
+
+fn seen(peers: &mut Vec<(TabInfo, f64)>, tab: TabInfo, now: f64) {
+    match peers.iter_mut().find(|(peer, _)| peer.id == tab.id) {
+        Some((_, last_seen)) => *last_seen = now,
+        None => peers.push((tab, now)),
+    }
+}
+
+fn post(message: &SyncMessage) {
+    SYNC.with(|sync| {
+        if let Some(sync) = sync.borrow().as_ref() {
+            post_on(&sync.channel, message);
+        }
+    });
+}
+
+fn post_on(channel: &BroadcastChannel, message: &SyncMessage) {
+    match serde_json::to_string(message) {
+        Ok(json) => {
+            if let Err(e) = channel.post_message(&JsValue::from_str(&json)) {
+                console::error!(&format!("❌ Failed to post sync message: {:?}", e));
+            }
+        }
+        Err(e) => console::error!(&format!("❌ Failed to serialize sync message: {}", e)),
+    }
+}
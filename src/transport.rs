@@ -0,0 +1,110 @@
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine as _,
+};
+
+// Base45 payloads are marked with this prefix. It only uses characters from
+// the QR alphanumeric set, so the whole payload stays in alphanumeric mode.
+pub const BASE45_PREFIX: &str = "QE45:";
+
+const AGE_HEADER: &[u8] = b"age-encryption.org/";
+
+const QR_ALPHANUMERIC: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+pub fn is_qr_alphanumeric(data: &str) -> bool {
+    data.bytes().all(|b| QR_ALPHANUMERIC.contains(&b))
+}
+
+pub fn is_base45(data: &str) -> bool {
+    data.starts_with(BASE45_PREFIX)
+}
+
+// Base64 ciphertexts are re-encoded from their binary form, anything else
+// (e.g. RTC signal JSON) is encoded from its UTF-8 bytes.
+pub fn encode_base45(payload: &str) -> String {
+    if is_base45(payload) {
+        return payload.to_string();
+    }
+    let bytes = match BASE64.decode(payload) {
+        Ok(bytes) if bytes.starts_with(AGE_HEADER) => bytes,
+        _ => payload.as_bytes().to_vec(),
+    };
+    format!("{}{}", BASE45_PREFIX, base45::encode(bytes))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkKind {
+    PublicKey,
+    Card,
+    Message,
+    Rtc,
+}
+
+impl LinkKind {
+    fn key(&self) -> &'static str {
+        match self {
+            LinkKind::PublicKey => "pk",
+            LinkKind::Card => "card",
+            LinkKind::Message => "msg",
+            LinkKind::Rtc => "rtc",
+        }
+    }
+}
+
+// Wraps a payload in a URI such as `https://<host>/#msg=...` so that a
+// native camera app opens the application directly.
+pub fn encode_deep_link(base_url: &str, kind: LinkKind, payload: &str) -> String {
+    let value = match kind {
+        LinkKind::PublicKey => payload.to_string(),
+        LinkKind::Message => match BASE64.decode(payload) {
+            Ok(bytes) => BASE64_URL.encode(bytes),
+            Err(_) => BASE64_URL.encode(payload.as_bytes()),
+        },
+        LinkKind::Card | LinkKind::Rtc => BASE64_URL.encode(payload.as_bytes()),
+    };
+    format!("{}#{}={}", base_url, kind.key(), value)
+}
+
+// Accepts either a full URL or a bare `#key=value` fragment. Returns None
+// when the data is not a deep link.
+pub fn decode_deep_link(data: &str) -> Option<Result<String, String>> {
+    let fragment = if let Some(fragment) = data.strip_prefix('#') {
+        fragment
+    } else if data.starts_with("https://") || data.starts_with("http://") {
+        data.split_once('#')?.1
+    } else {
+        return None;
+    };
+    let (key, value) = fragment.split_once('=')?;
+
+    let decode = |value: &str| {
+        BASE64_URL
+            .decode(value)
+            .map_err(|e| format!("Invalid link payload: {}", e))
+    };
+    let result = match key {
+        "pk" => Ok(value.to_string()),
+        "msg" => decode(value).map(|bytes| BASE64.encode(bytes)),
+        "card" | "rtc" => decode(value).and_then(|bytes| {
+            String::from_utf8(bytes).map_err(|_| "Link payload is not valid text".to_string())
+        }),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// Restores the canonical text form of a scanned payload. Data that is
+// neither a deep link nor Base45 is returned unchanged.
+pub fn decode_payload(data: &str) -> Result<String, String> {
+    if let Some(result) = decode_deep_link(data) {
+        return result;
+    }
+    let Some(encoded) = data.strip_prefix(BASE45_PREFIX) else {
+        return Ok(data.to_string());
+    };
+    let bytes = base45::decode(encoded).map_err(|e| format!("Invalid Base45 payload: {:?}", e))?;
+    if bytes.starts_with(AGE_HEADER) {
+        return Ok(BASE64.encode(&bytes));
+    }
+    String::from_utf8(bytes).map_err(|_| "Base45 payload is not valid text".to_string())
+}
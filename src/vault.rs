@@ -0,0 +1,367 @@
+use crate::groups::ContactGroup;
+use crate::history::ChatIndex;
+use crate::qr::QrSettings;
+use crate::rtc::IceSettings;
+use crate::storage::{self, KeyValueStore};
+use crate::sync::{self, SyncEvent};
+use crate::{Contact, Profile};
+use age::{x25519, Decryptor, Encryptor};
+use gloo::console;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const VAULT_KEY: &str = "vault";
+
+// The only plaintext stored with the vault. Everything after it is an age
+// file encrypted to our own X25519 identity.
+const VAULT_HEADER: &[u8] = b"qr-encrypt-vault/v1\n";
+
+// Larger data such as chat history is kept in separate entries under this
+// prefix so the vault itself stays small. Records are encrypted the same way.
+const RECORD_PREFIX: &str = "vault:";
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct VaultData {
+    pub contacts: Vec<Contact>,
+    pub groups: Vec<ContactGroup>,
+    pub qr_settings: QrSettings,
+    pub ice_settings: IceSettings,
+    pub profile: Profile,
+    // Conversations by peer public key
+    pub chats: HashMap<String, ChatIndex>,
+}
+
+struct Vault {
+    identity: x25519::Identity,
+    data: VaultData,
+}
+
+thread_local! {
+    static VAULT: RefCell<Option<Vault>> = const { RefCell::new(None) };
+}
+
+// Decrypts the vault into memory. Format changes are handled by the
+// migrations module beforehand, so a vault that cannot be read here is left
+// untouched and stays locked, and nothing overwrites it afterwards.
+pub async fn unlock(private_key: &str) -> Result<(), String> {
+    let identity = parse_identity(private_key)?;
+    let data = read(&identity).await?.unwrap_or_default();
+    VAULT.with(|vault| *vault.borrow_mut() = Some(Vault { identity, data }));
+    console::log!("🔓 Vault unlocked");
+    Ok(())
+}
+
+// Forgets the decrypted data and the identity
+pub fn lock() {
+    VAULT.with(|vault| *vault.borrow_mut() = None);
+    console::log!("🔒 Vault locked");
+}
+
+// Picks up changes saved by another tab
+pub async fn reload() -> Result<(), String> {
+    let identity = identity().ok_or("Vault is locked")?;
+    let data = read(&identity).await?.unwrap_or_default();
+    VAULT.with(|vault| {
+        if let Some(vault) = vault.borrow_mut().as_mut() {
+            vault.data = data;
+        }
+    });
+    Ok(())
+}
+
+// Reads the vault without unlocking it. Migrations use this with
+// `serde_json::Value` to rewrite older layouts.
+pub async fn read<T: DeserializeOwned>(identity: &x25519::Identity) -> Result<Option<T>, String> {
+    let Some(bytes) = storage::store().await.get(VAULT_KEY).await? else {
+        return Ok(None);
+    };
+    let json = decrypt(identity, &bytes)?;
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse vault: {}", e))
+}
+
+pub async fn write<T: Serialize>(identity: &x25519::Identity, data: &T) -> Result<(), String> {
+    let bytes = encrypt(identity, data)?;
+    storage::store().await.set(VAULT_KEY, &bytes).await
+}
+
+// Re-encrypts the vault and its records after our identity has changed.
+// Everything is re-encrypted in memory first, and the vault is saved under
+// the new identity only once every record has been written, so a failure
+// leaves all data readable with the previous key.
+pub async fn rekey(private_key: &str) -> Result<(), String> {
+    let identity = parse_identity(private_key)?;
+    let previous = self::identity().ok_or("Vault is locked")?;
+
+    // (key, current bytes, re-encrypted bytes)
+    let store = storage::store().await;
+    let mut records = Vec::new();
+    for key in store.keys().await? {
+        if !key.starts_with(RECORD_PREFIX) {
+            continue;
+        }
+        let Some(bytes) = store.get(&key).await? else {
+            continue;
+        };
+        let json = decrypt(&previous, &bytes).map_err(|e| format!("{}: {}", key, e))?;
+        let sealed = seal(&identity, &json)?;
+        records.push((key, bytes, sealed));
+    }
+
+    for (written, (key, _, sealed)) in records.iter().enumerate() {
+        if let Err(e) = store.set(key, sealed).await {
+            restore_records(&store, &records[..written]).await;
+            return Err(e);
+        }
+    }
+
+    let swap = |identity: x25519::Identity| {
+        VAULT.with(|vault| {
+            if let Some(vault) = vault.borrow_mut().as_mut() {
+                vault.identity = identity;
+            }
+        })
+    };
+    swap(identity);
+    if let Err(e) = save().await {
+        swap(previous);
+        restore_records(&store, &records).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Puts back records that a failed rekey had already re-encrypted
+async fn restore_records(store: &storage::Store, records: &[(String, Vec<u8>, Vec<u8>)]) {
+    for (key, bytes, _) in records {
+        if let Err(e) = store.set(key, bytes).await {
+            console::error!(&format!("❌ Failed to restore {}: {}", key, e));
+        }
+    }
+}
+
+pub async fn read_record<T: DeserializeOwned>(name: &str) -> Result<Option<T>, String> {
+    let identity = identity().ok_or("Vault is locked")?;
+    let key = format!("{}{}", RECORD_PREFIX, name);
+    let Some(bytes) = storage::store().await.get(&key).await? else {
+        return Ok(None);
+    };
+    let json = decrypt(&identity, &bytes)?;
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse {}: {}", name, e))
+}
+
+pub async fn write_record<T: Serialize>(name: &str, data: &T) -> Result<(), String> {
+    let identity = identity().ok_or("Vault is locked")?;
+    let bytes = encrypt(&identity, data)?;
+    storage::store()
+        .await
+        .set(&format!("{}{}", RECORD_PREFIX, name), &bytes)
+        .await
+}
+
+pub async fn remove_record(name: &str) -> Result<(), String> {
+    storage::store()
+        .await
+        .remove(&format!("{}{}", RECORD_PREFIX, name))
+        .await
+}
+
+fn identity() -> Option<x25519::Identity> {
+    VAULT.with(|vault| vault.borrow().as_ref().map(|vault| vault.identity.clone()))
+}
+
+pub fn data() -> Option<VaultData> {
+    VAULT.with(|vault| vault.borrow().as_ref().map(|vault| vault.data.clone()))
+}
+
+pub async fn update(f: impl FnOnce(&mut VaultData)) -> Result<(), String> {
+    let updated = VAULT.with(|vault| match vault.borrow_mut().as_mut() {
+        Some(vault) => {
+            f(&mut vault.data);
+            true
+        }
+        None => false,
+    });
+    if !updated {
+        return Err("Vault is locked".to_string());
+    }
+    save().await
+}
+
+async fn save() -> Result<(), String> {
+    let bytes = VAULT
+        .with(|vault| {
+            vault
+                .borrow()
+                .as_ref()
+                .map(|vault| encrypt(&vault.identity, &vault.data))
+        })
+        .ok_or("Vault is locked")??;
+    storage::store().await.set(VAULT_KEY, &bytes).await?;
+    sync::notify(SyncEvent::VaultChanged);
+    Ok(())
+}
+
+pub fn parse_identity(private_key: &str) -> Result<x25519::Identity, String> {
+    private_key
+        .trim()
+        .parse::<x25519::Identity>()
+        .map_err(|e| format!("Invalid private key: {}", e))
+}
+
+fn encrypt<T: Serialize>(identity: &x25519::Identity, data: &T) -> Result<Vec<u8>, String> {
+    let json = serde_json::to_vec(data).map_err(|e| e.to_string())?;
+    seal(identity, &json)
+}
+
+fn seal(identity: &x25519::Identity, json: &[u8]) -> Result<Vec<u8>, String> {
+    let recipient = identity.to_public();
+    let encryptor = Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+        .map_err(|e| format!("Failed to create vault encryptor: {}", e))?;
+
+    let mut bytes = VAULT_HEADER.to_vec();
+    let mut writer = encryptor
+        .wrap_output(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    writer.write_all(json).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+fn decrypt(identity: &x25519::Identity, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let encrypted = bytes
+        .strip_prefix(VAULT_HEADER)
+        .ok_or("Unsupported vault format")?;
+    let decryptor =
+        Decryptor::new(encrypted).map_err(|e| format!("Failed to read vault: {}", e))?;
+    let mut reader = decryptor
+        .decrypt(std::iter::once(identity as &dyn age::Identity))
+        .map_err(|e| format!("Failed to decrypt vault: {}", e))?;
+
+    let mut json = Vec::new();
+    reader.read_to_end(&mut json).map_err(|e| e.to_string())?;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use secrecy::ExposeSecret;
+
+    fn unlock_with(identity: &x25519::Identity) {
+        VAULT.with(|vault| {
+            *vault.borrow_mut() = Some(Vault {
+                identity: identity.clone(),
+                data: VaultData::default(),
+            })
+        });
+    }
+
+    #[test]
+    fn record_round_trip() {
+        block_on(async {
+            let store = storage::use_memory_store();
+            unlock_with(&x25519::Identity::generate());
+
+            assert_eq!(read_record::<Vec<String>>("notes").await, Ok(None));
+            let notes = vec!["secret note".to_string()];
+            write_record("notes", &notes).await.unwrap();
+            assert_eq!(read_record::<Vec<String>>("notes").await, Ok(Some(notes)));
+
+            // Stored encrypted under the record prefix
+            let bytes = store.get("vault:notes").await.unwrap().unwrap();
+            assert!(bytes.starts_with(VAULT_HEADER));
+            assert!(!String::from_utf8_lossy(&bytes).contains("secret note"));
+
+            remove_record("notes").await.unwrap();
+            assert_eq!(read_record::<Vec<String>>("notes").await, Ok(None));
+        });
+    }
+
+    #[test]
+    fn records_need_the_same_identity() {
+        block_on(async {
+            storage::use_memory_store();
+            unlock_with(&x25519::Identity::generate());
+            write_record("notes", &"secret").await.unwrap();
+
+            unlock_with(&x25519::Identity::generate());
+            assert!(read_record::<String>("notes").await.is_err());
+
+            VAULT.with(|vault| *vault.borrow_mut() = None);
+            assert!(read_record::<String>("notes").await.is_err());
+        });
+    }
+
+    #[test]
+    fn vault_round_trip() {
+        block_on(async {
+            storage::use_memory_store();
+            let identity = x25519::Identity::generate();
+            let mut data = VaultData::default();
+            data.profile.display_name = "Alice".to_string();
+            write(&identity, &data).await.unwrap();
+
+            let read_back = read::<VaultData>(&identity).await.unwrap().unwrap();
+            assert_eq!(read_back.profile.display_name, "Alice");
+            assert!(read::<VaultData>(&x25519::Identity::generate())
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn rekey_moves_everything_to_the_new_identity() {
+        block_on(async {
+            storage::use_memory_store();
+            let previous = x25519::Identity::generate();
+            let next = x25519::Identity::generate();
+            unlock_with(&previous);
+            update(|data| data.profile.display_name = "Alice".to_string())
+                .await
+                .unwrap();
+            write_record("a", &1).await.unwrap();
+            write_record("b", &2).await.unwrap();
+
+            rekey(next.to_string().expose_secret()).await.unwrap();
+
+            assert_eq!(read_record::<u32>("a").await, Ok(Some(1)));
+            assert_eq!(read_record::<u32>("b").await, Ok(Some(2)));
+            let data = read::<VaultData>(&next).await.unwrap().unwrap();
+            assert_eq!(data.profile.display_name, "Alice");
+            assert!(read::<VaultData>(&previous).await.is_err());
+        });
+    }
+
+    #[test]
+    fn failed_rekey_keeps_the_previous_identity() {
+        block_on(async {
+            let store = storage::use_memory_store();
+            let previous = x25519::Identity::generate();
+            let next = x25519::Identity::generate();
+            unlock_with(&previous);
+            update(|data| data.profile.display_name = "Alice".to_string())
+                .await
+                .unwrap();
+            write_record("a", &1).await.unwrap();
+            // A record that cannot be decrypted stops the rekey
+            store
+                .set("vault:broken", &seal(&next, b"1").unwrap())
+                .await
+                .unwrap();
+
+            assert!(rekey(next.to_string().expose_secret()).await.is_err());
+
+            assert_eq!(read_record::<u32>("a").await, Ok(Some(1)));
+            let data = read::<VaultData>(&previous).await.unwrap().unwrap();
+            assert_eq!(data.profile.display_name, "Alice");
+        });
+    }
+}
@@ -1,17 +1,25 @@
 #![no_main]
 
+#[allow(dead_code)]
+mod card;
 mod common;
 use common::*;
+#[allow(dead_code)]
+mod transport;
 
 use age::{x25519, Decryptor, Encryptor};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use gloo::console;
-use secrecy::ExposeSecret;
+use secrecy::{ExposeSecret, SecretString};
+use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
 use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
 
+// パスフレーズ暗号化（バックアップと鍵の保護）のscrypt作業係数 (N = 2^18)
+const PASSPHRASE_WORK_FACTOR: u8 = 18;
+
 fn error_report(message: &str) {
     let global = js_sys::global().unchecked_into::<DedicatedWorkerGlobalScope>();
     global
@@ -71,9 +79,9 @@ pub fn worker_main() {
                                 }
                             }
                         }
-                        MainMessage::Encrypt { public_key, data } => {
+                        MainMessage::Encrypt { public_keys, data } => {
                             console::log!("🔧 Encrypting message");
-                            match encrypt_message(&public_key, &data) {
+                            match encrypt_message(&public_keys, &data) {
                                 Ok(encrypted) => {
                                     match serde_wasm_bindgen::to_value(&WorkerMessage::Encrypted {
                                         encrypted_data: encrypted,
@@ -147,7 +155,10 @@ pub fn worker_main() {
                             private_key,
                         } => {
                             console::log!("🔧 Exporting private key");
-                            match encrypt_message(&recipient_public_key, &private_key) {
+                            match encrypt_message(
+                                std::slice::from_ref(&recipient_public_key),
+                                &private_key,
+                            ) {
                                 Ok(encrypted_private_key) => {
                                     match serde_wasm_bindgen::to_value(
                                         &WorkerMessage::PrivateKeyExported {
@@ -252,6 +263,281 @@ pub fn worker_main() {
                                 }
                             }
                         }
+                        MainMessage::DecodeQrImage { image } => {
+                            console::log!("🔧 Decoding QR code from image");
+                            match decode_qr_image(&image) {
+                                Ok(data) => {
+                                    match serde_wasm_bindgen::to_value(
+                                        &WorkerMessage::QrImageDecoded { data },
+                                    ) {
+                                        Ok(message) => {
+                                            console::log!(
+                                                "🔧 Sending decoded QR data to main thread"
+                                            );
+                                            if let Err(e) = global_inner.post_message(&message) {
+                                                error_report(&format!(
+                                                    "❌ Error posting decoded QR data: {:?}",
+                                                    e
+                                                ));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error_report(&format!(
+                                                "❌ Error serializing decoded QR data: {:?}",
+                                                e
+                                            ));
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!("❌ Error decoding QR image: {}", e));
+                                }
+                            }
+                        }
+                        MainMessage::EncryptBackup { passphrase, data } => {
+                            console::log!("🔧 Encrypting backup");
+                            let response = match encrypt_with_passphrase(&passphrase, &data) {
+                                Ok(data) => WorkerMessage::BackupEncrypted { data },
+                                Err(e) => WorkerMessage::BackupFailed {
+                                    message: format!("Failed to encrypt backup: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting encrypted backup: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing encrypted backup: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::DecryptBackup { passphrase, data } => {
+                            console::log!("🔧 Decrypting backup");
+                            let response = match decrypt_with_passphrase(&passphrase, &data) {
+                                Ok(data) => WorkerMessage::BackupDecrypted { data },
+                                Err(e) => WorkerMessage::BackupFailed {
+                                    message: format!("Failed to decrypt backup: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting decrypted backup: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing decrypted backup: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::ProtectKey {
+                            passphrase,
+                            private_key,
+                        } => {
+                            console::log!("🔧 Protecting private key");
+                            let response = match encrypt_with_passphrase(&passphrase, &private_key)
+                            {
+                                Ok(data) => WorkerMessage::KeyProtected { data },
+                                Err(e) => WorkerMessage::KeyUnlockFailed {
+                                    message: format!("Failed to protect private key: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting protected key: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing protected key: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::UnlockKey { passphrase, data } => {
+                            console::log!("🔧 Unlocking private key");
+                            // 間違ったパスフレーズは通常のエラーとして返す
+                            let response = match decrypt_with_passphrase(&passphrase, &data) {
+                                Ok(private_key) => WorkerMessage::KeyUnlocked { private_key },
+                                Err(_) => WorkerMessage::KeyUnlockFailed {
+                                    message: "Wrong passphrase".to_string(),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting unlocked key: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing unlocked key: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::EncryptChat {
+                            peer,
+                            public_key,
+                            data,
+                        } => {
+                            console::log!("🔧 Encrypting chat message");
+                            let response = match encrypt_message(&[public_key], &data) {
+                                Ok(encrypted_data) => WorkerMessage::ChatEncrypted {
+                                    peer,
+                                    encrypted_data,
+                                },
+                                Err(e) => WorkerMessage::Error {
+                                    message: format!("❌ Error encrypting chat message: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting encrypted chat message: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing encrypted chat message: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::DecryptChat {
+                            peer,
+                            private_key,
+                            data,
+                        } => {
+                            console::log!("🔧 Decrypting chat message");
+                            let response = match decrypt_message(&private_key, &data) {
+                                Ok(Some(decrypted_data)) => WorkerMessage::ChatDecrypted {
+                                    peer,
+                                    decrypted_data,
+                                },
+                                Ok(None) => WorkerMessage::Error {
+                                    message: "❌ Error decrypting chat message".to_string(),
+                                },
+                                Err(e) => WorkerMessage::Error {
+                                    message: format!("❌ Error decrypting chat message: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting decrypted chat message: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing decrypted chat message: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::EncryptFile {
+                            id,
+                            public_key,
+                            data,
+                        } => {
+                            console::log!("🔧 Encrypting file");
+                            let response = match encrypt_file(&public_key, &data) {
+                                Ok(data) => WorkerMessage::FileEncrypted {
+                                    id,
+                                    sha256: BASE64.encode(Sha256::digest(&data)),
+                                    data,
+                                },
+                                Err(e) => WorkerMessage::FileFailed {
+                                    id,
+                                    message: format!("Failed to encrypt file: {}", e),
+                                },
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting encrypted file: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing encrypted file: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
+                        MainMessage::DecryptFile {
+                            id,
+                            private_key,
+                            sha256,
+                            data,
+                        } => {
+                            console::log!("🔧 Decrypting file");
+                            let response = if BASE64.encode(Sha256::digest(&data)) != sha256 {
+                                WorkerMessage::FileFailed {
+                                    id,
+                                    message: "The received file is corrupted".to_string(),
+                                }
+                            } else {
+                                match decrypt_file(&private_key, &data) {
+                                    Ok(data) => WorkerMessage::FileDecrypted { id, data },
+                                    Err(e) => WorkerMessage::FileFailed {
+                                        id,
+                                        message: format!("Failed to decrypt file: {}", e),
+                                    },
+                                }
+                            };
+                            match serde_wasm_bindgen::to_value(&response) {
+                                Ok(message) => {
+                                    if let Err(e) = global_inner.post_message(&message) {
+                                        error_report(&format!(
+                                            "❌ Error posting decrypted file: {:?}",
+                                            e
+                                        ));
+                                    }
+                                }
+                                Err(e) => {
+                                    error_report(&format!(
+                                        "❌ Error serializing decrypted file: {:?}",
+                                        e
+                                    ));
+                                }
+                            }
+                        }
                     }
                 });
             }
@@ -292,17 +578,24 @@ async fn generate_key_pair_with_progress() -> Result<(String, String), Box<dyn s
     Ok((public_key_str, private_key_str))
 }
 
-fn encrypt_message(public_key: &str, message: &str) -> Result<String, Box<dyn std::error::Error>> {
-    console::log!("🔑 Parsing X25519 public key...");
-    let recipient: x25519::Recipient = public_key.parse()?;
+fn encrypt_message(
+    public_keys: &[String],
+    message: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    console::log!(&format!(
+        "🔑 Parsing {} X25519 public key(s)...",
+        public_keys.len()
+    ));
+    let recipients = public_keys
+        .iter()
+        .map(|key| {
+            key.parse::<x25519::Recipient>()
+                .map(|recipient| Box::new(recipient) as Box<dyn age::Recipient>)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     console::log!("🔐 Encrypting message...");
-    let encryptor = Encryptor::with_recipients(
-        vec![Box::new(recipient) as Box<dyn age::Recipient>]
-            .iter()
-            .map(|r| r.as_ref()),
-    )
-    .expect("we provided a recipient");
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref()))?;
 
     let mut encrypted = vec![];
     let mut writer = encryptor.wrap_output(&mut encrypted)?;
@@ -373,13 +666,18 @@ fn generate_public_key_from_private(
 
 fn process_qr_data(data: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
     console::log!("🔄 Processing QR data");
+    let data = transport::decode_payload(data.trim())?;
+    let data = data.as_str();
     console::log!(&format!("📊 Data length: {}", data.len()));
     console::log!(&format!(
         "🔍 Data preview: {}...",
-        &data[..data.len().min(50)]
+        data.chars().take(50).collect::<String>()
     ));
 
-    if is_valid_age_public_key(data) {
+    if card::ContactCard::parse(data).is_some() {
+        console::log!("📇 Contact card recognized");
+        Ok(("add_contact_card".to_string(), data.to_string()))
+    } else if is_valid_age_public_key(data) {
         console::log!("🔑 Age public key recognized");
         Ok(("add_contact".to_string(), data.to_string()))
     } else if is_base64(data) && data.len() > 50 && data.len() < 2000 {
@@ -391,6 +689,92 @@ fn process_qr_data(data: &str) -> Result<(String, String), Box<dyn std::error::E
     }
 }
 
+fn encrypt_with_passphrase(
+    passphrase: &str,
+    data: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    console::log!("🔑 Deriving key from passphrase...");
+    let mut recipient = age::scrypt::Recipient::new(SecretString::from(passphrase.to_string()));
+    recipient.set_work_factor(PASSPHRASE_WORK_FACTOR);
+    let encryptor = Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))?;
+
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(data.as_bytes())?;
+    writer.finish()?;
+
+    console::log!(&format!("✅ Data encrypted: {} bytes", encrypted.len()));
+    Ok(encrypted)
+}
+
+fn decrypt_with_passphrase(
+    passphrase: &str,
+    data: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    console::log!("🔑 Deriving key from passphrase...");
+    let mut identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+    identity.set_max_work_factor(PASSPHRASE_WORK_FACTOR + 2);
+
+    let decryptor = Decryptor::new(data)?;
+    let mut reader = decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?;
+    let mut decrypted = String::new();
+    reader.read_to_string(&mut decrypted)?;
+
+    console::log!("✅ Data decrypted");
+    Ok(decrypted)
+}
+
+fn encrypt_file(public_key: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let recipient: x25519::Recipient = public_key.parse()?;
+    let encryptor = Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))?;
+
+    let mut encrypted = Vec::with_capacity(data.len() + 1024);
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(data)?;
+    writer.finish()?;
+
+    console::log!(&format!("✅ File encrypted: {} bytes", encrypted.len()));
+    Ok(encrypted)
+}
+
+fn decrypt_file(private_key: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let identity: x25519::Identity = private_key.parse()?;
+    let decryptor = Decryptor::new(data)?;
+    let mut reader = decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?;
+    let mut decrypted = Vec::with_capacity(data.len());
+    reader.read_to_end(&mut decrypted)?;
+
+    console::log!(&format!("✅ File decrypted: {} bytes", decrypted.len()));
+    Ok(decrypted)
+}
+
+fn decode_qr_image(image: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    console::log!(&format!("🖼️ Image size: {} bytes", image.len()));
+    let luma = image::load_from_memory(image)?.to_luma8();
+    let (width, height) = luma.dimensions();
+    console::log!(&format!("📐 Image dimensions: {}x{}", width, height));
+
+    let mut prepared =
+        rqrr::PreparedImage::prepare_from_greyscale(width as usize, height as usize, |x, y| {
+            luma.get_pixel(x as u32, y as u32)[0]
+        });
+    let grids = prepared.detect_grids();
+    console::log!(&format!("🔍 QR candidates found: {}", grids.len()));
+
+    for grid in grids {
+        match grid.decode() {
+            Ok((_meta, content)) => {
+                console::log!("✅ QR code decoded from image");
+                return Ok(content);
+            }
+            Err(e) => {
+                console::log!(&format!("⚠️ Failed to decode QR candidate: {:?}", e));
+            }
+        }
+    }
+    Err("No readable QR code found in the image".into())
+}
+
 fn is_valid_age_public_key(data: &str) -> bool {
     match data.parse::<x25519::Recipient>() {
         Ok(_) => {